@@ -0,0 +1,100 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use flat_bytes::Flat;
+use threema::crypto;
+use threema::packets::{Header, Message, Packet, Text};
+use threema::{MessageID, ThreemaID};
+
+fn header() -> Header {
+    Header {
+        sender: ThreemaID::from_string("ECHOECHO").unwrap(),
+        receiver: ThreemaID::from_string("*TESTGW0").unwrap(),
+        msg_id: MessageID::from_bytes([1, 2, 3, 4, 5, 6, 7, 8]),
+        timestamp: 1_600_000_000,
+        flags: 1,
+        nickname: "Echo".to_owned(),
+        nonce: [0xaa; 24],
+    }
+}
+
+fn text() -> Message {
+    Message::Text(Text {
+        message: "Grüezi mitenand! ".repeat(20),
+    })
+}
+
+fn serialization(c: &mut Criterion) {
+    let msg = text();
+    let msg_data = msg.serialize();
+    let packet = Packet::OutgoingMessage(header(), vec![0x55; 400]);
+    let packet_data = packet.serialize();
+
+    c.bench_function("message serialize", |b| {
+        b.iter(|| black_box(&msg).serialize())
+    });
+    c.bench_function("message deserialize", |b| {
+        b.iter(|| Message::try_deserialize(black_box(&msg_data)).unwrap())
+    });
+    c.bench_function("packet serialize", |b| {
+        b.iter(|| black_box(&packet).serialize())
+    });
+    c.bench_function("packet deserialize", |b| {
+        b.iter(|| Packet::try_deserialize(black_box(&packet_data)).unwrap())
+    });
+}
+
+fn crypto(c: &mut Criterion) {
+    let (peer_pub, peer_priv) = crypto::keypair_from_seed(&crypto::Seed([1; 32]));
+    let (own_pub, own_priv) = crypto::keypair_from_seed(&crypto::Seed([2; 32]));
+    let nonce = crypto::Nonce([3; 24]);
+    let mut data = text().serialize();
+    data.resize(data.len() + 16, 16);
+    let sealed = crypto::seal(&data, &nonce, &peer_pub, &own_priv);
+
+    c.bench_function("message seal", |b| {
+        b.iter(|| crypto::seal(black_box(&data), &nonce, &peer_pub, &own_priv))
+    });
+    c.bench_function("message open", |b| {
+        b.iter(|| crypto::open(black_box(&sealed), &nonce, &own_pub, &peer_priv).unwrap())
+    });
+}
+
+/// Encodes a message into a length prefixed frame like the client does, and back.
+fn frames(c: &mut Criterion) {
+    let (server_pub, server_priv) = crypto::keypair_from_seed(&crypto::Seed([4; 32]));
+    let (eph_pub, eph_priv) = crypto::keypair_from_seed(&crypto::Seed([5; 32]));
+    let (peer_pub, peer_priv) = crypto::keypair_from_seed(&crypto::Seed([6; 32]));
+    let frame_nonce = crypto::Nonce([7; 24]);
+    let msg_nonce = crypto::Nonce(header().nonce);
+
+    let encode = |msg: &Message| {
+        let mut data = msg.serialize();
+        data.resize(data.len() + 16, 16);
+        let ciphertext = crypto::seal(&data, &msg_nonce, &peer_pub, &eph_priv);
+        let packet = Packet::OutgoingMessage(header(), ciphertext).serialize();
+        let enc = crypto::seal(&packet, &frame_nonce, &server_pub, &eph_priv);
+        #[allow(clippy::cast_possible_truncation)]
+        let mut frame = (enc.len() as u16).to_le_bytes().to_vec();
+        frame.extend_from_slice(&enc);
+        frame
+    };
+    let decode = |frame: &[u8]| {
+        let packet = crypto::open(&frame[2..], &frame_nonce, &eph_pub, &server_priv).unwrap();
+        match Packet::try_deserialize(&packet).unwrap() {
+            Packet::OutgoingMessage(hdr, payload) => {
+                let data = crypto::open(&payload, &crypto::Nonce(hdr.nonce), &eph_pub, &peer_priv)
+                    .unwrap();
+                let data = threema::packets::unpad(&data).unwrap();
+                Message::try_deserialize(data).unwrap()
+            }
+            _ => unreachable!(),
+        }
+    };
+
+    let msg = text();
+    let frame = encode(&msg);
+    c.bench_function("frame encode", |b| b.iter(|| encode(black_box(&msg))));
+    c.bench_function("frame decode", |b| b.iter(|| decode(black_box(&frame))));
+}
+
+criterion_group!(benches, serialization, crypto, frames);
+criterion_main!(benches);
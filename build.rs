@@ -1,10 +1,17 @@
-use std::env;
-use std::fs;
-use std::path;
-use webpki::TrustAnchor;
-
 fn main() {
     println!("cargo:rerun-if-changed=src/ca.der");
+    // the Threema CA is only needed by the REST client
+    #[cfg(feature = "rest")]
+    write_ca();
+}
+
+#[cfg(feature = "rest")]
+fn write_ca() {
+    use std::env;
+    use std::fs;
+    use std::path;
+    use webpki::TrustAnchor;
+
     let ca = fs::read("src/ca.der").expect("Couldn't open ca.der");
     let trust_anchor = TrustAnchor::try_from_cert_der(&ca).expect("Couldn't parse ca.der");
     let src = "static THREEMA_CA: [TrustAnchor<'static>; 1] = ".to_string()
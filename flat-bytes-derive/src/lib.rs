@@ -2,332 +2,840 @@
 #![allow(clippy::missing_panics_doc)]
 
 use proc_macro::TokenStream;
+use proc_macro2::Ident;
 use quote::format_ident;
 use quote::quote;
+use quote::quote_spanned;
 use quote::ToTokens;
+use syn::parse::ParseStream;
 use syn::parse_macro_input;
+use syn::spanned::Spanned;
+use syn::Data;
+use syn::DeriveInput;
 use syn::Field;
 use syn::Fields;
 use syn::ItemEnum;
-use syn::ItemStruct;
 
-#[proc_macro_derive(Flat)]
-pub fn derive_flat(input: TokenStream) -> TokenStream {
-    #![allow(clippy::similar_names)]
+type Variants = syn::punctuated::Punctuated<syn::Variant, syn::Token![,]>;
+
+/// How a field is laid out, if not by its `Flat` impl.
+enum Encoding {
+    /// prefixed by its length of the given type
+    Len(Box<syn::Type>),
+    /// fixed size in bytes
+    Size(syn::LitInt),
+    /// `Option` which is present iff bytes remain
+    Trailing,
+    /// not serialized at all, `Default::default()` when deserializing
+    Skip,
+    /// consumes all remaining bytes, only allowed on the last field
+    Rest,
+}
+
+/// Options given via `#[flat(...)]` on a field or container.
+#[derive(Default)]
+struct FieldAttrs {
+    encoding: Option<Encoding>,
+    big_endian: bool,
+    /// use `Default::default()` if the input ends before the field
+    default: bool,
+    /// type of an enum's discriminant, if not given by `#[repr]`
+    repr: Option<syn::Path>,
+    /// variant catching unknown discriminants
+    other: bool,
+}
+
+impl FieldAttrs {
+    fn parse(attrs: &[syn::Attribute]) -> syn::Result<Self> {
+        let mut res = Self::default();
+        for attr in attrs.iter().filter(|a| a.path.is_ident("flat")) {
+            attr.parse_args_with(|input: ParseStream| {
+                while !input.is_empty() {
+                    let name: Ident = input.parse()?;
+                    let encoding = if name == "big_endian" {
+                        res.big_endian = true;
+                        None
+                    } else if name == "other" {
+                        res.other = true;
+                        None
+                    } else if name == "repr" {
+                        input.parse::<syn::Token![=]>()?;
+                        res.repr = Some(input.parse()?);
+                        None
+                    } else if name == "len" {
+                        input.parse::<syn::Token![=]>()?;
+                        let ty: syn::LitStr = input.parse()?;
+                        Some(Encoding::Len(Box::new(ty.parse()?)))
+                    } else if name == "size" {
+                        input.parse::<syn::Token![=]>()?;
+                        Some(Encoding::Size(input.parse()?))
+                    } else if name == "trailing" {
+                        Some(Encoding::Trailing)
+                    } else if name == "skip" {
+                        Some(Encoding::Skip)
+                    } else if name == "rest" {
+                        Some(Encoding::Rest)
+                    } else if name == "default" {
+                        res.default = true;
+                        None
+                    } else {
+                        return Err(syn::Error::new(
+                            name.span(),
+                            format!("unknown flat attribute `{name}`"),
+                        ));
+                    };
+                    if let Some(encoding) = encoding {
+                        if res.encoding.replace(encoding).is_some() {
+                            return Err(syn::Error::new(
+                                name.span(),
+                                "only one of `len`, `size`, `trailing`, `skip` and `rest` is allowed",
+                            ));
+                        }
+                    }
+                    if !input.is_empty() {
+                        input.parse::<syn::Token![,]>()?;
+                    }
+                }
+                Ok(())
+            })?;
+        }
+        Ok(res)
+    }
+
+    fn parse_field(attrs: &[syn::Attribute]) -> syn::Result<Self> {
+        let res = Self::parse(attrs)?;
+        if res.repr.is_some() {
+            return Err(attr_error(attrs, "`repr` is only allowed on enums"));
+        }
+        if res.other {
+            return Err(attr_error(
+                attrs,
+                "`other` is only allowed on enum variants",
+            ));
+        }
+        Ok(res)
+    }
 
-    let input = parse_macro_input!(input as ItemStruct);
+    /// Parses the options of an enum variant, which only supports `other`.
+    fn parse_variant(attrs: &[syn::Attribute]) -> syn::Result<Self> {
+        let res = Self::parse(attrs)?;
+        if res.encoding.is_some() || res.repr.is_some() || res.big_endian || res.default {
+            return Err(attr_error(
+                attrs,
+                "only `other` is allowed on enum variants",
+            ));
+        }
+        Ok(res)
+    }
 
-    let ident = &input.ident;
+    /// Parses the options of a struct or enum, which only supports `big_endian` and `repr`.
+    fn parse_container(attrs: &[syn::Attribute]) -> syn::Result<Self> {
+        let res = Self::parse(attrs)?;
+        if res.encoding.is_some() || res.other || res.default {
+            return Err(attr_error(
+                attrs,
+                "`len`, `size`, `trailing`, `skip`, `rest` and `default` are only allowed on fields",
+            ));
+        }
+        Ok(res)
+    }
+}
 
-    let fields: Vec<Field> = match input.fields {
-        Fields::Named(ref n) => n.named.iter().cloned().collect(),
-        Fields::Unnamed(ref un) => un.unnamed.iter().cloned().collect(),
-        Fields::Unit => vec![],
+/// Error pointing at the first `#[flat(...)]` attribute, for options not allowed there.
+fn attr_error(attrs: &[syn::Attribute], message: &str) -> syn::Error {
+    match attrs.iter().find(|a| a.path.is_ident("flat")) {
+        Some(attr) => syn::Error::new_spanned(attr, message),
+        None => syn::Error::new(proc_macro2::Span::call_site(), message),
+    }
+}
+
+/// Statement serializing the field `f` from `value` (of type `&T`) into `__flat_out`.
+fn serialize_field(
+    f: &Field,
+    value: &proc_macro2::TokenStream,
+    big_endian: bool,
+) -> proc_macro2::TokenStream {
+    let ty = &f.ty;
+    let attrs = match FieldAttrs::parse_field(&f.attrs) {
+        Ok(attrs) => attrs,
+        Err(e) => return e.to_compile_error(),
     };
+    let be = big_endian || attrs.big_endian;
+    match attrs.encoding {
+        Some(Encoding::Len(len)) => {
+            quote! { flat_bytes::length::serialize::<#len, #ty>(#value, #be, __flat_out); }
+        }
+        Some(Encoding::Size(size)) => {
+            quote! { flat_bytes::fixed::serialize::<#ty>(#value, #size, __flat_out); }
+        }
+        Some(Encoding::Trailing) => {
+            quote! { flat_bytes::optional::serialize_trailing(#value, #be, __flat_out); }
+        }
+        Some(Encoding::Skip) => quote! { let _ = #value; },
+        Some(Encoding::Rest) => {
+            quote! { flat_bytes::rest::serialize::<#ty>(#value, #be, __flat_out); }
+        }
+        None if be => {
+            quote_spanned! {ty.span()=> __flat_out.append(&mut <#ty as flat_bytes::Flat>::serialize_be(#value)); }
+        }
+        None => {
+            quote_spanned! {ty.span()=> <#ty as flat_bytes::Flat>::serialize_into(#value, __flat_out); }
+        }
+    }
+}
 
-    let fields_ser = fields.iter().enumerate().map(|(idx, f)| {
-        let ty = &f.ty;
-        if let Some(i) = &f.ident {
-            quote! {
-                res.append(&mut <#ty as Flat>::serialize(&self.#i));
-            }
-        } else {
-            let idx = syn::Index::from(idx);
-            quote! {
-                res.append(&mut <#ty as Flat>::serialize(&self.#idx));
-            }
+/// Expression for the serialized size of the field `f` with `value` (of type `&T`).
+fn field_size(f: &Field, value: &proc_macro2::TokenStream) -> proc_macro2::TokenStream {
+    let ty = &f.ty;
+    let attrs = match FieldAttrs::parse_field(&f.attrs) {
+        Ok(attrs) => attrs,
+        Err(e) => return e.to_compile_error(),
+    };
+    match attrs.encoding {
+        Some(Encoding::Len(len)) => {
+            quote! { flat_bytes::length::serialized_size::<#len, #ty>(#value) }
         }
-    });
+        Some(Encoding::Size(size)) => quote! { #size },
+        Some(Encoding::Trailing) => quote! { flat_bytes::optional::trailing_size(#value) },
+        Some(Encoding::Skip) => quote! {{
+            let _ = #value;
+            0
+        }},
+        Some(Encoding::Rest) => quote! { flat_bytes::rest::serialized_size::<#ty>(#value) },
+        None => quote_spanned! {ty.span()=> <#ty as flat_bytes::Flat>::serialized_size(#value) },
+    }
+}
 
-    let fields_der = fields.iter().enumerate().map(|(idx, f)| {
-        let ty = &f.ty;
-        if let Some(i) = &f.ident {
-            quote! {
-                let #i = <#ty as flat_bytes::Flat>::deserialize_with_size(data)?;
-                total += #i.1;
-                let data = &data[#i.1..];
-                let #i = #i.0;
-            }
-        } else {
-            let i = format_ident!("field{}", idx);
-            quote! {
-                let #i = <#ty as flat_bytes::Flat>::deserialize_with_size(data)?;
-                total += #i.1;
-                let data = &data[#i.1..];
-                let #i = #i.0;
+/// Statement checking the lengths of the field `f` with `value` (of type `&T`), see
+/// `Flat::check_lengths`.
+///
+/// Errors are attributed to `context`, i.e. the field's name.
+fn check_field(
+    f: &Field,
+    value: &proc_macro2::TokenStream,
+    context: &str,
+) -> proc_macro2::TokenStream {
+    let ty = &f.ty;
+    let attrs = match FieldAttrs::parse_field(&f.attrs) {
+        Ok(attrs) => attrs,
+        Err(e) => return e.to_compile_error(),
+    };
+    let check = match attrs.encoding {
+        Some(Encoding::Len(len)) => quote! { flat_bytes::length::check::<#len, #ty>(#value) },
+        Some(Encoding::Trailing) => quote! { flat_bytes::optional::check_trailing(#value) },
+        Some(Encoding::Rest) => quote! { flat_bytes::rest::check::<#ty>(#value) },
+        Some(Encoding::Size(_) | Encoding::Skip) => return quote! { let _ = #value; },
+        None => quote_spanned! {ty.span()=> <#ty as flat_bytes::Flat>::check_lengths(#value) },
+    };
+    quote! { #check.map_err(|e| e.within(#context))?; }
+}
+
+/// Statements deserializing the field `f` into `name`, advancing the input and total size.
+///
+/// Errors are attributed to `context`, i.e. the field's name.
+fn deserialize_field(
+    f: &Field,
+    name: &Ident,
+    big_endian: bool,
+    context: &str,
+) -> proc_macro2::TokenStream {
+    let ty = &f.ty;
+    let attrs = match FieldAttrs::parse_field(&f.attrs) {
+        Ok(attrs) => attrs,
+        Err(e) => return e.to_compile_error(),
+    };
+    if let Some(Encoding::Skip) = attrs.encoding {
+        return quote! {
+            let #name: #ty = ::std::default::Default::default();
+        };
+    }
+    let be = big_endian || attrs.big_endian;
+    // only the default path reports errors on its own
+    let optional = be || attrs.encoding.is_some();
+    let call = match attrs.encoding {
+        Some(Encoding::Len(len)) => {
+            quote! { flat_bytes::length::deserialize::<#len, #ty>(__flat_data, #be) }
+        }
+        Some(Encoding::Size(size)) => {
+            quote! { flat_bytes::fixed::deserialize::<#ty>(__flat_data, #size) }
+        }
+        Some(Encoding::Trailing) => {
+            quote! { flat_bytes::optional::deserialize_trailing(__flat_data, #be) }
+        }
+        Some(Encoding::Skip) => unreachable!(),
+        Some(Encoding::Rest) => quote! { flat_bytes::rest::deserialize::<#ty>(__flat_data, #be) },
+        None if be => {
+            quote_spanned! {ty.span()=> <#ty as flat_bytes::Flat>::deserialize_be_with_size(__flat_data) }
+        }
+        None => {
+            quote_spanned! {ty.span()=> <#ty as flat_bytes::Flat>::try_deserialize_with_size(__flat_data) }
+        }
+    };
+    let call = if optional {
+        quote! { #call.ok_or_else(flat_bytes::DeserializeError::new::<#ty>) }
+    } else {
+        call
+    };
+    let call = if attrs.default {
+        quote! {
+            if __flat_data.is_empty() {
+                Ok((::std::default::Default::default(), 0))
+            } else {
+                #call
             }
         }
-    });
+    } else {
+        call
+    };
+    quote! {
+        let (#name, __flat_size): (#ty, usize) =
+            #call.map_err(|e| e.within(#context, __flat_total))?;
+        __flat_total += __flat_size;
+        let __flat_data = &__flat_data[__flat_size..];
+    }
+}
 
-    let alloc = match input.fields {
-        Fields::Named(ref n) => {
-            let names = n.named.iter().map(|f| f.ident.as_ref().unwrap());
-            quote! {
-                #ident{#(#names),*}
-            }
+/// Ensures that only the last field is marked `rest`.
+fn check_rest(fields: &Fields) -> syn::Result<()> {
+    for f in fields.iter().rev().skip(1) {
+        if let Some(Encoding::Rest) = FieldAttrs::parse_field(&f.attrs)?.encoding {
+            return Err(syn::Error::new_spanned(
+                f,
+                "`rest` is only allowed on the last field",
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Names used for binding the fields while (de)serializing.
+fn field_names(fields: &Fields) -> Vec<Ident> {
+    fields
+        .iter()
+        .enumerate()
+        .map(|(i, f)| {
+            f.ident
+                .clone()
+                .unwrap_or_else(|| format_ident!("field{}", i))
+        })
+        .collect()
+}
+
+/// Constructor or pattern for `path` with `fields` bound to `names`.
+fn construct(
+    path: &proc_macro2::TokenStream,
+    fields: &Fields,
+    names: &[Ident],
+) -> proc_macro2::TokenStream {
+    match fields {
+        Fields::Named(_) => quote! { #path{#(#names),*} },
+        Fields::Unnamed(_) => quote! { #path(#(#names),*) },
+        Fields::Unit => path.clone(),
+    }
+}
+
+#[proc_macro_derive(Flat, attributes(flat))]
+pub fn derive_flat(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    let attrs = match FieldAttrs::parse_container(&input.attrs) {
+        Ok(attrs) => attrs,
+        Err(e) => return e.to_compile_error().into(),
+    };
+
+    match &input.data {
+        Data::Struct(s) => {
+            derive_struct(&input.ident, &input.generics, &s.fields, attrs.big_endian).into()
+        }
+        Data::Enum(e) => {
+            let dtype = match repr(&input.ident, attrs.repr, &input.attrs) {
+                Ok(dtype) => dtype,
+                Err(e) => return e.to_compile_error().into(),
+            };
+            derive_enum(
+                &input.ident,
+                &input.generics,
+                &e.variants,
+                &dtype,
+                attrs.big_endian,
+            )
+            .into()
         }
-        Fields::Unnamed(ref un) => {
-            let names = (0..un.unnamed.len()).map(|i| format_ident!("field{}", i));
-            quote! {
-                #ident(#(#names),*)
+        Data::Union(_) => syn::Error::new(input.ident.span(), "unions are not supported")
+            .to_compile_error()
+            .into(),
+    }
+}
+
+/// Generics of the impl, requiring every type parameter to be `Flat` itself.
+fn add_bounds(generics: &syn::Generics) -> syn::Generics {
+    let mut generics = generics.clone();
+    let params: Vec<_> = generics.type_params().map(|p| p.ident.clone()).collect();
+    let where_clause = generics.make_where_clause();
+    for param in params {
+        where_clause
+            .predicates
+            .push(syn::parse_quote! { #param: flat_bytes::Flat });
+    }
+    generics
+}
+
+fn derive_struct(
+    ident: &Ident,
+    generics: &syn::Generics,
+    fields: &Fields,
+    big_endian: bool,
+) -> proc_macro2::TokenStream {
+    if let Err(e) = check_rest(fields) {
+        return e.to_compile_error();
+    }
+    let names = field_names(fields);
+
+    let accessors: Vec<_> = fields
+        .iter()
+        .enumerate()
+        .map(|(idx, f)| {
+            if let Some(i) = &f.ident {
+                quote! { &self.#i }
+            } else {
+                let idx = syn::Index::from(idx);
+                quote! { &self.#idx }
             }
+        })
+        .collect();
+    let serializers = fields
+        .iter()
+        .zip(&accessors)
+        .map(|(f, access)| serialize_field(f, access, big_endian));
+    let sizes = fields
+        .iter()
+        .zip(&accessors)
+        .map(|(f, access)| field_size(f, access));
+    let contexts: Vec<_> = fields
+        .iter()
+        .enumerate()
+        .map(|(idx, f)| {
+            f.ident
+                .as_ref()
+                .map_or_else(|| idx.to_string(), ToString::to_string)
+        })
+        .collect();
+    let checks = fields
+        .iter()
+        .zip(&accessors)
+        .zip(&contexts)
+        .map(|((f, access), context)| check_field(f, access, context));
+
+    let deserializers = fields
+        .iter()
+        .zip(&names)
+        .zip(&contexts)
+        .map(|((f, name), context)| deserialize_field(f, name, big_endian, context));
+
+    let alloc = construct(&quote! { Self }, fields, &names);
+    let generics = add_bounds(generics);
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
+    quote! {
+      impl #impl_generics flat_bytes::Flat for #ident #ty_generics #where_clause {
+        fn deserialize_with_size(__flat_data: &[u8]) -> Option<(Self, usize)> {
+            Self::try_deserialize_with_size(__flat_data).ok()
         }
-        Fields::Unit => ident.to_token_stream(),
-    };
 
-    let output = quote! {
-      impl flat_bytes::Flat for #ident {
-        fn deserialize_with_size(data: &[u8]) -> Option<(Self, usize)> {
-            let mut total = 0;
-            #(#fields_der)*
-            Some((#alloc, total))
+        fn try_deserialize_with_size(
+            __flat_data: &[u8],
+        ) -> ::std::result::Result<(Self, usize), flat_bytes::DeserializeError> {
+            let mut __flat_total = 0;
+            #(#deserializers)*
+            Ok((#alloc, __flat_total))
         }
 
         fn serialize(&self) -> Vec<u8> {
-            use flat_bytes::Flat;
-            let mut res = vec![];
-            #(#fields_ser;)*
-            res
+            let mut __flat_out = Vec::with_capacity(self.serialized_size());
+            self.serialize_into(&mut __flat_out);
+            __flat_out
+        }
+
+        fn serialize_into(&self, __flat_out: &mut Vec<u8>) {
+            #(#serializers)*
+        }
+
+        fn serialized_size(&self) -> usize {
+            0 #(+ #sizes)*
+        }
+
+        fn check_lengths(&self) -> ::std::result::Result<(), flat_bytes::SerializeError> {
+            #(#checks)*
+            Ok(())
         }
       }
-    };
-    output.into()
+    }
 }
 
-fn derive_serialize(input: &ItemEnum, dtype: &syn::Path) -> proc_macro2::TokenStream {
-    let mut last_idx = 0;
-    let match_arms = input.variants.iter().map(|v| {
-        let i = v.ident.clone();
-        let d = v
-            .discriminant
-            .as_ref()
-            .and_then(|(_, e)| match e {
-                syn::Expr::Lit(syn::ExprLit {
-                    lit: syn::Lit::Int(i),
-                    ..
-                }) => i.base10_parse::<u64>().ok(),
-                _ => None,
-            })
-            .unwrap_or(last_idx + 1);
-        last_idx = d;
-        match &v.fields {
-            syn::Fields::Unit => quote! {
-              Self::#i => {
-                let i = #d as #dtype;
-                res.extend_from_slice(&i.to_le_bytes());
-              }
-            },
-            syn::Fields::Unnamed(fu) => {
-                let fields = fu
-                    .unnamed
-                    .iter()
-                    .enumerate()
-                    .map(|(i, f)| {
-                        let ty = &f.ty;
-                        let i = format_ident!("field{}", i);
-                        let t = quote! {
-                            &mut <#ty as Flat>::serialize(#i)
-                        };
-                        (i, t)
-                    })
-                    .collect::<Vec<_>>();
-                let (names, fields): (Vec<_>, Vec<_>) = fields.iter().cloned().unzip();
-                quote! {
-                  Self::#i(#(#names),*) => {
-                    let i = #d as #dtype;
-                    res.extend_from_slice(&i.to_le_bytes());
-                    #(
-                      res.append(#fields);
-                    )*
-                  }
-                }
+/// Names of constants holding the discriminant of every variant, and their definitions.
+///
+/// Discriminants can be any const expression, missing ones follow the implicit
+/// `previous + 1` rule.
+fn discriminants(variants: &Variants, dtype: &syn::Path) -> (Vec<Ident>, proc_macro2::TokenStream) {
+    let mut names = vec![];
+    let mut defs = proc_macro2::TokenStream::new();
+    for (idx, v) in variants.iter().enumerate() {
+        let name = format_ident!("__FLAT_DISCRIMINANT_{}", idx);
+        let value = match (&v.discriminant, names.last()) {
+            (Some((_, e)), _) => quote! { #e },
+            (None, Some(prev)) => quote! { #prev + 1 },
+            (None, None) => quote! { 0 },
+        };
+        defs.extend(quote! {
+            #[allow(dead_code)]
+            const #name: #dtype = #value;
+        });
+        names.push(name);
+    }
+    (names, defs)
+}
+
+/// Type of the discriminant given by `#[flat(repr = ...)]` or `#[repr(...)]`.
+fn repr(
+    ident: &Ident,
+    flat: Option<syn::Path>,
+    attrs: &[syn::Attribute],
+) -> syn::Result<syn::Path> {
+    if let Some(path) = flat {
+        return Ok(path);
+    }
+    attrs
+        .iter()
+        .flat_map(syn::Attribute::parse_meta)
+        .find_map(|m| {
+            if !m.path().is_ident("repr") {
+                return None;
             }
-            syn::Fields::Named(fs) => {
-                let fields = fs
-                    .named
-                    .iter()
-                    .map(|f| {
-                        let ty = &f.ty;
-                        let i = f.ident.as_ref().unwrap();
-                        (
-                            i,
-                            quote! {
-                                &mut <#ty as Flat>::serialize(#i)
-                            },
-                        )
-                    })
-                    .collect::<Vec<_>>();
-                let (names, fields): (Vec<_>, Vec<_>) = fields.iter().cloned().unzip();
-                quote! {
-                  Self::#i{#(#names),*} => {
-                    let i = #d as #dtype;
-                    res.extend_from_slice(&i.to_le_bytes());
-                    #(
-                      res.append(#fields);
-                    )*
-                  }
-                }
+            match m {
+                syn::Meta::List(l) => match l.nested.first() {
+                    Some(syn::NestedMeta::Meta(m)) => Some(m.path().clone()),
+                    _ => None,
+                },
+                _ => None,
             }
+        })
+        .ok_or_else(|| {
+            syn::Error::new(
+                ident.span(),
+                format!("flat_enum requires #[repr(uN)] or #[flat(repr = uN)] on `{ident}`"),
+            )
+        })
+}
+
+/// The variant marked `#[flat(other)]`, which must look like `Unknown(u8, Vec<u8>)`.
+fn other_variant(variants: &Variants) -> syn::Result<Option<&syn::Variant>> {
+    let mut res = None;
+    for v in variants {
+        if !FieldAttrs::parse_variant(&v.attrs)?.other {
+            continue;
         }
-    });
+        if res.replace(v).is_some() {
+            return Err(syn::Error::new(
+                v.ident.span(),
+                "only one variant can be marked `other`",
+            ));
+        }
+        if !matches!(&v.fields, Fields::Unnamed(f) if f.unnamed.len() == 2) {
+            return Err(syn::Error::new(
+                v.ident.span(),
+                "`other` variant must have the discriminant and remaining bytes as fields",
+            ));
+        }
+    }
+    Ok(res)
+}
+
+/// Whether `v` is a regular variant, i.e. not the `other` one.
+fn is_known(v: &syn::Variant, other: Option<&syn::Variant>) -> bool {
+    other.is_none_or(|o| o.ident != v.ident)
+}
 
+/// Body of `check_lengths`.
+fn derive_check(variants: &Variants, other: Option<&syn::Variant>) -> proc_macro2::TokenStream {
+    let other_arm = other.map(|v| {
+        let i = &v.ident;
+        quote! { Self::#i(..) => {} }
+    });
+    let arms = variants.iter().filter(|v| is_known(v, other)).map(|v| {
+        let i = &v.ident;
+        let names = field_names(&v.fields);
+        let pattern = construct(&quote! { Self::#i }, &v.fields, &names);
+        let checks = v
+            .fields
+            .iter()
+            .zip(&names)
+            .enumerate()
+            .map(|(idx, (f, name))| {
+                let context = f
+                    .ident
+                    .as_ref()
+                    .map_or_else(|| format!("{i}.{idx}"), |f| format!("{i}.{f}"));
+                check_field(f, &name.to_token_stream(), &context)
+            });
+        quote! {
+          #pattern => {
+            #(#checks)*
+          }
+        }
+    });
     quote! {
-      let mut res: Vec<u8> = vec![];
       match self {
-        #(#match_arms),*
+        #(#arms,)*
+        #other_arm
       }
-      res
+      Ok(())
     }
 }
 
-fn derive_deserialize(input: &ItemEnum, dtype: &syn::Path) -> proc_macro2::TokenStream {
-    let ident = &input.ident;
-    let mut last_idx = 0;
-    let match_arms = input.variants.iter().map(|v| {
-        let i = v.ident.clone();
-        let d = v
-            .discriminant
-            .as_ref()
-            .and_then(|(_, e)| match e {
-                syn::Expr::Lit(syn::ExprLit {
-                    lit: syn::Lit::Int(i),
-                    ..
-                }) => i.base10_parse::<u64>().ok(),
-                _ => None,
-            })
-            .unwrap_or(last_idx + 1);
-        last_idx = d;
-        match &v.fields {
-            syn::Fields::Unit => quote! {
-              #d => {
-                Some((#ident::#i, total))
-              }
-            },
-            syn::Fields::Unnamed(fu) => {
-                let fields = fu
-                    .unnamed
-                    .iter()
-                    .enumerate()
-                    .map(|(i, f)| {
-                        let name = quote::format_ident!("field{}", i);
-                        let ty = &f.ty;
-                        quote! {
-                          let #name = #ty::deserialize_with_size(data)?;
-                          let data = &data[#name.1..];
-                          total += #name.1;
-                          let #name = #name.0;
-                        }
-                    })
-                    .collect::<Vec<_>>();
-                let field_names = fu
-                    .unnamed
-                    .iter()
-                    .enumerate()
-                    .map(|(i, _f)| quote::format_ident!("field{}", i))
-                    .collect::<Vec<_>>();
+/// Bodies of `serialize_into` and `serialized_size`.
+fn derive_serialize(
+    variants: &Variants,
+    other: Option<&syn::Variant>,
+    dtype: &syn::Path,
+    big_endian: bool,
+) -> (proc_macro2::TokenStream, proc_macro2::TokenStream) {
+    let to_bytes = if big_endian {
+        quote! { to_be_bytes }
+    } else {
+        quote! { to_le_bytes }
+    };
+    let (other_arm, other_size) = other
+        .map(|v| {
+            let i = &v.ident;
+            (
                 quote! {
-                  #d => {
-                    #(
-                      #fields
-                    )*
-                    Some((#ident::#i(#(#field_names),*), total))
+                  Self::#i(d, rest) => {
+                    __flat_out.extend_from_slice(&d.#to_bytes());
+                    __flat_out.extend_from_slice(rest);
                   }
-                }
-            }
-            syn::Fields::Named(fs) => {
-                let fields = fs
-                    .named
-                    .iter()
-                    .map(|f| {
-                        let name = f.ident.clone().unwrap();
-                        let ty = &f.ty;
-                        quote! {
-                          let #name = #ty::deserialize_with_size(data)?;
-                          let data = &data[#name.1..];
-                          total += #name.1;
-                          let #name = #name.0;
-                        }
-                    })
-                    .collect::<Vec<_>>();
-                let field_names = fs
-                    .named
-                    .iter()
-                    .map(|f| f.ident.clone().unwrap())
-                    .collect::<Vec<_>>();
+                },
                 quote! {
-                  #d => {
-                    #(
-                      #fields
-                    )*
-                    Some((#ident::#i{#(#field_names),*}, total))
-                  }
-                }
-            }
+                  Self::#i(_, rest) => ::std::mem::size_of::<#dtype>() + rest.len(),
+                },
+            )
+        })
+        .unzip();
+    let (discriminants, consts) = discriminants(variants, dtype);
+    let known: Vec<_> = variants
+        .iter()
+        .zip(discriminants)
+        .filter(|(v, _)| is_known(v, other))
+        .collect();
+    let match_arms = known.iter().map(|(v, d)| {
+        let i = &v.ident;
+        let names = field_names(&v.fields);
+        let pattern = construct(&quote! { Self::#i }, &v.fields, &names);
+        let fields = v
+            .fields
+            .iter()
+            .zip(&names)
+            .map(|(f, name)| serialize_field(f, &name.to_token_stream(), big_endian));
+        quote! {
+          #pattern => {
+            __flat_out.extend_from_slice(&#d.#to_bytes());
+            #(#fields)*
+          }
+        }
+    });
+    let size_arms = known.iter().map(|(v, _)| {
+        let i = &v.ident;
+        let names = field_names(&v.fields);
+        let pattern = construct(&quote! { Self::#i }, &v.fields, &names);
+        let sizes = v
+            .fields
+            .iter()
+            .zip(&names)
+            .map(|(f, name)| field_size(f, &name.to_token_stream()));
+        quote! {
+          #pattern => ::std::mem::size_of::<#dtype>() #(+ #sizes)*
+        }
+    });
+
+    (
+        quote! {
+          #consts
+          match self {
+            #(#match_arms,)*
+            #other_arm
+          }
+        },
+        quote! {
+          match self {
+            #(#size_arms,)*
+            #other_size
+          }
+        },
+    )
+}
+
+fn derive_deserialize(
+    variants: &Variants,
+    other: Option<&syn::Variant>,
+    dtype: &syn::Path,
+    big_endian: bool,
+) -> proc_macro2::TokenStream {
+    let from_bytes = if big_endian {
+        quote! { from_be_bytes }
+    } else {
+        quote! { from_le_bytes }
+    };
+    let fallback = if let Some(v) = other {
+        let i = &v.ident;
+        quote! {
+          _ => Ok((
+            Self::#i(__flat_idx, __flat_data.to_vec()),
+            __flat_total + __flat_data.len(),
+          )),
+        }
+    } else {
+        quote! {
+          _ => Err(flat_bytes::DeserializeError::new::<Self>()
+              .with_detail(format!("unknown discriminant {:#x}", __flat_idx))),
+        }
+    };
+    let (discriminants, consts) = discriminants(variants, dtype);
+    let known = variants
+        .iter()
+        .zip(discriminants)
+        .filter(|(v, _)| is_known(v, other));
+    let match_arms = known.map(|(v, d)| {
+        let i = &v.ident;
+        let names = field_names(&v.fields);
+        let alloc = construct(&quote! { Self::#i }, &v.fields, &names);
+        let fields = v
+            .fields
+            .iter()
+            .zip(&names)
+            .enumerate()
+            .map(|(idx, (f, name))| {
+                let context = f
+                    .ident
+                    .as_ref()
+                    .map_or_else(|| format!("{i}.{idx}"), |f| format!("{i}.{f}"));
+                deserialize_field(f, name, big_endian, &context)
+            });
+        quote! {
+          #d => {
+            #(#fields)*
+            Ok((#alloc, __flat_total))
+          }
         }
     });
 
     quote! {
-      if data.len() < ::std::mem::size_of::<#dtype>() {
-        return None
+      if __flat_data.len() < ::std::mem::size_of::<#dtype>() {
+        return Err(flat_bytes::DeserializeError::new::<#dtype>())
       }
-      let idx = {
+      let __flat_idx = {
         let mut tmp = [0u8; ::std::mem::size_of::<#dtype>()];
-        tmp.copy_from_slice(&data[..::std::mem::size_of::<#dtype>()]);
-        #dtype::from_le_bytes(tmp) as u64
+        tmp.copy_from_slice(&__flat_data[..::std::mem::size_of::<#dtype>()]);
+        #dtype::#from_bytes(tmp)
       };
-      let data = &data[::std::mem::size_of::<#dtype>()..];
-      let mut total = ::std::mem::size_of::<#dtype>();
+      let __flat_data = &__flat_data[::std::mem::size_of::<#dtype>()..];
+      let mut __flat_total = ::std::mem::size_of::<#dtype>();
 
-      match idx {
+      #consts
+      match __flat_idx {
         #(#match_arms,)*
-        _ => None,
+        #fallback
+      }
+    }
+}
+
+fn derive_enum(
+    ident: &Ident,
+    generics: &syn::Generics,
+    variants: &Variants,
+    dtype: &syn::Path,
+    big_endian: bool,
+) -> proc_macro2::TokenStream {
+    let other = match other_variant(variants) {
+        Ok(other) => other,
+        Err(e) => return e.to_compile_error(),
+    };
+    if let Err(e) = variants.iter().try_for_each(|v| check_rest(&v.fields)) {
+        return e.to_compile_error();
+    }
+    let (serialize, size) = derive_serialize(variants, other, dtype, big_endian);
+    let deserialize = derive_deserialize(variants, other, dtype, big_endian);
+    let check = derive_check(variants, other);
+    let generics = add_bounds(generics);
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
+    quote! {
+      impl #impl_generics flat_bytes::Flat for #ident #ty_generics #where_clause {
+        fn deserialize_with_size(__flat_data: &[u8]) -> Option<(Self, usize)> {
+          Self::try_deserialize_with_size(__flat_data).ok()
+        }
+
+        fn try_deserialize_with_size(
+          __flat_data: &[u8],
+        ) -> ::std::result::Result<(Self, usize), flat_bytes::DeserializeError> {
+          #deserialize
+        }
+
+        fn serialize(&self) -> Vec<u8> {
+          let mut __flat_out = Vec::with_capacity(self.serialized_size());
+          self.serialize_into(&mut __flat_out);
+          __flat_out
+        }
+
+        fn serialize_into(&self, __flat_out: &mut Vec<u8>) {
+          #serialize
+        }
+
+        fn serialized_size(&self) -> usize {
+          #size
+        }
+
+        fn check_lengths(&self) -> ::std::result::Result<(), flat_bytes::SerializeError> {
+          #check
+        }
       }
     }
 }
 
+/// Defines an enum and implements `Flat` for it.
+///
+/// Predates `#[derive(Flat)]` on enums and strips the discriminants from the emitted
+/// enum, which isn't needed anymore with `#[repr(uN)]`.
 #[proc_macro]
 pub fn flat_enum(input: TokenStream) -> TokenStream {
     let input = parse_macro_input!(input as ItemEnum);
+    let attrs = match FieldAttrs::parse_container(&input.attrs) {
+        Ok(attrs) => attrs,
+        Err(e) => return e.to_compile_error().into(),
+    };
     let mut enum_output = input.clone();
-    for v in enum_output.variants.iter_mut() {
+    enum_output.attrs.retain(|a| !a.path.is_ident("flat"));
+    for v in &mut enum_output.variants {
         v.discriminant = None;
+        v.attrs.retain(|a| !a.path.is_ident("flat"));
+        for f in &mut v.fields {
+            f.attrs.retain(|a| !a.path.is_ident("flat"));
+        }
     }
 
-    let ident = &input.ident;
-    let dtype = input
-        .attrs
-        .iter()
-        .flat_map(syn::Attribute::parse_meta)
-        .find_map(|m| {
-            if !m.path().is_ident("repr") {
-                return None;
-            }
-            match m {
-                syn::Meta::List(l) => match l.nested.first() {
-                    Some(syn::NestedMeta::Meta(m)) => Some(m.path().clone()),
-                    _ => None,
-                },
-                _ => None,
-            }
-        })
-        .unwrap();
-
-    let serialize = derive_serialize(&input, &dtype);
-    let deserialize = derive_deserialize(&input, &dtype);
+    let dtype = match repr(&input.ident, attrs.repr, &input.attrs) {
+        Ok(dtype) => dtype,
+        Err(e) => return e.to_compile_error().into(),
+    };
+    let flat = derive_enum(
+        &input.ident,
+        &input.generics,
+        &input.variants,
+        &dtype,
+        attrs.big_endian,
+    );
 
     (quote! {
       #enum_output
 
-      impl flat_bytes::Flat for #ident {
-        fn deserialize_with_size(data: &[u8]) -> Option<(Self, usize)> {
-          #deserialize
-        }
-
-        fn serialize(&self) -> Vec<u8> {
-          use flat_bytes::Flat;
-          #serialize
-        }
-      }
+      #flat
     })
     .into()
 }
@@ -0,0 +1,54 @@
+#![no_main]
+
+use flat_bytes::Flat;
+use libfuzzer_sys::fuzz_target;
+
+#[derive(Flat, Debug, PartialEq)]
+struct Header {
+    magic: [u8; 2],
+    #[flat(size = 8)]
+    name: String,
+    #[flat(len = "u8")]
+    ids: Vec<u16>,
+    flag: Option<u8>,
+}
+
+#[derive(Flat, Debug, PartialEq)]
+#[flat(big_endian)]
+struct Framed {
+    header: Header,
+    #[flat(default)]
+    version: u16,
+    #[flat(skip)]
+    cached: Option<String>,
+    #[flat(trailing)]
+    extra: Option<u32>,
+}
+
+#[derive(Flat, Debug, PartialEq)]
+#[repr(u8)]
+enum Packet {
+    Empty = 1,
+    Framed(Framed),
+    Pair {
+        a: u16,
+        #[flat(len = "u16")]
+        b: String,
+    },
+    Body(u8, #[flat(rest)] Vec<u16>),
+    #[flat(other)]
+    Unknown(u8, Vec<u8>),
+}
+
+fuzz_target!(|data: &[u8]| {
+    // `size` and `default` fields don't roundtrip byte for byte, so only check that
+    // whatever is accepted stays within bounds and can be serialized and read back.
+    if let Some((packet, size)) = Packet::deserialize_with_size(data) {
+        assert!(size <= data.len());
+        let out = packet.serialize();
+        assert_eq!(out.len(), packet.serialized_size());
+        let (again, again_size) = Packet::deserialize_with_size(&out).expect("reparse");
+        assert_eq!(again_size, out.len());
+        assert_eq!(again.serialize(), out);
+    }
+});
@@ -0,0 +1,28 @@
+#![no_main]
+
+use flat_bytes::Flat;
+use libfuzzer_sys::fuzz_target;
+
+/// Deserializes `data` as `T` in both byte orders and serializes any result again.
+fn check<T: Flat + PartialEq + std::fmt::Debug>(data: &[u8]) {
+    if let Some((value, size)) = T::deserialize_with_size(data) {
+        assert!(size <= data.len());
+        assert_eq!(value.serialized_size(), value.serialize().len());
+        assert_eq!(T::deserialize(&value.serialize()), Some(value));
+    }
+    if let Some((value, size)) = T::deserialize_be_with_size(data) {
+        assert!(size <= data.len());
+        assert_eq!(
+            T::deserialize_be_with_size(&value.serialize_be()).map(|(v, _)| v),
+            Some(value)
+        );
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    check::<(u8, i16, u32, i64)>(data);
+    check::<Vec<Option<u16>>>(data);
+    check::<Option<String>>(data);
+    check::<[Vec<u8>; 3]>(data);
+    check::<(String, [u16; 4], Vec<Vec<u32>>)>(data);
+});
@@ -3,6 +3,103 @@
 pub use flat_bytes_derive::flat_enum;
 pub use flat_bytes_derive::Flat;
 
+use std::fmt;
+
+/// Reason why deserializing failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeserializeError {
+    /// Position in the input where the failing value starts
+    pub offset: usize,
+    /// Type of the failing value
+    pub expected: &'static str,
+    /// Fields and variants leading to the failing value, outermost first
+    pub path: Vec<&'static str>,
+    pub detail: Option<String>,
+}
+
+impl DeserializeError {
+    /// Failure to deserialize a `T` at the start of the input.
+    #[must_use]
+    pub fn new<T>() -> Self {
+        Self {
+            offset: 0,
+            expected: std::any::type_name::<T>(),
+            path: vec![],
+            detail: None,
+        }
+    }
+
+    #[must_use]
+    pub fn with_detail(mut self, detail: String) -> Self {
+        self.detail = Some(detail);
+        self
+    }
+
+    /// Moves the error into `field`, which starts at `offset` of the outer value.
+    #[must_use]
+    pub fn within(mut self, field: &'static str, offset: usize) -> Self {
+        self.offset += offset;
+        self.path.insert(0, field);
+        self
+    }
+}
+
+impl fmt::Display for DeserializeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid {} at offset {}", self.expected, self.offset)?;
+        if !self.path.is_empty() {
+            write!(f, " in {}", self.path.join("."))?;
+        }
+        if let Some(detail) = &self.detail {
+            write!(f, ": {detail}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for DeserializeError {}
+
+/// Reason why serializing failed, see [`Flat::try_serialize`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SerializeError {
+    /// Fields and variants leading to the failing value, outermost first
+    pub path: Vec<&'static str>,
+    /// Number of items of the sequence
+    pub len: usize,
+    /// Largest number of items its length prefix can hold
+    pub max: usize,
+}
+
+impl SerializeError {
+    /// Moves the error into `field` of the outer value.
+    #[must_use]
+    pub fn within(mut self, field: &'static str) -> Self {
+        self.path.insert(0, field);
+        self
+    }
+}
+
+impl fmt::Display for SerializeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "sequence of {} items exceeds the maximum of {}",
+            self.len, self.max
+        )?;
+        if !self.path.is_empty() {
+            write!(f, " in {}", self.path.join("."))?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for SerializeError {}
+
+#[diagnostic::on_unimplemented(
+    message = "type `{Self}` does not implement Flat",
+    label = "field type must implement `Flat`",
+    note = "derive it with `#[derive(Flat)]` or pick a layout with `#[flat(len = ...)]`, `#[flat(size = ...)]` or `#[flat(rest)]`"
+)]
 pub trait Flat: Sized {
     fn serialize(&self) -> Vec<u8>;
     fn deserialize_with_size(data: &[u8]) -> Option<(Self, usize)>;
@@ -10,6 +107,76 @@ pub trait Flat: Sized {
     fn deserialize(data: &[u8]) -> Option<Self> {
         Self::deserialize_with_size(data).map(|(r, _)| r)
     }
+
+    /// Like [`Flat::deserialize_with_size`], but tells where and why it failed.
+    ///
+    /// # Errors
+    ///
+    /// Fails if `data` doesn't start with a valid `Self`.
+    fn try_deserialize_with_size(data: &[u8]) -> Result<(Self, usize), DeserializeError> {
+        Self::deserialize_with_size(data).ok_or_else(DeserializeError::new::<Self>)
+    }
+
+    /// Like [`Flat::deserialize`], but tells where and why it failed.
+    ///
+    /// # Errors
+    ///
+    /// Fails if `data` doesn't start with a valid `Self`.
+    fn try_deserialize(data: &[u8]) -> Result<Self, DeserializeError> {
+        Self::try_deserialize_with_size(data).map(|(r, _)| r)
+    }
+
+    /// Checks that every length prefixed sequence fits its prefix, i.e. that
+    /// [`Flat::serialize`] doesn't panic.
+    ///
+    /// # Errors
+    ///
+    /// Fails for the first sequence with more items than its prefix can count.
+    fn check_lengths(&self) -> Result<(), SerializeError> {
+        Ok(())
+    }
+
+    /// Like [`Flat::serialize`], but fails instead of panicking, e.g. for input which
+    /// isn't under the caller's control.
+    ///
+    /// # Errors
+    ///
+    /// Fails if [`Flat::check_lengths`] does.
+    fn try_serialize(&self) -> Result<Vec<u8>, SerializeError> {
+        self.check_lengths()?;
+        Ok(self.serialize())
+    }
+
+    /// Appends the serialized value to `out`, saving the intermediate allocation.
+    fn serialize_into(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.serialize());
+    }
+
+    /// Number of bytes [`Flat::serialize`] produces.
+    #[must_use]
+    fn serialized_size(&self) -> usize {
+        self.serialize().len()
+    }
+
+    /// Big endian variant of [`Flat::serialize`], used for `#[flat(big_endian)]`.
+    ///
+    /// Only integers and containers of them differ, other types keep their own byte order.
+    fn serialize_be(&self) -> Vec<u8> {
+        self.serialize()
+    }
+
+    /// Big endian variant of [`Flat::deserialize_with_size`].
+    #[must_use]
+    fn deserialize_be_with_size(data: &[u8]) -> Option<(Self, usize)> {
+        Self::deserialize_with_size(data)
+    }
+}
+
+/// Serializes `value` into a buffer allocated with the right size upfront.
+fn serialize_presized<T: Flat>(value: &T) -> Vec<u8> {
+    let mut out = Vec::with_capacity(value.serialized_size());
+    value.serialize_into(&mut out);
+    out
 }
 
 macro_rules! impl_primitive {
@@ -27,6 +194,23 @@ macro_rules! impl_primitive {
                 tmp.copy_from_slice(&data[..::std::mem::size_of::<Self>()]);
                 Some((Self::from_le_bytes(tmp), ::std::mem::size_of::<Self>()))
             }
+
+            fn serialize_into(&self, out: &mut Vec<u8>) {
+                out.extend_from_slice(&self.to_le_bytes());
+            }
+
+            fn serialized_size(&self) -> usize {
+                ::std::mem::size_of::<Self>()
+            }
+
+            fn serialize_be(&self) -> Vec<u8> {
+                self.to_be_bytes().to_vec()
+            }
+
+            fn deserialize_be_with_size(data: &[u8]) -> Option<(Self, usize)> {
+                let (v, size) = Self::deserialize_with_size(data)?;
+                Some((v.swap_bytes(), size))
+            }
         }
     };
 }
@@ -48,26 +232,63 @@ impl Flat for bool {
     fn deserialize_with_size(data: &[u8]) -> Option<(Self, usize)> {
         <u8 as Flat>::deserialize_with_size(data).map(|(v, s)| (v != 0, s))
     }
+
+    fn serialize_into(&self, out: &mut Vec<u8>) {
+        out.push(u8::from(*self));
+    }
+
+    fn serialized_size(&self) -> usize {
+        1
+    }
 }
 
 macro_rules! impl_array {
-    (@step ($d: ident, $idx:expr,) -> ($($body:tt)*)) => {
+    (@step ($d: ident, $s:ident, $f:ident,) -> ($($body:tt)*)) => {
         impl_array!(@as_expr [$($body)*])
     };
-    (@step ($d: ident, $idx:expr, $t:ident, $($ts:ident,)*) -> ($($body:tt)*)) => {
-        impl_array!(@step ($d, $idx+1, $($ts,)*) -> ($($body)* $t::deserialize(&$d[::std::mem::size_of::<$t>()*($idx)..])?,))
+    (@step ($d: ident, $s:ident, $f:ident, $t:ident, $($ts:ident,)*) -> ($($body:tt)*)) => {
+        impl_array!(@step ($d, $s, $f, $($ts,)*) -> ($($body)* {
+            let (item, size) = $t::$f($d.get($s..)?)?;
+            $s += size;
+            item
+        },))
     };
     (@as_expr $e:expr) => {$e};
     {$n:expr, $t:ident $($ts:ident)*}=> {
         impl<T: Flat> Flat for [T; $n] {
             fn serialize(&self) -> Vec<u8> {
-                self.iter().map(Flat::serialize).flatten().collect()
+                serialize_presized(self)
+            }
+
+            fn serialize_into(&self, out: &mut Vec<u8>) {
+                for item in self {
+                    item.serialize_into(out);
+                }
+            }
+
+            fn serialized_size(&self) -> usize {
+                self.iter().map(Flat::serialized_size).sum()
+            }
+
+            fn check_lengths(&self) -> Result<(), SerializeError> {
+                self.iter().try_for_each(Flat::check_lengths)
             }
 
             fn deserialize_with_size(data: &[u8]) -> Option<(Self, usize)> {
+                let mut size = 0;
+                let res = impl_array!(@step (data, size, deserialize_with_size, $t, $($ts,)*) -> ());
+                Some((res, size))
+            }
+
+            fn serialize_be(&self) -> Vec<u8> {
+                self.iter().map(Flat::serialize_be).flatten().collect()
+            }
+
+            fn deserialize_be_with_size(data: &[u8]) -> Option<(Self, usize)> {
+                let mut size = 0;
                 let res =
-                    impl_array!(@step (data, 0, $t, $($ts,)*) -> ());
-                Some((res, ::std::mem::size_of::<Self>()))
+                    impl_array!(@step (data, size, deserialize_be_with_size, $t, $($ts,)*) -> ());
+                Some((res, size))
             }
         }
         impl_array!{($n - 1), $($ts)*}
@@ -81,17 +302,581 @@ macro_rules! impl_array {
             fn deserialize_with_size(_data: &[u8]) -> Option<(Self, usize)> {
                 Some(([], 0))
             }
+
+            fn serialized_size(&self) -> usize {
+                0
+            }
         }
     };
 }
 impl_array! {32, T T T T T T T T T T T T T T T T T T T T T T T T T T T T T T T T}
 
+impl Flat for () {
+    fn serialize(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn deserialize_with_size(_data: &[u8]) -> Option<(Self, usize)> {
+        Some(((), 0))
+    }
+
+    fn serialize_into(&self, _out: &mut Vec<u8>) {}
+
+    fn serialized_size(&self) -> usize {
+        0
+    }
+}
+
+/// Tuples are laid out like a struct with the same fields.
+macro_rules! impl_tuple {
+    ($($t:ident $v:ident),+) => {
+        impl<$($t: Flat),+> Flat for ($($t,)+) {
+            fn serialize(&self) -> Vec<u8> {
+                serialize_presized(self)
+            }
+
+            fn deserialize_with_size(data: &[u8]) -> Option<(Self, usize)> {
+                let mut size = 0;
+                $(
+                    let ($v, s) = $t::deserialize_with_size(data.get(size..)?)?;
+                    size += s;
+                )+
+                Some((($($v,)+), size))
+            }
+
+            fn serialize_into(&self, out: &mut Vec<u8>) {
+                let ($($v,)+) = self;
+                $($v.serialize_into(out);)+
+            }
+
+            fn serialized_size(&self) -> usize {
+                let ($($v,)+) = self;
+                0 $(+ $v.serialized_size())+
+            }
+
+            fn check_lengths(&self) -> Result<(), SerializeError> {
+                let ($($v,)+) = self;
+                $($v.check_lengths()?;)+
+                Ok(())
+            }
+
+            fn serialize_be(&self) -> Vec<u8> {
+                let ($($v,)+) = self;
+                let mut out = Vec::with_capacity(self.serialized_size());
+                $(out.append(&mut $v.serialize_be());)+
+                out
+            }
+
+            fn deserialize_be_with_size(data: &[u8]) -> Option<(Self, usize)> {
+                let mut size = 0;
+                $(
+                    let ($v, s) = $t::deserialize_be_with_size(data.get(size..)?)?;
+                    size += s;
+                )+
+                Some((($($v,)+), size))
+            }
+        }
+    };
+}
+
+impl_tuple!(A a);
+impl_tuple!(A a, B b);
+impl_tuple!(A a, B b, C c);
+impl_tuple!(A a, B b, C c, D d);
+impl_tuple!(A a, B b, C c, D d, E e);
+impl_tuple!(A a, B b, C c, D d, E e, F f);
+impl_tuple!(A a, B b, C c, D d, E e, F f, G g);
+impl_tuple!(A a, B b, C c, D d, E e, F f, G g, H h);
+impl_tuple!(A a, B b, C c, D d, E e, F f, G g, H h, I i);
+impl_tuple!(A a, B b, C c, D d, E e, F f, G g, H h, I i, J j);
+impl_tuple!(A a, B b, C c, D d, E e, F f, G g, H h, I i, J j, K k);
+impl_tuple!(A a, B b, C c, D d, E e, F f, G g, H h, I i, J j, K k, L l);
+
+/// Collections serialized as a length prefix followed by their items.
+///
+/// Used for fields annotated with `#[flat(len = "u16")]`.
+pub mod length {
+    use super::{Flat, SerializeError};
+    use std::convert::TryFrom;
+
+    /// Integer type usable as length prefix.
+    pub trait LengthPrefix: Flat {
+        /// Largest length the type can hold.
+        fn max_len() -> usize;
+        fn from_len(len: usize) -> Option<Self>;
+        fn to_len(&self) -> Option<usize>;
+    }
+
+    macro_rules! impl_length_prefix {
+        ($t:ident) => {
+            impl LengthPrefix for $t {
+                fn max_len() -> usize {
+                    usize::try_from($t::MAX).unwrap_or(usize::MAX)
+                }
+
+                fn from_len(len: usize) -> Option<Self> {
+                    $t::try_from(len).ok()
+                }
+
+                fn to_len(&self) -> Option<usize> {
+                    usize::try_from(*self).ok()
+                }
+            }
+        };
+    }
+
+    impl_length_prefix!(u8);
+    impl_length_prefix!(u16);
+    impl_length_prefix!(u32);
+    impl_length_prefix!(u64);
+
+    /// Collection with a variable number of items.
+    pub trait Sequence: Sized {
+        fn item_count(&self) -> usize;
+        /// Number of bytes of all serialized items.
+        fn items_size(&self) -> usize;
+        fn serialize_items(&self, big_endian: bool, out: &mut Vec<u8>);
+        fn deserialize_items(count: usize, data: &[u8], big_endian: bool) -> Option<(Self, usize)>;
+        /// Checks the lengths of sequences within the items, see [`Flat::check_lengths`].
+        ///
+        /// # Errors
+        ///
+        /// Fails if an item contains a sequence too long for its prefix.
+        fn check_items(&self) -> Result<(), SerializeError> {
+            Ok(())
+        }
+    }
+
+    /// Can't claim more items than bytes follow if the items take no space, e.g. `()`,
+    /// so that a forged count can't keep deserializing for billions of rounds.
+    impl<T: Flat> Sequence for Vec<T> {
+        fn item_count(&self) -> usize {
+            self.len()
+        }
+
+        fn items_size(&self) -> usize {
+            self.iter().map(Flat::serialized_size).sum()
+        }
+
+        fn serialize_items(&self, big_endian: bool, out: &mut Vec<u8>) {
+            for item in self {
+                if big_endian {
+                    out.append(&mut item.serialize_be());
+                } else {
+                    item.serialize_into(out);
+                }
+            }
+        }
+
+        fn deserialize_items(count: usize, data: &[u8], big_endian: bool) -> Option<(Self, usize)> {
+            // don't trust `count` for the allocation
+            let mut res = Vec::with_capacity(count.min(data.len()));
+            let mut total = 0;
+            for _ in 0..count {
+                let (item, size) = if big_endian {
+                    T::deserialize_be_with_size(&data[total..])?
+                } else {
+                    T::deserialize_with_size(&data[total..])?
+                };
+                if size == 0 && count > data.len() {
+                    return None;
+                }
+                total += size;
+                res.push(item);
+            }
+            Some((res, total))
+        }
+
+        fn check_items(&self) -> Result<(), SerializeError> {
+            self.iter().try_for_each(Flat::check_lengths)
+        }
+    }
+
+    /// Counts bytes, not chars.
+    impl Sequence for String {
+        fn item_count(&self) -> usize {
+            self.len()
+        }
+
+        fn items_size(&self) -> usize {
+            self.len()
+        }
+
+        fn serialize_items(&self, _big_endian: bool, out: &mut Vec<u8>) {
+            out.extend_from_slice(self.as_bytes());
+        }
+
+        fn deserialize_items(
+            count: usize,
+            data: &[u8],
+            _big_endian: bool,
+        ) -> Option<(Self, usize)> {
+            let s = std::str::from_utf8(data.get(..count)?).ok()?;
+            Some((s.to_owned(), count))
+        }
+    }
+
+    /// Serializes `value` into `out`, prefixed by its item count as `L`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the item count doesn't fit into `L`, see [`check`] and
+    /// [`Flat::try_serialize`] to fail instead.
+    pub fn serialize<L: LengthPrefix, S: Sequence>(value: &S, big_endian: bool, out: &mut Vec<u8>) {
+        let len = L::from_len(value.item_count()).expect("length exceeds length prefix type");
+        if big_endian {
+            out.append(&mut len.serialize_be());
+        } else {
+            len.serialize_into(out);
+        }
+        value.serialize_items(big_endian, out);
+    }
+
+    #[must_use]
+    pub fn serialized_size<L: LengthPrefix, S: Sequence>(value: &S) -> usize {
+        std::mem::size_of::<L>() + value.items_size()
+    }
+
+    /// Checks that the item count of `value` and of all sequences within fit their
+    /// prefixes.
+    ///
+    /// # Errors
+    ///
+    /// Fails for the first sequence with more items than its prefix can count.
+    pub fn check<L: LengthPrefix, S: Sequence>(value: &S) -> Result<(), SerializeError> {
+        if value.item_count() > L::max_len() {
+            return Err(SerializeError {
+                path: vec![],
+                len: value.item_count(),
+                max: L::max_len(),
+            });
+        }
+        value.check_items()
+    }
+
+    #[must_use]
+    pub fn deserialize<L: LengthPrefix, S: Sequence>(
+        data: &[u8],
+        big_endian: bool,
+    ) -> Option<(S, usize)> {
+        let (len, size) = if big_endian {
+            L::deserialize_be_with_size(data)?
+        } else {
+            L::deserialize_with_size(data)?
+        };
+        let (value, items_size) = S::deserialize_items(len.to_len()?, &data[size..], big_endian)?;
+        Some((value, size + items_size))
+    }
+}
+
+/// Values occupying a fixed number of bytes regardless of their content.
+///
+/// Used for fields annotated with `#[flat(size = 32)]`.
+pub mod fixed {
+    /// Value which can be padded or truncated to a given size.
+    pub trait FixedSize: Sized {
+        /// Serializes into exactly `size` bytes.
+        fn serialize_fixed(&self, size: usize, out: &mut Vec<u8>);
+        fn deserialize_fixed(data: &[u8]) -> Option<Self>;
+    }
+
+    /// Zero padded, truncated at a char boundary if too long.
+    ///
+    /// Invalid UTF-8 (e.g. a char cut off by a less careful sender) is replaced
+    /// instead of rejected.
+    impl FixedSize for String {
+        fn serialize_fixed(&self, size: usize, out: &mut Vec<u8>) {
+            let mut end = self.len().min(size);
+            while !self.is_char_boundary(end) {
+                end -= 1;
+            }
+            out.extend_from_slice(&self.as_bytes()[..end]);
+            out.resize(out.len() + size - end, 0);
+        }
+
+        fn deserialize_fixed(data: &[u8]) -> Option<Self> {
+            let end = data.iter().position(|b| *b == 0).unwrap_or(data.len());
+            Some(String::from_utf8_lossy(&data[..end]).into_owned())
+        }
+    }
+
+    pub fn serialize<T: FixedSize>(value: &T, size: usize, out: &mut Vec<u8>) {
+        value.serialize_fixed(size, out);
+    }
+
+    #[must_use]
+    pub fn deserialize<T: FixedSize>(data: &[u8], size: usize) -> Option<(T, usize)> {
+        let value = T::deserialize_fixed(data.get(..size)?)?;
+        Some((value, size))
+    }
+}
+
+/// Optional values at the end of a message.
+///
+/// Used for fields annotated with `#[flat(trailing)]`.
+pub mod optional {
+    use super::{Flat, SerializeError};
+
+    /// Serializes nothing for `None`, without any presence marker.
+    pub fn serialize_trailing<T: Flat>(value: &Option<T>, big_endian: bool, out: &mut Vec<u8>) {
+        match value {
+            Some(value) if big_endian => out.append(&mut value.serialize_be()),
+            Some(value) => value.serialize_into(out),
+            None => {}
+        }
+    }
+
+    #[must_use]
+    pub fn trailing_size<T: Flat>(value: &Option<T>) -> usize {
+        value.as_ref().map_or(0, Flat::serialized_size)
+    }
+
+    /// See [`Flat::check_lengths`].
+    ///
+    /// # Errors
+    ///
+    /// Fails if the value contains a sequence too long for its prefix.
+    pub fn check_trailing<T: Flat>(value: &Option<T>) -> Result<(), SerializeError> {
+        value.as_ref().map_or(Ok(()), Flat::check_lengths)
+    }
+
+    /// Deserializes a value if there are any bytes left.
+    #[must_use]
+    pub fn deserialize_trailing<T: Flat>(
+        data: &[u8],
+        big_endian: bool,
+    ) -> Option<(Option<T>, usize)> {
+        if data.is_empty() {
+            return Some((None, 0));
+        }
+        let (value, size) = if big_endian {
+            T::deserialize_be_with_size(data)?
+        } else {
+            T::deserialize_with_size(data)?
+        };
+        Some((Some(value), size))
+    }
+}
+
+/// Collections filling the remainder of the input, e.g. the body after a header.
+///
+/// Used for fields annotated with `#[flat(rest)]`.
+pub mod rest {
+    use super::{Flat, SerializeError};
+
+    /// Collection which can be deserialized without knowing its length.
+    pub trait Rest: Sized {
+        fn rest_size(&self) -> usize;
+        fn serialize_rest(&self, big_endian: bool, out: &mut Vec<u8>);
+        fn deserialize_rest(data: &[u8], big_endian: bool) -> Option<Self>;
+        /// Checks the lengths of sequences within the items, see [`Flat::check_lengths`].
+        ///
+        /// # Errors
+        ///
+        /// Fails if an item contains a sequence too long for its prefix.
+        fn check_items(&self) -> Result<(), SerializeError> {
+            Ok(())
+        }
+    }
+
+    /// Deserializes items until the input is exhausted.
+    impl<T: Flat> Rest for Vec<T> {
+        fn rest_size(&self) -> usize {
+            self.iter().map(Flat::serialized_size).sum()
+        }
+
+        fn serialize_rest(&self, big_endian: bool, out: &mut Vec<u8>) {
+            for item in self {
+                if big_endian {
+                    out.append(&mut item.serialize_be());
+                } else {
+                    item.serialize_into(out);
+                }
+            }
+        }
+
+        fn deserialize_rest(mut data: &[u8], big_endian: bool) -> Option<Self> {
+            let mut res = vec![];
+            while !data.is_empty() {
+                let (item, size) = if big_endian {
+                    T::deserialize_be_with_size(data)?
+                } else {
+                    T::deserialize_with_size(data)?
+                };
+                if size == 0 {
+                    return None;
+                }
+                data = &data[size..];
+                res.push(item);
+            }
+            Some(res)
+        }
+
+        fn check_items(&self) -> Result<(), SerializeError> {
+            self.iter().try_for_each(Flat::check_lengths)
+        }
+    }
+
+    impl Rest for String {
+        fn rest_size(&self) -> usize {
+            self.len()
+        }
+
+        fn serialize_rest(&self, _big_endian: bool, out: &mut Vec<u8>) {
+            out.extend_from_slice(self.as_bytes());
+        }
+
+        fn deserialize_rest(data: &[u8], _big_endian: bool) -> Option<Self> {
+            String::from_utf8(data.to_vec()).ok()
+        }
+    }
+
+    pub fn serialize<T: Rest>(value: &T, big_endian: bool, out: &mut Vec<u8>) {
+        value.serialize_rest(big_endian, out);
+    }
+
+    #[must_use]
+    pub fn serialized_size<T: Rest>(value: &T) -> usize {
+        value.rest_size()
+    }
+
+    /// See [`Flat::check_lengths`].
+    ///
+    /// # Errors
+    ///
+    /// Fails if an item contains a sequence too long for its prefix.
+    pub fn check<T: Rest>(value: &T) -> Result<(), SerializeError> {
+        value.check_items()
+    }
+
+    #[must_use]
+    pub fn deserialize<T: Rest>(data: &[u8], big_endian: bool) -> Option<(T, usize)> {
+        Some((T::deserialize_rest(data, big_endian)?, data.len()))
+    }
+}
+
+/// Uses a `u32` length prefix, see [`length`] for other sizes.
+impl<T: Flat> Flat for Vec<T> {
+    fn serialize(&self) -> Vec<u8> {
+        serialize_presized(self)
+    }
+
+    fn deserialize_with_size(data: &[u8]) -> Option<(Self, usize)> {
+        length::deserialize::<u32, _>(data, false)
+    }
+
+    fn serialize_into(&self, out: &mut Vec<u8>) {
+        length::serialize::<u32, _>(self, false, out);
+    }
+
+    fn serialized_size(&self) -> usize {
+        length::serialized_size::<u32, _>(self)
+    }
+
+    fn check_lengths(&self) -> Result<(), SerializeError> {
+        length::check::<u32, _>(self)
+    }
+
+    fn serialize_be(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.serialized_size());
+        length::serialize::<u32, _>(self, true, &mut out);
+        out
+    }
+
+    fn deserialize_be_with_size(data: &[u8]) -> Option<(Self, usize)> {
+        length::deserialize::<u32, _>(data, true)
+    }
+}
+
+/// Prefixed by a presence byte, see [`optional`] for trailing values.
+impl<T: Flat> Flat for Option<T> {
+    fn serialize(&self) -> Vec<u8> {
+        serialize_presized(self)
+    }
+
+    fn deserialize_with_size(data: &[u8]) -> Option<(Self, usize)> {
+        match data.first()? {
+            0 => Some((None, 1)),
+            1 => {
+                let (value, size) = T::deserialize_with_size(&data[1..])?;
+                Some((Some(value), size + 1))
+            }
+            _ => None,
+        }
+    }
+
+    fn serialize_into(&self, out: &mut Vec<u8>) {
+        out.push(u8::from(self.is_some()));
+        optional::serialize_trailing(self, false, out);
+    }
+
+    fn serialized_size(&self) -> usize {
+        1 + optional::trailing_size(self)
+    }
+
+    fn check_lengths(&self) -> Result<(), SerializeError> {
+        optional::check_trailing(self)
+    }
+
+    fn serialize_be(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.serialized_size());
+        out.push(u8::from(self.is_some()));
+        optional::serialize_trailing(self, true, &mut out);
+        out
+    }
+
+    fn deserialize_be_with_size(data: &[u8]) -> Option<(Self, usize)> {
+        match data.first()? {
+            0 => Some((None, 1)),
+            1 => {
+                let (value, size) = T::deserialize_be_with_size(&data[1..])?;
+                Some((Some(value), size + 1))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// UTF-8 with a `u32` length prefix, see [`length`] and [`fixed`] for other encodings.
+impl Flat for String {
+    fn serialize(&self) -> Vec<u8> {
+        serialize_presized(self)
+    }
+
+    fn deserialize_with_size(data: &[u8]) -> Option<(Self, usize)> {
+        length::deserialize::<u32, _>(data, false)
+    }
+
+    fn serialize_into(&self, out: &mut Vec<u8>) {
+        length::serialize::<u32, _>(self, false, out);
+    }
+
+    fn serialized_size(&self) -> usize {
+        length::serialized_size::<u32, _>(self)
+    }
+
+    fn check_lengths(&self) -> Result<(), SerializeError> {
+        length::check::<u32, _>(self)
+    }
+
+    fn serialize_be(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.serialized_size());
+        length::serialize::<u32, _>(self, true, &mut out);
+        out
+    }
+
+    fn deserialize_be_with_size(data: &[u8]) -> Option<(Self, usize)> {
+        length::deserialize::<u32, _>(data, true)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate as flat_bytes;
 
     flat_enum! {
+        #[derive(Debug)]
         #[repr(u8)]
         pub enum Foo {
             Bar = 1,
@@ -112,6 +897,123 @@ mod tests {
     #[derive(Flat)]
     struct Wrapper(Foo);
 
+    #[derive(Flat, Debug, PartialEq)]
+    struct Pair<A, B: Copy>(A, B)
+    where
+        A: Clone;
+
+    flat_enum! {
+        #[derive(Debug, PartialEq)]
+        #[repr(u8)]
+        enum Either<L, R> {
+            Left(L) = 1,
+            Right { value: R },
+        }
+    }
+
+    #[derive(Flat, Debug, PartialEq)]
+    struct Lists {
+        #[flat(len = "u8")]
+        short: Vec<u16>,
+        nested: Vec<Vec<u8>>,
+    }
+
+    #[derive(Flat, Debug, PartialEq)]
+    struct Names {
+        #[flat(size = 7)]
+        nick: String,
+        #[flat(len = "u8")]
+        id: String,
+        status: String,
+    }
+
+    #[derive(Flat, Debug, PartialEq)]
+    #[flat(big_endian)]
+    struct Network {
+        port: u16,
+        #[flat(len = "u16")]
+        addrs: Vec<[u16; 2]>,
+        ttl: Option<i32>,
+    }
+
+    #[derive(Flat, Debug, PartialEq)]
+    struct Mixed {
+        le: u16,
+        #[flat(big_endian)]
+        be: u16,
+    }
+
+    flat_enum! {
+        #[derive(Debug, PartialEq)]
+        #[repr(u16)]
+        #[flat(big_endian)]
+        enum Command {
+            Ping(u32) = 0x0102,
+        }
+    }
+
+    #[derive(Flat, Debug, PartialEq)]
+    #[repr(u8)]
+    enum Shape {
+        Point = 2,
+        Circle(u16) = 5,
+        Rect { w: u8, h: u8 },
+    }
+
+    #[derive(Flat, Debug, PartialEq, Clone, Copy)]
+    #[flat(repr = u16)]
+    enum Plain {
+        A,
+        B,
+    }
+
+    flat_enum! {
+        #[derive(Debug, PartialEq)]
+        #[repr(u8)]
+        enum Versioned {
+            Known(u8) = 1,
+            #[flat(other)]
+            Unknown(u8, Vec<u8>),
+        }
+    }
+
+    #[derive(Flat, Debug, PartialEq)]
+    struct Versions {
+        version: u8,
+        #[flat(skip)]
+        cached: Option<String>,
+        #[flat(default)]
+        flags: u16,
+        #[flat(default, len = "u8")]
+        extensions: Vec<u8>,
+    }
+
+    #[derive(Flat, Debug, PartialEq)]
+    struct Framed {
+        kind: u8,
+        #[flat(rest)]
+        body: Vec<u16>,
+    }
+
+    mod consts {
+        pub const PING: u8 = 0x10;
+    }
+
+    #[derive(Flat, Debug, PartialEq)]
+    #[repr(u8)]
+    enum Control {
+        Ping = consts::PING,
+        Pong,
+        Close = 2 * consts::PING,
+    }
+
+    #[derive(Flat, Debug, PartialEq)]
+    struct Optionals {
+        flag: Option<u8>,
+        #[flat(trailing)]
+        extra: Option<u16>,
+    }
+
     #[test]
     fn serialize() {
         #![allow(clippy::many_single_char_names)]
@@ -164,4 +1066,453 @@ mod tests {
         let w = Wrapper::deserialize(&[1]).unwrap();
         assert!(matches!(w.0, Foo::Bar));
     }
+
+    #[test]
+    fn length_prefixed() {
+        let l = Lists {
+            short: vec![1, 2],
+            nested: vec![vec![3], vec![]],
+        };
+        let data = vec![
+            2, 1, 0, 2, 0, // short
+            2, 0, 0, 0, 1, 0, 0, 0, 3, 0, 0, 0, 0, // nested
+        ];
+        assert_eq!(l.serialize(), data);
+        assert_eq!(Lists::deserialize_with_size(&data), Some((l, data.len())));
+        assert!(Lists::deserialize(&data[..data.len() - 1]).is_none());
+        assert!(<Vec<u8>>::deserialize(&[0xff, 0xff, 0xff, 0xff]).is_none());
+        // zero sized items are bounded by the input, not the claimed count
+        assert!(<Vec<()>>::deserialize(&[0xff, 0xff, 0xff, 0xff]).is_none());
+        assert!(length::deserialize::<u64, Vec<()>>(&[0xff; 8], false).is_none());
+        assert_eq!(
+            <Vec<()>>::deserialize(&[2, 0, 0, 0, 0, 0]),
+            Some(vec![(), ()])
+        );
+
+        // more items than the prefix can count fail instead of being dropped
+        let fits = Lists {
+            short: (0..255).collect(),
+            nested: vec![vec![1]],
+        };
+        assert_eq!(fits.try_serialize(), Ok(fits.serialize()));
+        let long = Lists {
+            short: (0..256).collect(),
+            nested: vec![],
+        };
+        let error = SerializeError {
+            path: vec!["short"],
+            len: 256,
+            max: 255,
+        };
+        assert_eq!(long.try_serialize(), Err(error.clone()));
+        assert_eq!(Some(long).try_serialize(), Err(error));
+        let long = Lists {
+            short: (0..256).collect(),
+            nested: vec![],
+        };
+        assert!(std::panic::catch_unwind(|| long.serialize()).is_err());
+    }
+
+    #[test]
+    fn strings() {
+        let n = Names {
+            nick: "Zoë Müller".to_owned(),
+            id: "ECHO".to_owned(),
+            status: "hi".to_owned(),
+        };
+        let data = n.serialize();
+        assert_eq!(data.len(), 7 + 5 + 6);
+        assert_eq!(&data[..7], b"Zo\xc3\xab M\0");
+        assert_eq!(&data[7..], b"\x04ECHO\x02\0\0\0hi");
+        let n = Names::deserialize(&data).unwrap();
+        assert_eq!(n.nick, "Zoë M");
+        assert_eq!(n.id, "ECHO");
+        assert_eq!(
+            fixed::deserialize::<String>(b"M\xc3\0", 3),
+            Some(("M\u{fffd}".to_owned(), 3))
+        );
+
+        let short = Names {
+            nick: "Bob".to_owned(),
+            ..n
+        };
+        let n = Names::deserialize(&short.serialize()).unwrap();
+        assert_eq!(n, short);
+        assert!(<String>::deserialize(&[1, 0, 0, 0, 0xff]).is_none());
+
+        // the prefix counts bytes, not chars
+        let long = Names {
+            id: format!("{}ë", "a".repeat(254)),
+            ..short
+        };
+        assert_eq!(
+            long.try_serialize().unwrap_err().to_string(),
+            "sequence of 256 items exceeds the maximum of 255 in id"
+        );
+    }
+
+    #[test]
+    fn optionals() {
+        let full = Optionals {
+            flag: Some(7),
+            extra: Some(0x0102),
+        };
+        assert_eq!(full.serialize(), vec![1, 7, 2, 1]);
+        assert_eq!(Optionals::deserialize(&[1, 7, 2, 1]), Some(full));
+
+        let empty = Optionals {
+            flag: None,
+            extra: None,
+        };
+        assert_eq!(empty.serialize(), vec![0]);
+        assert_eq!(Optionals::deserialize_with_size(&[0]), Some((empty, 1)));
+
+        assert!(Optionals::deserialize(&[2]).is_none());
+        assert!(Optionals::deserialize(&[0, 1]).is_none());
+    }
+
+    #[test]
+    fn big_endian() {
+        let n = Network {
+            port: 0x0102,
+            addrs: vec![[3, 4]],
+            ttl: Some(-2),
+        };
+        let data = vec![1, 2, 0, 1, 0, 3, 0, 4, 1, 0xff, 0xff, 0xff, 0xfe];
+        assert_eq!(n.serialize(), data);
+        assert_eq!(Network::deserialize(&data), Some(n));
+
+        let m = Mixed { le: 1, be: 1 };
+        assert_eq!(m.serialize(), vec![1, 0, 0, 1]);
+        assert_eq!(Mixed::deserialize(&[1, 0, 0, 1]), Some(m));
+
+        let c = Command::Ping(5);
+        assert_eq!(c.serialize(), vec![1, 2, 0, 0, 0, 5]);
+        assert_eq!(Command::deserialize(&[1, 2, 0, 0, 0, 5]), Some(c));
+    }
+
+    #[test]
+    fn errors() {
+        let e = Foo::try_deserialize(&[5]).unwrap_err();
+        assert_eq!(e.offset, 0);
+        assert_eq!(e.detail.as_deref(), Some("unknown discriminant 0x5"));
+
+        let e = Foo::try_deserialize(&[4, 1]).unwrap_err();
+        assert_eq!(e.offset, 2);
+        assert_eq!(e.expected, "u8");
+        assert_eq!(e.path, ["Blubb.b"]);
+
+        let e = Lists::try_deserialize(&[1, 0, 0, 1, 0, 0, 0, 2]).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "invalid alloc::vec::Vec<alloc::vec::Vec<u8>> at offset 3 in nested"
+        );
+        assert!(Header::try_deserialize(&[0x41, 0x42, 123, 0, 1]).is_ok());
+    }
+
+    #[test]
+    fn derived_enums() {
+        assert_eq!(Shape::Point.serialize(), vec![2]);
+        assert_eq!(Shape::Circle(1).serialize(), vec![5, 1, 0]);
+        assert_eq!(Shape::Rect { w: 1, h: 2 }.serialize(), vec![6, 1, 2]);
+        assert_eq!(
+            Shape::deserialize(&[6, 1, 2]),
+            Some(Shape::Rect { w: 1, h: 2 })
+        );
+        assert!(Shape::deserialize(&[3]).is_none());
+
+        assert_eq!(Plain::B.serialize(), (Plain::B as u16).to_le_bytes());
+        assert_eq!(Plain::deserialize(&[0, 0]), Some(Plain::A));
+    }
+
+    #[test]
+    fn other_variant() {
+        assert_eq!(Versioned::deserialize(&[1, 2]), Some(Versioned::Known(2)));
+        let v = Versioned::deserialize_with_size(&[7, 1, 2]).unwrap();
+        assert_eq!(v, (Versioned::Unknown(7, vec![1, 2]), 3));
+        assert_eq!(v.0.serialize(), vec![7, 1, 2]);
+    }
+
+    #[test]
+    fn skip_and_default() {
+        let v = Versions {
+            version: 2,
+            cached: Some("ignored".to_owned()),
+            flags: 3,
+            extensions: vec![4],
+        };
+        assert_eq!(v.serialize(), vec![2, 3, 0, 1, 4]);
+        let v = Versions::deserialize(&[2, 3, 0, 1, 4]).unwrap();
+        assert_eq!(v.cached, None);
+        assert_eq!(v.extensions, [4]);
+
+        let v = Versions::deserialize_with_size(&[1]).unwrap();
+        assert_eq!(
+            v,
+            (
+                Versions {
+                    version: 1,
+                    cached: None,
+                    flags: 0,
+                    extensions: vec![],
+                },
+                1
+            )
+        );
+        assert!(Versions::deserialize(&[1, 3]).is_none());
+    }
+
+    #[test]
+    fn rest() {
+        let f = Framed {
+            kind: 1,
+            body: vec![2, 3],
+        };
+        assert_eq!(f.serialize(), vec![1, 2, 0, 3, 0]);
+        assert_eq!(
+            Framed::deserialize_with_size(&[1, 2, 0, 3, 0]),
+            Some((f, 5))
+        );
+        assert!(Framed::deserialize(&[1, 2, 0, 3]).is_none());
+        assert_eq!(
+            rest::deserialize::<String>(b"hi", false),
+            Some(("hi".to_owned(), 2))
+        );
+    }
+
+    #[test]
+    fn serialized_size() {
+        let n = Network {
+            port: 1,
+            addrs: vec![[3, 4], [5, 6]],
+            ttl: None,
+        };
+        let v = Versions {
+            version: 2,
+            cached: Some("ignored".to_owned()),
+            flags: 3,
+            extensions: vec![4],
+        };
+        let shapes = [Shape::Point, Shape::Circle(1), Shape::Rect { w: 1, h: 2 }];
+        let unknown = Versioned::Unknown(7, vec![1, 2]);
+
+        assert_eq!(n.serialized_size(), n.serialize().len());
+        assert_eq!(v.serialized_size(), v.serialize().len());
+        assert_eq!(shapes.serialized_size(), shapes.serialize().len());
+        assert_eq!(unknown.serialized_size(), 3);
+
+        let data = v.serialize();
+        assert_eq!(data.capacity(), data.len());
+        let mut out = vec![0xff];
+        v.serialize_into(&mut out);
+        assert_eq!(out[1..], data[..]);
+    }
+
+    #[test]
+    fn generics() {
+        let p = Pair(1u8, 2u16);
+        assert_eq!(p.serialize(), vec![1, 2, 0]);
+        assert_eq!(Pair::deserialize(&[1, 2, 0]), Some(p));
+
+        let e: Either<u8, Pair<bool, u8>> = Either::Right {
+            value: Pair(true, 3),
+        };
+        assert_eq!(e.serialize(), vec![2, 1, 3]);
+        assert_eq!(Either::deserialize(&[2, 1, 3]), Some(e));
+    }
+
+    #[test]
+    fn const_discriminants() {
+        assert_eq!(Control::Ping.serialize(), vec![0x10]);
+        assert_eq!(Control::Pong.serialize(), vec![0x11]);
+        assert_eq!(Control::deserialize(&[0x20]), Some(Control::Close));
+        assert_eq!(Control::Close as u8, 0x20);
+    }
+
+    #[test]
+    fn tuples() {
+        assert_eq!(().serialize(), Vec::<u8>::new());
+        assert_eq!(<()>::deserialize_with_size(&[1]), Some(((), 0)));
+
+        let pair = (*b"ECHOECHO", 0x0102_0304_0506_0708u64);
+        let data = pair.serialize();
+        assert_eq!(&data[..8], b"ECHOECHO");
+        assert_eq!(&data[8..], &[8, 7, 6, 5, 4, 3, 2, 1]);
+        assert_eq!(pair.serialized_size(), 16);
+        assert_eq!(
+            <([u8; 8], u64)>::deserialize_with_size(&data),
+            Some((pair, 16))
+        );
+        assert_eq!(<([u8; 8], u64)>::deserialize(&data[..15]), None);
+
+        let nested = (1u8, (String::from("a"), true));
+        assert_eq!(nested.serialize(), vec![1, 1, 0, 0, 0, b'a', 1]);
+        assert_eq!(
+            <(u8, (String, bool))>::deserialize(&nested.serialize()),
+            Some(nested)
+        );
+        assert_eq!((1u16, 2u8).serialize_be(), vec![0, 1, 2]);
+        assert_eq!(
+            <(u16, u8)>::deserialize_be_with_size(&[0, 1, 2]),
+            Some(((1, 2), 3))
+        );
+    }
+
+    /// Property tests: everything serialized deserializes back to the same value, and
+    /// arbitrary input never panics or claims to consume more bytes than it got.
+    mod props {
+        use super::*;
+        use proptest::collection::vec;
+        use proptest::prelude::*;
+        use proptest::strategy::LazyJust;
+        use std::fmt::Debug;
+
+        fn roundtrip<T: Flat + PartialEq + Debug>(value: &T) -> Result<(), TestCaseError> {
+            let data = value.serialize();
+            prop_assert_eq!(data.len(), value.serialized_size());
+            let mut out = vec![0xff];
+            value.serialize_into(&mut out);
+            prop_assert_eq!(&out[1..], &data[..]);
+
+            let (res, size) = T::try_deserialize_with_size(&data)?;
+            prop_assert_eq!(&res, value);
+            prop_assert_eq!(size, data.len());
+
+            let be = value.serialize_be();
+            prop_assert_eq!(be.len(), data.len());
+            let res = T::deserialize_be_with_size(&be);
+            prop_assert_eq!(res.as_ref().map(|(v, _)| v), Some(value));
+            prop_assert_eq!(res.map(|(_, size)| size), Some(be.len()));
+
+            for len in 0..data.len() {
+                garbage::<T>(&data[..len])?;
+            }
+            Ok(())
+        }
+
+        fn garbage<T: Flat>(data: &[u8]) -> Result<(), TestCaseError> {
+            if let Some((_, size)) = T::deserialize_with_size(data) {
+                prop_assert!(size <= data.len());
+            }
+            if let Some((_, size)) = T::deserialize_be_with_size(data) {
+                prop_assert!(size <= data.len());
+            }
+            Ok(())
+        }
+
+        fn lists() -> impl Strategy<Value = Lists> {
+            (vec(any::<u16>(), 0..255), vec(vec(any::<u8>(), 0..8), 0..8))
+                .prop_map(|(short, nested)| Lists { short, nested })
+        }
+
+        fn names() -> impl Strategy<Value = Names> {
+            ("[a-zA-Z ]{0,7}", "\\PC{0,40}", any::<String>()).prop_map(|(nick, id, status)| Names {
+                nick,
+                id,
+                status,
+            })
+        }
+
+        fn network() -> impl Strategy<Value = Network> {
+            (
+                any::<u16>(),
+                vec(any::<[u16; 2]>(), 0..16),
+                any::<Option<i32>>(),
+            )
+                .prop_map(|(port, addrs, ttl)| Network { port, addrs, ttl })
+        }
+
+        fn versions() -> impl Strategy<Value = Versions> {
+            (any::<u8>(), any::<u16>(), vec(any::<u8>(), 0..255)).prop_map(
+                |(version, flags, extensions)| Versions {
+                    version,
+                    cached: None,
+                    flags,
+                    extensions,
+                },
+            )
+        }
+
+        fn shape() -> impl Strategy<Value = Shape> {
+            prop_oneof![
+                LazyJust::new(|| Shape::Point),
+                any::<u16>().prop_map(Shape::Circle),
+                any::<(u8, u8)>().prop_map(|(w, h)| Shape::Rect { w, h }),
+            ]
+        }
+
+        fn versioned() -> impl Strategy<Value = Versioned> {
+            prop_oneof![
+                any::<u8>().prop_map(Versioned::Known),
+                (
+                    any::<u8>().prop_filter("known", |d| *d != 1),
+                    vec(any::<u8>(), 0..16)
+                )
+                    .prop_map(|(d, rest)| Versioned::Unknown(d, rest)),
+            ]
+        }
+
+        proptest! {
+            #[test]
+            fn primitives(value in any::<(u8, i16, u32, i64, bool, ())>()) {
+                roundtrip(&value)?;
+            }
+
+            #[test]
+            fn containers(
+                value in any::<(Vec<Option<u16>>, String, [Vec<u8>; 3], Option<[i32; 2]>)>()
+            ) {
+                roundtrip(&value)?;
+            }
+
+            #[test]
+            fn derived_structs(
+                lists in lists(),
+                names in names(),
+                network in network(),
+                versions in versions(),
+                framed in (any::<u8>(), vec(any::<u16>(), 0..32)),
+                optionals in any::<(Option<u8>, Option<u16>)>(),
+                pair in any::<(u32, i8)>(),
+            ) {
+                roundtrip(&lists)?;
+                roundtrip(&names)?;
+                roundtrip(&network)?;
+                roundtrip(&versions)?;
+                roundtrip(&Framed { kind: framed.0, body: framed.1 })?;
+                roundtrip(&Optionals { flag: optionals.0, extra: optionals.1 })?;
+                roundtrip(&Pair(pair.0, pair.1))?;
+            }
+
+            #[test]
+            fn derived_enums(
+                shape in shape(),
+                versioned in versioned(),
+                either in any::<Result<u8, String>>(),
+            ) {
+                roundtrip(&shape)?;
+                roundtrip(&versioned)?;
+                roundtrip(&match either {
+                    Ok(left) => Either::Left(left),
+                    Err(value) => Either::Right { value },
+                })?;
+            }
+
+            #[test]
+            fn arbitrary_input(data in vec(any::<u8>(), 0..64)) {
+                garbage::<(u8, i16, u32, i64, bool)>(&data)?;
+                garbage::<(Vec<Option<u16>>, String, [Vec<u8>; 3])>(&data)?;
+                garbage::<Lists>(&data)?;
+                garbage::<Names>(&data)?;
+                garbage::<Network>(&data)?;
+                garbage::<Versions>(&data)?;
+                garbage::<Framed>(&data)?;
+                garbage::<Optionals>(&data)?;
+                garbage::<Shape>(&data)?;
+                garbage::<Versioned>(&data)?;
+                garbage::<Control>(&data)?;
+                garbage::<Command>(&data)?;
+            }
+        }
+    }
 }
@@ -0,0 +1,19 @@
+#![no_main]
+
+use flat_bytes::Flat;
+use libfuzzer_sys::fuzz_target;
+use threema::packets::{Ballot, BallotUpdates, File};
+
+/// Parses `data` as `T` and serializes it again, which must parse as well.
+fn check<T: Flat>(data: &[u8]) {
+    if let Some(value) = T::deserialize(data) {
+        assert!(T::deserialize(&value.serialize()).is_some());
+    }
+}
+
+// JSON bodies of file, ballot and vote messages.
+fuzz_target!(|data: &[u8]| {
+    check::<File>(data);
+    check::<Ballot>(data);
+    check::<BallotUpdates>(data);
+});
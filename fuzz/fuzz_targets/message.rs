@@ -0,0 +1,15 @@
+#![no_main]
+
+use flat_bytes::Flat;
+use libfuzzer_sys::fuzz_target;
+use threema::packets::{self, Message};
+
+// Decrypted message bodies, including their padding, as sent by any peer.
+fuzz_target!(|data: &[u8]| {
+    if let Ok(data) = packets::unpad(data) {
+        if let Ok((msg, size)) = Message::try_deserialize_with_size(data) {
+            assert!(size <= data.len());
+            let _ = msg.serialize();
+        }
+    }
+});
@@ -0,0 +1,13 @@
+#![no_main]
+
+use flat_bytes::Flat;
+use libfuzzer_sys::fuzz_target;
+use threema::packets::Packet;
+
+// Decrypted packets as received from the chat server.
+fuzz_target!(|data: &[u8]| {
+    if let Ok((packet, size)) = Packet::try_deserialize_with_size(data) {
+        assert!(size <= data.len());
+        let _ = packet.serialize();
+    }
+});
@@ -0,0 +1,490 @@
+//! Threema ⇄ HTTP bridge, e.g. for home automation and alerting.
+//!
+//! [`Webhook`] is a [`Handler`] posting every incoming message as JSON to a URL:
+//!
+//! ```json
+//! {"type": "text", "from": "ECHOECHO", "id": "0102030405060708", "text": "hi"}
+//! {"type": "group_text", "from": "ECHOECHO", "id": "…", "creator": "…", "group_id": "…", "text": "hi"}
+//! {"type": "file", "from": "ECHOECHO", "id": "…", "name": "a.jpg", "mime": "image/jpeg", "size": 1234}
+//! {"type": "receipt", "from": "ECHOECHO", "status": "Read", "for": "…"}
+//! ```
+//!
+//! Messages are posted from a background thread, so a slow endpoint doesn't hold up
+//! receiving; if it falls too far behind, further messages aren't forwarded.
+//!
+//! With [`Webhook::listen`], it also accepts `POST /send` requests with a body like
+//! `{"to": "ECHOECHO", "text": "hi"}` and sends them from within [`Threema::run`]. The
+//! response is `{"id": "…"}` with the ID of the sent message.
+
+use crate::handler::Handler;
+use crate::packets::{File, GroupText, MessageStatus, Text};
+use crate::rest::{post_json, Retry};
+use crate::{Error, MessageID, Result, Threema, ThreemaID};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::Digest;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+/// How often queued requests are picked up while waiting for messages.
+const TICK: Duration = Duration::from_millis(100);
+/// How long a `/send` request waits for the message to be sent.
+const SEND_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_HEADER_SIZE: usize = 8 * 1024;
+const MAX_BODY_SIZE: usize = 64 * 1024;
+/// Requests answered at the same time, further ones are rejected.
+const MAX_CONNECTIONS: usize = 16;
+/// Messages waiting to be forwarded, further ones are dropped.
+const MAX_FORWARDS: usize = 256;
+
+/// Forwards incoming messages to a URL and optionally sends messages posted to it, see
+/// the [module documentation](self).
+pub struct Webhook {
+    /// queue of the thread posting to the URL
+    forwards: Option<mpsc::SyncSender<Value>>,
+    requests: Option<mpsc::Receiver<SendRequest>>,
+    local_addr: Option<SocketAddr>,
+}
+
+/// Body of a `/send` request.
+#[derive(Deserialize)]
+struct Outgoing {
+    to: String,
+    text: String,
+}
+
+/// A message to send, with the channel to report the result to.
+struct SendRequest {
+    message: Outgoing,
+    result: mpsc::Sender<Result<MessageID>>,
+}
+
+impl Webhook {
+    /// Posts incoming messages to `url`, or nowhere if `None`, e.g. for only sending.
+    #[must_use]
+    pub fn new(url: Option<String>) -> Self {
+        let forwards = url.map(|url| {
+            let (tx, rx) = mpsc::sync_channel::<Value>(MAX_FORWARDS);
+            thread::spawn(move || {
+                for event in rx {
+                    if let Err(e) = post_json(&url, &event, Retry::Never) {
+                        warn!(%url, error = %e, "Couldn't forward message");
+                    }
+                }
+            });
+            tx
+        });
+        Self {
+            forwards,
+            requests: None,
+            local_addr: None,
+        }
+    }
+
+    /// Accepts messages to send on `addr`. Requests must carry `token` as
+    /// `Authorization: Bearer` header; it may only be omitted on a loopback address.
+    pub fn listen(mut self, addr: impl ToSocketAddrs, token: Option<String>) -> Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+        if token.is_none() && !local_addr.ip().is_loopback() {
+            return Err(Error::InvalidConfig(format!(
+                "a token is required to listen on {local_addr}"
+            )));
+        }
+        info!(%local_addr, "Accepting messages to send");
+        let (tx, rx) = mpsc::channel();
+        let active = Arc::new(AtomicUsize::new(0));
+        thread::spawn(move || {
+            for conn in listener.incoming() {
+                match conn {
+                    Ok(conn) if active.load(Ordering::SeqCst) >= MAX_CONNECTIONS => {
+                        debug!("Too many requests, rejecting");
+                        let _ = conn.set_write_timeout(Some(Duration::from_secs(1)));
+                        let error = json!({"error": "too many requests"});
+                        let _ = respond(conn, "503 Service Unavailable", &error);
+                    }
+                    Ok(conn) => {
+                        let (token, tx) = (token.clone(), tx.clone());
+                        let slot = Slot::take(&active);
+                        thread::spawn(move || {
+                            let _slot = slot;
+                            if let Err(e) = serve(conn, token.as_deref(), &tx) {
+                                debug!(error = %e, "Failed to answer request");
+                            }
+                        });
+                    }
+                    Err(e) => warn!(error = %e, "Couldn't accept connection"),
+                }
+            }
+        });
+        self.requests = Some(rx);
+        self.local_addr = Some(local_addr);
+        Ok(self)
+    }
+
+    /// The address requests are accepted on, if listening.
+    #[must_use]
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+
+    fn forward(&self, event: Value) {
+        if let Some(forwards) = &self.forwards {
+            if forwards.try_send(event).is_err() {
+                warn!("Webhook is too slow, dropping message");
+            }
+        }
+    }
+}
+
+/// One of the [`MAX_CONNECTIONS`] requests answered at a time, freed when dropped.
+struct Slot(Arc<AtomicUsize>);
+
+impl Slot {
+    fn take(active: &Arc<AtomicUsize>) -> Self {
+        active.fetch_add(1, Ordering::SeqCst);
+        Self(Arc::clone(active))
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Handler for Webhook {
+    fn on_text(&mut self, _: &mut Threema, sender: ThreemaID, msg_id: MessageID, text: &Text) {
+        self.forward(json!({
+            "type": "text",
+            "from": sender.to_string(),
+            "id": msg_id.to_string(),
+            "text": text.message,
+        }));
+    }
+
+    fn on_file(&mut self, _: &mut Threema, sender: ThreemaID, msg_id: MessageID, file: &File) {
+        self.forward(json!({
+            "type": "file",
+            "from": sender.to_string(),
+            "id": msg_id.to_string(),
+            "name": file.name,
+            "mime": file.mime,
+            "size": file.size,
+        }));
+    }
+
+    fn on_group_text(
+        &mut self,
+        _: &mut Threema,
+        sender: ThreemaID,
+        msg_id: MessageID,
+        text: &GroupText,
+    ) {
+        self.forward(json!({
+            "type": "group_text",
+            "from": sender.to_string(),
+            "id": msg_id.to_string(),
+            "creator": text.creator.to_string(),
+            "group_id": text.group_id.to_string(),
+            "text": text.message,
+        }));
+    }
+
+    fn on_receipt(
+        &mut self,
+        _: &mut Threema,
+        sender: ThreemaID,
+        status: &MessageStatus,
+        receipt_for: MessageID,
+    ) {
+        self.forward(json!({
+            "type": "receipt",
+            "from": sender.to_string(),
+            "status": status,
+            "for": receipt_for.to_string(),
+        }));
+    }
+
+    fn tick_interval(&self) -> Option<Duration> {
+        self.requests.as_ref().map(|_| TICK)
+    }
+
+    fn on_tick(&mut self, client: &mut Threema) {
+        let Some(requests) = &self.requests else {
+            return;
+        };
+        for SendRequest {
+            message: Outgoing { to, text },
+            result,
+        } in requests.try_iter()
+        {
+            let sent = ThreemaID::from_string(&to)
+                .and_then(|receiver| client.send_text_message(receiver, text));
+            if let Err(e) = &sent {
+                warn!(%to, error = %e, "Couldn't send message");
+            }
+            // the requester may have given up already
+            let _ = result.send(sent);
+        }
+    }
+}
+
+/// Answers one HTTP request on `conn`.
+fn serve(
+    mut conn: TcpStream,
+    token: Option<&str>,
+    requests: &mpsc::Sender<SendRequest>,
+) -> io::Result<()> {
+    conn.set_read_timeout(Some(SEND_TIMEOUT))?;
+    let (status, body) = match read_request(&mut conn, token) {
+        Ok(message) => {
+            let (tx, rx) = mpsc::channel();
+            let request = SendRequest {
+                message,
+                result: tx,
+            };
+            if requests.send(request).is_err() {
+                ("503 Service Unavailable", json!({"error": "not running"}))
+            } else {
+                match rx.recv_timeout(SEND_TIMEOUT) {
+                    Ok(Ok(id)) => ("200 OK", json!({"id": id.to_string()})),
+                    Ok(Err(e)) => ("502 Bad Gateway", json!({"error": e.to_string()})),
+                    Err(_) => ("504 Gateway Timeout", json!({"error": "not sent in time"})),
+                }
+            }
+        }
+        Err((status, error)) => (status, json!({ "error": error })),
+    };
+    respond(conn, status, &body)
+}
+
+fn respond(mut conn: TcpStream, status: &str, body: &Value) -> io::Result<()> {
+    let body = body.to_string();
+    write!(
+        conn,
+        "HTTP/1.1 {status}\r\n\
+         Content-Type: application/json\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n\
+         {body}",
+        body.len()
+    )
+}
+
+/// Parses a `POST /send` request, or returns the status and reason to reject it with.
+fn read_request(
+    conn: &mut TcpStream,
+    token: Option<&str>,
+) -> std::result::Result<Outgoing, (&'static str, String)> {
+    let bad_request = |reason: &str| ("400 Bad Request", reason.to_owned());
+    let mut reader = BufReader::new(conn).take(MAX_HEADER_SIZE as u64);
+    let mut line = String::new();
+    reader
+        .read_line(&mut line)
+        .map_err(|_| bad_request("invalid request line"))?;
+    let mut parts = line.split_whitespace();
+    let (method, path) = (parts.next(), parts.next());
+    if path != Some("/send") {
+        return Err(("404 Not Found", "unknown path".to_owned()));
+    }
+    if method != Some("POST") {
+        return Err(("405 Method Not Allowed", "use POST".to_owned()));
+    }
+
+    let mut length = None;
+    let mut authorized = token.is_none();
+    loop {
+        line.clear();
+        if reader
+            .read_line(&mut line)
+            .map_err(|_| bad_request("invalid header"))?
+            == 0
+        {
+            return Err(bad_request("headers too long"));
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let Some((name, value)) = line.split_once(':') else {
+            return Err(bad_request("invalid header"));
+        };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("content-length") {
+            length = Some(
+                value
+                    .parse::<usize>()
+                    .map_err(|_| bad_request("invalid length"))?,
+            );
+        } else if name.eq_ignore_ascii_case("authorization") {
+            authorized |= token.is_some_and(|token| {
+                value
+                    .strip_prefix("Bearer ")
+                    .is_some_and(|value| token_matches(value, token))
+            });
+        }
+    }
+    if !authorized {
+        return Err(("401 Unauthorized", "invalid token".to_owned()));
+    }
+    let length = length.ok_or(("411 Length Required", "length required".to_owned()))?;
+    if length > MAX_BODY_SIZE {
+        return Err(("413 Payload Too Large", "body too large".to_owned()));
+    }
+
+    let mut body = vec![0; length];
+    let mut reader = reader.into_inner();
+    reader
+        .read_exact(&mut body)
+        .map_err(|_| bad_request("incomplete body"))?;
+    serde_json::from_slice(&body).map_err(|e| bad_request(&e.to_string()))
+}
+
+/// Compares the hashes of both tokens, so the time taken reveals neither the position of
+/// the first difference nor the length of `expected`.
+fn token_matches(given: &str, expected: &str) -> bool {
+    let given = sha2::Sha256::digest(given.as_bytes());
+    let expected = sha2::Sha256::digest(expected.as_bytes());
+    given
+        .iter()
+        .zip(expected.iter())
+        .fold(0, |diff, (a, b)| diff | (a ^ b))
+        == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn try_request(addr: SocketAddr, request: &str) -> io::Result<String> {
+        let mut conn = TcpStream::connect(addr)?;
+        conn.write_all(request.as_bytes())?;
+        let mut response = String::new();
+        conn.read_to_string(&mut response)?;
+        Ok(response)
+    }
+
+    fn request(addr: SocketAddr, request: &str) -> String {
+        try_request(addr, request).unwrap()
+    }
+
+    fn post(addr: SocketAddr, token: &str, body: &str) -> String {
+        request(
+            addr,
+            &format!(
+                "POST /send HTTP/1.1\r\nAuthorization: Bearer {token}\r\n\
+                 Content-Length: {}\r\n\r\n{body}",
+                body.len()
+            ),
+        )
+    }
+
+    #[test]
+    fn tokens() {
+        assert!(token_matches("secret", "secret"));
+        assert!(!token_matches("secreT", "secret"));
+        assert!(!token_matches("secret2", "secret"));
+        assert!(!token_matches("", "secret"));
+
+        assert!(matches!(
+            Webhook::new(None).listen("0.0.0.0:0", None),
+            Err(Error::InvalidConfig(_))
+        ));
+        assert!(Webhook::new(None).listen("127.0.0.1:0", None).is_ok());
+        assert!(Webhook::new(None)
+            .listen("0.0.0.0:0", Some("secret".to_owned()))
+            .is_ok());
+    }
+
+    #[test]
+    fn connection_limit() {
+        let webhook = Webhook::new(None)
+            .listen("127.0.0.1:0", Some("secret".to_owned()))
+            .unwrap();
+        let addr = webhook.local_addr().unwrap();
+        let idle: Vec<_> = (0..MAX_CONNECTIONS)
+            .map(|_| TcpStream::connect(addr).unwrap())
+            .collect();
+        // rejected right away, without waiting for the request
+        assert!(request(addr, "").starts_with("HTTP/1.1 503"));
+        drop(idle);
+        // the slots are freed once the idle requests were answered
+        let mut response = String::new();
+        for _ in 0..100 {
+            // a rejected request may be reset before its response is read
+            response = try_request(addr, "GET /send HTTP/1.1\r\n\r\n").unwrap_or_default();
+            if response.starts_with("HTTP/1.1 405") {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(response.starts_with("HTTP/1.1 405"), "{}", response);
+    }
+
+    #[test]
+    fn forwarding() {
+        let _settings = crate::rest::SETTINGS
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        let endpoint = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", endpoint.local_addr().unwrap());
+        let mut webhook = Webhook::new(Some(url));
+        let mut client =
+            Threema::new(ThreemaID::from_string("ECHOECHO").unwrap(), &[1; 32]).unwrap();
+        let sender = ThreemaID::from_string("*TESTGW0").unwrap();
+        let text = Text {
+            message: "hi".to_owned(),
+        };
+        // returns right away, the endpoint hasn't even accepted the request yet
+        webhook.on_text(&mut client, sender, MessageID::from_bytes([1; 8]), &text);
+
+        let (mut conn, _) = endpoint.accept().unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0; 1024];
+        while !request.ends_with(b"}") {
+            let n = conn.read(&mut buf).unwrap();
+            assert!(n > 0);
+            request.extend_from_slice(&buf[..n]);
+        }
+        conn.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").unwrap();
+        let request = String::from_utf8(request).unwrap();
+        assert!(request.starts_with("POST /hook "));
+        assert!(request.contains(r#""text":"hi""#), "{}", request);
+        assert!(request.contains(r#""from":"*TESTGW0""#));
+    }
+
+    #[test]
+    fn listener() {
+        let mut webhook = Webhook::new(None)
+            .listen("127.0.0.1:0", Some("secret".to_owned()))
+            .unwrap();
+        let addr = webhook.local_addr().unwrap();
+        assert!(webhook.tick_interval().is_some());
+        assert!(Webhook::new(None).tick_interval().is_none());
+
+        assert!(request(addr, "GET /other HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 404"));
+        assert!(request(addr, "GET /send HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 405"));
+        let body = r#"{"to": "ECHOECHO", "text": "hi"}"#;
+        assert!(post(addr, "wrong", body).starts_with("HTTP/1.1 401"));
+        assert!(post(addr, "secret", "{}").starts_with("HTTP/1.1 400"));
+
+        // sent by the handler, failing as the client isn't connected
+        let sender = thread::spawn(move || post(addr, "secret", body));
+        let mut client =
+            Threema::new(ThreemaID::from_string("ECHOECHO").unwrap(), &[1; 32]).unwrap();
+        client.set_directory(Box::new(crate::directory::MemoryDirectory::new()));
+        while !sender.is_finished() {
+            webhook.on_tick(&mut client);
+            thread::sleep(Duration::from_millis(10));
+        }
+        let response = sender.join().unwrap();
+        assert!(response.starts_with("HTTP/1.1 502"), "{}", response);
+        assert!(response.contains(r#"{"error":"#));
+    }
+}
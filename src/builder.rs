@@ -0,0 +1,482 @@
+//! Configuration of a [`Threema`] client.
+
+use crate::contacts::{ContactStore, MemoryContactStore};
+use crate::dedup::{DuplicatePolicy, RecentMessages};
+use crate::directory::{self, DirectoryClient};
+use crate::filter::{BlockMode, Filter, Verdict};
+use crate::identity;
+use crate::keystore::{MemoryKeyStore, PeerKeyStore};
+use crate::nonces::{MemoryNonceStore, NonceStore};
+use crate::outbox::Outbox;
+use crate::packets::Header;
+#[cfg(feature = "rest")]
+use crate::rest;
+use crate::servers::{self, ServerInfo};
+use crate::sources::{Clock, OsRng, RngSource, SystemClock};
+use crate::status::Session;
+use crate::store::MessageStore;
+use crate::transport::SocketOptions;
+use crate::{
+    AutoReplies, Error, LongTexts, Nickname, Padding, PrivateKey, Result, Threema, ThreemaID,
+    MAX_FRAME_SIZE, MIN_FRAME_SIZE,
+};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::Duration;
+
+/// Number of received message IDs remembered to detect duplicates.
+const RECENT_MESSAGES: usize = 1000;
+/// Idle time after which [`Threema::run`] checks the connection with an echo request.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_mins(3);
+
+enum Credentials {
+    Key(ThreemaID, Vec<u8>),
+    Backup(String, String),
+}
+
+/// Builder for [`Threema`], created by [`Threema::builder`].
+///
+/// Server info is a process-wide setting (see [`servers::set`]), which is applied when
+/// building. The proxy only applies to the built client.
+#[derive(Default)]
+#[must_use]
+pub struct ThreemaBuilder {
+    credentials: Option<Credentials>,
+    nick: Nickname,
+    long_texts: LongTexts,
+    padding: Padding,
+    servers: Option<ServerInfo>,
+    #[cfg(feature = "rest")]
+    proxy: Option<String>,
+    key_store: Option<Box<dyn PeerKeyStore>>,
+    contacts: Option<Box<dyn ContactStore>>,
+    history: Option<Box<dyn MessageStore>>,
+    outbox: Option<Box<dyn Outbox>>,
+    nonces: Option<Box<dyn NonceStore>>,
+    block_mode: BlockMode,
+    filter: Option<Filter>,
+    recent: Option<RecentMessages>,
+    duplicates: DuplicatePolicy,
+    directory: Option<Box<dyn DirectoryClient>>,
+    refresh_keys: bool,
+    rng: Option<Box<dyn RngSource>>,
+    clock: Option<Box<dyn Clock>>,
+    keepalive: Option<Duration>,
+    max_frame_size: Option<usize>,
+    socket: SocketOptions,
+    auto_replies: AutoReplies,
+}
+
+impl ThreemaBuilder {
+    /// Uses the identity `id` with its raw `private_key`.
+    pub fn identity(mut self, id: ThreemaID, private_key: &[u8]) -> Self {
+        self.credentials = Some(Credentials::Key(id, private_key.to_vec()));
+        self
+    }
+
+    /// Uses the identity from an ID export, decrypted with `password`.
+    pub fn backup(mut self, data: &str, password: &str) -> Self {
+        self.credentials = Some(Credentials::Backup(data.to_owned(), password.to_owned()));
+        self
+    }
+
+    /// Nickname sent along with messages, defaults to the identity.
+    pub fn nick(mut self, nick: impl Into<String>) -> Self {
+        self.nick = Nickname::Name(nick.into());
+        self
+    }
+
+    /// Sends messages without a nickname, so the header doesn't reveal the identity
+    /// a second time.
+    pub fn hide_nick(mut self) -> Self {
+        self.nick = Nickname::Hidden;
+        self
+    }
+
+    /// What happens to texts which are too long for a single message, split into several
+    /// ones by default.
+    pub fn long_texts(mut self, policy: LongTexts) -> Self {
+        self.long_texts = policy;
+        self
+    }
+
+    /// How messages are padded to hide their length, [`Padding::Short`] by default.
+    pub fn padding(mut self, padding: Padding) -> Self {
+        self.padding = padding;
+        self
+    }
+
+    /// Uses `info` instead of the public Threema servers.
+    pub fn servers(mut self, info: ServerInfo) -> Self {
+        self.servers = Some(info);
+        self
+    }
+
+    /// Sends the REST requests of this client through `proxy`, e.g.
+    /// `socks5://localhost:9050`, instead of the one set with `rest::set_proxy`.
+    ///
+    /// Applies to the default directory and blob uploads, not to a custom
+    /// [directory](Self::directory).
+    #[cfg(feature = "rest")]
+    pub fn proxy(mut self, proxy: impl Into<String>) -> Self {
+        self.proxy = Some(proxy.into());
+        self
+    }
+
+    /// Cache for public keys of peers, defaults to a [`MemoryKeyStore`].
+    pub fn key_store(mut self, store: Box<dyn PeerKeyStore>) -> Self {
+        self.key_store = Some(store);
+        self
+    }
+
+    /// Storage of contacts, defaults to a [`MemoryContactStore`].
+    pub fn contacts(mut self, store: Box<dyn ContactStore>) -> Self {
+        self.contacts = Some(store);
+        self
+    }
+
+    /// Keeps a history of sent and received messages in `store`, off by default.
+    pub fn message_store(mut self, store: Box<dyn MessageStore>) -> Self {
+        self.history = Some(store);
+        self
+    }
+
+    /// Keeps unacknowledged messages in `outbox` to send them again on the next connect,
+    /// which also allows sending while disconnected. Off by default.
+    pub fn outbox(mut self, outbox: Box<dyn Outbox>) -> Self {
+        self.outbox = Some(outbox);
+        self
+    }
+
+    /// Nonces of processed messages, to drop replayed ones. Defaults to a
+    /// [`MemoryNonceStore`], which only detects replays within the same process.
+    ///
+    /// With [automatic acks](Self::auto_ack) disabled, a nonce is only recorded once the
+    /// message is acknowledged, so messages delivered again after a crash aren't dropped.
+    pub fn nonce_store(mut self, store: Box<dyn NonceStore>) -> Self {
+        self.nonces = Some(store);
+        self
+    }
+
+    /// How messages of [blocked](Threema::block) senders are dropped, silently by default.
+    pub fn block_mode(mut self, mode: BlockMode) -> Self {
+        self.block_mode = mode;
+        self
+    }
+
+    /// Hook deciding about incoming messages of senders which aren't blocked, before
+    /// they are decrypted or confirmed.
+    pub fn filter(mut self, filter: impl FnMut(&Header) -> Verdict + Send + 'static) -> Self {
+        self.filter = Some(Box::new(filter));
+        self
+    }
+
+    /// Whether messages received again are dropped, the default, or flagged.
+    pub fn duplicates(mut self, policy: DuplicatePolicy) -> Self {
+        self.duplicates = policy;
+        self
+    }
+
+    /// IDs of received messages used to detect duplicates, defaults to the last 1000
+    /// messages in memory.
+    pub fn recent_messages(mut self, recent: RecentMessages) -> Self {
+        self.recent = Some(recent);
+        self
+    }
+
+    /// Client used to look up peers, defaults to [`HttpDirectory`](directory::HttpDirectory),
+    /// or to an empty directory without the `rest` feature.
+    pub fn directory(mut self, directory: Box<dyn DirectoryClient>) -> Self {
+        self.directory = Some(directory);
+        self
+    }
+
+    /// Whether the key of a sender is fetched from the directory again if one of its
+    /// messages can't be decrypted, off by default.
+    ///
+    /// If the message can be decrypted with the new key, e.g. because the peer created a
+    /// new identity, the key is reported in
+    /// [`ServerMessage::key_changed`](crate::ServerMessage::key_changed). It doesn't
+    /// replace the pinned key: whoever controls the directory response could take over
+    /// the contact otherwise. Call [`Threema::accept_key_change`] to trust it.
+    ///
+    /// The key of a sender is fetched at most every ten minutes, the result is reused for
+    /// its other messages in between.
+    pub fn refresh_keys(mut self, enabled: bool) -> Self {
+        self.refresh_keys = enabled;
+        self
+    }
+
+    /// Source of nonces, keys, message IDs and padding, defaults to [`OsRng`].
+    pub fn rng(mut self, rng: Box<dyn RngSource>) -> Self {
+        self.rng = Some(rng);
+        self
+    }
+
+    /// Clock used for message timestamps, defaults to [`SystemClock`].
+    pub fn clock(mut self, clock: Box<dyn Clock>) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// Idle time after which [`Threema::run`] sends an echo request, defaults to 3 minutes.
+    pub fn keepalive(mut self, interval: Duration) -> Self {
+        self.keepalive = Some(interval);
+        self
+    }
+
+    /// Largest frame accepted from the chat server, defaults to [`MAX_FRAME_SIZE`].
+    ///
+    /// A longer frame closes the connection with [`Error::FrameTooLarge`] instead of
+    /// being read.
+    pub fn max_frame_size(mut self, size: usize) -> Self {
+        self.max_frame_size = Some(size);
+        self
+    }
+
+    /// Whether small writes are sent right away (`TCP_NODELAY`), off by default.
+    pub fn tcp_nodelay(mut self, enabled: bool) -> Self {
+        self.socket.nodelay = enabled;
+        self
+    }
+
+    /// Enables OS-level TCP keepalive probes after the connection was idle for `idle`.
+    ///
+    /// Unlike [`keepalive`](Self::keepalive), this also detects dead connections while
+    /// not in [`Threema::run`].
+    pub fn tcp_keepalive(mut self, idle: Duration) -> Self {
+        self.socket.keepalive = Some(idle);
+        self
+    }
+
+    /// Sizes of the socket's receive and send buffers in bytes, `None` keeps the OS
+    /// default.
+    pub fn socket_buffers(mut self, recv: Option<usize>, send: Option<usize>) -> Self {
+        self.socket.recv_buffer_size = recv;
+        self.socket.send_buffer_size = send;
+        self
+    }
+
+    /// Whether received messages are acknowledged to the server right away, the default.
+    ///
+    /// Disable to process messages at least once, acknowledging them with
+    /// [`Threema::ack`] afterwards.
+    pub fn auto_ack(mut self, enabled: bool) -> Self {
+        self.auto_replies.ack = enabled;
+        self
+    }
+
+    /// Whether received messages are confirmed to their sender as delivered, the default.
+    pub fn auto_delivery_receipt(mut self, enabled: bool) -> Self {
+        self.auto_replies.delivery_receipt = enabled;
+        self
+    }
+
+    /// Whether received messages are confirmed to their sender as read, off by default.
+    pub fn auto_read_receipt(mut self, enabled: bool) -> Self {
+        self.auto_replies.read_receipt = enabled;
+        self
+    }
+
+    /// Checks the settings for values the client can't work with.
+    fn validate(&self) -> Result<()> {
+        if let Nickname::Name(nick) = &self.nick {
+            if nick.len() > Nickname::MAX_LEN {
+                return Err(Error::InvalidConfig(format!(
+                    "nickname is longer than {} bytes",
+                    Nickname::MAX_LEN
+                )));
+            }
+        }
+        if self.keepalive == Some(Duration::ZERO) {
+            return Err(Error::InvalidConfig(
+                "keepalive interval must not be zero".to_owned(),
+            ));
+        }
+        if self.socket.keepalive == Some(Duration::ZERO) {
+            return Err(Error::InvalidConfig(
+                "TCP keepalive time must not be zero".to_owned(),
+            ));
+        }
+        if let Padding::Bucket(size) = self.padding {
+            if size.get() > Padding::MAX_BUCKET {
+                return Err(Error::InvalidConfig(format!(
+                    "padding bucket size must be at most {}",
+                    Padding::MAX_BUCKET
+                )));
+            }
+        }
+        if let Some(size) = self.max_frame_size {
+            if !(MIN_FRAME_SIZE..=MAX_FRAME_SIZE).contains(&size) {
+                return Err(Error::InvalidConfig(format!(
+                    "maximum frame size must be between {MIN_FRAME_SIZE} and {MAX_FRAME_SIZE}"
+                )));
+            }
+        }
+        if let Some(info) = &self.servers {
+            if info.chat_ports.is_empty() {
+                return Err(Error::InvalidConfig("no chat server ports".to_owned()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Validates the configuration and creates the client.
+    pub fn build(self) -> Result<Threema> {
+        self.validate()?;
+        let (id, private_key) = match self.credentials {
+            None => {
+                return Err(Error::InvalidConfig(
+                    "an identity or backup is required".to_owned(),
+                ))
+            }
+            Some(Credentials::Key(id, private_key)) => (id, private_key),
+            Some(Credentials::Backup(data, password)) => {
+                let (id, private_key) =
+                    identity::decrypt(&data, &password).ok_or(Error::InvalidBackupOrPassword)?;
+                (ThreemaID::from_string(&id)?, private_key)
+            }
+        };
+        let private_key = PrivateKey::from_slice(&private_key).ok_or(Error::InvalidPrivateKey)?;
+        #[cfg(feature = "rest")]
+        let agent = self.proxy.as_deref().map(rest::proxy_agent).transpose()?;
+        #[cfg(feature = "rest")]
+        let directory = match (self.directory, &agent) {
+            (Some(directory), _) => directory,
+            (None, Some(agent)) => Box::new(directory::HttpDirectory::with_agent(agent.clone())),
+            (None, None) => directory::default_directory(),
+        };
+        #[cfg(not(feature = "rest"))]
+        let directory = self.directory.unwrap_or_else(directory::default_directory);
+        if let Some(info) = self.servers {
+            servers::set(info);
+        }
+
+        Ok(Threema {
+            id,
+            private_key,
+            peers: self
+                .key_store
+                .unwrap_or_else(|| Box::new(MemoryKeyStore::new())),
+            contacts: self
+                .contacts
+                .unwrap_or_else(|| Box::new(MemoryContactStore::new())),
+            history: self.history,
+            outbox: self.outbox,
+            nonces: self
+                .nonces
+                .unwrap_or_else(|| Box::new(MemoryNonceStore::new())),
+            blocked: HashSet::new(),
+            block_mode: self.block_mode,
+            filter: self.filter,
+            recent: self
+                .recent
+                .unwrap_or_else(|| RecentMessages::new(RECENT_MESSAGES)),
+            duplicates: self.duplicates,
+            directory,
+            #[cfg(feature = "rest")]
+            agent,
+            peer_status: HashMap::new(),
+            refresh_keys: self.refresh_keys,
+            key_refreshes: HashMap::new(),
+            nick: self.nick,
+            long_texts: self.long_texts,
+            padding: self.padding,
+            client_nonce: None,
+            server_nonce: None,
+            server_pubkey: None,
+            ephemeral_private_key: None,
+            conn: None,
+            send_buf: Vec::new(),
+            recv_buf: Vec::new(),
+            max_frame_size: self.max_frame_size.unwrap_or(MAX_FRAME_SIZE),
+            socket: self.socket,
+            rng: self.rng.unwrap_or_else(|| Box::new(OsRng)),
+            clock: self.clock.unwrap_or_else(|| Box::new(SystemClock)),
+            keepalive: self.keepalive.unwrap_or(KEEPALIVE_INTERVAL),
+            echo_pending: None,
+            idle: Duration::ZERO,
+            echo_counter: 0,
+            events: VecDeque::new(),
+            unacked_nonces: VecDeque::new(),
+            session: Session::default(),
+            messages_sent: 0,
+            messages_received: 0,
+            auto_replies: self.auto_replies,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::num::NonZeroU16;
+
+    #[test]
+    fn validation() {
+        let id = ThreemaID::from_string("ECHOECHO").unwrap();
+        assert!(matches!(
+            Threema::builder().build(),
+            Err(Error::InvalidConfig(_))
+        ));
+        assert!(matches!(
+            Threema::builder().identity(id, &[1; 31]).build(),
+            Err(Error::InvalidPrivateKey)
+        ));
+        assert!(matches!(
+            Threema::builder()
+                .identity(id, &[1; 32])
+                .nick("x".repeat(33))
+                .build(),
+            Err(Error::InvalidConfig(_))
+        ));
+        assert!(matches!(
+            Threema::builder()
+                .identity(id, &[1; 32])
+                .padding(Padding::Bucket(NonZeroU16::new(256).unwrap()))
+                .build(),
+            Err(Error::InvalidConfig(_))
+        ));
+        for size in [MIN_FRAME_SIZE - 1, MAX_FRAME_SIZE + 1] {
+            assert!(matches!(
+                Threema::builder()
+                    .identity(id, &[1; 32])
+                    .max_frame_size(size)
+                    .build(),
+                Err(Error::InvalidConfig(_))
+            ));
+        }
+        assert!(matches!(
+            Threema::builder().backup("AAAA", "secret").build(),
+            Err(Error::InvalidBackupOrPassword)
+        ));
+
+        let client = Threema::builder()
+            .identity(id, &[1; 32])
+            .nick("Echo")
+            .build()
+            .unwrap();
+        assert_eq!(client.id(), id);
+        assert_eq!(client.nick(), Some("Echo"));
+    }
+
+    #[cfg(feature = "rest")]
+    #[test]
+    fn proxy() {
+        let id = ThreemaID::from_string("ECHOECHO").unwrap();
+        assert!(matches!(
+            Threema::builder()
+                .identity(id, &[1; 32])
+                .proxy("ftp://localhost")
+                .build(),
+            Err(Error::ParseError(_))
+        ));
+        let proxied = Threema::builder()
+            .identity(id, &[1; 32])
+            .proxy("socks5://localhost:9050")
+            .build()
+            .unwrap();
+        let direct = Threema::new(id, &[1; 32]).unwrap();
+        assert!(proxied.agent.is_some());
+        assert!(direct.agent.is_none());
+    }
+}
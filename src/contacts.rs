@@ -0,0 +1,230 @@
+//! Contacts of the own identity, whose public keys take precedence over the directory.
+
+use crate::crypto::PublicKey;
+use crate::Result;
+use crate::ThreemaID;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, PoisonError};
+
+/// How much the public key of a contact can be trusted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Serialize, Deserialize)]
+pub enum VerificationLevel {
+    /// The key was fetched from the directory by ID.
+    #[default]
+    Unverified,
+    /// The identity was found by one of its linked phone numbers or email addresses.
+    ServerVerified,
+    /// The key was obtained from the contact in person, e.g. by scanning its QR code.
+    FullyVerified,
+}
+
+impl VerificationLevel {
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Unverified => "unverified",
+            Self::ServerVerified => "server_verified",
+            Self::FullyVerified => "fully_verified",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Contact {
+    pub id: ThreemaID,
+    pub public_key: PublicKey,
+    /// Nickname sent by the contact along with its messages
+    pub nickname: Option<String>,
+    pub first_name: Option<String>,
+    pub last_name: Option<String>,
+    pub verification: VerificationLevel,
+    /// Messages of blocked contacts are dropped, see [`Threema::block`](crate::Threema::block).
+    pub blocked: bool,
+}
+
+impl Contact {
+    /// Unverified contact without any names.
+    #[must_use]
+    pub fn new(id: ThreemaID, public_key: PublicKey) -> Self {
+        Self {
+            id,
+            public_key,
+            nickname: None,
+            first_name: None,
+            last_name: None,
+            verification: VerificationLevel::default(),
+            blocked: false,
+        }
+    }
+
+    /// Name to show for the contact: its first and last name, nickname or ID.
+    #[must_use]
+    pub fn display_name(&self) -> String {
+        let name = [&self.first_name, &self.last_name]
+            .iter()
+            .filter_map(|n| n.as_deref())
+            .collect::<Vec<_>>()
+            .join(" ");
+        if !name.is_empty() {
+            return name;
+        }
+        self.nickname.clone().unwrap_or_else(|| self.id.to_string())
+    }
+}
+
+/// Storage of contacts, see [`MemoryContactStore`] and [`FileContactStore`].
+pub trait ContactStore: Send {
+    fn get(&self, id: ThreemaID) -> Option<Contact>;
+    /// Adds `contact` or replaces the one with the same ID.
+    fn put(&mut self, contact: Contact);
+    fn remove(&mut self, id: ThreemaID);
+    fn list(&self) -> Vec<Contact>;
+}
+
+/// Shares the contacts between several clients.
+impl<S: ContactStore + ?Sized> ContactStore for Arc<Mutex<S>> {
+    fn get(&self, id: ThreemaID) -> Option<Contact> {
+        self.lock().unwrap_or_else(PoisonError::into_inner).get(id)
+    }
+
+    fn put(&mut self, contact: Contact) {
+        self.lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .put(contact);
+    }
+
+    fn remove(&mut self, id: ThreemaID) {
+        self.lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(id);
+    }
+
+    fn list(&self) -> Vec<Contact> {
+        self.lock().unwrap_or_else(PoisonError::into_inner).list()
+    }
+}
+
+/// Keeps contacts for the lifetime of the process.
+#[derive(Debug, Default)]
+pub struct MemoryContactStore {
+    contacts: HashMap<ThreemaID, Contact>,
+}
+
+impl MemoryContactStore {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl ContactStore for MemoryContactStore {
+    fn get(&self, id: ThreemaID) -> Option<Contact> {
+        self.contacts.get(&id).cloned()
+    }
+
+    fn put(&mut self, contact: Contact) {
+        self.contacts.insert(contact.id, contact);
+    }
+
+    fn remove(&mut self, id: ThreemaID) {
+        self.contacts.remove(&id);
+    }
+
+    fn list(&self) -> Vec<Contact> {
+        self.contacts.values().cloned().collect()
+    }
+}
+
+/// Persists contacts as JSON file.
+#[derive(Debug)]
+pub struct FileContactStore {
+    path: PathBuf,
+    contacts: Vec<Contact>,
+}
+
+impl FileContactStore {
+    /// Opens the store at `path`, creating it on the first change if it doesn't exist.
+    pub fn open<P: Into<PathBuf>>(path: P) -> Result<Self> {
+        let path = path.into();
+        let contacts = match fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self { path, contacts })
+    }
+
+    fn save(&self) -> Result<()> {
+        let data = serde_json::to_vec_pretty(&self.contacts)?;
+        fs::write(&self.path, data)?;
+        Ok(())
+    }
+
+    fn save_or_warn(&self) {
+        if let Err(e) = self.save() {
+            tracing::warn!("Couldn't save contacts to {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+impl ContactStore for FileContactStore {
+    fn get(&self, id: ThreemaID) -> Option<Contact> {
+        self.contacts.iter().find(|c| c.id == id).cloned()
+    }
+
+    fn put(&mut self, contact: Contact) {
+        match self.contacts.iter_mut().find(|c| c.id == contact.id) {
+            Some(c) => *c = contact,
+            None => self.contacts.push(contact),
+        }
+        self.save_or_warn();
+    }
+
+    fn remove(&mut self, id: ThreemaID) {
+        let len = self.contacts.len();
+        self.contacts.retain(|c| c.id != id);
+        if self.contacts.len() != len {
+            self.save_or_warn();
+        }
+    }
+
+    fn list(&self) -> Vec<Contact> {
+        self.contacts.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_store() {
+        let path =
+            std::env::temp_dir().join(format!("threema-contacts-{}.json", std::process::id()));
+        let id = ThreemaID::from_string("ECHOECHO").unwrap();
+        let mut contact = Contact::new(id, PublicKey([42; 32]));
+        assert_eq!(contact.display_name(), "ECHOECHO");
+        contact.nickname = Some("Echo".to_owned());
+        assert_eq!(contact.display_name(), "Echo");
+        contact.first_name = Some("Eve".to_owned());
+        contact.verification = VerificationLevel::FullyVerified;
+        assert_eq!(contact.display_name(), "Eve");
+        assert_eq!(contact.verification.as_str(), "fully_verified");
+
+        let mut store = FileContactStore::open(&path).unwrap();
+        assert_eq!(store.get(id), None);
+        store.put(contact.clone());
+        store.put(contact.clone());
+        assert_eq!(
+            FileContactStore::open(&path).unwrap().list(),
+            [contact.clone()]
+        );
+        store.remove(id);
+        assert!(FileContactStore::open(&path).unwrap().list().is_empty());
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,189 @@
+//! Messaging with a single peer, see [`Threema::conversation`].
+
+use crate::packets::{Message, MessageStatus};
+use crate::store::StoredMessage;
+#[cfg(feature = "rest")]
+use crate::{
+    crypto,
+    packets::File,
+    rest::{self, blob},
+    sources::{OsRng, RngSource},
+};
+use crate::{MessageID, Result, Threema, ThreemaID};
+#[cfg(feature = "rest")]
+use std::io::{self, Read, Seek};
+#[cfg(all(feature = "rest", feature = "thumbnails"))]
+use {crate::thumbnail, tracing::warn};
+
+/// Handle for sending to and reading the history with one peer.
+pub struct Conversation<'a> {
+    pub(crate) client: &'a mut Threema,
+    pub(crate) peer: ThreemaID,
+}
+
+impl Conversation<'_> {
+    #[must_use]
+    pub fn peer(&self) -> ThreemaID {
+        self.peer
+    }
+
+    /// The client the conversation belongs to.
+    pub fn client(&mut self) -> &mut Threema {
+        self.client
+    }
+
+    pub fn send_text(&mut self, text: impl Into<String>) -> Result<MessageID> {
+        self.client.send_text_message(self.peer, text.into())
+    }
+
+    /// Encrypts `data` with a new key, uploads it to the blob server and sends it as file
+    /// message named `name`.
+    ///
+    /// With the `thumbnails` feature, images are sent with a preview and their size.
+    #[cfg(feature = "rest")]
+    pub fn send_file(&mut self, name: &str, mime: &str, data: &[u8]) -> Result<MessageID> {
+        let mut key = [0; crypto::KEYBYTES];
+        OsRng.fill(&mut key);
+        let agent = self.client.rest_agent();
+        let blob_id = blob::upload_with(
+            &agent,
+            &crypto::secretbox_seal(data, &File::DATA_NONCE, &key),
+        )?;
+        let file = File::new(&blob_id.to_string(), &key, name, mime, data.len() as u64);
+        #[cfg(feature = "thumbnails")]
+        let file = if mime.starts_with("image/") {
+            match thumbnail::generate(data) {
+                Ok(thumbnail) => with_thumbnail(&agent, file, &key, &thumbnail)?,
+                Err(e) => {
+                    warn!(name, error = %e, "Sending image without thumbnail");
+                    file
+                }
+            }
+        } else {
+            file
+        };
+        self.client
+            .send_message(self.peer, &Message::File(file), None)
+    }
+
+    /// Like [`send_file`](Self::send_file), with a thumbnail created from `preview`, e.g.
+    /// the first frame of a video. The file is shown with the size of `preview`.
+    #[cfg(all(feature = "rest", feature = "thumbnails"))]
+    pub fn send_file_with_preview(
+        &mut self,
+        name: &str,
+        mime: &str,
+        data: &[u8],
+        preview: &[u8],
+    ) -> Result<MessageID> {
+        let thumbnail = thumbnail::generate(preview)?;
+        let mut key = [0; crypto::KEYBYTES];
+        OsRng.fill(&mut key);
+        let agent = self.client.rest_agent();
+        let blob_id = blob::upload_with(
+            &agent,
+            &crypto::secretbox_seal(data, &File::DATA_NONCE, &key),
+        )?;
+        let file = File::new(&blob_id.to_string(), &key, name, mime, data.len() as u64);
+        let file = with_thumbnail(&agent, file, &key, &thumbnail)?;
+        self.client
+            .send_message(self.peer, &Message::File(file), None)
+    }
+
+    /// Like [`send_file`](Self::send_file), but streams the rest of `data` to the blob
+    /// server instead of keeping it in memory, see [`blob::upload_from`].
+    #[cfg(feature = "rest")]
+    pub fn send_file_from<R: Read + Seek>(
+        &mut self,
+        name: &str,
+        mime: &str,
+        data: &mut R,
+        progress: impl FnMut(u64, u64),
+    ) -> Result<MessageID> {
+        let agent = self.client.rest_agent();
+        let file = upload_file_with(&agent, name, mime, data, progress)?;
+        self.send_file_message(file)
+    }
+
+    /// Sends a file message for a blob uploaded before, e.g. with [`upload_file_from`]
+    /// and a caption added.
+    #[cfg(feature = "rest")]
+    pub fn send_file_message(&mut self, file: File) -> Result<MessageID> {
+        self.client
+            .send_message(self.peer, &Message::File(file), None)
+    }
+
+    /// Tells the peer that we're typing. Never queued in the outbox.
+    pub fn send_typing(&mut self) -> Result<MessageID> {
+        self.client
+            .send_message(self.peer, &Message::TypingNotification, None)
+    }
+
+    /// Sends a read receipt for the message `msg_id` of the peer.
+    pub fn mark_read(&mut self, msg_id: MessageID) -> Result<MessageID> {
+        self.send_receipt(MessageStatus::Read, msg_id)
+    }
+
+    /// Sends a receipt with `status` for the message `msg_id` of the peer, e.g.
+    /// [`MessageStatus::Approved`] to react with a thumbs up.
+    pub fn send_receipt(&mut self, status: MessageStatus, msg_id: MessageID) -> Result<MessageID> {
+        self.client.confirm_receipt(self.peer, status, msg_id)
+    }
+
+    /// The last `limit` messages exchanged with the peer, oldest first.
+    ///
+    /// Empty if no [history](crate::ThreemaBuilder::message_store) is kept.
+    pub fn history(&mut self, limit: usize) -> Result<Vec<StoredMessage>> {
+        match self.client.message_store() {
+            Some(store) => store.conversation(self.peer, limit),
+            None => Ok(vec![]),
+        }
+    }
+}
+
+/// Encrypts the rest of `data` with a new key and streams it to the blob server,
+/// returning the description to send with [`Conversation::send_file_message`].
+#[cfg(feature = "rest")]
+pub fn upload_file_from<R: Read + Seek>(
+    name: &str,
+    mime: &str,
+    data: &mut R,
+    progress: impl FnMut(u64, u64),
+) -> Result<File> {
+    upload_file_with(&rest::agent(), name, mime, data, progress)
+}
+
+/// Like [`upload_file_from`], sending the request with `agent`.
+#[cfg(feature = "rest")]
+fn upload_file_with<R: Read + Seek>(
+    agent: &ureq::Agent,
+    name: &str,
+    mime: &str,
+    data: &mut R,
+    progress: impl FnMut(u64, u64),
+) -> Result<File> {
+    let mut key = [0; crypto::KEYBYTES];
+    OsRng.fill(&mut key);
+    let start = data.stream_position()?;
+    let size = data.seek(io::SeekFrom::End(0))? - start;
+    data.seek(io::SeekFrom::Start(start))?;
+    let blob_id = blob::upload_from_with(agent, data, &File::DATA_NONCE, &key, progress)?;
+    Ok(File::new(&blob_id.to_string(), &key, name, mime, size))
+}
+
+/// Uploads `thumbnail`, encrypted with the `key` of `file`, and adds it to the file.
+#[cfg(all(feature = "rest", feature = "thumbnails"))]
+fn with_thumbnail(
+    agent: &ureq::Agent,
+    file: File,
+    key: &[u8; crypto::KEYBYTES],
+    thumbnail: &thumbnail::Thumbnail,
+) -> Result<File> {
+    let blob_id = blob::upload_with(
+        agent,
+        &crypto::secretbox_seal(&thumbnail.jpeg, &File::THUMBNAIL_NONCE, key),
+    )?;
+    Ok(file
+        .with_thumbnail(&blob_id.to_string(), thumbnail::MIME)
+        .with_dimensions(thumbnail.width, thumbnail.height))
+}
@@ -0,0 +1,582 @@
+//! The `NaCl` primitives used by the protocol, byte compatible with libsodium.
+//!
+//! Handshake, frames and messages are encrypted with `crypto_box` (Curve25519, `XSalsa20`
+//! and Poly1305), blobs with `crypto_secretbox` and identity backups with the plain
+//! `XSalsa20` stream.
+//!
+//! The primitives are implemented by an internal backend, the `RustCrypto` crates by default
+//! or libsodium with the `libsodium` feature.
+
+#[cfg(any(not(feature = "libsodium"), test))]
+mod rustcrypto;
+#[cfg(feature = "libsodium")]
+mod sodium;
+
+use crate::sources::{OsRng, RngSource};
+use chacha20::cipher::{KeyIvInit, StreamCipher};
+use chacha20::ChaCha20;
+use poly1305::universal_hash::{KeyInit, UniversalHash};
+use poly1305::Poly1305;
+use salsa20::XSalsa20;
+use sha2::{Digest, Sha512};
+use std::convert::TryInto;
+use std::fmt;
+use zeroize::Zeroize;
+
+pub const PUBLICKEYBYTES: usize = 32;
+pub const SECRETKEYBYTES: usize = 32;
+pub const NONCEBYTES: usize = 24;
+pub const SEEDBYTES: usize = 32;
+/// Size of a symmetric key, e.g. for [`secretbox_seal`]
+pub const KEYBYTES: usize = 32;
+/// Size of the authenticator prepended to every box
+pub const MACBYTES: usize = 16;
+
+/// Implementation of the primitives, all byte compatible with libsodium.
+pub(crate) trait Backend {
+    /// The Curve25519 public key of `secret_key`.
+    fn public_key(secret_key: &[u8; SECRETKEYBYTES]) -> [u8; PUBLICKEYBYTES];
+    /// Like `crypto_box_easy`, replacing the data in `buf` with the MAC followed by the
+    /// ciphertext.
+    fn seal_in_place(
+        buf: &mut Vec<u8>,
+        nonce: &[u8; NONCEBYTES],
+        public_key: &[u8; PUBLICKEYBYTES],
+        secret_key: &[u8; SECRETKEYBYTES],
+    );
+    /// Like `crypto_box_open_easy`, replacing the box in `buf` with the data. Returns
+    /// `false` and leaves `buf` as is if the box is invalid.
+    fn open_in_place(
+        buf: &mut Vec<u8>,
+        nonce: &[u8; NONCEBYTES],
+        public_key: &[u8; PUBLICKEYBYTES],
+        secret_key: &[u8; SECRETKEYBYTES],
+    ) -> bool;
+    /// Like `crypto_secretbox_easy`, returning the MAC followed by the ciphertext.
+    fn secretbox_seal(data: &[u8], nonce: &[u8; NONCEBYTES], key: &[u8; KEYBYTES]) -> Vec<u8>;
+    /// Like `crypto_secretbox_open_easy`, returning `None` if the box is invalid.
+    fn secretbox_open(
+        ciphertext: &[u8],
+        nonce: &[u8; NONCEBYTES],
+        key: &[u8; KEYBYTES],
+    ) -> Option<Vec<u8>>;
+    /// Like `crypto_stream_xsalsa20_xor`.
+    fn xsalsa20_xor(data: &[u8], nonce: &[u8; NONCEBYTES], key: &[u8; 32]) -> Vec<u8>;
+    /// Fills `buf` with cryptographically secure random bytes.
+    fn random(buf: &mut [u8]);
+}
+
+#[cfg(not(feature = "libsodium"))]
+type Active = rustcrypto::RustCrypto;
+#[cfg(feature = "libsodium")]
+type Active = sodium::Sodium;
+
+/// Curve25519 public key.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PublicKey(pub [u8; PUBLICKEYBYTES]);
+
+impl PublicKey {
+    #[must_use]
+    pub fn from_slice(bytes: &[u8]) -> Option<Self> {
+        bytes.try_into().ok().map(Self)
+    }
+
+    #[must_use]
+    pub fn from_hex(s: &str) -> Option<Self> {
+        crate::decode_hex(s).map(Self)
+    }
+
+    #[must_use]
+    pub fn to_hex(&self) -> String {
+        crate::encode_hex(&self.0)
+    }
+
+    /// First half of the SHA-256 hash of the key, which the apps show to compare keys.
+    #[must_use]
+    pub fn fingerprint(&self) -> [u8; 16] {
+        let hash = sha2::Sha256::digest(self.0);
+        let mut fingerprint = [0; 16];
+        fingerprint.copy_from_slice(&hash[..16]);
+        fingerprint
+    }
+}
+
+impl AsRef<[u8]> for PublicKey {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl fmt::Debug for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("PublicKey")
+            .field(&crate::encode_hex(&self.0))
+            .finish()
+    }
+}
+
+// same representation as sodiumoxide, so existing key stores stay readable
+impl serde::Serialize for PublicKey {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.0)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for PublicKey {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl<'de> serde::de::Visitor<'de> for Visitor {
+            type Value = PublicKey;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "{PUBLICKEYBYTES} bytes")
+            }
+
+            fn visit_bytes<E: serde::de::Error>(self, v: &[u8]) -> Result<PublicKey, E> {
+                PublicKey::from_slice(v).ok_or_else(|| E::invalid_length(v.len(), &self))
+            }
+
+            fn visit_seq<A: serde::de::SeqAccess<'de>>(
+                self,
+                mut seq: A,
+            ) -> Result<PublicKey, A::Error> {
+                let mut key = PublicKey([0; PUBLICKEYBYTES]);
+                for (i, b) in key.0.iter_mut().enumerate() {
+                    *b = seq
+                        .next_element()?
+                        .ok_or_else(|| serde::de::Error::invalid_length(i, &self))?;
+                }
+                if seq.next_element::<u8>()?.is_some() {
+                    return Err(serde::de::Error::invalid_length(PUBLICKEYBYTES + 1, &self));
+                }
+                Ok(key)
+            }
+        }
+
+        deserializer.deserialize_bytes(Visitor)
+    }
+}
+
+/// Curve25519 secret key, cleared from memory when dropped.
+#[derive(Clone)]
+pub struct SecretKey(pub [u8; SECRETKEYBYTES]);
+
+impl SecretKey {
+    #[must_use]
+    pub fn from_slice(bytes: &[u8]) -> Option<Self> {
+        bytes.try_into().ok().map(Self)
+    }
+
+    #[must_use]
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey(Active::public_key(&self.0))
+    }
+}
+
+impl Drop for SecretKey {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SecretKey(****)")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Nonce(pub [u8; NONCEBYTES]);
+
+impl AsRef<[u8]> for Nonce {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// Input of [`keypair_from_seed`].
+pub struct Seed(pub [u8; SEEDBYTES]);
+
+/// Derives a key pair from `seed` like `crypto_box_seed_keypair`.
+#[must_use]
+pub fn keypair_from_seed(seed: &Seed) -> (PublicKey, SecretKey) {
+    let hash = Sha512::digest(seed.0);
+    let mut secret = SecretKey([0; SECRETKEYBYTES]);
+    secret.0.copy_from_slice(&hash[..SECRETKEYBYTES]);
+    (secret.public_key(), secret)
+}
+
+#[must_use]
+pub fn gen_keypair() -> (PublicKey, SecretKey) {
+    let mut secret = SecretKey([0; SECRETKEYBYTES]);
+    OsRng.fill(&mut secret.0);
+    (secret.public_key(), secret)
+}
+
+#[must_use]
+pub fn gen_nonce() -> Nonce {
+    let mut nonce = Nonce([0; NONCEBYTES]);
+    OsRng.fill(&mut nonce.0);
+    nonce
+}
+
+/// Encrypts and authenticates `data` from the owner of `secret_key` to the owner of
+/// `public_key`, returning the MAC followed by the ciphertext.
+#[must_use]
+pub fn seal(data: &[u8], nonce: &Nonce, public_key: &PublicKey, secret_key: &SecretKey) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(data.len() + MACBYTES);
+    buf.extend_from_slice(data);
+    seal_in_place(&mut buf, nonce, public_key, secret_key);
+    buf
+}
+
+/// Like [`seal`], but encrypts the data in `buf`, reusing its allocation.
+pub fn seal_in_place(
+    buf: &mut Vec<u8>,
+    nonce: &Nonce,
+    public_key: &PublicKey,
+    secret_key: &SecretKey,
+) {
+    Active::seal_in_place(buf, &nonce.0, &public_key.0, &secret_key.0);
+}
+
+/// Verifies and decrypts a box created by [`seal`], or returns `None` if it's invalid.
+#[must_use]
+pub fn open(
+    ciphertext: &[u8],
+    nonce: &Nonce,
+    public_key: &PublicKey,
+    secret_key: &SecretKey,
+) -> Option<Vec<u8>> {
+    let mut buf = ciphertext.to_vec();
+    open_in_place(&mut buf, nonce, public_key, secret_key).then_some(buf)
+}
+
+/// Like [`open`], but decrypts the box in `buf`, reusing its allocation. Returns `false`
+/// and leaves `buf` as is if the box is invalid.
+#[must_use]
+pub fn open_in_place(
+    buf: &mut Vec<u8>,
+    nonce: &Nonce,
+    public_key: &PublicKey,
+    secret_key: &SecretKey,
+) -> bool {
+    Active::open_in_place(buf, &nonce.0, &public_key.0, &secret_key.0)
+}
+
+/// Encrypts and authenticates `data` with the symmetric `key`, returning the MAC followed
+/// by the ciphertext.
+#[must_use]
+pub fn secretbox_seal(data: &[u8], nonce: &Nonce, key: &[u8; KEYBYTES]) -> Vec<u8> {
+    Active::secretbox_seal(data, &nonce.0, key)
+}
+
+/// Verifies and decrypts a box created by [`secretbox_seal`], or returns `None` if it's
+/// invalid.
+#[must_use]
+pub fn secretbox_open(ciphertext: &[u8], nonce: &Nonce, key: &[u8; KEYBYTES]) -> Option<Vec<u8>> {
+    Active::secretbox_open(ciphertext, &nonce.0, key)
+}
+
+/// XORs `data` with the `XSalsa20` key stream, like `crypto_stream_xsalsa20_xor`.
+#[must_use]
+pub fn xsalsa20_xor(data: &[u8], nonce: &Nonce, key: &[u8; 32]) -> Vec<u8> {
+    Active::xsalsa20_xor(data, &nonce.0, key)
+}
+
+/// Incremental `crypto_secretbox`, for data too large to keep in memory.
+///
+/// The MAC of a secret box precedes the ciphertext but covers all of it. Sealing thus
+/// needs the whole ciphertext before the MAC can be written, and opening produces
+/// plaintext before it's authenticated, which must be discarded if [`verify`](Self::verify)
+/// fails.
+pub struct SecretboxStream {
+    cipher: XSalsa20,
+    mac: Option<Poly1305>,
+    /// ciphertext not yet authenticated as it doesn't fill a Poly1305 block
+    pending: Vec<u8>,
+}
+
+impl SecretboxStream {
+    const BLOCK: usize = 16;
+
+    #[must_use]
+    pub fn new(nonce: &Nonce, key: &[u8; KEYBYTES]) -> Self {
+        let mut cipher = XSalsa20::new(key.into(), (&nonce.0).into());
+        // the first 32 bytes of the key stream are the one-time Poly1305 key
+        let mut mac_key = [0; 32];
+        cipher.apply_keystream(&mut mac_key);
+        let mac = Poly1305::new((&mac_key).into());
+        mac_key.zeroize();
+        Self {
+            cipher,
+            mac: Some(mac),
+            pending: Vec::with_capacity(Self::BLOCK),
+        }
+    }
+
+    /// Encrypts the next part of the data in place.
+    pub fn encrypt(&mut self, buf: &mut [u8]) {
+        self.cipher.apply_keystream(buf);
+        self.authenticate(buf);
+    }
+
+    /// Decrypts the next part of the ciphertext in place.
+    pub fn decrypt(&mut self, buf: &mut [u8]) {
+        self.authenticate(buf);
+        self.cipher.apply_keystream(buf);
+    }
+
+    fn block(bytes: &[u8]) -> poly1305::Block {
+        let block: [u8; Self::BLOCK] = bytes.try_into().expect("a full block");
+        block.into()
+    }
+
+    fn authenticate(&mut self, mut ciphertext: &[u8]) {
+        let mac = self.mac.as_mut().expect("only taken when finishing");
+        if !self.pending.is_empty() {
+            let take = ciphertext.len().min(Self::BLOCK - self.pending.len());
+            self.pending.extend_from_slice(&ciphertext[..take]);
+            ciphertext = &ciphertext[take..];
+            if self.pending.len() < Self::BLOCK {
+                return;
+            }
+            mac.update(&[Self::block(&self.pending)]);
+            self.pending.clear();
+        }
+        let full = ciphertext.len() - ciphertext.len() % Self::BLOCK;
+        let blocks: Vec<_> = ciphertext[..full]
+            .chunks_exact(Self::BLOCK)
+            .map(Self::block)
+            .collect();
+        mac.update(&blocks);
+        self.pending.extend_from_slice(&ciphertext[full..]);
+    }
+
+    /// The MAC of all data encrypted so far, to be put in front of the ciphertext.
+    #[must_use]
+    pub fn mac(mut self) -> [u8; MACBYTES] {
+        let mac = self.mac.take().expect("only taken when finishing");
+        mac.compute_unpadded(&self.pending).into()
+    }
+
+    /// Whether `mac` authenticates all ciphertext decrypted so far.
+    #[must_use]
+    pub fn verify(self, mac: &[u8; MACBYTES]) -> bool {
+        let expected = self.mac();
+        // constant time, no early exit on the first difference
+        expected
+            .iter()
+            .zip(mac)
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+    }
+}
+
+/// Fills `buf` with cryptographically secure random bytes.
+pub(crate) fn random_bytes(buf: &mut [u8]) {
+    Active::random(buf);
+}
+
+/// Fills `buf` with bytes derived from `seed`, like `randombytes_buf_deterministic`.
+pub(crate) fn deterministic_bytes(buf: &mut [u8], seed: &[u8; 32]) {
+    buf.fill(0);
+    ChaCha20::new(seed.into(), b"LibsodiumDRG".into()).apply_keystream(buf);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sodiumoxide::crypto::box_ as sodium;
+    use sodiumoxide::crypto::secretbox as sodium_secretbox;
+    use sodiumoxide::crypto::stream::xsalsa20 as sodium_stream;
+    use sodiumoxide::randombytes as sodium_random;
+
+    #[test]
+    fn keys() {
+        for seed in 0..8 {
+            let (pk, sk) = keypair_from_seed(&Seed([seed; 32]));
+            let (spk, ssk) = sodium::keypair_from_seed(&sodium::Seed([seed; 32]));
+            assert_eq!(pk.0, spk.0);
+            assert_eq!(sk.0, ssk.0);
+            assert_eq!(sk.public_key(), pk);
+        }
+        let (pk, _) = gen_keypair();
+        assert_eq!(PublicKey::from_slice(pk.as_ref()), Some(pk));
+        assert_eq!(PublicKey::from_slice(&[0; 31]), None);
+        assert_eq!(PublicKey::from_hex(&pk.to_hex()), Some(pk));
+        assert_eq!(PublicKey::from_hex("00"), None);
+        assert_eq!(
+            crate::encode_hex(&PublicKey([0; 32]).fingerprint()),
+            "66687aadf862bd776c8fc18b8e9f8e20"
+        );
+    }
+
+    #[test]
+    fn boxes() {
+        let (alice_pub, alice_priv) = keypair_from_seed(&Seed([1; 32]));
+        let (bob_pub, bob_priv) = keypair_from_seed(&Seed([2; 32]));
+        let sodium_alice = sodium::SecretKey(alice_priv.0);
+        let sodium_bob = sodium::SecretKey(bob_priv.0);
+        // sizes of the handshake boxes, a frame and an empty message
+        for (n, len) in (0u8..).zip([0, 32, 48, 128, 1000]) {
+            let data = vec![n; len];
+            let nonce = Nonce([n; 24]);
+            let sodium_nonce = sodium::Nonce(nonce.0);
+
+            let sealed = seal(&data, &nonce, &bob_pub, &alice_priv);
+            assert_eq!(sealed.len(), len + MACBYTES);
+            assert_eq!(
+                sealed,
+                sodium::seal(
+                    &data,
+                    &sodium_nonce,
+                    &sodium::PublicKey(bob_pub.0),
+                    &sodium_alice
+                )
+            );
+            assert_eq!(
+                sodium::open(
+                    &sealed,
+                    &sodium_nonce,
+                    &sodium::PublicKey(alice_pub.0),
+                    &sodium_bob
+                ),
+                Ok(data.clone())
+            );
+            assert_eq!(
+                open(&sealed, &nonce, &alice_pub, &bob_priv),
+                Some(data.clone())
+            );
+
+            let mut tampered = sealed;
+            tampered[0] ^= 1;
+            assert_eq!(open(&tampered, &nonce, &alice_pub, &bob_priv), None);
+
+            let mut buf = data.clone();
+            seal_in_place(&mut buf, &nonce, &bob_pub, &alice_priv);
+            assert!(!open_in_place(&mut buf, &nonce, &bob_pub, &bob_priv));
+            assert!(open_in_place(&mut buf, &nonce, &alice_pub, &bob_priv));
+            assert_eq!(buf, data);
+        }
+        assert_eq!(open(&[0; 15], &Nonce([0; 24]), &alice_pub, &bob_priv), None);
+    }
+
+    #[test]
+    fn secretboxes() {
+        let key = [7; KEYBYTES];
+        for len in [0, 16, 1000] {
+            let data = vec![1; len];
+            let nonce = Nonce([2; 24]);
+            let sealed = secretbox_seal(&data, &nonce, &key);
+            assert_eq!(
+                sealed,
+                sodium_secretbox::seal(
+                    &data,
+                    &sodium_secretbox::Nonce(nonce.0),
+                    &sodium_secretbox::Key(key)
+                )
+            );
+            assert_eq!(secretbox_open(&sealed, &nonce, &key), Some(data));
+            assert_eq!(secretbox_open(&sealed, &nonce, &[8; KEYBYTES]), None);
+        }
+    }
+
+    #[test]
+    fn secretbox_streams() {
+        let key = [7; KEYBYTES];
+        let nonce = Nonce([2; 24]);
+        let data: Vec<u8> = (0..=255).cycle().take(1000).collect();
+        let sealed = secretbox_seal(&data, &nonce, &key);
+        for chunk in [1, 15, 16, 17, 1000] {
+            let mut encrypted = data.clone();
+            let mut stream = SecretboxStream::new(&nonce, &key);
+            for part in encrypted.chunks_mut(chunk) {
+                stream.encrypt(part);
+            }
+            assert_eq!(stream.mac()[..], sealed[..MACBYTES]);
+            assert_eq!(encrypted, sealed[MACBYTES..]);
+
+            let mac = sealed[..MACBYTES].try_into().unwrap();
+            let mut stream = SecretboxStream::new(&nonce, &key);
+            for part in encrypted.chunks_mut(chunk) {
+                stream.decrypt(part);
+            }
+            assert_eq!(encrypted, data);
+            assert!(stream.verify(&mac));
+        }
+        let mut stream = SecretboxStream::new(&nonce, &key);
+        stream.decrypt(&mut sealed[MACBYTES + 1..].to_vec());
+        assert!(!stream.verify(&sealed[..MACBYTES].try_into().unwrap()));
+    }
+
+    #[test]
+    fn streams() {
+        let data = b"backup".repeat(20);
+        let nonce = Nonce([3; 24]);
+        assert_eq!(
+            xsalsa20_xor(&data, &nonce, &[4; 32]),
+            sodium_stream::stream_xor(
+                &data,
+                &sodium_stream::Nonce(nonce.0),
+                &sodium_stream::Key([4; 32])
+            )
+        );
+
+        let mut buf = [0xff; 100];
+        deterministic_bytes(&mut buf, &[5; 32]);
+        let mut expected = [0; 100];
+        sodium_random::randombytes_buf_deterministic_into(
+            &mut expected,
+            &sodium_random::Seed([5; 32]),
+        );
+        assert_eq!(buf, expected);
+    }
+
+    #[cfg(feature = "libsodium")]
+    #[test]
+    fn backends() {
+        use super::rustcrypto::RustCrypto;
+        use super::sodium::Sodium;
+
+        let (sk, nonce, key) = ([1; 32], [2; 24], [3; 32]);
+        let pk = Sodium::public_key(&[4; 32]);
+        assert_eq!(RustCrypto::public_key(&sk), Sodium::public_key(&sk));
+        let mut sealed = b"hi".to_vec();
+        RustCrypto::seal_in_place(&mut sealed, &nonce, &pk, &sk);
+        let mut sodium_sealed = b"hi".to_vec();
+        Sodium::seal_in_place(&mut sodium_sealed, &nonce, &pk, &sk);
+        assert_eq!(sealed, sodium_sealed);
+        assert!(!Sodium::open_in_place(&mut sealed, &nonce, &pk, &[4; 32]));
+        assert_eq!(sealed, sodium_sealed);
+        assert!(Sodium::open_in_place(
+            &mut sealed,
+            &nonce,
+            &RustCrypto::public_key(&sk),
+            &[4; 32]
+        ));
+        assert_eq!(sealed, b"hi");
+        let sealed = RustCrypto::secretbox_seal(b"hi", &nonce, &key);
+        assert_eq!(sealed, Sodium::secretbox_seal(b"hi", &nonce, &key));
+        assert_eq!(
+            Sodium::secretbox_open(&sealed, &nonce, &key),
+            Some(b"hi".to_vec())
+        );
+        assert_eq!(
+            RustCrypto::xsalsa20_xor(b"hi", &nonce, &key),
+            Sodium::xsalsa20_xor(b"hi", &nonce, &key)
+        );
+    }
+
+    #[test]
+    fn serde() {
+        let (pk, _) = keypair_from_seed(&Seed([1; 32]));
+        let json = serde_json::to_string(&pk).unwrap();
+        let sodium_pk = sodium::PublicKey(pk.0);
+        // sodiumoxide's `serde` feature isn't enabled, so compare against a plain array
+        assert_eq!(json, serde_json::to_string(&sodium_pk.0.to_vec()).unwrap());
+        assert_eq!(serde_json::from_str::<PublicKey>(&json).unwrap(), pk);
+        assert!(serde_json::from_str::<PublicKey>("[1, 2, 3]").is_err());
+    }
+}
@@ -0,0 +1,69 @@
+//! Pure Rust backend based on the `RustCrypto` crates, used by default.
+
+use super::{Backend, KEYBYTES, NONCEBYTES, PUBLICKEYBYTES, SECRETKEYBYTES};
+use crypto_box::aead::{Aead, AeadInPlace};
+use crypto_box::{PublicKey, SalsaBox, SecretKey};
+use crypto_secretbox::{KeyInit, XSalsa20Poly1305};
+use salsa20::cipher::{KeyIvInit, StreamCipher};
+use salsa20::XSalsa20;
+
+pub(crate) struct RustCrypto;
+
+fn salsa_box(public_key: &[u8; PUBLICKEYBYTES], secret_key: &[u8; SECRETKEYBYTES]) -> SalsaBox {
+    SalsaBox::new(&PublicKey::from(*public_key), &SecretKey::from(*secret_key))
+}
+
+impl Backend for RustCrypto {
+    fn public_key(secret_key: &[u8; SECRETKEYBYTES]) -> [u8; PUBLICKEYBYTES] {
+        *SecretKey::from(*secret_key).public_key().as_bytes()
+    }
+
+    fn seal_in_place(
+        buf: &mut Vec<u8>,
+        nonce: &[u8; NONCEBYTES],
+        public_key: &[u8; PUBLICKEYBYTES],
+        secret_key: &[u8; SECRETKEYBYTES],
+    ) {
+        salsa_box(public_key, secret_key)
+            .encrypt_in_place(nonce.into(), b"", buf)
+            .expect("encrypting never fails");
+    }
+
+    fn open_in_place(
+        buf: &mut Vec<u8>,
+        nonce: &[u8; NONCEBYTES],
+        public_key: &[u8; PUBLICKEYBYTES],
+        secret_key: &[u8; SECRETKEYBYTES],
+    ) -> bool {
+        // the MAC is checked before anything is decrypted
+        salsa_box(public_key, secret_key)
+            .decrypt_in_place(nonce.into(), b"", buf)
+            .is_ok()
+    }
+
+    fn secretbox_seal(data: &[u8], nonce: &[u8; NONCEBYTES], key: &[u8; KEYBYTES]) -> Vec<u8> {
+        XSalsa20Poly1305::new(key.into())
+            .encrypt(nonce.into(), data)
+            .expect("encrypting never fails")
+    }
+
+    fn secretbox_open(
+        ciphertext: &[u8],
+        nonce: &[u8; NONCEBYTES],
+        key: &[u8; KEYBYTES],
+    ) -> Option<Vec<u8>> {
+        XSalsa20Poly1305::new(key.into())
+            .decrypt(nonce.into(), ciphertext)
+            .ok()
+    }
+
+    fn xsalsa20_xor(data: &[u8], nonce: &[u8; NONCEBYTES], key: &[u8; 32]) -> Vec<u8> {
+        let mut out = data.to_vec();
+        XSalsa20::new(key.into(), nonce.into()).apply_keystream(&mut out);
+        out
+    }
+
+    fn random(buf: &mut [u8]) {
+        getrandom::getrandom(buf).expect("OS random number generator failed");
+    }
+}
@@ -0,0 +1,88 @@
+//! Backend based on the C library libsodium, enabled by the `libsodium` feature.
+
+use super::{Backend, KEYBYTES, MACBYTES, NONCEBYTES, PUBLICKEYBYTES, SECRETKEYBYTES};
+use sodiumoxide::crypto::box_;
+use sodiumoxide::crypto::secretbox;
+use sodiumoxide::crypto::stream::xsalsa20;
+use sodiumoxide::randombytes;
+use std::sync::Once;
+
+pub(crate) struct Sodium;
+
+fn init() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        sodiumoxide::init().expect("Couldn't initialize libsodium");
+    });
+}
+
+impl Backend for Sodium {
+    fn public_key(secret_key: &[u8; SECRETKEYBYTES]) -> [u8; PUBLICKEYBYTES] {
+        init();
+        box_::SecretKey(*secret_key).public_key().0
+    }
+
+    fn seal_in_place(
+        buf: &mut Vec<u8>,
+        nonce: &[u8; NONCEBYTES],
+        public_key: &[u8; PUBLICKEYBYTES],
+        secret_key: &[u8; SECRETKEYBYTES],
+    ) {
+        init();
+        let tag = box_::seal_detached(
+            buf,
+            &box_::Nonce(*nonce),
+            &box_::PublicKey(*public_key),
+            &box_::SecretKey(*secret_key),
+        );
+        buf.splice(..0, tag.0);
+    }
+
+    fn open_in_place(
+        buf: &mut Vec<u8>,
+        nonce: &[u8; NONCEBYTES],
+        public_key: &[u8; PUBLICKEYBYTES],
+        secret_key: &[u8; SECRETKEYBYTES],
+    ) -> bool {
+        init();
+        if buf.len() < MACBYTES {
+            return false;
+        }
+        let (tag, data) = buf.split_at_mut(MACBYTES);
+        let opened = box_::open_detached(
+            data,
+            &box_::Tag::from_slice(tag).expect("tag has the right size"),
+            &box_::Nonce(*nonce),
+            &box_::PublicKey(*public_key),
+            &box_::SecretKey(*secret_key),
+        );
+        if opened.is_ok() {
+            buf.drain(..MACBYTES);
+        }
+        opened.is_ok()
+    }
+
+    fn secretbox_seal(data: &[u8], nonce: &[u8; NONCEBYTES], key: &[u8; KEYBYTES]) -> Vec<u8> {
+        init();
+        secretbox::seal(data, &secretbox::Nonce(*nonce), &secretbox::Key(*key))
+    }
+
+    fn secretbox_open(
+        ciphertext: &[u8],
+        nonce: &[u8; NONCEBYTES],
+        key: &[u8; KEYBYTES],
+    ) -> Option<Vec<u8>> {
+        init();
+        secretbox::open(ciphertext, &secretbox::Nonce(*nonce), &secretbox::Key(*key)).ok()
+    }
+
+    fn xsalsa20_xor(data: &[u8], nonce: &[u8; NONCEBYTES], key: &[u8; 32]) -> Vec<u8> {
+        init();
+        xsalsa20::stream_xor(data, &xsalsa20::Nonce(*nonce), &xsalsa20::Key(*key))
+    }
+
+    fn random(buf: &mut [u8]) {
+        init();
+        randombytes::randombytes_into(buf);
+    }
+}
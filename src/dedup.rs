@@ -0,0 +1,123 @@
+//! Detection of messages received more than once.
+//!
+//! Senders may send a message again with the same ID after reconnecting, encrypted with
+//! a new nonce, and the server redelivers messages whose ack got lost. Messages reusing
+//! a known nonce are only passed through the [`DuplicatePolicy`] if their ID is known
+//! too, otherwise they're dropped as replays by the [nonce store](crate::nonces).
+
+use crate::{MessageID, Result, ThreemaID};
+use std::collections::{HashSet, VecDeque};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+/// What happens to a message which was already received.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DuplicatePolicy {
+    /// Acknowledge and drop it
+    #[default]
+    Suppress,
+    /// Pass it on with [`ServerMessage::duplicate`](crate::ServerMessage::duplicate) set,
+    /// without sending receipts again
+    Flag,
+}
+
+/// The IDs of the last received messages, optionally persisted as JSON file.
+#[derive(Debug)]
+pub struct RecentMessages {
+    capacity: usize,
+    order: VecDeque<(ThreemaID, MessageID)>,
+    seen: HashSet<(ThreemaID, MessageID)>,
+    path: Option<PathBuf>,
+}
+
+impl RecentMessages {
+    /// Remembers the last `capacity` messages in memory.
+    #[must_use]
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            order: VecDeque::new(),
+            seen: HashSet::new(),
+            path: None,
+        }
+    }
+
+    /// Remembers the last `capacity` messages in the file at `path`, creating it on the
+    /// first message if it doesn't exist.
+    pub fn open<P: Into<PathBuf>>(path: P, capacity: usize) -> Result<Self> {
+        let path = path.into();
+        let entries: Vec<(ThreemaID, MessageID)> = match fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let mut recent = Self::new(capacity);
+        for (sender, msg_id) in entries {
+            recent.remember(sender, msg_id);
+        }
+        recent.path = Some(path);
+        Ok(recent)
+    }
+
+    /// Whether the message `msg_id` of `sender` was seen before, without recording it.
+    #[must_use]
+    pub fn contains(&self, sender: ThreemaID, msg_id: MessageID) -> bool {
+        self.seen.contains(&(sender, msg_id))
+    }
+
+    /// Records the message `msg_id` of `sender`, returning whether it was seen before.
+    pub fn check(&mut self, sender: ThreemaID, msg_id: MessageID) -> bool {
+        if self.seen.contains(&(sender, msg_id)) {
+            return true;
+        }
+        self.remember(sender, msg_id);
+        if let Some(path) = &self.path {
+            let saved = serde_json::to_vec(&self.order)
+                .map_err(crate::Error::from)
+                .and_then(|data| Ok(fs::write(path, data)?));
+            if let Err(e) = saved {
+                tracing::warn!("Couldn't save message IDs to {}: {}", path.display(), e);
+            }
+        }
+        false
+    }
+
+    fn remember(&mut self, sender: ThreemaID, msg_id: MessageID) {
+        if self.capacity == 0 || !self.seen.insert((sender, msg_id)) {
+            return;
+        }
+        self.order.push_back((sender, msg_id));
+        while self.order.len() > self.capacity {
+            if let Some(old) = self.order.pop_front() {
+                self.seen.remove(&old);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn eviction_and_persistence() {
+        let peer = ThreemaID::from_string("ECHOECHO").unwrap();
+        let id = |n| MessageID::from_bytes([n; 8]);
+        let path = std::env::temp_dir().join(format!("threema-recent-{}.json", std::process::id()));
+
+        let mut recent = RecentMessages::open(&path, 2).unwrap();
+        assert!(!recent.check(peer, id(1)));
+        assert!(recent.check(peer, id(1)));
+        assert!(!recent.check(peer, id(2)));
+        assert!(!recent.check(peer, id(3)));
+        // evicted
+        assert!(!recent.check(peer, id(1)));
+
+        let mut recent = RecentMessages::open(&path, 2).unwrap();
+        assert!(recent.check(peer, id(1)));
+        assert!(recent.check(peer, id(3)));
+        assert!(!recent.check(peer, id(2)));
+        fs::remove_file(path).unwrap();
+    }
+}
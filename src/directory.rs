@@ -0,0 +1,205 @@
+//! Access to the identity directory, which knows the public keys and states of identities.
+
+use crate::crypto::PublicKey;
+use crate::identity::{self, FeatureMask, IdentityMatch, IdentityState, IdentityStatus};
+#[cfg(feature = "rest")]
+use crate::rest;
+use crate::rest::{RestError, RestErrorKind};
+use crate::Error;
+use crate::Result;
+use crate::ThreemaID;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// What the directory knows about an identity.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirectoryEntry {
+    pub public_key: PublicKey,
+    pub status: IdentityStatus,
+}
+
+/// Client of the identity directory, see `HttpDirectory` (requires the `rest` feature)
+/// and [`MemoryDirectory`].
+pub trait DirectoryClient: Send {
+    /// Looks up `id`, returning `None` if the directory doesn't know it.
+    fn fetch_identity(&self, id: ThreemaID) -> Result<Option<DirectoryEntry>>;
+    /// Looks up the identities linked to the given phone numbers and email addresses.
+    fn match_identities(&self, phones: &[&str], emails: &[&str]) -> Result<Vec<IdentityMatch>>;
+}
+
+/// Shares one client, e.g. with its connection pool, between several clients.
+impl<D: DirectoryClient + Sync + ?Sized> DirectoryClient for Arc<D> {
+    fn fetch_identity(&self, id: ThreemaID) -> Result<Option<DirectoryEntry>> {
+        (**self).fetch_identity(id)
+    }
+
+    fn match_identities(&self, phones: &[&str], emails: &[&str]) -> Result<Vec<IdentityMatch>> {
+        (**self).match_identities(phones, emails)
+    }
+}
+
+/// Error returned when a required identity isn't known to the directory.
+pub(crate) fn unknown_identity(id: ThreemaID) -> Error {
+    Error::Rest(RestError {
+        status: Some(404),
+        body: Some(format!("unknown identity {id}")),
+        kind: RestErrorKind::UnknownIdentity,
+    })
+}
+
+/// The directory used unless another one is configured.
+#[cfg(feature = "rest")]
+pub(crate) fn default_directory() -> Box<dyn DirectoryClient> {
+    Box::new(HttpDirectory::new())
+}
+
+/// Without the `rest` feature, no identities are known besides the contacts and the keys
+/// added with [`Threema::add_peer_key`](crate::Threema::add_peer_key).
+#[cfg(not(feature = "rest"))]
+pub(crate) fn default_directory() -> Box<dyn DirectoryClient> {
+    Box::new(MemoryDirectory::new())
+}
+
+/// The directory API of the current [servers](crate::servers).
+#[cfg(feature = "rest")]
+#[derive(Debug, Default, Clone)]
+pub struct HttpDirectory {
+    /// agent with its own proxy, the shared one if `None`
+    agent: Option<ureq::Agent>,
+}
+
+#[cfg(feature = "rest")]
+impl HttpDirectory {
+    /// Sends requests through the proxy set with [`rest::set_proxy`], if any.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sends requests through `proxy`, e.g. `socks5://localhost:9050`, regardless of
+    /// [`rest::set_proxy`].
+    pub fn with_proxy(proxy: &str) -> Result<Self> {
+        Ok(Self::with_agent(rest::proxy_agent(proxy)?))
+    }
+
+    pub(crate) fn with_agent(agent: ureq::Agent) -> Self {
+        Self { agent: Some(agent) }
+    }
+
+    fn agent(&self) -> ureq::Agent {
+        self.agent.clone().unwrap_or_else(rest::agent)
+    }
+}
+
+#[cfg(feature = "rest")]
+impl DirectoryClient for HttpDirectory {
+    fn fetch_identity(&self, id: ThreemaID) -> Result<Option<DirectoryEntry>> {
+        let resp: Option<rest::messages::GetPubKeyResponse> =
+            rest::request_optional(&self.agent(), &format!("/identity/{id}"))?;
+        let Some(resp) = resp else {
+            return Ok(None);
+        };
+        if resp.identity != id.to_string() {
+            return Err(Error::InvalidID);
+        }
+        Ok(Some(DirectoryEntry {
+            public_key: PublicKey::from_slice(resp.public_key.as_ref())
+                .ok_or(Error::InvalidPublicKey)?,
+            status: IdentityStatus::from(&resp),
+        }))
+    }
+
+    fn match_identities(&self, phones: &[&str], emails: &[&str]) -> Result<Vec<IdentityMatch>> {
+        let req = rest::messages::MatchRequest {
+            email_hashes: emails
+                .iter()
+                .map(|e| identity::hash_email(e).into())
+                .collect(),
+            mobile_no_hashes: phones
+                .iter()
+                .map(|p| identity::hash_phone(p).into())
+                .collect(),
+        };
+        let resp: Vec<rest::messages::MatchResponseEntry> = rest::post(
+            &self.agent(),
+            "/identity/match",
+            &req,
+            rest::Retry::Idempotent,
+        )?;
+        resp.into_iter()
+            .map(|entry| {
+                Ok(IdentityMatch {
+                    id: ThreemaID::from_string(&entry.identity)?,
+                    public_key: PublicKey::from_slice(entry.public_key.as_ref())
+                        .ok_or(Error::InvalidPublicKey)?,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Directory with a fixed set of identities, for tests and offline use.
+#[derive(Debug, Default)]
+pub struct MemoryDirectory {
+    entries: HashMap<ThreemaID, DirectoryEntry>,
+    /// phone and email hashes
+    links: HashMap<Vec<u8>, ThreemaID>,
+}
+
+impl MemoryDirectory {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds an active identity supporting `features`.
+    pub fn insert(&mut self, id: ThreemaID, public_key: PublicKey, features: u64) {
+        self.insert_entry(
+            id,
+            DirectoryEntry {
+                public_key,
+                status: IdentityStatus {
+                    state: IdentityState::Active,
+                    feature_mask: FeatureMask(features),
+                },
+            },
+        );
+    }
+
+    pub fn insert_entry(&mut self, id: ThreemaID, entry: DirectoryEntry) {
+        self.entries.insert(id, entry);
+    }
+
+    /// Links the phone number `phone` (E.164 format) to `id`.
+    pub fn link_phone(&mut self, phone: &str, id: ThreemaID) {
+        self.links.insert(identity::hash_phone(phone), id);
+    }
+
+    /// Links the email address `email` to `id`.
+    pub fn link_email(&mut self, email: &str, id: ThreemaID) {
+        self.links.insert(identity::hash_email(email), id);
+    }
+}
+
+impl DirectoryClient for MemoryDirectory {
+    fn fetch_identity(&self, id: ThreemaID) -> Result<Option<DirectoryEntry>> {
+        Ok(self.entries.get(&id).cloned())
+    }
+
+    fn match_identities(&self, phones: &[&str], emails: &[&str]) -> Result<Vec<IdentityMatch>> {
+        let hashes = phones
+            .iter()
+            .map(|p| identity::hash_phone(p))
+            .chain(emails.iter().map(|e| identity::hash_email(e)));
+        Ok(hashes
+            .filter_map(|hash| {
+                let id = *self.links.get(&hash)?;
+                let entry = self.entries.get(&id)?;
+                Some(IdentityMatch {
+                    id,
+                    public_key: entry.public_key,
+                })
+            })
+            .collect())
+    }
+}
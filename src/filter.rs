@@ -0,0 +1,39 @@
+//! Dropping unwanted incoming messages before they are decrypted, see
+//! [`Threema::block`](crate::Threema::block) and
+//! [`ThreemaBuilder::filter`](crate::ThreemaBuilder::filter).
+//!
+//! Dropped messages are always acknowledged to the server, so they aren't delivered again.
+
+use crate::packets::Header;
+
+/// Decision of a filter about an incoming message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Accept,
+    /// Drop without sending a receipt
+    Drop,
+    /// Drop, but confirm the message as delivered, hiding that it was dropped
+    DropWithReceipt,
+}
+
+/// How messages of blocked senders are dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BlockMode {
+    /// Without a receipt, like the official apps do
+    #[default]
+    Silent,
+    /// Confirmed as delivered, so the sender can't tell it's blocked
+    Hidden,
+}
+
+impl From<BlockMode> for Verdict {
+    fn from(mode: BlockMode) -> Self {
+        match mode {
+            BlockMode::Silent => Self::Drop,
+            BlockMode::Hidden => Self::DropWithReceipt,
+        }
+    }
+}
+
+/// Hook deciding about incoming messages based on their unencrypted header.
+pub type Filter = Box<dyn FnMut(&Header) -> Verdict + Send>;
@@ -0,0 +1,377 @@
+//! Client for the [Threema Gateway](https://gateway.threema.ch) HTTP API.
+
+use crate::crypto::{self, PublicKey, SecretKey};
+use crate::identity;
+use crate::packets;
+use crate::packets::Message;
+use crate::rest;
+use crate::sources::{OsRng, RngSource};
+use crate::Error;
+use crate::MessageID;
+use crate::Result;
+use crate::ThreemaID;
+use flat_bytes::Flat;
+use hmac::Mac;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const GATEWAY_API: &str = "https://msgapi.threema.ch";
+
+/// Receiver of a gateway message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Recipient {
+    Id(ThreemaID),
+    /// Phone number in E.164 format, e.g. `41791234567`
+    Phone(String),
+    Email(String),
+}
+
+impl Recipient {
+    fn as_param(&self) -> (&'static str, String) {
+        match self {
+            Self::Id(id) => ("to", id.to_string()),
+            Self::Phone(phone) => ("phone", phone.trim_start_matches('+').to_owned()),
+            Self::Email(email) => ("email", email.clone()),
+        }
+    }
+}
+
+/// Message types a recipient is able to receive, as reported by the gateway.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Capabilities(Vec<String>);
+
+impl Capabilities {
+    /// Returns whether `capability` (e.g. `"file"` or `"audio"`) is supported.
+    #[must_use]
+    pub fn supports(&self, capability: &str) -> bool {
+        self.0.iter().any(|c| c == capability)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &str> {
+        self.0.iter().map(String::as_str)
+    }
+}
+
+/// A message encrypted for a single recipient.
+#[derive(Debug, Clone)]
+pub struct EncryptedMessage {
+    pub nonce: crypto::Nonce,
+    pub ciphertext: Vec<u8>,
+}
+
+/// Serializes and pads `msg` and encrypts it from `sender` to `recipient`.
+#[must_use]
+pub fn encrypt_message(
+    msg: &Message,
+    recipient: &PublicKey,
+    sender: &SecretKey,
+) -> EncryptedMessage {
+    let mut data = msg.serialize();
+    // PKCS#7 style padding of 1 to 255 bytes
+    #[allow(clippy::cast_possible_truncation)]
+    let pad = OsRng.uniform(255) as u8 + 1;
+    data.resize(data.len() + pad as usize, pad);
+
+    let nonce = crypto::gen_nonce();
+    let ciphertext = crypto::seal(&data, &nonce, recipient, sender);
+    EncryptedMessage { nonce, ciphertext }
+}
+
+/// Decrypts a message from `sender` to `recipient` and removes its padding.
+pub fn decrypt_message(
+    msg: &EncryptedMessage,
+    sender: &PublicKey,
+    recipient: &SecretKey,
+) -> Result<Message> {
+    let data = crypto::open(&msg.ciphertext, &msg.nonce, sender, recipient)
+        .ok_or(Error::MessageDecrypt { sender: None })?;
+    let (msg, _) = Message::parse(packets::unpad(&data)?)?;
+    Ok(msg)
+}
+
+/// Parameters of an incoming message posted to the gateway callback URL.
+#[derive(Debug, Clone)]
+pub struct Callback {
+    pub from: String,
+    pub to: String,
+    pub message_id: String,
+    /// Unix timestamp, see [`Callback::time`]
+    pub date: String,
+    /// hex encoded
+    pub nonce: String,
+    /// hex encoded
+    pub box_data: String,
+    /// hex encoded
+    pub mac: String,
+}
+
+impl Callback {
+    /// When the message was sent, or `None` if the date isn't a valid timestamp.
+    #[must_use]
+    pub fn time(&self) -> Option<SystemTime> {
+        let secs = self.date.parse().ok()?;
+        UNIX_EPOCH.checked_add(Duration::from_secs(secs))
+    }
+}
+
+/// Gateway identity (`*XXXXXXX`) with its API secret.
+pub struct Gateway {
+    id: ThreemaID,
+    secret: String,
+    private_key: Option<SecretKey>,
+}
+
+impl Gateway {
+    #[must_use]
+    pub fn new(id: ThreemaID, secret: &str) -> Self {
+        Self {
+            id,
+            secret: secret.to_owned(),
+            private_key: None,
+        }
+    }
+
+    /// Enables end-to-end mode, where messages are encrypted locally with `private_key`.
+    pub fn with_private_key(mut self, private_key: &[u8]) -> Result<Self> {
+        self.private_key =
+            Some(SecretKey::from_slice(private_key).ok_or(Error::InvalidPrivateKey)?);
+        Ok(self)
+    }
+
+    fn private_key(&self) -> Result<&SecretKey> {
+        self.private_key
+            .as_ref()
+            .ok_or_else(|| Error::Unsupported("end-to-end mode requires a private key".into()))
+    }
+
+    #[must_use]
+    pub fn id(&self) -> ThreemaID {
+        self.id
+    }
+
+    /// Sends `text` in simple mode, i.e. the gateway server encrypts it on our behalf.
+    pub fn send_simple(&self, to: &Recipient, text: &str) -> Result<MessageID> {
+        let from = self.id.to_string();
+        let (to_key, to_value) = to.as_param();
+        let resp = rest::post_form(
+            &format!("{GATEWAY_API}/send_simple"),
+            &[
+                ("from", &from),
+                (to_key, &to_value),
+                ("text", text),
+                ("secret", &self.secret),
+            ],
+            rest::Retry::Never,
+        )?;
+        MessageID::from_hex(resp.trim())
+            .ok_or_else(|| Error::ParseError(format!("message id: {resp:?}")))
+    }
+
+    /// Sends `msg` in simple mode. Only text messages are supported.
+    pub fn send_message(&self, to: &Recipient, msg: &Message) -> Result<MessageID> {
+        match msg {
+            Message::Text(t) => self.send_simple(to, &t.message),
+            other => Err(Error::Unsupported(format!(
+                "simple mode only supports text messages, got {other:?}"
+            ))),
+        }
+    }
+
+    fn get(&self, path: &str) -> Result<String> {
+        let from = self.id.to_string();
+        let resp = rest::get_text(
+            &format!("{GATEWAY_API}{path}"),
+            &[("from", &from), ("secret", &self.secret)],
+        )?;
+        Ok(resp.trim().to_owned())
+    }
+
+    fn lookup(&self, path: &str) -> Result<Option<ThreemaID>> {
+        match self.get(path) {
+            Ok(id) => ThreemaID::from_string(&id).map(Some),
+            Err(Error::Rest(rest::RestError {
+                kind: rest::RestErrorKind::NotFound,
+                ..
+            })) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Fetches the public key of `id` via the gateway.
+    pub fn fetch_public_key(&self, id: ThreemaID) -> Result<PublicKey> {
+        let resp = self.get(&format!("/pubkeys/{id}"))?;
+        crate::decode_hex::<32>(&resp)
+            .map(PublicKey)
+            .ok_or(Error::InvalidPublicKey)
+    }
+
+    /// Returns the number of remaining credits of the gateway account.
+    pub fn credits(&self) -> Result<u64> {
+        let resp = self.get("/credits")?;
+        resp.parse()
+            .map_err(|_| Error::ParseError(format!("credits: {resp:?}")))
+    }
+
+    /// Looks up the identity linked to `phone` (E.164 format, e.g. `41791234567`).
+    pub fn lookup_phone(&self, phone: &str) -> Result<Option<ThreemaID>> {
+        self.lookup(&format!("/lookup/phone/{}", phone.trim_start_matches('+')))
+    }
+
+    /// Looks up the identity linked to `email`.
+    ///
+    /// Only the hash of the address is sent, like for
+    /// [`match_identities`](crate::identity::match_identities).
+    pub fn lookup_email(&self, email: &str) -> Result<Option<ThreemaID>> {
+        self.lookup(&email_lookup_path(email))
+    }
+
+    /// Fetches which message types `id` is able to receive.
+    pub fn capabilities(&self, id: ThreemaID) -> Result<Capabilities> {
+        let resp = self.get(&format!("/capabilities/{id}"))?;
+        Ok(Capabilities(
+            resp.split(',')
+                .map(str::trim)
+                .filter(|c| !c.is_empty())
+                .map(str::to_owned)
+                .collect(),
+        ))
+    }
+
+    /// Encrypts `msg` for `recipient_key` and sends it in end-to-end mode.
+    pub fn send_e2e(
+        &self,
+        to: ThreemaID,
+        recipient_key: &PublicKey,
+        msg: &Message,
+    ) -> Result<MessageID> {
+        let enc = encrypt_message(msg, recipient_key, self.private_key()?);
+        let from = self.id.to_string();
+        let to = to.to_string();
+        let resp = rest::post_form(
+            &format!("{GATEWAY_API}/send_e2e"),
+            &[
+                ("from", &from),
+                ("to", &to),
+                ("nonce", &crate::encode_hex(enc.nonce.as_ref())),
+                ("box", &crate::encode_hex(&enc.ciphertext)),
+                ("secret", &self.secret),
+            ],
+            rest::Retry::Never,
+        )?;
+        MessageID::from_hex(resp.trim())
+            .ok_or_else(|| Error::ParseError(format!("message id: {resp:?}")))
+    }
+
+    /// Checks the MAC of a callback request against our API secret.
+    #[must_use]
+    pub fn verify_callback(&self, cb: &Callback) -> bool {
+        let Some(mac) = crate::decode_hex::<32>(&cb.mac) else {
+            return false;
+        };
+        let mut hmac = hmac::Hmac::<sha2::Sha256>::new_from_slice(self.secret.as_bytes())
+            .expect("HMAC accepts any key size");
+        for part in [
+            &cb.from,
+            &cb.to,
+            &cb.message_id,
+            &cb.date,
+            &cb.nonce,
+            &cb.box_data,
+        ] {
+            hmac.update(part.as_bytes());
+        }
+        hmac.verify_slice(&mac).is_ok()
+    }
+
+    /// Verifies and decrypts a callback request sent by `sender_key`.
+    pub fn decrypt_callback(&self, cb: &Callback, sender_key: &PublicKey) -> Result<Message> {
+        if !self.verify_callback(cb) {
+            return Err(Error::InvalidCallbackMac);
+        }
+        let nonce = crate::decode_hex::<24>(&cb.nonce)
+            .map(crypto::Nonce)
+            .ok_or_else(|| Error::ParseError(format!("nonce: {:?}", cb.nonce)))?;
+        let ciphertext = crate::decode_hex_vec(&cb.box_data)
+            .ok_or_else(|| Error::ParseError(format!("box: {:?}", cb.box_data)))?;
+        decrypt_message(
+            &EncryptedMessage { nonce, ciphertext },
+            sender_key,
+            self.private_key()?,
+        )
+        .map_err(|e| match e {
+            Error::MessageDecrypt { sender: None } => Error::MessageDecrypt {
+                sender: ThreemaID::from_string(&cb.from).ok(),
+            },
+            e => e,
+        })
+    }
+}
+
+/// Path of the lookup of `email`, which can't be put into the path verbatim.
+fn email_lookup_path(email: &str) -> String {
+    format!(
+        "/lookup/email_hash/{}",
+        crate::encode_hex(&identity::hash_email(email))
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packets::Text;
+
+    #[test]
+    fn e2e_roundtrip() {
+        let (alice_pub, alice_priv) = crypto::gen_keypair();
+        let (bob_pub, bob_priv) = crypto::gen_keypair();
+        let msg = Message::Text(Text {
+            message: "hello".to_owned(),
+        });
+        let enc = encrypt_message(&msg, &bob_pub, &alice_priv);
+        let dec = decrypt_message(&enc, &alice_pub, &bob_priv).unwrap();
+        assert!(matches!(dec, Message::Text(t) if t.message == "hello"));
+        assert!(matches!(
+            decrypt_message(&enc, &bob_pub, &bob_priv),
+            Err(Error::MessageDecrypt { sender: None })
+        ));
+
+        // types without a body survive the padding
+        let enc = encrypt_message(&Message::ContactRequestPhoto, &bob_pub, &alice_priv);
+        let dec = decrypt_message(&enc, &alice_pub, &bob_priv).unwrap();
+        assert_eq!(dec, Message::ContactRequestPhoto);
+    }
+
+    #[test]
+    fn email_lookup() {
+        let path = email_lookup_path(" A+B@c.d");
+        assert_eq!(path, email_lookup_path("a+b@c.d"));
+        let hash = path.strip_prefix("/lookup/email_hash/").unwrap();
+        assert_eq!(hash, crate::encode_hex(&identity::hash_email("a+b@c.d")));
+        assert!(!path.contains(['+', '@']));
+    }
+
+    #[test]
+    fn callback_mac() {
+        let gw = Gateway::new(ThreemaID::from_string("*TESTGW0").unwrap(), "secret");
+        let mut cb = Callback {
+            from: "ECHOECHO".to_owned(),
+            to: "*TESTGW0".to_owned(),
+            message_id: "0011223344556677".to_owned(),
+            date: "1700000000".to_owned(),
+            nonce: "aa".repeat(24),
+            box_data: "bbcc".to_owned(),
+            mac: "d79df604e8905bff273868027238be96067664e97481697d4b3bb0392599f6cc".to_owned(),
+        };
+        assert!(gw.verify_callback(&cb));
+        assert_eq!(
+            cb.time(),
+            Some(UNIX_EPOCH + Duration::from_secs(1_700_000_000))
+        );
+        cb.date = "1700000001".to_owned();
+        assert!(!gw.verify_callback(&cb));
+        let (pk, _) = crypto::gen_keypair();
+        assert!(matches!(
+            gw.decrypt_callback(&cb, &pk),
+            Err(Error::InvalidCallbackMac)
+        ));
+    }
+}
@@ -0,0 +1,303 @@
+//! Callback based event loop, see [`Threema::run`].
+//!
+//! The loop connects on its own and is therefore not available on `wasm32`.
+
+use crate::crypto::PublicKey;
+use crate::packets::{File, GroupText, Message, MessageStatus, Text};
+use crate::{ClientEvent, Error, MessageID, ServerMessage, Threema, ThreemaID};
+use tracing::{debug, warn};
+#[cfg(not(target_arch = "wasm32"))]
+use {
+    crate::packets::Packet,
+    crate::stats,
+    crate::Result,
+    std::thread,
+    std::time::{Duration, Instant},
+    tracing::info,
+};
+
+/// Delay before the first reconnection attempt, doubled after each failed one.
+#[cfg(not(target_arch = "wasm32"))]
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+#[cfg(not(target_arch = "wasm32"))]
+const MAX_RECONNECT_DELAY: Duration = Duration::from_mins(1);
+
+/// Receives the events of [`Threema::run`].
+///
+/// All methods have defaults, so implementations only need to override the events they
+/// care about. The client is passed along to allow replying from within the callbacks.
+#[allow(unused_variables)]
+pub trait Handler {
+    /// Called for every received message. The default passes it on with [`dispatch`];
+    /// override it to see all of the message, e.g. its timestamps.
+    fn on_message(&mut self, client: &mut Threema, msg: &ServerMessage) {
+        dispatch(self, client, msg);
+    }
+
+    fn on_text(&mut self, client: &mut Threema, sender: ThreemaID, msg_id: MessageID, text: &Text) {
+    }
+
+    fn on_file(&mut self, client: &mut Threema, sender: ThreemaID, msg_id: MessageID, file: &File) {
+    }
+
+    fn on_group_text(
+        &mut self,
+        client: &mut Threema,
+        sender: ThreemaID,
+        msg_id: MessageID,
+        text: &GroupText,
+    ) {
+    }
+
+    /// `sender` reports `status` for the message `receipt_for`.
+    fn on_receipt(
+        &mut self,
+        client: &mut Threema,
+        sender: ThreemaID,
+        status: &MessageStatus,
+        receipt_for: MessageID,
+    ) {
+    }
+
+    /// Any message without a dedicated callback, see [`dispatch`].
+    fn on_other(&mut self, client: &mut Threema, msg: &ServerMessage) {
+        debug!("Unhandled message {:?}", msg);
+    }
+
+    /// A message of `peer` could only be decrypted with `public_key` fetched from the
+    /// directory again, instead of the pinned key. The message is passed to the other
+    /// callbacks afterwards; the new key is only pinned by
+    /// [`Threema::accept_key_change`]. See
+    /// [`ThreemaBuilder::refresh_keys`](crate::ThreemaBuilder::refresh_keys).
+    fn on_key_changed(&mut self, client: &mut Threema, peer: ThreemaID, public_key: &PublicKey) {}
+
+    /// Alert sent by the server, meant to be shown to the user.
+    fn on_alert(&mut self, client: &mut Threema, message: &str) {
+        warn!(%message, "Server alert");
+    }
+
+    /// The message `msg_id` of `sender` was dropped because it couldn't be processed,
+    /// e.g. decrypted.
+    fn on_message_error(
+        &mut self,
+        client: &mut Threema,
+        sender: ThreemaID,
+        msg_id: MessageID,
+        cause: &Error,
+    ) {
+        warn!(peer = %sender, %msg_id, error = %cause, "Dropped message");
+    }
+
+    /// Other errors which don't affect the connection, e.g. a packet which couldn't be
+    /// parsed.
+    fn on_error(&mut self, client: &mut Threema, error: &Error) {
+        warn!(%error, "Error while receiving");
+    }
+
+    /// How often [`on_tick`](Self::on_tick) is called at least while waiting for
+    /// messages. `None`, the default, only calls it after each message.
+    fn tick_interval(&self) -> Option<std::time::Duration> {
+        None
+    }
+
+    /// Called regularly from [`Threema::run`], e.g. to send messages queued by another
+    /// thread.
+    fn on_tick(&mut self, client: &mut Threema) {}
+
+    /// Called when the connection was lost or reconnecting failed. Returns whether to
+    /// (try to) reconnect, which is the default. Errors after which the server doesn't
+    /// allow reconnecting end [`Threema::run`] regardless, see [`Error::can_reconnect`].
+    fn on_disconnect(&mut self, error: &Error) -> bool {
+        warn!(%error, "Disconnected");
+        true
+    }
+}
+
+/// Calls the callback of `handler` for the type of `msg`, or
+/// [`on_other`](Handler::on_other) if there is none.
+pub fn dispatch(handler: &mut (impl Handler + ?Sized), client: &mut Threema, msg: &ServerMessage) {
+    let (sender, msg_id) = (msg.sender, msg.msg_id);
+    match &msg.data {
+        Message::Text(text) => handler.on_text(client, sender, msg_id, text),
+        Message::File(file) => handler.on_file(client, sender, msg_id, file),
+        Message::GroupText(text) => handler.on_group_text(client, sender, msg_id, text),
+        Message::DeliveryReceipt(status, receipt_for) => {
+            handler.on_receipt(client, sender, status, *receipt_for);
+        }
+        _ => handler.on_other(client, msg),
+    }
+}
+
+/// Reconnection state of [`Threema::run`], kept between [steps](Threema::step).
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Default)]
+pub(crate) struct RunState {
+    /// time of the next attempt and the delay before it
+    reconnect: Option<(Instant, Duration)>,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl RunState {
+    fn schedule_reconnect(&mut self, delay: Duration) {
+        info!(?delay, "Reconnecting");
+        self.reconnect = Some((Instant::now() + delay, delay));
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Threema {
+    /// Receives messages and passes them to `handler` until it declines to reconnect.
+    ///
+    /// Connects first if necessary. While idle, the connection is checked with an echo
+    /// request every [keepalive](crate::ThreemaBuilder::keepalive) interval; it is
+    /// considered lost if the server doesn't answer within another interval. Lost
+    /// connections are re-established with an exponential backoff.
+    ///
+    /// Returns `Ok` once [`Handler::on_disconnect`] returned `false`, or the error if the
+    /// server didn't allow reconnecting.
+    pub fn run(&mut self, handler: &mut impl Handler) -> Result<()> {
+        if self.conn.is_none() {
+            self.connect()?;
+        }
+        let mut state = RunState::default();
+        while self.step(handler, &mut state, None)? {}
+        Ok(())
+    }
+
+    /// Does one iteration of [`run`](Self::run): dispatches a packet, waits for one or
+    /// tries to reconnect. Blocks at most `max_wait`, if given, and returns whether to
+    /// continue.
+    pub(crate) fn step(
+        &mut self,
+        handler: &mut (impl Handler + ?Sized),
+        state: &mut RunState,
+        max_wait: Option<Duration>,
+    ) -> Result<bool> {
+        if let Some((at, delay)) = state.reconnect {
+            let remaining = at.saturating_duration_since(Instant::now());
+            if !remaining.is_zero() {
+                thread::sleep(max_wait.map_or(remaining, |max| max.min(remaining)));
+                return Ok(true);
+            }
+            let connected = self.connect();
+            stats::reconnect(connected.is_ok());
+            self.record_reconnect(connected.is_ok());
+            match connected {
+                Ok(()) => state.reconnect = None,
+                Err(e) => {
+                    if !handler.on_disconnect(&e) {
+                        return Ok(false);
+                    }
+                    if !e.can_reconnect() {
+                        return Err(e);
+                    }
+                    state.schedule_reconnect((delay * 2).min(MAX_RECONNECT_DELAY));
+                }
+            }
+            return Ok(true);
+        }
+
+        let error = match self.poll(handler, max_wait) {
+            Ok(()) => return Ok(true),
+            Err(e) if !e.is_connection_error() => {
+                match &e {
+                    Error::ServerAlert(alert) => handler.on_alert(self, &alert.message),
+                    _ => handler.on_error(self, &e),
+                }
+                return Ok(true);
+            }
+            Err(e) => e,
+        };
+
+        self.disconnect();
+        let reconnect = handler.on_disconnect(&error);
+        if !error.can_reconnect() {
+            return Err(error);
+        }
+        if reconnect {
+            state.schedule_reconnect(MIN_RECONNECT_DELAY);
+        }
+        Ok(reconnect)
+    }
+
+    /// Dispatches the next event, waiting for a packet unless [`Threema::ping`] kept one.
+    fn poll(
+        &mut self,
+        handler: &mut (impl Handler + ?Sized),
+        max_wait: Option<Duration>,
+    ) -> Result<()> {
+        let event = match self.events.pop_front() {
+            Some(event) => event,
+            None => match self.wait(handler, max_wait)? {
+                Some(event) => event,
+                None => return Ok(()),
+            },
+        };
+        let msg = match event {
+            ClientEvent::Message(msg) => msg,
+            ClientEvent::MessageError {
+                sender,
+                msg_id,
+                cause,
+            } => {
+                handler.on_message_error(self, sender, msg_id, &cause);
+                return Ok(());
+            }
+        };
+        if let Some(public_key) = &msg.key_changed {
+            handler.on_key_changed(self, msg.sender, public_key);
+        }
+        handler.on_message(self, &msg);
+        handler.on_tick(self);
+        Ok(())
+    }
+
+    /// Waits for the next packet and handles it, sending an echo request when idle.
+    fn wait(
+        &mut self,
+        handler: &mut (impl Handler + ?Sized),
+        max_wait: Option<Duration>,
+    ) -> Result<Option<ClientEvent>> {
+        let wait = self.keepalive.saturating_sub(self.idle);
+        let wait = handler.tick_interval().map_or(wait, |tick| tick.min(wait));
+        let wait = max_wait.map_or(wait, |max| max.min(wait));
+        let conn = self.conn.as_mut().ok_or(Error::NotConnected)?;
+        conn.set_read_timeout(Some(wait))?;
+        match conn.peek(&mut [0]) {
+            Ok(0) => return Err(Error::NotConnected),
+            Ok(_) => {}
+            Err(e) => match Error::from(e) {
+                Error::Timeout => {
+                    self.idle += wait;
+                    if self.idle >= self.keepalive {
+                        self.check_alive()?;
+                    }
+                    handler.on_tick(self);
+                    return Ok(None);
+                }
+                e => return Err(e),
+            },
+        }
+
+        let packet = self.receive_packet()?;
+        // any packet shows the connection is alive
+        self.echo_pending = None;
+        self.idle = Duration::ZERO;
+        self.handle_packet(packet)
+    }
+
+    /// Sends an echo request after a keepalive interval without packets, or fails if the
+    /// previous one is still unanswered.
+    fn check_alive(&mut self) -> Result<()> {
+        if self.echo_pending.is_some() {
+            return Err(Error::Timeout);
+        }
+        let counter = self.echo_counter;
+        self.echo_counter += 1;
+        debug!(echo = counter, "Sending echo request");
+        self.send(&Packet::EchoRequest(counter))?;
+        self.echo_pending = Some(counter);
+        self.idle = Duration::ZERO;
+        Ok(())
+    }
+}
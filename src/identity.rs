@@ -1,9 +1,32 @@
+use crate::crypto::{self, PublicKey, SecretKey};
+use crate::directory::DirectoryEntry;
+#[cfg(feature = "rest")]
+use crate::directory::{DirectoryClient, HttpDirectory};
+use crate::rest;
+#[cfg(feature = "rest")]
+use crate::rest::{RestError, RestErrorKind};
+use crate::ThreemaID;
+#[cfg(feature = "rest")]
+use crate::{Error, Result};
+use hmac::Mac;
 use pbkdf2::pbkdf2;
 use sha2::Digest;
-use sodiumoxide::crypto::stream::xsalsa20;
+use unicode_normalization::UnicodeNormalization;
+
+// from https://github.com/threema-ch/threema-msgapi-sdk-python/blob/master/threema/gateway/util.py
+const EMAIL_HMAC_KEY: [u8; 32] = [
+    0x30, 0xa5, 0x50, 0x0f, 0xed, 0x97, 0x01, 0xfa, 0x6d, 0xef, 0xdb, 0x61, 0x08, 0x41, 0x90, 0x0f,
+    0xeb, 0xb8, 0xe4, 0x30, 0x88, 0x1f, 0x7a, 0xd8, 0x16, 0x82, 0x62, 0x64, 0xec, 0x09, 0xba, 0xd7,
+];
+const PHONE_HMAC_KEY: [u8; 32] = [
+    0x85, 0xad, 0xf8, 0x22, 0x69, 0x53, 0xf3, 0xd9, 0x6c, 0xfd, 0x5d, 0x09, 0xbf, 0x29, 0x55, 0x5e,
+    0xb9, 0x55, 0xfc, 0xd8, 0xaa, 0x5e, 0xc4, 0xf9, 0xfc, 0xd8, 0x69, 0xe2, 0x58, 0x37, 0x07, 0x23,
+];
+
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
 
 fn base32(input: &str) -> Option<Vec<u8>> {
-    let alphabet = "ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+    let alphabet = std::str::from_utf8(BASE32_ALPHABET).unwrap();
 
     let mut out = vec![];
     let mut skip = 0u8;
@@ -32,36 +55,444 @@ fn base32(input: &str) -> Option<Vec<u8>> {
     Some(out)
 }
 
-#[must_use]
-pub fn decrypt(identity: &str, password: &str) -> Option<(String, Vec<u8>)> {
-    let identity = identity.replace('-', "");
-    let identity = base32(&identity)?;
-    let (salt, identity) = identity.split_at(8);
+fn base32_encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity((data.len() * 8).div_ceil(5));
+    let mut bits = 0u16;
+    let mut count = 0;
+    for &byte in data {
+        bits = (bits << 8) | u16::from(byte);
+        count += 8;
+        while count >= 5 {
+            count -= 5;
+            out.push(BASE32_ALPHABET[usize::from((bits >> count) & 0x1f)].into());
+        }
+    }
+    if count > 0 {
+        out.push(BASE32_ALPHABET[usize::from((bits << (5 - count)) & 0x1f)].into());
+    }
+    out
+}
 
+/// Key protecting an ID export, derived from `password` and `salt`.
+fn backup_key(password: &str, salt: &[u8]) -> [u8; 32] {
+    // the same password might be entered in composed or decomposed form
+    let password: String = password.nfc().collect();
     let mut key = [0u8; 32];
     pbkdf2::<hmac::Hmac<sha2::Sha256>>(password.as_bytes(), salt, 100_000, &mut key);
+    key
+}
 
-    let plain = xsalsa20::stream_xor(
-        identity,
-        &xsalsa20::Nonce::from_slice(&[0u8; xsalsa20::NONCEBYTES])?,
-        &xsalsa20::Key::from_slice(&key)?,
-    );
-
-    let (identity, plain) = plain.split_at(8);
-    let (private_key, expected_hash) = plain.split_at(32);
-
+/// First two bytes of the SHA-256 hash of `identity` and `private_key`, the checksum in
+/// ID exports.
+fn backup_check(identity: &[u8], private_key: &[u8]) -> [u8; 2] {
     let mut md = sha2::Sha256::new();
     md.update(identity);
     md.update(private_key);
     let hash = md.finalize();
-    let hash = hash.as_slice();
+    [hash[0], hash[1]]
+}
 
-    if expected_hash[0] != hash[0] || expected_hash[1] != hash[1] {
-        None
-    } else {
+/// Creates an ID export of `id` and its key, as shown by the apps, which
+/// [`decrypt`] can read with the same `password`.
+#[must_use]
+pub fn encrypt(id: ThreemaID, private_key: &SecretKey, password: &str) -> String {
+    let mut salt = [0; 8];
+    crypto::random_bytes(&mut salt);
+    encrypt_with_salt(id, private_key, password, salt)
+}
+
+fn encrypt_with_salt(
+    id: ThreemaID,
+    private_key: &SecretKey,
+    password: &str,
+    salt: [u8; 8],
+) -> String {
+    let mut plain = id.as_bytes().to_vec();
+    plain.extend_from_slice(&private_key.0);
+    plain.extend_from_slice(&backup_check(&id.as_bytes(), &private_key.0));
+    let key = backup_key(password, &salt);
+    let cipher = crypto::xsalsa20_xor(&plain, &crypto::Nonce([0; crypto::NONCEBYTES]), &key);
+
+    let mut data = salt.to_vec();
+    data.extend_from_slice(&cipher);
+    let encoded = base32_encode(&data);
+    let groups: Vec<&str> = encoded
+        .as_bytes()
+        .chunks(4)
+        .map(|group| std::str::from_utf8(group).unwrap())
+        .collect();
+    groups.join("-")
+}
+
+#[must_use]
+pub fn decrypt(identity: &str, password: &str) -> Option<(String, Vec<u8>)> {
+    // tolerate separators and line breaks from copy & pasted backups
+    let identity: String = identity
+        .chars()
+        .filter(|c| *c != '-' && !c.is_whitespace())
+        .collect();
+    let identity = base32(&identity)?;
+    if identity.len() < 8 + 8 + 32 + 2 {
+        return None;
+    }
+    let (salt, identity) = identity.split_at(8);
+    let key = backup_key(password, salt);
+
+    let plain = crypto::xsalsa20_xor(identity, &crypto::Nonce([0; crypto::NONCEBYTES]), &key);
+
+    let (identity, plain) = plain.split_at(8);
+    let (private_key, expected_hash) = plain.split_at(32);
+
+    if expected_hash == backup_check(identity, private_key) {
         Some((
             String::from_utf8(identity.to_vec()).ok()?,
             private_key.to_vec(),
         ))
+    } else {
+        None
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum IdentityState {
+    /// Identity is in use
+    Active,
+    /// Identity exists, but hasn't been used for a long time
+    Inactive,
+    /// Identity has been revoked
+    Revoked,
+    /// Identity is unknown to the directory
+    Invalid,
+}
+
+/// Features supported by the client of an identity, as announced to the directory.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct FeatureMask(pub u64);
+
+// see ThreemaFeature in threema-android
+impl FeatureMask {
+    pub const VOICE_MESSAGES: u64 = 0x01;
+    pub const GROUPS: u64 = 0x02;
+    pub const BALLOTS: u64 = 0x04;
+    pub const FILES: u64 = 0x08;
+    pub const VOIP: u64 = 0x10;
+    pub const VIDEO_CALLS: u64 = 0x20;
+    pub const FORWARD_SECURITY: u64 = 0x40;
+    pub const GROUP_CALLS: u64 = 0x80;
+    pub const EDIT_MESSAGES: u64 = 0x100;
+    pub const DELETE_MESSAGES: u64 = 0x200;
+
+    const NAMES: [(u64, &'static str); 10] = [
+        (Self::VOICE_MESSAGES, "voice_messages"),
+        (Self::GROUPS, "groups"),
+        (Self::BALLOTS, "ballots"),
+        (Self::FILES, "files"),
+        (Self::VOIP, "voip"),
+        (Self::VIDEO_CALLS, "video_calls"),
+        (Self::FORWARD_SECURITY, "forward_security"),
+        (Self::GROUP_CALLS, "group_calls"),
+        (Self::EDIT_MESSAGES, "edit_messages"),
+        (Self::DELETE_MESSAGES, "delete_messages"),
+    ];
+
+    /// Returns whether all bits of `features` are set.
+    #[must_use]
+    pub fn contains(self, features: u64) -> bool {
+        self.0 & features == features
+    }
+
+    /// Names of the known features which are set, e.g. `files`.
+    #[must_use]
+    pub fn names(self) -> Vec<&'static str> {
+        Self::NAMES
+            .iter()
+            .filter(|(bit, _)| self.contains(*bit))
+            .map(|(_, name)| *name)
+            .collect()
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct IdentityStatus {
+    pub state: IdentityState,
+    pub feature_mask: FeatureMask,
+}
+
+impl IdentityStatus {
+    #[must_use]
+    pub fn is_active(&self) -> bool {
+        self.state == IdentityState::Active
+    }
+}
+
+impl From<&rest::messages::GetPubKeyResponse> for IdentityStatus {
+    fn from(resp: &rest::messages::GetPubKeyResponse) -> Self {
+        let state = match resp.state {
+            0 => IdentityState::Active,
+            1 => IdentityState::Inactive,
+            _ => IdentityState::Revoked,
+        };
+        Self {
+            state,
+            feature_mask: FeatureMask(resp.feature_mask),
+        }
+    }
+}
+
+/// Queries the directory for the current state and feature mask of `id`.
+#[cfg(feature = "rest")]
+pub fn check_status(id: ThreemaID) -> Result<IdentityStatus> {
+    Ok(status_of(HttpDirectory::new().fetch_identity(id)?.as_ref()))
+}
+
+/// State of an identity the directory returned `entry` for.
+pub(crate) fn status_of(entry: Option<&DirectoryEntry>) -> IdentityStatus {
+    entry.map_or(
+        IdentityStatus {
+            state: IdentityState::Invalid,
+            feature_mask: FeatureMask::default(),
+        },
+        |entry| entry.status,
+    )
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac =
+        hmac::Hmac::<sha2::Sha256>::new_from_slice(key).expect("HMAC accepts any key size");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Hashes a phone number in E.164 format (e.g. `+41791234567`) for directory lookups.
+#[must_use]
+pub fn hash_phone(phone: &str) -> Vec<u8> {
+    let digits: String = phone.chars().filter(char::is_ascii_digit).collect();
+    hmac_sha256(&PHONE_HMAC_KEY, digits.as_bytes())
+}
+
+/// Hashes an email address for directory lookups.
+#[must_use]
+pub fn hash_email(email: &str) -> Vec<u8> {
+    hmac_sha256(&EMAIL_HMAC_KEY, email.trim().to_lowercase().as_bytes())
+}
+
+#[derive(Debug, Clone)]
+pub struct IdentityMatch {
+    pub id: ThreemaID,
+    pub public_key: PublicKey,
+}
+
+/// Looks up the identities linked to the given phone numbers and email addresses.
+#[cfg(feature = "rest")]
+pub fn match_identities(phones: &[&str], emails: &[&str]) -> Result<Vec<IdentityMatch>> {
+    HttpDirectory::new().match_identities(phones, emails)
+}
+
+/// Registers a new identity with a fresh key pair at the directory.
+///
+/// The directory doesn't ask for a proof of work, but for proof of the private key: it
+/// returns a token which has to be sent back encrypted for its one-time key. Threema
+/// Work and on-premises servers additionally require a `license_key`.
+#[cfg(feature = "rest")]
+pub fn create(license_key: Option<&str>) -> Result<(ThreemaID, SecretKey)> {
+    let (public_key, secret_key) = crypto::gen_keypair();
+    let request = rest::messages::CreateIdentityRequest {
+        public_key: public_key.0.to_vec().into(),
+        ..Default::default()
+    };
+    let challenge: rest::messages::CreateIdentityChallenge = rest::post(
+        &rest::agent(),
+        "/identity/create",
+        &request,
+        rest::Retry::Never,
+    )?;
+    let request = challenge_response(&challenge, &secret_key, license_key)?;
+    let response: rest::messages::CreateIdentityResponse = rest::post(
+        &rest::agent(),
+        "/identity/create",
+        &request,
+        rest::Retry::Never,
+    )?;
+    Ok((created_identity(response)?, secret_key))
+}
+
+/// The second request of [`create`], proving that we own `secret_key`.
+#[cfg(feature = "rest")]
+fn challenge_response(
+    challenge: &rest::messages::CreateIdentityChallenge,
+    secret_key: &SecretKey,
+    license_key: Option<&str>,
+) -> Result<rest::messages::CreateIdentityRequest> {
+    let server_key = PublicKey::from_slice(challenge.token_resp_key_pub.as_ref())
+        .ok_or(Error::InvalidPublicKey)?;
+    let nonce = crypto::gen_nonce();
+    let response = crypto::seal(challenge.token.as_ref(), &nonce, &server_key, secret_key);
+    Ok(rest::messages::CreateIdentityRequest {
+        public_key: secret_key.public_key().0.to_vec().into(),
+        token: Some(challenge.token.as_ref().to_vec().into()),
+        response: Some(response.into()),
+        nonce: Some(nonce.0.to_vec().into()),
+        license_key: license_key.map(str::to_owned),
+    })
+}
+
+#[cfg(feature = "rest")]
+fn created_identity(response: rest::messages::CreateIdentityResponse) -> Result<ThreemaID> {
+    match response {
+        rest::messages::CreateIdentityResponse {
+            success: true,
+            identity: Some(id),
+            ..
+        } => ThreemaID::from_string(&id),
+        rest::messages::CreateIdentityResponse { error, .. } => Err(Error::Rest(RestError {
+            status: None,
+            body: Some(error.unwrap_or_else(|| "identity creation failed".to_owned())),
+            kind: RestErrorKind::Other,
+        })),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unhex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn hashes() {
+        // examples from the Threema Gateway API documentation
+        assert_eq!(
+            hash_phone("+41 79 123 45 67"),
+            unhex("ad398f4d7ebe63c6550a486cc6e07f9baa09bd9d8b3d8cb9d9be106d35a7fdbc")
+        );
+        assert_eq!(
+            hash_email(" Test@Threema.ch"),
+            unhex("1ea093239cc5f0e1b6ec81b866265b921f26dc4033025410063309f4d1a8ee2c")
+        );
+    }
+
+    #[test]
+    fn status() {
+        let resp = |state| rest::messages::GetPubKeyResponse {
+            identity: "ECHOECHO".to_owned(),
+            public_key: vec![0; 32].into(),
+            state,
+            feature_mask: 0b11,
+        };
+        let state = |state| IdentityStatus::from(&resp(state)).state;
+        assert_eq!(state(0), IdentityState::Active);
+        assert_eq!(state(1), IdentityState::Inactive);
+        assert_eq!(state(2), IdentityState::Revoked);
+        assert_eq!(
+            IdentityStatus::from(&resp(0)).feature_mask,
+            FeatureMask(0b11)
+        );
+        assert!(IdentityStatus::from(&resp(0)).is_active());
+
+        let status = status_of(None);
+        assert_eq!(status.state, IdentityState::Invalid);
+        assert_eq!(status.feature_mask, FeatureMask::default());
+        let entry = DirectoryEntry {
+            public_key: PublicKey([1; 32]),
+            status: IdentityStatus::from(&resp(1)),
+        };
+        assert_eq!(status_of(Some(&entry)), entry.status);
+    }
+
+    #[test]
+    fn feature_names() {
+        assert!(FeatureMask(0).names().is_empty());
+        assert_eq!(
+            FeatureMask(FeatureMask::GROUPS | FeatureMask::FILES | 0x8000).names(),
+            ["groups", "files"]
+        );
+    }
+
+    #[test]
+    fn backup_roundtrip() {
+        let id = ThreemaID::from_string("ECHOECHO").unwrap();
+        let key = SecretKey([7; 32]);
+        let backup = encrypt_with_salt(id, &key, "password", [1; 8]);
+        assert_eq!(backup.len(), 80 + 19);
+        assert!(backup
+            .split('-')
+            .all(|group| group.len() == 4 && base32(group).is_some()));
+        assert_eq!(
+            decrypt(&backup, "password"),
+            Some(("ECHOECHO".to_owned(), key.0.to_vec()))
+        );
+        assert_eq!(decrypt(&backup, "wrong"), None);
+        assert_ne!(encrypt(id, &key, "password"), backup);
+        assert_eq!(base32_encode(b"f"), "MY");
+        assert_eq!(base32(&base32_encode(b"fooba")).unwrap(), b"fooba");
+    }
+
+    #[test]
+    fn backup_normalization() {
+        let id = ThreemaID::from_string("ECHOECHO").unwrap();
+        let key = SecretKey([7; 32]);
+        let expected = Some(("ECHOECHO".to_owned(), key.0.to_vec()));
+
+        // "é" composed and as "e" with a combining acute accent
+        let backup = encrypt_with_salt(id, &key, "caf\u{e9}", [1; 8]);
+        assert_eq!(decrypt(&backup, "cafe\u{301}"), expected);
+        let backup = encrypt_with_salt(id, &key, "cafe\u{301}", [1; 8]);
+        assert_eq!(decrypt(&backup, "caf\u{e9}"), expected);
+
+        // line breaks and spaces around and within the groups
+        let backup = encrypt_with_salt(id, &key, "password", [1; 8]);
+        assert_eq!(decrypt(&format!("  {backup}\n"), "password"), expected);
+        let wrapped = backup
+            .split('-')
+            .collect::<Vec<_>>()
+            .chunks(4)
+            .map(|line| line.join(" - "))
+            .collect::<Vec<_>>()
+            .join("\r\n\t");
+        assert_eq!(decrypt(&wrapped, "password"), expected);
+        let (head, tail) = backup.split_at(2);
+        assert_eq!(decrypt(&format!("{head} {tail}"), "password"), expected);
+    }
+
+    #[cfg(feature = "rest")]
+    #[test]
+    fn creation() {
+        use rest::messages::{CreateIdentityChallenge, CreateIdentityResponse};
+        use std::convert::TryInto;
+
+        let (server_public, server_secret) = crypto::gen_keypair();
+        let (_, secret_key) = crypto::gen_keypair();
+        let challenge = CreateIdentityChallenge {
+            token: b"token".to_vec().into(),
+            token_resp_key_pub: server_public.0.to_vec().into(),
+        };
+        let request = challenge_response(&challenge, &secret_key, Some("license")).unwrap();
+        let nonce = crypto::Nonce(request.nonce.unwrap().as_ref().try_into().unwrap());
+        let response = crypto::open(
+            request.response.unwrap().as_ref(),
+            &nonce,
+            &secret_key.public_key(),
+            &server_secret,
+        );
+        assert_eq!(response.as_deref(), Some(&b"token"[..]));
+        assert_eq!(request.license_key.as_deref(), Some("license"));
+
+        let created = created_identity(CreateIdentityResponse {
+            success: true,
+            identity: Some("ECHOECHO".to_owned()),
+            error: None,
+        });
+        assert_eq!(created.unwrap().as_str(), "ECHOECHO");
+        let failed = created_identity(CreateIdentityResponse {
+            success: false,
+            identity: None,
+            error: Some("invalid license".to_owned()),
+        });
+        assert!(failed.unwrap_err().to_string().contains("invalid license"));
     }
 }
@@ -0,0 +1,187 @@
+use crate::crypto::PublicKey;
+use crate::store;
+use crate::Result;
+use crate::ThreemaID;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Cache for public keys of peers, consulted before asking the directory.
+pub trait PeerKeyStore: Send {
+    /// Returns the cached key of `id`, unless it is missing or expired.
+    fn get(&mut self, id: ThreemaID) -> Option<PublicKey>;
+    fn insert(&mut self, id: ThreemaID, key: PublicKey);
+    /// Removes `id` so that the next lookup hits the directory again.
+    fn invalidate(&mut self, id: ThreemaID);
+}
+
+/// Shares one cache between several clients, e.g. of a [`ClientPool`](crate::pool::ClientPool).
+impl<S: PeerKeyStore + ?Sized> PeerKeyStore for Arc<Mutex<S>> {
+    fn get(&mut self, id: ThreemaID) -> Option<PublicKey> {
+        self.lock().unwrap_or_else(PoisonError::into_inner).get(id)
+    }
+
+    fn insert(&mut self, id: ThreemaID, key: PublicKey) {
+        self.lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(id, key);
+    }
+
+    fn invalidate(&mut self, id: ThreemaID) {
+        self.lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .invalidate(id);
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    key: PublicKey,
+    /// seconds since the unix epoch
+    fetched: u64,
+}
+
+impl Entry {
+    fn new(key: PublicKey) -> Self {
+        let fetched = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        Self { key, fetched }
+    }
+
+    fn is_expired(&self, ttl: Option<Duration>) -> bool {
+        ttl.is_some_and(|ttl| {
+            let fetched = UNIX_EPOCH + Duration::from_secs(self.fetched);
+            fetched + ttl < SystemTime::now()
+        })
+    }
+}
+
+/// Keeps keys for the lifetime of the process.
+#[derive(Debug, Default)]
+pub struct MemoryKeyStore {
+    entries: HashMap<ThreemaID, Entry>,
+    ttl: Option<Duration>,
+}
+
+impl MemoryKeyStore {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[must_use]
+    pub fn with_ttl(ttl: Duration) -> Self {
+        Self {
+            entries: HashMap::new(),
+            ttl: Some(ttl),
+        }
+    }
+}
+
+impl PeerKeyStore for MemoryKeyStore {
+    fn get(&mut self, id: ThreemaID) -> Option<PublicKey> {
+        let entry = self.entries.get(&id)?;
+        if entry.is_expired(self.ttl) {
+            self.entries.remove(&id);
+            return None;
+        }
+        Some(entry.key)
+    }
+
+    fn insert(&mut self, id: ThreemaID, key: PublicKey) {
+        self.entries.insert(id, Entry::new(key));
+    }
+
+    fn invalidate(&mut self, id: ThreemaID) {
+        self.entries.remove(&id);
+    }
+}
+
+/// Persists keys as JSON file, so they survive restarts.
+#[derive(Debug)]
+pub struct FileKeyStore {
+    path: PathBuf,
+    entries: HashMap<String, Entry>,
+    ttl: Option<Duration>,
+}
+
+impl FileKeyStore {
+    /// Opens the store at `path`, creating it on the first insert if it doesn't exist.
+    pub fn open<P: Into<PathBuf>>(path: P, ttl: Option<Duration>) -> Result<Self> {
+        let path = path.into();
+        let entries = match fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self { path, entries, ttl })
+    }
+
+    fn save(&self) -> Result<()> {
+        let data = serde_json::to_vec_pretty(&self.entries)?;
+        store::write_atomically(&self.path, &data)?;
+        Ok(())
+    }
+
+    fn save_or_warn(&self) {
+        if let Err(e) = self.save() {
+            tracing::warn!("Couldn't save peer keys to {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+impl PeerKeyStore for FileKeyStore {
+    fn get(&mut self, id: ThreemaID) -> Option<PublicKey> {
+        let id = id.to_string();
+        let entry = self.entries.get(&id)?;
+        if entry.is_expired(self.ttl) {
+            self.entries.remove(&id);
+            self.save_or_warn();
+            return None;
+        }
+        Some(entry.key)
+    }
+
+    fn insert(&mut self, id: ThreemaID, key: PublicKey) {
+        self.entries.insert(id.to_string(), Entry::new(key));
+        self.save_or_warn();
+    }
+
+    fn invalidate(&mut self, id: ThreemaID) {
+        if self.entries.remove(&id.to_string()).is_some() {
+            self.save_or_warn();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expiry_and_persistence() {
+        let id = ThreemaID::from_string("ECHOECHO").unwrap();
+        let key = PublicKey([42; 32]);
+
+        let mut mem = MemoryKeyStore::with_ttl(Duration::from_secs(0));
+        mem.insert(id, key);
+        mem.entries.get_mut(&id).unwrap().fetched -= 1;
+        assert!(mem.get(id).is_none());
+
+        let path = std::env::temp_dir().join(format!("threema-keys-{}.json", std::process::id()));
+        let mut store = FileKeyStore::open(&path, None).unwrap();
+        store.insert(id, key);
+        let mut store = FileKeyStore::open(&path, None).unwrap();
+        assert_eq!(store.get(id), Some(key));
+        store.invalidate(id);
+        let mut store = FileKeyStore::open(&path, None).unwrap();
+        assert!(store.get(id).is_none());
+        fs::remove_file(path).unwrap();
+    }
+}
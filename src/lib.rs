@@ -2,81 +2,258 @@
 #![allow(clippy::missing_errors_doc)]
 #![allow(clippy::missing_panics_doc)]
 
+#[cfg(all(feature = "rest", not(target_arch = "wasm32")))]
+pub mod bridge;
+mod builder;
+pub mod contacts;
+pub mod conversation;
+pub mod crypto;
+pub mod dedup;
+pub mod directory;
+pub mod filter;
+#[cfg(feature = "rest")]
+pub mod gateway;
+pub mod handler;
 pub mod identity;
+pub mod keystore;
+pub mod nonces;
+pub mod outbox;
 pub mod packets;
-mod rest;
-
-use std::collections::HashMap;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod pool;
+pub mod rest;
+pub mod servers;
+pub mod sources;
+mod stats;
+pub mod status;
+pub mod store;
+#[cfg(feature = "thumbnails")]
+pub mod thumbnail;
+pub mod transport;
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::TryFrom;
 use std::io::Read;
 use std::io::Write;
+#[cfg(not(target_arch = "wasm32"))]
 use std::net::TcpStream;
 use std::time;
-use std::{error, fmt, io};
+use std::{fmt, io};
 
+use crypto::{PublicKey, SecretKey};
 use flat_bytes::Flat;
-use log::debug;
-use log::warn;
-use sodiumoxide::crypto::box_;
-use sodiumoxide::crypto::box_::PublicKey;
-use sodiumoxide::crypto::box_::SecretKey;
-use sodiumoxide::randombytes;
-
-use packets::{Header, Message, MessageStatus, Packet, Text};
-
-// https://github.com/threema-ch/threema-android/blob/329b33d7bace99f5078ff08ef996a27c628be6e5/app/build.gradle#L91-L93
-const MSG_SERVER: &str = "g-33.0.threema.ch:5222";
-// https://github.com/threema-ch/threema-android/blob/329b33d7bace99f5078ff08ef996a27c628be6e5/app/build.gradle#L98
-const SERVER_LONG_TERM_PUBKEY: [u8; 32] = [
-    69, 11, 151, 87, 53, 39, 159, 222, 203, 51, 19, 100, 143, 95, 198, 238, 159, 244, 54, 14, 169,
-    42, 140, 23, 81, 198, 97, 228, 192, 216, 201, 9,
-];
+use tracing::{debug, instrument, warn};
+
+pub use builder::ThreemaBuilder;
+use contacts::ContactStore;
+use directory::DirectoryClient;
+use keystore::PeerKeyStore;
+use packets::{Header, Message, MessageStatus, Packet, ServerAlert, ServerError, Text};
+use sources::{Clock, RngSource};
 
 type PrivateKey = SecretKey;
 
-#[derive(Debug)]
+/// Step of the chat server handshake which failed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum HandshakeStage {
+    /// Reading or decrypting the server hello
+    ServerHello,
+    /// The server hello didn't echo the client nonce
+    ServerAuth,
+    /// Encrypting the client authentication
+    ClientAuth,
+    /// Reading or verifying the server's login acknowledgement
+    LoginAck,
+}
+
+impl fmt::Display for HandshakeStage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::ServerHello => "server hello",
+            Self::ServerAuth => "server authentication",
+            Self::ClientAuth => "client authentication",
+            Self::LoginAck => "login acknowledgement",
+        })
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
 pub enum Error {
+    #[error("Invalid private key")]
     InvalidPrivateKey,
+    #[error("Invalid public key")]
     InvalidPublicKey,
+    #[error("Invalid backup or password")]
     InvalidBackupOrPassword,
-    Io(io::Error),
+    #[error("I/O error: {0}")]
+    Io(#[source] io::Error),
+    /// A read or write on the connection timed out
+    #[error("Timed out")]
+    Timeout,
+    /// Malformed data which isn't covered by a more specific variant
+    #[error("Parser error: {0}")]
     ParseError(String),
-    RequestError,
+    /// A packet or message didn't match its wire format
+    #[error("Invalid data: {0}")]
+    InvalidData(#[from] flat_bytes::DeserializeError),
+    #[error("Invalid JSON: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Request failed: {0}")]
+    Rest(#[from] rest::RestError),
+    #[error("Invalid ID format")]
     InvalidID,
+    #[error("Not connected")]
     NotConnected,
-    DecryptionFailed,
+    #[error("Handshake failed at {stage}")]
+    HandshakeFailed { stage: HandshakeStage },
+    #[error("Looking up the public key of {peer} failed: {source}")]
+    PeerKeyLookup { peer: ThreemaID, source: Box<Error> },
+    /// The server sent a packet which couldn't be decrypted
+    #[error("Decrypting a packet from the server failed")]
+    PacketDecrypt,
+    /// `sender` is unknown when decrypting with a bare public key
+    #[error(
+        "Decrypting the message{} failed",
+        .sender.map(|s| format!(" from {s}")).unwrap_or_default()
+    )]
+    MessageDecrypt { sender: Option<ThreemaID> },
+    /// A downloaded blob didn't match its MAC
+    #[error("Decrypting a blob failed")]
+    BlobDecrypt,
+    /// See [`rest::blob::MAX_BLOB_SIZE`]
+    #[error("Blob exceeds the maximum of {max} bytes")]
+    BlobTooLarge { max: u64 },
+    #[error("Invalid message padding")]
+    InvalidPadding,
+    #[error("Callback MAC mismatch")]
+    InvalidCallbackMac,
+    /// Provisioned server info wasn't signed by a trusted key, see [`servers::fetch`]
+    #[error("Server info signature mismatch")]
+    InvalidServerInfoSignature,
+    #[error("Frame of {size} bytes exceeds the maximum of {max}")]
+    FrameTooLarge { size: usize, max: usize },
+    /// The server announced a frame which can't even hold a packet type
+    #[error("Frame of {size} bytes is shorter than the minimum of {min}")]
+    FrameTooSmall { size: usize, min: usize },
+    /// The server closed the connection, possibly in the middle of a frame
+    #[error("Connection closed by the server")]
+    ConnectionClosed,
+    /// See [`LongTexts::Reject`]
+    #[error("Text of {len} bytes exceeds the maximum of {max}")]
+    TextTooLong { len: usize, max: usize },
+    /// Alert sent by the server, meant to be shown to the user
+    #[error("Server alert: {}", .0.message)]
+    ServerAlert(ServerAlert),
+    /// The server closed the connection, see [`Error::can_reconnect`]
+    #[error("Server error: {}", .0.message)]
+    ServerError(ServerError),
+    /// The directory returned a different key than the one pinned for a contact, see
+    /// [`Threema::accept_key_change`]
+    #[error("Public key of {peer} changed")]
+    KeyChanged {
+        peer: ThreemaID,
+        pinned: PublicKey,
+        new: PublicKey,
+    },
+    #[cfg(feature = "sqlite")]
+    #[error("Database error: {0}")]
+    Database(#[from] rusqlite::Error),
+    #[cfg(feature = "thumbnails")]
+    #[error("Image error: {0}")]
+    Image(#[from] image::ImageError),
+    #[error("Unsupported: {0}")]
+    Unsupported(String),
+    /// Rejected by [`ThreemaBuilder::build`]
+    #[error("Invalid configuration: {0}")]
+    InvalidConfig(String),
+    /// A handler panicked, stopping every client on its thread of a
+    /// [`ClientPool`](pool::ClientPool)
+    #[error("Handler panicked: {0}")]
+    HandlerPanicked(String),
 }
 
-impl fmt::Display for Error {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            Self::InvalidPrivateKey => f.write_str("Invalid private key"),
-            Self::InvalidPublicKey => f.write_str("Invalid public key"),
-            Self::InvalidBackupOrPassword => f.write_str("Invalid backup or password"),
-            Self::ParseError(s) => write!(f, "Parser error: {}", s),
-            Self::RequestError => f.write_str("Request failed"),
-            Self::InvalidID => f.write_str("Invalid ID format"),
-            Self::NotConnected => f.write_str("Not connected"),
-            Self::DecryptionFailed => f.write_str("decryption failed"),
-            Self::Io(e) => write!(f, "I/O error: {}", e),
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => Self::Timeout,
+            _ => Self::Io(e),
         }
     }
 }
-impl From<io::Error> for Error {
-    fn from(e: io::Error) -> Self {
-        Self::Io(e)
+
+impl Error {
+    /// Whether the connection to the chat server is unusable after this error.
+    #[must_use]
+    pub fn is_connection_error(&self) -> bool {
+        matches!(
+            self,
+            Self::Io(_)
+                | Self::Timeout
+                | Self::NotConnected
+                | Self::HandshakeFailed { .. }
+                | Self::PacketDecrypt
+                | Self::FrameTooLarge { .. }
+                | Self::FrameTooSmall { .. }
+                | Self::ConnectionClosed
+                | Self::ServerError(_)
+        )
+    }
+
+    /// Whether connecting again after this error is allowed, which it is unless the
+    /// server said otherwise.
+    #[must_use]
+    pub fn can_reconnect(&self) -> bool {
+        !matches!(
+            self,
+            Self::ServerError(ServerError {
+                can_reconnect: false,
+                ..
+            })
+        )
     }
 }
 
-impl error::Error for Error {}
 type Result<T> = std::result::Result<T, Error>;
 
+/// Reports a connection which ended while reading as closed.
+fn closed_on_eof(e: io::Error) -> Error {
+    if e.kind() == io::ErrorKind::UnexpectedEof {
+        Error::ConnectionClosed
+    } else {
+        e.into()
+    }
+}
+
+fn encode_hex(data: &[u8]) -> String {
+    use std::fmt::Write;
+    data.iter().fold(String::new(), |mut s, b| {
+        let _ = write!(s, "{b:02x}");
+        s
+    })
+}
+
+fn decode_hex_vec(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
+fn decode_hex<const N: usize>(s: &str) -> Option<[u8; N]> {
+    use std::convert::TryInto;
+    decode_hex_vec(s)?.try_into().ok()
+}
+
 struct Nonce {
-    prefix: Vec<u8>,
+    prefix: [u8; 16],
     counter: u64,
 }
 
 impl Nonce {
-    fn new(prefix: Vec<u8>) -> Self {
+    fn new(prefix: [u8; 16]) -> Self {
         Self { prefix, counter: 1 }
     }
 
@@ -84,14 +261,11 @@ impl Nonce {
         &self.prefix
     }
 
-    fn as_bytes(&self) -> Vec<u8> {
-        let mut res = self.prefix.clone();
-        res.extend_from_slice(&self.counter.to_le_bytes());
-        res
-    }
-
-    fn as_nonce(&self) -> Option<box_::Nonce> {
-        box_::Nonce::from_slice(&self.as_bytes())
+    fn as_nonce(&self) -> crypto::Nonce {
+        let mut res = [0u8; 24];
+        res[..16].copy_from_slice(&self.prefix);
+        res[16..].copy_from_slice(&self.counter.to_le_bytes());
+        crypto::Nonce(res)
     }
 
     fn inc(&mut self) {
@@ -99,7 +273,7 @@ impl Nonce {
     }
 }
 
-#[derive(Copy, Clone, PartialEq, Eq, Flat)]
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Flat)]
 pub struct MessageID([u8; 8]);
 
 impl MessageID {
@@ -117,12 +291,37 @@ impl MessageID {
         tmp.copy_from_slice(data);
         Some(Self::from_bytes(tmp))
     }
+
+    #[must_use]
+    pub fn from_hex(s: &str) -> Option<Self> {
+        decode_hex(s).map(Self::from_bytes)
+    }
+
+    #[must_use]
+    pub fn as_bytes(&self) -> &[u8; 8] {
+        &self.0
+    }
+}
+
+impl From<[u8; 8]> for MessageID {
+    fn from(data: [u8; 8]) -> Self {
+        Self(data)
+    }
+}
+
+/// Parses the hex representation, as shown by `Display`.
+impl std::str::FromStr for MessageID {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::from_hex(s).ok_or_else(|| Error::ParseError(format!("message ID: {s:?}")))
+    }
 }
 
 impl fmt::Display for MessageID {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         for b in &self.0 {
-            write!(f, "{:02x}", b)?;
+            write!(f, "{b:02x}")?;
         }
         Ok(())
     }
@@ -134,10 +333,29 @@ impl fmt::Debug for MessageID {
     }
 }
 
+impl serde::Serialize for MessageID {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for MessageID {
+    fn deserialize<D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> std::result::Result<Self, D::Error> {
+        let s = <String as serde::Deserialize>::deserialize(deserializer)?;
+        Self::from_hex(&s)
+            .ok_or_else(|| serde::de::Error::custom(format!("invalid message ID {s:?}")))
+    }
+}
+
 impl Default for MessageID {
     fn default() -> Self {
         let mut res = Self(Default::default());
-        randombytes::randombytes_into(&mut res.0);
+        sources::OsRng.fill(&mut res.0);
         res
     }
 }
@@ -151,7 +369,9 @@ impl ThreemaID {
             return Err(Error::InvalidID);
         }
         let alphabet = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789";
-        if id.iter().any(|c| !alphabet.contains(c)) {
+        // gateway IDs start with a '*'
+        let rest = id.strip_prefix(b"*").unwrap_or(id);
+        if rest.iter().any(|c| !alphabet.contains(c)) {
             return Err(Error::InvalidID);
         }
         let mut tmp = [0u8; 8];
@@ -159,13 +379,44 @@ impl ThreemaID {
         Ok(Self(tmp))
     }
 
+    /// Parses `s`, which may also be in lowercase.
     pub fn from_string(s: &str) -> Result<Self> {
-        Self::from_slice(s.as_bytes())
+        Self::from_slice(s.to_ascii_uppercase().as_bytes())
     }
 
     fn as_bytes(self) -> [u8; 8] {
         self.0
     }
+
+    /// The ID as string, empty if it isn't valid UTF-8, e.g. when sent by a broken peer.
+    #[must_use]
+    pub fn as_str(&self) -> &str {
+        std::str::from_utf8(&self.0).unwrap_or_default()
+    }
+}
+
+impl std::str::FromStr for ThreemaID {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::from_string(s)
+    }
+}
+
+impl TryFrom<&str> for ThreemaID {
+    type Error = Error;
+
+    fn try_from(s: &str) -> Result<Self> {
+        Self::from_string(s)
+    }
+}
+
+impl TryFrom<[u8; 8]> for ThreemaID {
+    type Error = Error;
+
+    fn try_from(id: [u8; 8]) -> Result<Self> {
+        Self::from_slice(&id)
+    }
 }
 
 impl fmt::Display for ThreemaID {
@@ -182,323 +433,3210 @@ impl fmt::Debug for ThreemaID {
     }
 }
 
-#[derive(Copy, Clone, PartialEq, Eq, Flat)]
+impl serde::Serialize for ThreemaID {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for ThreemaID {
+    fn deserialize<D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> std::result::Result<Self, D::Error> {
+        let s = <String as serde::Deserialize>::deserialize(deserializer)?;
+        Self::from_string(&s).map_err(|_| serde::de::Error::custom(format!("invalid ID {s:?}")))
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Flat)]
 pub struct GroupID([u8; 8]);
 
+impl GroupID {
+    #[must_use]
+    pub fn from_bytes(data: [u8; 8]) -> Self {
+        Self(data)
+    }
+
+    #[must_use]
+    pub fn from_hex(s: &str) -> Option<Self> {
+        decode_hex(s).map(Self)
+    }
+
+    /// A new random ID, e.g. for creating a group.
+    #[must_use]
+    pub fn random() -> Self {
+        let mut res = Self([0; 8]);
+        sources::OsRng.fill(&mut res.0);
+        res
+    }
+
+    #[must_use]
+    pub fn as_bytes(&self) -> &[u8; 8] {
+        &self.0
+    }
+}
+
+impl From<[u8; 8]> for GroupID {
+    fn from(data: [u8; 8]) -> Self {
+        Self(data)
+    }
+}
+
+/// Parses the hex representation, as shown by `Display`.
+impl std::str::FromStr for GroupID {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::from_hex(s).ok_or_else(|| Error::ParseError(format!("group ID: {s:?}")))
+    }
+}
+
+impl fmt::Display for GroupID {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&encode_hex(&self.0))
+    }
+}
+
+impl serde::Serialize for GroupID {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(&encode_hex(&self.0))
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for GroupID {
+    fn deserialize<D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> std::result::Result<Self, D::Error> {
+        let s = <String as serde::Deserialize>::deserialize(deserializer)?;
+        decode_hex(&s)
+            .map(Self)
+            .ok_or_else(|| serde::de::Error::custom(format!("invalid group ID {s:?}")))
+    }
+}
+
+impl fmt::Debug for GroupID {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("GroupID")
+            .field(&encode_hex(&self.0))
+            .finish()
+    }
+}
+
+/// Nickname sent in the header of outgoing messages.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum Nickname {
+    /// The own identity, which is also what apps show without a nickname.
+    #[default]
+    Id,
+    /// No nickname at all, the header field is left zeroed.
+    Hidden,
+    /// A custom name, cut to 32 bytes at a character boundary.
+    Name(String),
+}
+
+impl Nickname {
+    /// Longest nickname the message header can hold, in bytes.
+    pub const MAX_LEN: usize = 32;
+
+    /// Value of the header field for the identity `id`.
+    fn header_value(&self, id: ThreemaID) -> String {
+        match self {
+            Nickname::Id => id.to_string(),
+            Nickname::Hidden => String::new(),
+            Nickname::Name(name) => name[..floor_char_boundary(name, Self::MAX_LEN)].to_owned(),
+        }
+    }
+}
+
+/// What happens to texts longer than [`Text::MAX_LEN`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LongTexts {
+    /// Send them as several messages, split after whitespace where possible
+    #[default]
+    Split,
+    /// Cut them at the last character which fits
+    Truncate,
+    /// Refuse to send them with [`Error::TextTooLong`]
+    Reject,
+}
+
+/// How outgoing messages are padded before encryption, hiding their exact length.
+///
+/// Receivers strip the padding PKCS#7 style, so it is always 1 to 255 bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Padding {
+    /// 1 to 32 random bytes, cheap but only hides the length of short messages
+    #[default]
+    Short,
+    /// 1 to 255 random bytes, like the official apps
+    Random,
+    /// Up to the next multiple of the given size, e.g. 128, so that all messages of a
+    /// bucket look alike. Exact multiples get another full bucket.
+    ///
+    /// At most [`Padding::MAX_BUCKET`]: larger buckets would need more padding than
+    /// receivers can strip, and are rejected by [`ThreemaBuilder::build`].
+    Bucket(std::num::NonZeroU16),
+}
+
+impl Padding {
+    /// Largest bucket size, the most padding a message can carry.
+    pub const MAX_BUCKET: u16 = 255;
+
+    /// Number of padding bytes for a message of `len` bytes.
+    fn length(self, len: usize, rng: &mut dyn RngSource) -> u8 {
+        match self {
+            #[allow(clippy::cast_possible_truncation)]
+            Padding::Short => rng.uniform(32) as u8 + 1,
+            #[allow(clippy::cast_possible_truncation)]
+            Padding::Random => rng.uniform(255) as u8 + 1,
+            Padding::Bucket(size) => {
+                let size = usize::from(size.get().min(Self::MAX_BUCKET));
+                #[allow(clippy::cast_possible_truncation)]
+                let pad = (size - len % size) as u8;
+                pad
+            }
+        }
+    }
+}
+
+/// Splits `text` into parts of at most `max` bytes, breaking after the last whitespace
+/// of each part if there is one.
+fn split_text(text: &str, max: usize) -> Vec<&str> {
+    let mut parts = vec![];
+    let mut rest = text;
+    while rest.len() > max {
+        let mut end = floor_char_boundary(rest, max);
+        if let Some((pos, c)) = rest[..end]
+            .char_indices()
+            .rev()
+            .find(|(_, c)| c.is_whitespace())
+        {
+            if pos > 0 {
+                end = pos + c.len_utf8();
+            }
+        }
+        let (part, tail) = rest.split_at(end);
+        parts.push(part);
+        rest = tail;
+    }
+    parts.push(rest);
+    parts
+}
+
+/// The largest index up to `index` which isn't inside a character of `s`.
+fn floor_char_boundary(s: &str, index: usize) -> usize {
+    let mut end = index.min(s.len());
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    end
+}
+
+/// Number of decrypted but unacknowledged messages whose nonces are kept until they're
+/// acknowledged.
+const UNACKED_NONCES: usize = 1000;
+
+/// Minimum time between directory lookups for the key of a sender whose messages can't
+/// be decrypted, see [`ThreemaBuilder::refresh_keys`].
+const KEY_REFRESH_INTERVAL: time::Duration = time::Duration::from_mins(10);
+
+/// Replies sent automatically for incoming messages.
+#[derive(Debug, Clone, Copy)]
+struct AutoReplies {
+    ack: bool,
+    delivery_receipt: bool,
+    read_receipt: bool,
+}
+
+impl Default for AutoReplies {
+    fn default() -> Self {
+        Self {
+            ack: true,
+            delivery_receipt: true,
+            read_receipt: false,
+        }
+    }
+}
+
+/// Smallest valid frame: the MAC and a packet type.
+pub const MIN_FRAME_SIZE: usize = crypto::MACBYTES + 4;
+/// Largest frame the 16-bit length prefix can announce.
+pub const MAX_FRAME_SIZE: usize = 0xffff;
+
+/// Client of the chat server, created with [`Threema::builder`].
+///
+/// # Metrics
+///
+/// With the `metrics` feature, the client reports to the recorder installed for the
+/// [`metrics`](https://docs.rs/metrics) facade, e.g. a Prometheus exporter:
+///
+/// - `threema_messages_sent_total` and `threema_messages_received_total`, labeled with
+///   the `type` of the message, see [`Message::kind`]
+/// - `threema_acks_sent_total` and `threema_acks_received_total`
+/// - `threema_reconnects_total` by [`Threema::run`], labeled with the `result`
+/// - `threema_handshake_duration_seconds`
+/// - `threema_ping_duration_seconds`, the round trips measured by [`Threema::ping`]
+/// - `threema_rest_request_duration_seconds`, labeled with the `result`
+/// - `threema_outbox_messages`, the number of messages in the
+///   [outbox](ThreemaBuilder::outbox)
 pub struct Threema {
     id: ThreemaID,
     private_key: PrivateKey,
-    peers: HashMap<ThreemaID, PublicKey>,
-    pub nick: Option<String>,
+    peers: Box<dyn PeerKeyStore>,
+    contacts: Box<dyn ContactStore>,
+    history: Option<Box<dyn store::MessageStore>>,
+    outbox: Option<Box<dyn outbox::Outbox>>,
+    nonces: Box<dyn nonces::NonceStore>,
+    /// nonces of messages received with automatic acks disabled, recorded once acked
+    unacked_nonces: VecDeque<(ThreemaID, MessageID, nonces::MessageNonce)>,
+    blocked: HashSet<ThreemaID>,
+    block_mode: filter::BlockMode,
+    filter: Option<filter::Filter>,
+    recent: dedup::RecentMessages,
+    duplicates: dedup::DuplicatePolicy,
+    directory: Box<dyn DirectoryClient>,
+    /// agent for blob uploads if the client has its own [proxy](ThreemaBuilder::proxy)
+    #[cfg(feature = "rest")]
+    agent: Option<ureq::Agent>,
+    peer_status: HashMap<ThreemaID, identity::IdentityStatus>,
+    refresh_keys: bool,
+    /// last key refresh of senders, with the differing key if any, until it's accepted
+    key_refreshes: HashMap<ThreemaID, (time::SystemTime, Option<PublicKey>)>,
+    nick: Nickname,
+    long_texts: LongTexts,
+    padding: Padding,
     client_nonce: Option<Nonce>,
     server_nonce: Option<Nonce>,
     server_pubkey: Option<PublicKey>,
     ephemeral_private_key: Option<PrivateKey>,
     // ephemeral_public_key: Option<PublicKey>,
-    conn: Option<TcpStream>,
+    conn: Option<Box<dyn transport::Transport>>,
+    /// buffers for frames, kept to avoid allocating for every packet
+    send_buf: Vec<u8>,
+    recv_buf: Vec<u8>,
+    /// see [`ThreemaBuilder::max_frame_size`]
+    max_frame_size: usize,
+    socket: transport::SocketOptions,
+    rng: Box<dyn RngSource>,
+    clock: Box<dyn Clock>,
+    keepalive: time::Duration,
+    /// counter of an unanswered echo request sent by [`Threema::run`]
+    echo_pending: Option<u64>,
+    /// time [`Threema::run`] waited for a packet since the last one
+    idle: time::Duration,
+    echo_counter: u64,
+    /// events received by [`Threema::ping`] while waiting for the reply, handed out first
+    events: VecDeque<ClientEvent>,
+    /// the current connection, see [`Threema::status`]
+    session: status::Session,
+    messages_sent: u64,
+    messages_received: u64,
+    auto_replies: AutoReplies,
 }
 
 impl Threema {
+    /// Starts configuring a client, see [`ThreemaBuilder`].
+    pub fn builder() -> ThreemaBuilder {
+        ThreemaBuilder::default()
+    }
+
     pub fn new(id: ThreemaID, private_key: &[u8]) -> Result<Self> {
-        Ok(Self {
-            id,
-            private_key: PrivateKey::from_slice(private_key).ok_or(Error::InvalidPrivateKey)?,
-            peers: HashMap::new(),
-            client_nonce: None,
-            server_nonce: None,
-            nick: None,
-            server_pubkey: None,
-            ephemeral_private_key: None,
-            // ephemeral_public_key: None,
-            conn: None,
-        })
+        Self::builder().identity(id, private_key).build()
     }
 
     pub fn from_backup(data: &str, password: &str) -> Result<Self> {
-        let (id, private_key) =
-            identity::decrypt(data, password).ok_or(Error::InvalidBackupOrPassword)?;
-        Self::new(ThreemaID::from_string(&id)?, &private_key)
+        Self::builder().backup(data, password).build()
+    }
+
+    #[must_use]
+    pub fn id(&self) -> ThreemaID {
+        self.id
+    }
+
+    /// Custom nickname sent along with messages, if any.
+    #[must_use]
+    pub fn nick(&self) -> Option<&str> {
+        match &self.nick {
+            Nickname::Name(name) => Some(name),
+            Nickname::Id | Nickname::Hidden => None,
+        }
+    }
+
+    /// Changes the nickname sent along with later messages.
+    pub fn set_nick(&mut self, nick: Nickname) {
+        self.nick = nick;
+    }
+
+    /// Queries the directory for the state of the own identity.
+    pub fn check_status(&self) -> Result<identity::IdentityStatus> {
+        Ok(identity::status_of(
+            self.directory.fetch_identity(self.id)?.as_ref(),
+        ))
+    }
+
+    /// Looks up the identity linked to `phone` (E.164 format, e.g. `+41791234567`).
+    pub fn lookup_by_phone(&mut self, phone: &str) -> Result<Option<ThreemaID>> {
+        let matches = self.directory.match_identities(&[phone], &[])?;
+        self.add_matches(matches)
+    }
+
+    /// Looks up the identity linked to `email`.
+    pub fn lookup_by_email(&mut self, email: &str) -> Result<Option<ThreemaID>> {
+        let matches = self.directory.match_identities(&[], &[email])?;
+        self.add_matches(matches)
+    }
+
+    fn add_matches(&mut self, matches: Vec<identity::IdentityMatch>) -> Result<Option<ThreemaID>> {
+        let mut res = None;
+        for m in matches {
+            self.check_pinned_key(m.id, &m.public_key)?;
+            self.peers.insert(m.id, m.public_key);
+            if let Some(mut contact) = self.contacts.get(m.id) {
+                if contact.verification < contacts::VerificationLevel::ServerVerified {
+                    contact.verification = contacts::VerificationLevel::ServerVerified;
+                    self.contacts.put(contact);
+                }
+            }
+            res = Some(m.id);
+        }
+        Ok(res)
+    }
+
+    /// Fails with [`Error::KeyChanged`] if `public_key` of `peer`, as returned by the
+    /// directory, differs from the one pinned in its contact.
+    fn check_pinned_key(&self, peer: ThreemaID, public_key: &PublicKey) -> Result<()> {
+        match self.contacts.get(peer) {
+            Some(contact) if contact.public_key != *public_key => Err(Error::KeyChanged {
+                peer,
+                pinned: contact.public_key,
+                new: *public_key,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Replaces the pinned key of `peer` after an [`Error::KeyChanged`], resetting its
+    /// verification level. Creates the contact if necessary.
+    pub fn accept_key_change(&mut self, peer: ThreemaID, public_key: PublicKey) {
+        let contact = match self.contacts.get(peer) {
+            Some(mut contact) => {
+                contact.public_key = public_key;
+                contact.verification = contacts::VerificationLevel::Unverified;
+                contact
+            }
+            None => contacts::Contact::new(peer, public_key),
+        };
+        self.contacts.put(contact);
+        self.invalidate_peer_key(peer);
+    }
+
+    /// Replaces the cache used for public keys of peers.
+    pub fn set_key_store(&mut self, store: Box<dyn PeerKeyStore>) {
+        self.peers = store;
+    }
+
+    /// Replaces the client used to look up peers, e.g. with a
+    /// [`MemoryDirectory`](directory::MemoryDirectory) in tests.
+    pub fn set_directory(&mut self, directory: Box<dyn DirectoryClient>) {
+        self.directory = directory;
+    }
+
+    /// The agent for REST requests of this client.
+    #[cfg(feature = "rest")]
+    pub(crate) fn rest_agent(&self) -> ureq::Agent {
+        self.agent.clone().unwrap_or_else(rest::agent)
+    }
+
+    /// Replaces the source of nonces, keys, message IDs and padding.
+    pub fn set_rng(&mut self, rng: Box<dyn RngSource>) {
+        self.rng = rng;
+    }
+
+    /// Replaces the clock used for message timestamps.
+    pub fn set_clock(&mut self, clock: Box<dyn Clock>) {
+        self.clock = clock;
     }
 
-    fn fetch_peer_key(peer: ThreemaID) -> Result<PublicKey> {
-        let resp: rest::messages::GetPubKeyResponse =
-            rest::request(&format!("/identity/{}", peer)).unwrap();
-        PublicKey::from_slice(resp.public_key.as_ref()).ok_or(Error::InvalidPublicKey)
+    /// Contacts, whose keys are used instead of asking the directory.
+    pub fn contacts(&mut self) -> &mut dyn ContactStore {
+        self.contacts.as_mut()
     }
 
+    /// Replaces the store of [contacts](Threema::contacts).
+    pub fn set_contact_store(&mut self, store: Box<dyn ContactStore>) {
+        self.contacts = store;
+    }
+
+    /// Drops all further messages of `peer`, as configured by
+    /// [`ThreemaBuilder::block_mode`].
+    pub fn block(&mut self, peer: ThreemaID) {
+        self.blocked.insert(peer);
+    }
+
+    pub fn unblock(&mut self, peer: ThreemaID) {
+        self.blocked.remove(&peer);
+    }
+
+    /// Whether `peer` was [blocked](Threema::block) or is a blocked contact.
+    #[must_use]
+    pub fn is_blocked(&self, peer: ThreemaID) -> bool {
+        self.blocked.contains(&peer) || self.contacts.get(peer).is_some_and(|c| c.blocked)
+    }
+
+    /// Replaces the hook deciding about incoming messages of senders which aren't
+    /// blocked, see [`ThreemaBuilder::filter`].
+    pub fn set_filter(&mut self, filter: Option<filter::Filter>) {
+        self.filter = filter;
+    }
+
+    /// Stores the known public key of `peer`, so it isn't looked up in the directory.
+    ///
+    /// Together with a key store without expiry (the default), this allows messaging
+    /// without any access to the directory.
+    pub fn add_peer_key(&mut self, peer: ThreemaID, public_key: PublicKey) {
+        self.peers.insert(peer, public_key);
+    }
+
+    /// Returns the public key of `peer` if it is known, without asking the directory.
+    ///
+    /// That's the key pinned in its contact if there is one, otherwise the cached one.
+    pub fn peer_key(&mut self, peer: ThreemaID) -> Option<PublicKey> {
+        let cached = self.peers.get(peer);
+        self.contacts
+            .get(peer)
+            .map(|contact| contact.public_key)
+            .or(cached)
+    }
+
+    /// Drops the cached public key of `peer`, forcing a directory lookup on next use.
+    ///
+    /// The lookup doesn't replace a pinned key: messages keep using it, a differing key
+    /// is only logged until it's [accepted](Threema::accept_key_change).
+    pub fn invalidate_peer_key(&mut self, peer: ThreemaID) {
+        self.peers.invalidate(peer);
+        self.peer_status.remove(&peer);
+        self.key_refreshes.remove(&peer);
+    }
+
+    /// Fetches the key of `peer` again after decrypting with `stale` failed, returning it
+    /// if it differs.
+    ///
+    /// The result is reused for [`KEY_REFRESH_INTERVAL`], so that a sender of
+    /// undecryptable messages doesn't cause a lookup for each of them.
+    fn refresh_peer_key(&mut self, peer: ThreemaID, stale: &PublicKey) -> Option<PublicKey> {
+        let now = self.clock.now();
+        let recent = |at: &time::SystemTime| {
+            now.duration_since(*at)
+                .is_ok_and(|d| d < KEY_REFRESH_INTERVAL)
+        };
+        if let Some((at, key)) = self.key_refreshes.get(&peer) {
+            if recent(at) {
+                return *key;
+            }
+        }
+        let key = match self.directory.fetch_identity(peer) {
+            Ok(Some(entry)) if entry.public_key != *stale => Some(entry.public_key),
+            Ok(_) => None,
+            Err(e) => {
+                warn!(%peer, error = %e, "Couldn't refresh public key");
+                None
+            }
+        };
+        self.key_refreshes.retain(|_, (at, _)| recent(at));
+        self.key_refreshes.insert(peer, (now, key));
+        key
+    }
+
+    fn fetch_peer_key(&mut self, peer: ThreemaID) -> Result<PublicKey> {
+        let entry = self
+            .directory
+            .fetch_identity(peer)?
+            .ok_or_else(|| directory::unknown_identity(peer))?;
+        self.check_pinned_key(peer, &entry.public_key)?;
+        self.peer_status.insert(peer, entry.status);
+        Ok(entry.public_key)
+    }
+
+    /// Returns the directory state and feature mask of `peer`, fetching it if not known yet.
+    pub fn peer_status(&mut self, peer: ThreemaID) -> Result<identity::IdentityStatus> {
+        if let Some(status) = self.peer_status.get(&peer) {
+            return Ok(*status);
+        }
+        let entry = self.directory.fetch_identity(peer)?;
+        if let Some(entry) = &entry {
+            self.check_pinned_key(peer, &entry.public_key)?;
+        }
+        let status = identity::status_of(entry.as_ref());
+        self.peer_status.insert(peer, status);
+        Ok(status)
+    }
+
+    /// Returns the features supported by `peer`, e.g. to check for file support before
+    /// sending one.
+    pub fn peer_capabilities(&mut self, peer: ThreemaID) -> Result<identity::FeatureMask> {
+        Ok(self.peer_status(peer)?.feature_mask)
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn connect_chat_server(
+        servers: &servers::ServerInfo,
+        options: &transport::SocketOptions,
+    ) -> Result<(TcpStream, String)> {
+        let mut last_err = None;
+        for addr in servers.chat_addresses() {
+            match TcpStream::connect(&addr) {
+                Ok(conn) => {
+                    options.apply(&conn)?;
+                    return Ok((conn, format!("{}:{}", addr.0, addr.1)));
+                }
+                Err(e) => {
+                    warn!(host = %addr.0, port = addr.1, error = %e, "Couldn't connect");
+                    last_err = Some(e);
+                }
+            }
+        }
+        Err(last_err.map_or(Error::NotConnected, Error::Io))
+    }
+
+    /// Connects to the chat server and sends the messages waiting in the outbox, if any.
+    #[cfg(not(target_arch = "wasm32"))]
     pub fn connect(&mut self) -> Result<()> {
-        let mut conn = TcpStream::connect(MSG_SERVER)?;
-        let client_nonce_prefix = randombytes::randombytes(16);
+        let (conn, server) = Self::connect_chat_server(&servers::current(), &self.socket)?;
+        self.establish(Box::new(conn), Some(server))
+    }
+
+    /// Like [`connect`](Self::connect), but talks to the chat server over `transport`
+    /// instead of a TCP connection of its own.
+    pub fn connect_with(&mut self, transport: Box<dyn transport::Transport>) -> Result<()> {
+        self.establish(transport, None)
+    }
+
+    /// Runs the handshake on `transport` to the chat server at `server`, if known, and
+    /// sends the outbox.
+    #[instrument(skip_all, fields(id = %self.id))]
+    fn establish(
+        &mut self,
+        mut transport: Box<dyn transport::Transport>,
+        server: Option<String>,
+    ) -> Result<()> {
+        let start = time::Instant::now();
+        self.handshake(transport.as_mut(), &servers::current())?;
+        stats::handshake(start.elapsed());
+        debug!("Connected");
+        self.conn = Some(transport);
+        self.session = status::Session {
+            server,
+            connected_since: Some(self.clock.now()),
+            ..status::Session::default()
+        };
+        self.flush_outbox()
+    }
+
+    /// Snapshot of the connection, e.g. to tell a stalled one.
+    #[must_use]
+    pub fn status(&self) -> status::Status {
+        let mut status = status::Status {
+            messages_sent: self.messages_sent,
+            messages_received: self.messages_received,
+            ..status::Status::default()
+        };
+        if self.conn.is_none() {
+            return status;
+        }
+        status.server.clone_from(&self.session.server);
+        status.connected_since = self.session.connected_since;
+        status.last_frame = self.session.last_frame;
+        status.pending_acks = self.session.unacked.len();
+        status.client_nonce = self.client_nonce.as_ref().map(|nonce| nonce.counter);
+        status.server_nonce = self.server_nonce.as_ref().map(|nonce| nonce.counter);
+        status
+    }
+
+    /// Authenticates on `conn` and sets up the session keys.
+    #[instrument(skip_all)]
+    fn handshake(
+        &mut self,
+        conn: &mut dyn transport::Transport,
+        servers: &servers::ServerInfo,
+    ) -> Result<()> {
+        let mut client_nonce_prefix = [0u8; 16];
+        self.rng.fill(&mut client_nonce_prefix);
         let mut client_nonce = Nonce::new(client_nonce_prefix);
 
-        let (eph_pub, eph_priv) = box_::gen_keypair();
+        let mut seed = crypto::Seed([0u8; crypto::SEEDBYTES]);
+        self.rng.fill(&mut seed.0);
+        let (eph_pub, eph_priv) = crypto::keypair_from_seed(&seed);
 
-        conn.write_all(eph_pub.as_ref()).unwrap();
-        conn.write_all(client_nonce.prefix()).unwrap();
+        let failed = |stage| Error::HandshakeFailed { stage };
+
+        conn.write_all(eph_pub.as_ref())?;
+        conn.write_all(client_nonce.prefix())?;
 
         let mut server_nonce_prefix = [0u8; 16];
-        conn.read_exact(&mut server_nonce_prefix).unwrap();
+        conn.read_exact(&mut server_nonce_prefix)?;
         let mut ciphertext = [0u8; 64];
-        conn.read_exact(&mut ciphertext).unwrap();
+        conn.read_exact(&mut ciphertext)?;
 
-        let mut server_nonce = Nonce::new(server_nonce_prefix.to_vec());
-        let server_lt_pub = box_::PublicKey::from_slice(&SERVER_LONG_TERM_PUBKEY).unwrap();
+        let mut server_nonce = Nonce::new(server_nonce_prefix);
+        let server_lt_pub = servers.chat_public_key;
 
-        let plaintext = box_::open(
+        let plaintext = crypto::open(
             &ciphertext,
-            &server_nonce.as_nonce().unwrap(),
+            &server_nonce.as_nonce(),
             &server_lt_pub,
             &eph_priv,
         )
-        .unwrap();
+        .ok_or_else(|| failed(HandshakeStage::ServerHello))?;
 
         let (server_pkey, tmp) = plaintext.split_at(32);
-        assert!(client_nonce.prefix() == tmp);
-        let server_pkey = box_::PublicKey::from_slice(server_pkey).unwrap();
+        if client_nonce.prefix() != tmp {
+            return Err(failed(HandshakeStage::ServerAuth));
+        }
+        let server_pkey = crypto::PublicKey::from_slice(server_pkey)
+            .ok_or_else(|| failed(HandshakeStage::ServerHello))?;
 
         server_nonce.inc();
 
-        let nonce = Nonce::new(randombytes::randombytes(16));
+        let mut nonce_prefix = [0u8; 16];
+        self.rng.fill(&mut nonce_prefix);
+        let nonce = Nonce::new(nonce_prefix);
 
-        let mut inner = box_::seal(
+        let mut inner = crypto::seal(
             eph_pub.as_ref(),
-            &nonce.as_nonce().unwrap(),
+            &nonce.as_nonce(),
             &server_lt_pub,
             &self.private_key,
         );
-        assert!(inner.len() == 48);
+        if inner.len() != 48 {
+            return Err(failed(HandshakeStage::ClientAuth));
+        }
 
         let mut outer = vec![];
         outer.extend(self.id.as_bytes().iter());
         outer.resize(outer.len() + 32, 0);
         outer.extend(server_nonce.prefix());
-        outer.append(&mut nonce.as_bytes());
+        outer.extend_from_slice(&nonce.as_nonce().0);
         outer.append(&mut inner);
 
-        let outer = box_::seal(
-            &outer,
-            &client_nonce.as_nonce().unwrap(),
-            &server_pkey,
-            &eph_priv,
-        );
-        assert!(outer.len() == 144);
+        let outer = crypto::seal(&outer, &client_nonce.as_nonce(), &server_pkey, &eph_priv);
+        if outer.len() != 144 {
+            return Err(failed(HandshakeStage::ClientAuth));
+        }
 
-        conn.write_all(&outer).unwrap();
+        conn.write_all(&outer)?;
         client_nonce.inc();
 
         let mut ack = [0u8; 32];
-        conn.read_exact(&mut ack).unwrap();
-        let ack = box_::open(
-            &ack,
-            &server_nonce.as_nonce().unwrap(),
-            &server_pkey,
-            &eph_priv,
-        )
-        .unwrap();
+        conn.read_exact(&mut ack)?;
+        let ack = crypto::open(&ack, &server_nonce.as_nonce(), &server_pkey, &eph_priv)
+            .ok_or_else(|| failed(HandshakeStage::LoginAck))?;
         server_nonce.inc();
 
-        assert!(ack == [0u8; 16]);
+        if ack != [0u8; 16] {
+            return Err(failed(HandshakeStage::LoginAck));
+        }
 
         self.client_nonce = Some(client_nonce);
         self.server_nonce = Some(server_nonce);
         self.server_pubkey = Some(server_pkey);
         self.ephemeral_private_key = Some(eph_priv);
         // self.ephemeral_public_key = Some(eph_pub);
-        self.conn = Some(conn);
         Ok(())
     }
 
-    fn send(&mut self, data: &[u8]) -> Result<()> {
-        let enc_packet = box_::seal(
-            data,
-            &self
-                .client_nonce
-                .as_ref()
-                .and_then(Nonce::as_nonce)
+    /// Closes the connection to the chat server, if any.
+    pub fn disconnect(&mut self) {
+        self.conn = None;
+        self.client_nonce = None;
+        self.server_nonce = None;
+        self.server_pubkey = None;
+        self.ephemeral_private_key = None;
+        self.echo_pending = None;
+        self.idle = time::Duration::ZERO;
+        self.session = status::Session::default();
+    }
+
+    fn send(&mut self, packet: &Packet) -> Result<()> {
+        self.send_with(packet, &[])
+    }
+
+    /// Sends `packet` followed by `extra` data, serialized and encrypted in a buffer
+    /// kept across calls.
+    fn send_with(&mut self, packet: &Packet, extra: &[u8]) -> Result<()> {
+        let mut frame = std::mem::take(&mut self.send_buf);
+        frame.clear();
+        packet.serialize_into(&mut frame);
+        frame.extend_from_slice(extra);
+        let res = self.send_frame(&mut frame);
+        self.send_buf = frame;
+        res
+    }
+
+    /// Encrypts the packet in `frame` and sends it.
+    fn send_frame(&mut self, frame: &mut Vec<u8>) -> Result<()> {
+        crypto::seal_in_place(
+            frame,
+            &self
+                .client_nonce
+                .as_ref()
+                .map(Nonce::as_nonce)
                 .ok_or(Error::NotConnected)?,
             self.server_pubkey.as_ref().ok_or(Error::NotConnected)?,
             self.ephemeral_private_key
                 .as_ref()
                 .ok_or(Error::NotConnected)?,
         );
-        #[allow(clippy::cast_possible_truncation)]
-        let len = enc_packet.len() as u16;
-        self.conn
-            .as_ref()
-            .ok_or(Error::NotConnected)?
-            .write_all(&len.to_le_bytes())?;
-        self.conn
-            .as_ref()
-            .ok_or(Error::NotConnected)?
-            .write_all(&enc_packet)?;
+        let len = u16::try_from(frame.len()).map_err(|_| Error::FrameTooLarge {
+            size: frame.len(),
+            max: MAX_FRAME_SIZE,
+        })?;
+        // a single write, so that the length doesn't go out on its own with `TCP_NODELAY`
+        frame.splice(..0, len.to_le_bytes());
+        let conn = self.conn.as_mut().ok_or(Error::NotConnected)?;
+        conn.write_all(frame)?;
         self.client_nonce.as_mut().map(Nonce::inc);
         Ok(())
     }
 
-    fn get_peer_key(&mut self, peer: ThreemaID) -> Result<&PublicKey> {
-        use std::collections::hash_map::Entry::{Occupied, Vacant};
-        let pk = match self.peers.entry(peer) {
-            Occupied(entry) => entry.into_mut(),
-            Vacant(entry) => {
-                let pk = Self::fetch_peer_key(peer)?;
-                entry.insert(pk)
+    /// Sends `packet` followed by `extra_payload` to the server.
+    ///
+    /// Meant for experiments with the protocol: unlike the other methods, nothing is
+    /// checked, queued or recorded, and outgoing messages have to be encrypted already.
+    pub fn send_raw_packet(&mut self, packet: &Packet, extra_payload: &[u8]) -> Result<()> {
+        self.send_with(packet, extra_payload)
+    }
+
+    /// Pads and encrypts `msg` for `receiver` with `public_key`, like sending it would,
+    /// and returns the packet instead of sending it.
+    ///
+    /// Meant for experiments with the protocol, e.g. to look at the bytes of a message:
+    /// no connection is needed, and nothing is sent, queued or recorded.
+    pub fn seal_raw_message(
+        &mut self,
+        receiver: ThreemaID,
+        public_key: &PublicKey,
+        msg: &Message,
+    ) -> Packet {
+        let msg_id = self.new_message_id();
+        self.seal_message(receiver, public_key, msg_id, msg.serialize(), None)
+    }
+
+    /// Returns the key to encrypt for `peer`, looking it up in the directory if it isn't
+    /// cached (anymore).
+    ///
+    /// A pinned key always wins; the lookup only confirms it or reports a change, and it
+    /// is used as is if the directory can't be reached.
+    fn get_peer_key(&mut self, peer: ThreemaID) -> Result<PublicKey> {
+        let pinned = self.contacts.get(peer).map(|contact| contact.public_key);
+        if let Some(pk) = self.peers.get(peer) {
+            return Ok(pinned.unwrap_or(pk));
+        }
+        match (self.fetch_peer_key(peer), pinned) {
+            (Ok(pk), _) => {
+                self.peers.insert(peer, pk);
+                if pinned.is_none() {
+                    // pin the key on first use, later changes have to be accepted explicitly
+                    self.contacts.put(contacts::Contact::new(peer, pk));
+                }
+                Ok(pk)
             }
-        };
-        Ok(pk)
+            (Err(Error::KeyChanged { pinned, new, .. }), _) => {
+                warn!(%peer, public_key = ?new, "Public key changed, keeping the pinned one");
+                self.peers.insert(peer, pinned);
+                Ok(pinned)
+            }
+            (Err(e), Some(pk)) => {
+                warn!(%peer, error = %e, "Couldn't look up public key, using the pinned one");
+                Ok(pk)
+            }
+            (Err(e), None) => Err(Error::PeerKeyLookup {
+                peer,
+                source: Box::new(e),
+            }),
+        }
     }
 
-    fn get_nickname(&self) -> [u8; 32] {
-        let id_bytes = &self.id.as_bytes();
-        let nick = self
-            .nick
-            .as_ref()
-            .map_or(id_bytes.as_slice(), String::as_bytes);
-        let mut nickname = [0u8; 32];
-        let n = if nick.len() < 32 { nick.len() } else { 32 };
-        nickname[..n].copy_from_slice(&nick[..n]);
-        nickname
+    /// Sends `msg` to `receiver`.
+    ///
+    /// With an outbox, the message is also added to it, and only queued while
+    /// disconnected. It stays there if sending fails.
+    ///
+    /// `nick` overrides the client's nickname for this message, but not when it's
+    /// sent again from the outbox.
+    fn send_message(
+        &mut self,
+        receiver: ThreemaID,
+        msg: &Message,
+        nick: Option<&Nickname>,
+    ) -> Result<MessageID> {
+        let data = msg.serialize();
+        let msg_id = self.new_message_id();
+        let now = self.clock.now();
+        let mut state = store::DeliveryState::Sent;
+        let queued = !matches!(msg, Message::TypingNotification);
+        match &mut self.outbox {
+            Some(outbox) if queued => {
+                outbox.push(outbox::OutboxEntry {
+                    receiver,
+                    msg_id,
+                    body: data.clone(),
+                    created: now,
+                })?;
+                self.report_outbox_size();
+                if self.conn.is_none() {
+                    debug!(peer = %receiver, msg_id = %msg_id, "Queued message until connected");
+                    state = store::DeliveryState::Queued;
+                }
+            }
+            _ => {}
+        }
+        if state == store::DeliveryState::Sent {
+            self.transmit(receiver, msg_id, data.clone(), nick)?;
+            stats::message_sent(msg.kind());
+        }
+
+        if is_stored(msg) {
+            self.record(store::StoredMessage {
+                sender: self.id,
+                receiver,
+                msg_id,
+                body: data,
+                state,
+                timestamp: now,
+                updated: now,
+            });
+        }
+        Ok(msg_id)
     }
 
-    fn send_message(&mut self, receiver: ThreemaID, mut data: Vec<u8>) -> Result<MessageID> {
-        let sender = self.id;
-        let nickname = self.get_nickname();
-        // workaround for https://github.com/rust-lang/rust/issues/21906
-        let priv_key = self.private_key.clone();
+    /// Encrypts the serialized message `data` and sends it to the server.
+    #[instrument(skip_all, fields(peer = %receiver, msg_id = %msg_id))]
+    fn transmit(
+        &mut self,
+        receiver: ThreemaID,
+        msg_id: MessageID,
+        data: Vec<u8>,
+        nick: Option<&Nickname>,
+    ) -> Result<()> {
         let public_key = self.get_peer_key(receiver)?;
-        let now = time::SystemTime::now();
-        let now = now.duration_since(time::UNIX_EPOCH).unwrap_or_default();
+        let pt = self.seal_message(receiver, &public_key, msg_id, data, nick);
+        debug!("Sending packet {:#?}", pt);
+        self.send(&pt)?;
+        self.messages_sent += 1;
+        self.session.unacked.insert((receiver, msg_id));
+        Ok(())
+    }
 
-        #[allow(clippy::cast_possible_truncation)]
-        let timestamp = now.as_secs() as u32;
+    /// Sends the messages in the outbox again, e.g. after reconnecting.
+    ///
+    /// Messages which can't be sent for other reasons than a lost connection, e.g. an
+    /// unknown receiver, are kept and logged.
+    pub fn flush_outbox(&mut self) -> Result<()> {
+        let pending = match &self.outbox {
+            Some(outbox) => outbox.pending()?,
+            None => return Ok(()),
+        };
+        stats::outbox_size(pending.len());
+        for entry in pending {
+            match self.transmit(entry.receiver, entry.msg_id, entry.body, None) {
+                Ok(()) => self.record_state(self.id, entry.msg_id, store::DeliveryState::Sent),
+                Err(e) if e.is_connection_error() => return Err(e),
+                Err(e) => warn!(
+                    peer = %entry.receiver,
+                    msg_id = %entry.msg_id,
+                    error = %e,
+                    "Couldn't send queued message"
+                ),
+            }
+        }
+        Ok(())
+    }
+
+    fn report_outbox_size(&self) {
+        if let Some(Ok(count)) = self.outbox.as_ref().map(|outbox| outbox.count()) {
+            stats::outbox_size(count);
+        }
+    }
+
+    /// The outbox, if one is used.
+    pub fn outbox(&mut self) -> Option<&mut (dyn outbox::Outbox + 'static)> {
+        self.outbox.as_deref_mut()
+    }
+
+    fn new_message_id(&mut self) -> MessageID {
+        let mut msg_id = MessageID::from_bytes([0; 8]);
+        self.rng.fill(&mut msg_id.0);
+        msg_id
+    }
+
+    /// The message history, if one is kept.
+    pub fn message_store(&mut self) -> Option<&mut (dyn store::MessageStore + 'static)> {
+        self.history.as_deref_mut()
+    }
+
+    /// Adds `msg` to the history, if one is kept.
+    fn record(&mut self, msg: store::StoredMessage) {
+        if let Some(history) = &mut self.history {
+            if let Err(e) = history.insert(msg) {
+                warn!(error = %e, "Couldn't store message");
+            }
+        }
+    }
+
+    /// Updates the state of a message in the history, if one is kept.
+    fn record_state(&mut self, sender: ThreemaID, msg_id: MessageID, state: store::DeliveryState) {
+        let now = self.clock.now();
+        if let Some(history) = &mut self.history {
+            if let Err(e) = history.set_state(sender, msg_id, state, now) {
+                warn!(msg_id = %msg_id, error = %e, "Couldn't update message state");
+            }
+        }
+    }
+
+    /// Notes an attempt to reconnect in the history, if one is kept.
+    pub(crate) fn record_reconnect(&mut self, ok: bool) {
+        let now = self.clock.now();
+        if let Some(history) = &mut self.history {
+            if let Err(e) = history.record_reconnect(now, ok) {
+                warn!(error = %e, "Couldn't store reconnect");
+            }
+        }
+    }
+
+    /// Pads and encrypts a serialized message for `receiver`.
+    fn seal_message(
+        &mut self,
+        receiver: ThreemaID,
+        public_key: &PublicKey,
+        msg_id: MessageID,
+        mut data: Vec<u8>,
+        nick: Option<&Nickname>,
+    ) -> Packet {
+        let sender = self.id;
+        let nickname = nick.unwrap_or(&self.nick).header_value(self.id);
+        let timestamp = Header::timestamp_of(self.clock.now());
         let mut header = Header {
             sender,
             receiver,
             nonce: Default::default(),
-            msg_id: MessageID::default(),
+            msg_id,
             nickname,
             timestamp,
             flags: 1,
         };
-        randombytes::randombytes_into(&mut header.nonce);
-        let msg_id = header.msg_id;
+        self.rng.fill(&mut header.nonce);
 
-        #[allow(clippy::cast_possible_truncation)]
-        let pad = randombytes::randombytes_uniform(32) as u8;
-        data.append(&mut vec![pad; pad as usize]);
+        let pad = self.padding.length(data.len(), &mut *self.rng);
+        data.resize(data.len() + usize::from(pad), pad);
 
-        let ciphertext = box_::seal(
+        let ciphertext = crypto::seal(
             &data,
-            &box_::Nonce::from_slice(&header.nonce).unwrap(),
+            &crypto::Nonce(header.nonce),
             public_key,
-            &priv_key,
+            &self.private_key,
         );
 
-        let pt = Packet::OutgoingMessage(header);
-        debug!("Sending packet {:#?}", pt);
+        Packet::OutgoingMessage(header, ciphertext)
+    }
 
-        let mut packet = pt.serialize();
-        packet.extend(ciphertext.into_iter());
-        self.send(&packet)?;
+    /// Sends `message` to `receiver`, returning the ID of the last message if it had to
+    /// be [split](LongTexts::Split).
+    pub fn send_text_message(&mut self, receiver: ThreemaID, message: String) -> Result<MessageID> {
+        self.send_text(receiver, message, None)
+    }
 
-        Ok(msg_id)
+    /// Sends a text message with `nick` instead of the client's nickname, e.g.
+    /// [`Nickname::Hidden`] to reveal neither nickname nor identity in the header.
+    pub fn send_text_message_as(
+        &mut self,
+        receiver: ThreemaID,
+        message: String,
+        nick: &Nickname,
+    ) -> Result<MessageID> {
+        self.send_text(receiver, message, Some(nick))
     }
 
-    pub fn send_text_message(&mut self, receiver: ThreemaID, message: String) -> Result<MessageID> {
-        let msg = Message::Text(Text { message });
-        debug!("Sending text {:#?}", msg);
-        let data = msg.serialize();
-        self.send_message(receiver, data)
+    fn send_text(
+        &mut self,
+        receiver: ThreemaID,
+        mut message: String,
+        nick: Option<&Nickname>,
+    ) -> Result<MessageID> {
+        let parts = if message.len() <= Text::MAX_LEN {
+            vec![message]
+        } else {
+            match self.long_texts {
+                LongTexts::Split => split_text(&message, Text::MAX_LEN)
+                    .into_iter()
+                    .map(str::to_owned)
+                    .collect(),
+                LongTexts::Truncate => {
+                    message.truncate(floor_char_boundary(&message, Text::MAX_LEN));
+                    vec![message]
+                }
+                LongTexts::Reject => {
+                    return Err(Error::TextTooLong {
+                        len: message.len(),
+                        max: Text::MAX_LEN,
+                    })
+                }
+            }
+        };
+        let mut msg_id = None;
+        for message in parts {
+            let msg = Message::Text(Text { message });
+            debug!("Sending text {:#?}", msg);
+            msg_id = Some(self.send_message(receiver, &msg, nick)?);
+        }
+        Ok(msg_id.expect("at least one part"))
     }
 
-    fn confirm_receipt(&mut self, receiver: ThreemaID, msg_id: MessageID) -> Result<MessageID> {
-        let rcpt = Message::DeliveryReceipt(MessageStatus::Delivered, msg_id);
+    fn confirm_receipt(
+        &mut self,
+        receiver: ThreemaID,
+        status: MessageStatus,
+        msg_id: MessageID,
+    ) -> Result<MessageID> {
+        let state = store::DeliveryState::from(&status);
+        let rcpt = Message::DeliveryReceipt(status, msg_id);
         debug!("Sending receipt {:#?}", rcpt);
-        let data = rcpt.serialize();
-        self.send_message(receiver, data)
+        let id = self.send_message(receiver, &rcpt, None)?;
+        self.record_state(receiver, msg_id, state);
+        Ok(id)
+    }
+
+    /// Tells the server that `msg` was processed, so it's removed from the queue.
+    ///
+    /// Only needed if [automatic acks](ThreemaBuilder::auto_ack) are disabled;
+    /// unacknowledged messages are delivered again after reconnecting.
+    pub fn ack(&mut self, msg: &ServerMessage) -> Result<()> {
+        self.send_ack(msg.sender, msg.msg_id)
+    }
+
+    /// Sends a receipt with `status` for `msg` to its sender.
+    pub fn confirm(&mut self, msg: &ServerMessage, status: MessageStatus) -> Result<MessageID> {
+        self.confirm_receipt(msg.sender, status, msg.msg_id)
     }
 
     fn send_ack(&mut self, receiver: ThreemaID, msg_id: MessageID) -> Result<()> {
         let ack = Packet::IncomingMessageAck(receiver, msg_id);
-        debug!("Sending ack {:#?}", ack);
-        let data = ack.serialize();
-        self.send(&data)
+        debug!(peer = %receiver, msg_id = %msg_id, "Sending ack");
+        self.send(&ack)?;
+        stats::ack_sent();
+        if let Some(i) = self
+            .unacked_nonces
+            .iter()
+            .position(|&(sender, id, _)| (sender, id) == (receiver, msg_id))
+        {
+            if let Some((_, _, nonce)) = self.unacked_nonces.remove(i) {
+                self.nonces.insert(nonce);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn receive_packet(&mut self) -> Result<Packet> {
+        let (packet, rest) = self.next_raw_packet()?;
+        if !rest.is_empty() {
+            warn!("Unprocessed packet data: {:#x?}", rest);
+        }
+        Ok(packet)
+    }
+
+    /// Waits for the next packet, returning it along with the data following it which
+    /// isn't part of the packet as modelled by this crate.
+    ///
+    /// Meant for experiments with the protocol: unlike [`Threema::receive`], incoming
+    /// messages are neither decrypted nor acknowledged.
+    pub fn next_raw_packet(&mut self) -> Result<(Packet, Vec<u8>)> {
+        let mut frame = std::mem::take(&mut self.recv_buf);
+        let res = self.receive_frame(&mut frame).and_then(|()| {
+            let (packet, size) = Packet::try_deserialize_with_size(&frame)?;
+            Ok((packet, frame[size..].to_vec()))
+        });
+        self.recv_buf = frame;
+        res
     }
 
-    pub fn receive_packet(&mut self) -> Result<(Packet, Vec<u8>)> {
+    /// Reads the next frame into `frame` and decrypts it there.
+    ///
+    /// Frames outside of [`MIN_FRAME_SIZE`] and the configured maximum close the
+    /// connection before their content is read.
+    fn receive_frame(&mut self, frame: &mut Vec<u8>) -> Result<()> {
         let mut l = [0u8; 2];
         let conn = self.conn.as_mut().ok_or(Error::NotConnected)?;
-        conn.read_exact(&mut l)?;
-        let l = u16::from_le_bytes(l);
-        let mut buf = vec![0u8; l as usize];
-        conn.read_exact(&mut buf).unwrap();
+        conn.read_exact(&mut l).map_err(closed_on_eof)?;
+        let size = usize::from(u16::from_le_bytes(l));
+        if size < MIN_FRAME_SIZE || size > self.max_frame_size {
+            warn!(size, "Dropping connection after invalid frame length");
+            self.disconnect();
+            return Err(if size < MIN_FRAME_SIZE {
+                Error::FrameTooSmall {
+                    size,
+                    min: MIN_FRAME_SIZE,
+                }
+            } else {
+                Error::FrameTooLarge {
+                    size,
+                    max: self.max_frame_size,
+                }
+            });
+        }
+        frame.clear();
+        frame.resize(size, 0);
+        let conn = self.conn.as_mut().ok_or(Error::NotConnected)?;
+        conn.read_exact(frame).map_err(closed_on_eof)?;
         let server_nonce = self.server_nonce.as_mut().ok_or(Error::NotConnected)?;
-        let mut msg = box_::open(
-            &buf,
-            &server_nonce.as_nonce().unwrap(),
+        let opened = crypto::open_in_place(
+            frame,
+            &server_nonce.as_nonce(),
             self.server_pubkey.as_ref().ok_or(Error::NotConnected)?,
             self.ephemeral_private_key
                 .as_ref()
                 .ok_or(Error::NotConnected)?,
-        )
-        .map_err(|_| Error::DecryptionFailed)?;
+        );
+        if !opened {
+            return Err(Error::PacketDecrypt);
+        }
         server_nonce.inc();
-        let (packet, size) = Packet::deserialize_with_size(&msg)
-            .ok_or_else(|| Error::ParseError(format!("packet: {:?}", msg)))?;
-        msg.drain(0..size);
-        Ok((packet, msg))
+        self.session.last_frame = Some(self.clock.now());
+        Ok(())
     }
 
+    /// Waits for the next message, handling acks and other packets in between.
+    ///
+    /// Messages which can't be processed are logged and skipped, see
+    /// [`Threema::next_event`] to handle them.
     pub fn receive(&mut self) -> Result<ServerMessage> {
         loop {
-            let (packet, payload) = self.receive_packet()?;
+            match self.next_event()? {
+                ClientEvent::Message(msg) => return Ok(msg),
+                ClientEvent::MessageError {
+                    sender,
+                    msg_id,
+                    cause,
+                } => warn!(peer = %sender, %msg_id, error = %cause, "Skipping message"),
+            }
+        }
+    }
+
+    /// Waits for the next message or message which couldn't be processed, handling acks
+    /// and other packets in between.
+    pub fn next_event(&mut self) -> Result<ClientEvent> {
+        if let Some(event) = self.events.pop_front() {
+            return Ok(event);
+        }
+        loop {
+            let packet = self.receive_packet()?;
+            if let Some(event) = self.handle_packet(packet)? {
+                return Ok(event);
+            }
+        }
+    }
+
+    /// Measures the round trip to the chat server: sends an echo request and waits up to
+    /// a [keepalive](ThreemaBuilder::keepalive) interval for the reply, failing with
+    /// [`Error::Timeout`] afterwards.
+    ///
+    /// Messages arriving in between are processed as usual and kept for
+    /// [`next_event`](Self::next_event) and [`run`](Self::run); alerts end the wait like
+    /// any other error. If the time runs out while a frame is being received, the
+    /// connection is dropped, as the rest of the frame would be mistaken for the next one.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn ping(&mut self) -> Result<time::Duration> {
+        let counter = self.echo_counter;
+        self.echo_counter += 1;
+        let start = time::Instant::now();
+        self.send(&Packet::EchoRequest(counter))?;
+        let answered = self.wait_for_echo(counter, start + self.keepalive);
+        // reads block again, as after connecting
+        if let Some(conn) = &mut self.conn {
+            conn.set_read_timeout(None)?;
+        }
+        answered?;
+        let rtt = start.elapsed();
+        debug!(echo = counter, ?rtt, "Echo answered by server");
+        stats::ping(rtt);
+        Ok(rtt)
+    }
+
+    /// Handles packets until the reply to the echo request `counter` arrives.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn wait_for_echo(&mut self, counter: u64, deadline: time::Instant) -> Result<()> {
+        loop {
+            let remaining = deadline.saturating_duration_since(time::Instant::now());
+            if remaining.is_zero() {
+                return Err(Error::Timeout);
+            }
+            let conn = self.conn.as_mut().ok_or(Error::NotConnected)?;
+            conn.set_read_timeout(Some(remaining))?;
+            if conn.peek(&mut [0])? == 0 {
+                return Err(Error::NotConnected);
+            }
+            let packet = match self.receive_packet() {
+                Ok(packet) => packet,
+                Err(Error::Timeout) => {
+                    // the rest of the frame can't be told apart from the next one anymore
+                    warn!("Timed out within a frame, dropping connection");
+                    self.disconnect();
+                    return Err(Error::Timeout);
+                }
+                Err(e) => return Err(e),
+            };
             match packet {
-                Packet::IncomingMessage(hdr) => {
-                    let sender = hdr.sender;
-                    self.send_ack(sender, hdr.msg_id)?;
-                    // workaround for https://github.com/rust-lang/rust/issues/21906
-                    let priv_key = self.private_key.clone();
-                    let pub_key = self.get_peer_key(sender)?;
-                    let data = box_::open(
-                        &payload,
-                        &box_::Nonce::from_slice(&hdr.nonce).unwrap(),
-                        pub_key,
-                        &priv_key,
-                    )
-                    .map_err(|_| Error::DecryptionFailed)?;
-                    let pad = *data.last().unwrap() as usize;
-                    let data = &data[..data.len() - pad];
-                    let (msg, s) = Message::deserialize_with_size(data)
-                        .ok_or_else(|| Error::ParseError(format!("message: {:?}", data)))?;
-                    if s < data.len() {
-                        warn!("Unprocessed data: {:#x?}", &data[s..]);
+                Packet::EchoReply(n) if n == counter => {
+                    // the connection is alive, as after any packet in `run`
+                    self.echo_pending = None;
+                    self.idle = time::Duration::ZERO;
+                    return Ok(());
+                }
+                packet => {
+                    if let Some(event) = self.handle_packet(packet)? {
+                        self.events.push_back(event);
                     }
+                }
+            }
+        }
+    }
 
-                    match msg {
-                        Message::TypingNotification | Message::DeliveryReceipt(_, _) => {}
-                        _ => {
-                            self.confirm_receipt(sender, hdr.msg_id)?;
-                        }
-                    }
+    /// Handle for messaging with `peer`.
+    pub fn conversation(&mut self, peer: ThreemaID) -> conversation::Conversation<'_> {
+        conversation::Conversation { client: self, peer }
+    }
 
-                    return Ok(ServerMessage {
-                        msg_id: hdr.msg_id,
-                        sender,
-                        data: msg,
-                    });
+    /// Iterates over the incoming messages, see [`Messages`].
+    pub fn messages(&mut self) -> Messages<'_> {
+        Messages { client: self }
+    }
+
+    /// Decrypts the `payload` of a message from `sender`, returning the new key of the
+    /// sender if it had to be [refreshed](ThreemaBuilder::refresh_keys).
+    fn open_message(
+        &mut self,
+        sender: ThreemaID,
+        nonce: &[u8; 24],
+        payload: &[u8],
+    ) -> Result<(Vec<u8>, Option<PublicKey>)> {
+        let pub_key = self.get_peer_key(sender)?;
+        let nonce = crypto::Nonce(*nonce);
+        if let Some(data) = crypto::open(payload, &nonce, &pub_key, &self.private_key) {
+            return Ok((data, None));
+        }
+        let decrypt_error = Error::MessageDecrypt {
+            sender: Some(sender),
+        };
+        if !self.refresh_keys {
+            return Err(decrypt_error);
+        }
+        let new_key = self
+            .refresh_peer_key(sender, &pub_key)
+            .ok_or(decrypt_error)?;
+        let data = crypto::open(payload, &nonce, &new_key, &self.private_key).ok_or(
+            Error::MessageDecrypt {
+                sender: Some(sender),
+            },
+        )?;
+        warn!(public_key = ?new_key, "Public key changed, keeping the pinned one");
+        Ok((data, Some(new_key)))
+    }
+
+    /// Decrypts and confirms an incoming message, unless it is dropped.
+    #[instrument(skip_all, fields(peer = %hdr.sender, msg_id = %hdr.msg_id))]
+    fn handle_incoming(&mut self, hdr: Header, payload: &[u8]) -> Result<Option<ServerMessage>> {
+        let sender = hdr.sender;
+        let replayed = self.nonces.contains(&hdr.nonce);
+        // a redelivery of a known message is up to the duplicate policy
+        let redelivered = replayed
+            && self.duplicates == dedup::DuplicatePolicy::Flag
+            && self.recent.contains(sender, hdr.msg_id);
+        if self.auto_replies.ack || replayed {
+            self.send_ack(sender, hdr.msg_id)?;
+        }
+        if replayed && !redelivered {
+            warn!("Dropping replayed message");
+            return Ok(None);
+        }
+        let verdict = if self.is_blocked(sender) {
+            self.block_mode.into()
+        } else {
+            self.filter
+                .as_mut()
+                .map_or(filter::Verdict::Accept, |f| f(&hdr))
+        };
+        if verdict != filter::Verdict::Accept {
+            debug!(?verdict, "Dropping message");
+            if !self.auto_replies.ack {
+                self.send_ack(sender, hdr.msg_id)?;
+            }
+            if verdict == filter::Verdict::DropWithReceipt {
+                self.confirm_receipt(sender, MessageStatus::Delivered, hdr.msg_id)?;
+            }
+            return Ok(None);
+        }
+        let (data, key_changed) = self.open_message(sender, &hdr.nonce, payload)?;
+        if self.auto_replies.ack {
+            self.nonces.insert(hdr.nonce);
+        } else if !replayed {
+            // until acked, the server delivers the message again after a reconnect, which
+            // mustn't be mistaken for a replay
+            self.unacked_nonces
+                .push_back((sender, hdr.msg_id, hdr.nonce));
+            if self.unacked_nonces.len() > UNACKED_NONCES {
+                self.unacked_nonces.pop_front();
+            }
+        }
+        let data = packets::unpad(&data)?;
+        let sent = hdr.time();
+        if let Some(mut contact) = self.contacts.get(sender) {
+            if !hdr.nickname.is_empty()
+                && contact.nickname.as_deref() != Some(hdr.nickname.as_str())
+            {
+                contact.nickname = Some(hdr.nickname);
+                self.contacts.put(contact);
+            }
+        }
+        let (msg, s) = Message::parse(data)?;
+        if s < data.len() {
+            warn!("Unprocessed data: {:#x?}", &data[s..]);
+        }
+
+        let duplicate = self.recent.check(sender, hdr.msg_id);
+        if duplicate {
+            debug!("Message was received before");
+            if self.duplicates == dedup::DuplicatePolicy::Suppress {
+                return Ok(None);
+            }
+        }
+
+        match &msg {
+            _ if duplicate => {}
+            Message::TypingNotification => {}
+            Message::DeliveryReceipt(status, mid) => {
+                self.record_state(self.id, *mid, status.into());
+            }
+            _ => {
+                self.record(store::StoredMessage {
+                    sender,
+                    receiver: hdr.receiver,
+                    msg_id: hdr.msg_id,
+                    body: data[..s].to_vec(),
+                    state: store::DeliveryState::Received,
+                    timestamp: sent,
+                    updated: self.clock.now(),
+                });
+                if self.auto_replies.delivery_receipt {
+                    self.confirm_receipt(sender, MessageStatus::Delivered, hdr.msg_id)?;
+                }
+                if self.auto_replies.read_receipt {
+                    self.confirm_receipt(sender, MessageStatus::Read, hdr.msg_id)?;
                 }
-                Packet::QueueSendComplete => debug!("server completed sending its queue"),
-                Packet::OutgoingMessageAck(_, mid) => debug!("Packet {} acked by server", mid),
-                _ => {
-                    warn!("Unhandled packet: {:#?} {:#?}", packet, payload);
+            }
+        }
+
+        stats::message_received(msg.kind());
+        Ok(Some(ServerMessage {
+            msg_id: hdr.msg_id,
+            sender,
+            data: msg,
+            duplicate,
+            key_changed,
+            sent,
+            received: self.clock.now(),
+        }))
+    }
+
+    /// Processes a packet from the server, returning the message it contained, if any.
+    ///
+    /// Only fails with errors concerning the whole connection, not a single message.
+    fn handle_packet(&mut self, packet: Packet) -> Result<Option<ClientEvent>> {
+        match packet {
+            Packet::IncomingMessage(hdr, payload) => {
+                self.messages_received += 1;
+                let (sender, msg_id) = (hdr.sender, hdr.msg_id);
+                return match self.handle_incoming(hdr, &payload) {
+                    Ok(msg) => Ok(msg.map(ClientEvent::Message)),
+                    Err(e) if e.is_connection_error() => Err(e),
+                    Err(cause) => Ok(Some(ClientEvent::MessageError {
+                        sender,
+                        msg_id,
+                        cause,
+                    })),
+                };
+            }
+            Packet::QueueSendComplete => debug!("server completed sending its queue"),
+            Packet::OutgoingMessageAck(receiver, mid) => {
+                debug!(peer = %receiver, msg_id = %mid, "Message acked by server");
+                stats::ack_received();
+                self.session.unacked.remove(&(receiver, mid));
+                if let Some(outbox) = &mut self.outbox {
+                    if let Err(e) = outbox.remove(receiver, mid) {
+                        warn!(msg_id = %mid, error = %e, "Couldn't remove message from the outbox");
+                    }
+                    self.report_outbox_size();
                 }
+                self.record_state(self.id, mid, store::DeliveryState::Acked);
+            }
+            Packet::EchoReply(n) => debug!(echo = n, "Echo answered by server"),
+            Packet::Alert(alert) => return Err(Error::ServerAlert(alert)),
+            Packet::Error(error) => {
+                self.disconnect();
+                return Err(Error::ServerError(error));
+            }
+            _ => {
+                warn!("Unhandled packet: {:#?}", packet);
             }
         }
+        Ok(None)
     }
 }
 
+/// Iterator over incoming messages, created by [`Threema::messages`].
+///
+/// Yields the result of each [`Threema::receive`]. After an error which closed the
+/// connection (see [`Error::is_connection_error`]), the client is disconnected and the
+/// iteration ends.
+pub struct Messages<'a> {
+    client: &'a mut Threema,
+}
+
+impl Iterator for Messages<'_> {
+    type Item = Result<ServerMessage>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.client.conn.as_ref()?;
+        let res = self.client.receive();
+        if matches!(&res, Err(e) if e.is_connection_error()) {
+            self.client.disconnect();
+        }
+        Some(res)
+    }
+}
+
+/// Whether `msg` is kept in the history, unlike receipts and typing notifications.
+fn is_stored(msg: &Message) -> bool {
+    !matches!(
+        msg,
+        Message::TypingNotification | Message::DeliveryReceipt(..)
+    )
+}
+
+/// Result of processing an incoming message, see [`Threema::next_event`].
+// short-lived, so boxing the message isn't worth it
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug)]
+pub enum ClientEvent {
+    Message(ServerMessage),
+    /// The message was dropped because it couldn't be processed, e.g. decrypted or
+    /// parsed. Like any other message, it was acknowledged to the server if
+    /// [automatic acks](ThreemaBuilder::auto_ack) are enabled.
+    MessageError {
+        sender: ThreemaID,
+        msg_id: MessageID,
+        cause: Error,
+    },
+}
+
+#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct ServerMessage {
     pub msg_id: MessageID,
     pub sender: ThreemaID,
     pub data: Message,
+    /// The message was received before, see [`ThreemaBuilder::duplicates`]
+    pub duplicate: bool,
+    /// New public key of the sender, if the message could only be decrypted after
+    /// fetching it again, see [`ThreemaBuilder::refresh_keys`]. The pinned key stays
+    /// until [`Threema::accept_key_change`] is called.
+    pub key_changed: Option<PublicKey>,
+    /// When the message was sent, according to the clock of the sender
+    pub sent: time::SystemTime,
+    /// When the message was received, according to the [clock](ThreemaBuilder::clock)
+    pub received: time::SystemTime,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use directory::MemoryDirectory;
+    use sources::{FixedClock, SeededRng};
+    use std::net::TcpListener;
+
+    fn client(seed: u8) -> Threema {
+        let mut client =
+            Threema::new(ThreemaID::from_string("ECHOECHO").unwrap(), &[1; 32]).unwrap();
+        client.set_rng(Box::new(SeededRng::new([seed; 32])));
+        client.set_clock(Box::new(FixedClock(
+            time::UNIX_EPOCH + time::Duration::from_secs(1_600_000_000),
+        )));
+        client
+    }
+
+    #[test]
+    fn ids() {
+        let id: ThreemaID = "echoecho".parse().unwrap();
+        assert_eq!(id, ThreemaID::try_from("ECHOECHO").unwrap());
+        assert_eq!(id, ThreemaID::try_from(*b"ECHOECHO").unwrap());
+        assert_eq!(id.as_str(), "ECHOECHO");
+        assert_eq!(
+            "*testgw0".parse::<ThreemaID>().unwrap().as_str(),
+            "*TESTGW0"
+        );
+        assert!(matches!(
+            ThreemaID::try_from("ECHO-ECH"),
+            Err(Error::InvalidID)
+        ));
+        assert!(ThreemaID::try_from(*b"echo\0\0\0\0").is_err());
+
+        let msg_id: MessageID = "0001020304050607".parse().unwrap();
+        assert_eq!(msg_id, MessageID::from([0, 1, 2, 3, 4, 5, 6, 7]));
+        assert_eq!(msg_id.as_bytes(), &[0, 1, 2, 3, 4, 5, 6, 7]);
+        assert!("00010203".parse::<MessageID>().is_err());
+
+        let group = GroupID::from_bytes([0xa1; 8]);
+        assert_eq!(group.to_string(), "a1a1a1a1a1a1a1a1");
+        assert_eq!("A1A1A1A1A1A1A1A1".parse::<GroupID>().unwrap(), group);
+        assert_eq!(GroupID::from_hex(&group.to_string()), Some(group));
+        assert!(matches!(
+            "xyz".parse::<GroupID>(),
+            Err(Error::ParseError(_))
+        ));
+    }
+
+    #[test]
+    fn deterministic_messages() {
+        let receiver = ThreemaID::from_string("*TESTGW0").unwrap();
+        let (public_key, _) = crypto::keypair_from_seed(&crypto::Seed([2; 32]));
+        let data = Message::Text(Text {
+            message: "hi".to_owned(),
+        })
+        .serialize();
+
+        let seal = |seed, data| {
+            let mut client = client(seed);
+            let msg_id = client.new_message_id();
+            (
+                client.seal_message(receiver, &public_key, msg_id, data, None),
+                msg_id,
+            )
+        };
+        let (a, a_id) = seal(3, data.clone());
+        let (b, b_id) = seal(3, data.clone());
+        assert_eq!(a.serialize(), b.serialize());
+        assert_eq!(a_id, b_id);
+        assert!(matches!(
+            a,
+            Packet::OutgoingMessage(ref header, _) if header.timestamp == 1_600_000_000
+        ));
+
+        let (c, _) = seal(4, data);
+        assert_ne!(b.serialize(), c.serialize());
+    }
+
+    #[test]
+    fn nicknames() {
+        let receiver = ThreemaID::from_string("*TESTGW0").unwrap();
+        let (public_key, _) = crypto::keypair_from_seed(&crypto::Seed([2; 32]));
+        let mut client = client(1);
+        let nickname = |client: &mut Threema, nick: Option<&Nickname>| {
+            let msg_id = client.new_message_id();
+            match client.seal_message(receiver, &public_key, msg_id, vec![1], nick) {
+                Packet::OutgoingMessage(header, _) => {
+                    let raw = header.serialize();
+                    assert_eq!(Header::deserialize(&raw), Some(header));
+                    raw[32..64].to_vec()
+                }
+                p => panic!("unexpected packet {:?}", p),
+            }
+        };
+
+        let id = nickname(&mut client, None);
+        assert_eq!(&id[..8], b"ECHOECHO");
+        assert_eq!(id[8..], [0; 24]);
+        assert_eq!(nickname(&mut client, Some(&Nickname::Hidden)), [0; 32]);
+
+        client.set_nick(Nickname::Hidden);
+        assert_eq!(nickname(&mut client, None), [0; 32]);
+        let custom = Nickname::Name("Echo".to_owned());
+        assert_eq!(&nickname(&mut client, Some(&custom))[..5], b"Echo\0");
+
+        // 31 bytes of ASCII and a 2 byte char, which doesn't fit anymore
+        client.set_nick(Nickname::Name(format!("{}ä", "x".repeat(31))));
+        assert_eq!(client.nick().map(str::len), Some(33));
+        let cut = nickname(&mut client, None);
+        assert_eq!(cut[..31], *"x".repeat(31).as_bytes());
+        assert_eq!(cut[31], 0);
+    }
+
+    #[test]
+    fn offline_directory() {
+        let peer = ThreemaID::from_string("ECHOECHO").unwrap();
+        let unknown = ThreemaID::from_string("UNKNOWN0").unwrap();
+        let (public_key, _) = crypto::keypair_from_seed(&crypto::Seed([2; 32]));
+        let mut directory = MemoryDirectory::new();
+        directory.insert(peer, public_key, identity::FeatureMask::FILES);
+        directory.link_phone("+41791234567", peer);
+
+        let mut client = client(1);
+        client.set_directory(Box::new(directory));
+        assert_eq!(client.get_peer_key(peer).unwrap(), public_key);
+        assert!(client
+            .peer_capabilities(peer)
+            .unwrap()
+            .contains(identity::FeatureMask::FILES));
+        assert_eq!(
+            client.lookup_by_phone("+41 79 123 45 67").unwrap(),
+            Some(peer)
+        );
+        assert_eq!(client.lookup_by_email("echo@example.com").unwrap(), None);
+
+        assert!(!client.peer_status(unknown).unwrap().is_active());
+        match client.get_peer_key(unknown) {
+            Err(Error::PeerKeyLookup { peer, source }) => {
+                assert_eq!(peer, unknown);
+                assert!(matches!(
+                    *source,
+                    Error::Rest(rest::RestError {
+                        kind: rest::RestErrorKind::UnknownIdentity,
+                        ..
+                    })
+                ));
+            }
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+
+    /// Server end of a connection whose handshake was skipped.
+    struct FakeServer {
+        conn: TcpStream,
+        nonce: Nonce,
+        client_nonce: Nonce,
+        client_key: PublicKey,
+        private_key: PrivateKey,
+    }
+
+    impl FakeServer {
+        /// Sends `packet` encrypted like the chat server does.
+        fn send(&mut self, packet: &[u8]) {
+            let frame = self.frame(packet);
+            self.raw(&frame);
+        }
+
+        /// Encrypts `packet` and prepends the length.
+        fn frame(&mut self, packet: &[u8]) -> Vec<u8> {
+            let enc = crypto::seal(
+                packet,
+                &self.nonce.as_nonce(),
+                &self.client_key,
+                &self.private_key,
+            );
+            self.nonce.inc();
+            [&u16::try_from(enc.len()).unwrap().to_le_bytes()[..], &enc].concat()
+        }
+
+        fn raw(&mut self, data: &[u8]) {
+            self.conn.write_all(data).unwrap();
+        }
+
+        /// Reads and decrypts the next packet sent by the client.
+        fn receive(&mut self) -> Packet {
+            Packet::deserialize(&self.receive_raw()).unwrap()
+        }
+
+        /// Reads and decrypts the next frame sent by the client.
+        fn receive_raw(&mut self) -> Vec<u8> {
+            let mut len = [0; 2];
+            self.conn.read_exact(&mut len).unwrap();
+            let mut enc = vec![0; u16::from_le_bytes(len).into()];
+            self.conn.read_exact(&mut enc).unwrap();
+            let data = crypto::open(
+                &enc,
+                &self.client_nonce.as_nonce(),
+                &self.client_key,
+                &self.private_key,
+            )
+            .unwrap();
+            self.client_nonce.inc();
+            data
+        }
+    }
+
+    fn connected(client: &mut Threema) -> FakeServer {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let conn = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        conn.set_read_timeout(Some(time::Duration::from_secs(5)))
+            .unwrap();
+        let (server_pub, server_priv) = crypto::keypair_from_seed(&crypto::Seed([4; 32]));
+        let (eph_pub, eph_priv) = crypto::keypair_from_seed(&crypto::Seed([5; 32]));
+        client.conn = Some(Box::new(conn));
+        client.client_nonce = Some(Nonce::new([1; 16]));
+        client.server_nonce = Some(Nonce::new([2; 16]));
+        client.server_pubkey = Some(server_pub);
+        client.ephemeral_private_key = Some(eph_priv);
+        client.session.connected_since = Some(client.clock.now());
+        FakeServer {
+            conn: listener.accept().unwrap().0,
+            nonce: Nonce::new([2; 16]),
+            client_nonce: Nonce::new([1; 16]),
+            client_key: eph_pub,
+            private_key: server_priv,
+        }
+    }
+
+    /// Runs the handshake against a chat server implemented with sodiumoxide, the
+    /// reference the crypto has to stay compatible with.
+    #[test]
+    fn handshake() {
+        use sodiumoxide::crypto::box_ as sodium;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut conn = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        conn.set_read_timeout(Some(time::Duration::from_secs(5)))
+            .unwrap();
+        let (server_pub, server_priv) = sodium::keypair_from_seed(&sodium::Seed([4; 32]));
+        let (tmp_pub, tmp_priv) = sodium::keypair_from_seed(&sodium::Seed([5; 32]));
+        let client_pub = sodium::SecretKey([1; 32]).public_key();
+        let nonce = |prefix: &[u8], counter: u64| {
+            sodium::Nonce::from_slice(&[prefix, &counter.to_le_bytes()].concat()).unwrap()
+        };
+
+        let server = std::thread::spawn(move || {
+            let mut conn = listener.accept().unwrap().0;
+            let mut hello = [0; 48];
+            conn.read_exact(&mut hello).unwrap();
+            let eph_pub = sodium::PublicKey::from_slice(&hello[..32]).unwrap();
+            let client_prefix = &hello[32..];
+            let server_prefix = [2; 16];
+            let server_hello = [tmp_pub.as_ref(), client_prefix].concat();
+            conn.write_all(&server_prefix).unwrap();
+            conn.write_all(&sodium::seal(
+                &server_hello,
+                &nonce(&server_prefix, 1),
+                &eph_pub,
+                &server_priv,
+            ))
+            .unwrap();
+
+            let mut auth = [0; 144];
+            conn.read_exact(&mut auth).unwrap();
+            let auth = sodium::open(&auth, &nonce(client_prefix, 1), &eph_pub, &tmp_priv).unwrap();
+            assert_eq!(&auth[..8], b"ECHOECHO");
+            assert_eq!(auth[40..56], server_prefix);
+            let vouch_nonce = sodium::Nonce::from_slice(&auth[56..80]).unwrap();
+            let vouch = sodium::open(&auth[80..], &vouch_nonce, &client_pub, &server_priv).unwrap();
+            assert_eq!(vouch, eph_pub.as_ref());
+            let ack = sodium::seal(&[0; 16], &nonce(&server_prefix, 2), &eph_pub, &tmp_priv);
+            conn.write_all(&ack).unwrap();
+
+            let frame = sodium::seal(
+                &Packet::EchoReply(7).serialize(),
+                &nonce(&server_prefix, 3),
+                &eph_pub,
+                &tmp_priv,
+            );
+            conn.write_all(&u16::try_from(frame.len()).unwrap().to_le_bytes())
+                .unwrap();
+            conn.write_all(&frame).unwrap();
+            let mut len = [0; 2];
+            conn.read_exact(&mut len).unwrap();
+            let mut frame = vec![0; u16::from_le_bytes(len).into()];
+            conn.read_exact(&mut frame).unwrap();
+            sodium::open(&frame, &nonce(client_prefix, 2), &eph_pub, &tmp_priv).unwrap()
+        });
+
+        let mut client = client(1);
+        let servers = servers::ServerInfo {
+            chat_public_key: PublicKey(server_pub.0),
+            ..servers::ServerInfo::default()
+        };
+        client.handshake(&mut conn, &servers).unwrap();
+        client.conn = Some(Box::new(conn));
+        assert_eq!(client.receive_packet().unwrap(), Packet::EchoReply(7));
+        client.send(&Packet::EchoRequest(8)).unwrap();
+        let request = server.join().unwrap();
+        assert_eq!(Packet::deserialize(&request), Some(Packet::EchoRequest(8)));
+    }
+
+    /// Transport delivering recorded server frames and discarding everything sent.
+    struct Replay(io::Cursor<Vec<u8>>);
+
+    impl Read for Replay {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl Write for Replay {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl transport::Transport for Replay {
+        fn set_read_timeout(&mut self, _timeout: Option<time::Duration>) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn peek(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let pos = self.0.position();
+            let len = self.0.read(buf)?;
+            self.0.set_position(pos);
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn custom_transport() {
+        let mut client = client(1);
+        let mut server = connected(&mut client);
+        let frames = [
+            server.frame(&Packet::EchoReply(1).serialize()),
+            server.frame(&Packet::QueueSendComplete.serialize()),
+        ]
+        .concat();
+        client.conn = Some(Box::new(Replay(io::Cursor::new(frames))));
+
+        assert_eq!(client.receive_packet().unwrap(), Packet::EchoReply(1));
+        client.send(&Packet::EchoRequest(2)).unwrap();
+        assert_eq!(client.receive_packet().unwrap(), Packet::QueueSendComplete);
+        assert!(matches!(
+            client.receive_packet(),
+            Err(Error::ConnectionClosed)
+        ));
+    }
+
+    #[test]
+    fn sealed_raw_message() {
+        let mut client = client(1);
+        let peer = ThreemaID::from_string("*TESTGW0").unwrap();
+        let (peer_pub, peer_priv) = crypto::keypair_from_seed(&crypto::Seed([6; 32]));
+        let msg = Message::Text(Text {
+            message: "hi".to_owned(),
+        });
+        let Packet::OutgoingMessage(header, ciphertext) =
+            client.seal_raw_message(peer, &peer_pub, &msg)
+        else {
+            panic!("expected an outgoing message");
+        };
+        assert_eq!((header.sender, header.receiver), (client.id, peer));
+        let plaintext = crypto::open(
+            &ciphertext,
+            &crypto::Nonce(header.nonce),
+            &client.private_key.public_key(),
+            &peer_priv,
+        )
+        .unwrap();
+        assert_eq!(packets::unpad(&plaintext).unwrap(), msg.serialize());
+        assert!(client.conn.is_none());
+    }
+
+    #[test]
+    fn raw_packets() {
+        let mut client = client(1);
+        let mut server = connected(&mut client);
+        client
+            .send_raw_packet(&Packet::EchoRequest(7), &[1, 2])
+            .unwrap();
+        assert_eq!(
+            server.receive_raw(),
+            [&Packet::EchoRequest(7).serialize()[..], &[1, 2]].concat()
+        );
+
+        let peer = ThreemaID::from_string("*TESTGW0").unwrap();
+        let header = Header {
+            sender: peer,
+            receiver: client.id,
+            msg_id: MessageID::from_bytes([1; 8]),
+            timestamp: 0,
+            flags: 0,
+            nickname: String::new(),
+            nonce: [1; 24],
+        };
+        let incoming = Packet::IncomingMessage(header, vec![3; 20]).serialize();
+        server.send(&incoming);
+        server.send(&[&Packet::EchoReply(7).serialize()[..], &[4]].concat());
+        let (packet, rest) = client.next_raw_packet().unwrap();
+        assert!(
+            matches!(packet, Packet::IncomingMessage(hdr, payload) if hdr.sender == peer && payload == [3; 20])
+        );
+        assert!(rest.is_empty());
+        assert_eq!(
+            client.next_raw_packet().unwrap(),
+            (Packet::EchoReply(7), vec![4])
+        );
+        // nothing was acknowledged
+        client.send(&Packet::EchoRequest(8)).unwrap();
+        assert_eq!(server.receive(), Packet::EchoRequest(8));
+    }
+
+    #[test]
+    fn frame_buffers() {
+        let mut client = client(1);
+        let mut server = connected(&mut client);
+        client.send(&Packet::EchoRequest(1)).unwrap();
+        let send_buf = client.send_buf.as_ptr();
+        client.send(&Packet::EchoRequest(2)).unwrap();
+        assert_eq!(client.send_buf.as_ptr(), send_buf);
+        assert_eq!(server.receive(), Packet::EchoRequest(1));
+        assert_eq!(server.receive(), Packet::EchoRequest(2));
+
+        server.send(&Packet::EchoReply(1).serialize());
+        server.send(&Packet::EchoReply(2).serialize());
+        assert_eq!(client.receive_packet().unwrap(), Packet::EchoReply(1));
+        let recv_buf = client.recv_buf.as_ptr();
+        assert_eq!(client.receive_packet().unwrap(), Packet::EchoReply(2));
+        assert_eq!(client.recv_buf.as_ptr(), recv_buf);
+    }
+
+    #[test]
+    fn malformed_frames() {
+        let mut client = client(1);
+        let mut server = connected(&mut client);
+
+        server.send(&[0x99, 0, 0, 0]);
+        assert!(matches!(
+            client.receive_packet(),
+            Err(Error::InvalidData(_))
+        ));
+        server.send(&[2, 0, 0, 0, 1, 2, 3]);
+        assert!(matches!(
+            client.receive_packet(),
+            Err(Error::InvalidData(_))
+        ));
+        server.send(&[0xe1, 0, 0, 0, b'h', b'i']);
+        assert!(
+            matches!(client.receive(), Err(Error::ServerAlert(alert)) if alert.message == "hi")
+        );
+
+        // not authentic
+        server.raw(&[20, 0]);
+        server.raw(&[0; 20]);
+        assert!(matches!(client.receive_packet(), Err(Error::PacketDecrypt)));
+
+        // announces more than it sends
+        server.raw(&[100, 0, 1, 2, 3]);
+        drop(server);
+        assert!(matches!(
+            client.receive_packet(),
+            Err(Error::ConnectionClosed)
+        ));
+        assert!(matches!(
+            client.receive_packet(),
+            Err(Error::ConnectionClosed)
+        ));
+    }
+
+    #[test]
+    fn frame_limits() {
+        let mut client = client(1);
+        let mut server = connected(&mut client);
+        // too short for the MAC and a packet type
+        server.raw(&[3, 0, 1, 2, 3]);
+        assert!(matches!(
+            client.receive_packet(),
+            Err(Error::FrameTooSmall {
+                size: 3,
+                min: MIN_FRAME_SIZE
+            })
+        ));
+        assert!(client.conn.is_none());
+        // only a MAC
+        let mut server = connected(&mut client);
+        server.send(&[]);
+        assert!(matches!(
+            client.receive_packet(),
+            Err(Error::FrameTooSmall { size: 16, .. })
+        ));
+
+        client.max_frame_size = 64;
+        let mut server = connected(&mut client);
+        server.send(&Packet::EchoReply(1).serialize());
+        assert_eq!(client.receive_packet().unwrap(), Packet::EchoReply(1));
+        server.send(&[&Packet::EchoReply(2).serialize()[..], &[0; 60]].concat());
+        assert!(matches!(
+            client.receive_packet(),
+            Err(Error::FrameTooLarge { size: 88, max: 64 })
+        ));
+        assert!(client.conn.is_none());
+        assert!(matches!(client.receive_packet(), Err(Error::NotConnected)));
+    }
+
+    #[test]
+    fn malformed_messages() {
+        let peer = ThreemaID::from_string("ECHOECHO").unwrap();
+        let (peer_pub, peer_priv) = crypto::keypair_from_seed(&crypto::Seed([6; 32]));
+        let mut directory = MemoryDirectory::new();
+        directory.insert(peer, peer_pub, 0);
+        let mut client = client(1);
+        client.set_directory(Box::new(directory));
+        let own_pub = client.private_key.public_key();
+        let mut server = connected(&mut client);
+
+        let own = client.id;
+        let incoming = |server: &mut FakeServer, n: u8, payload: Vec<u8>| {
+            let header = Header {
+                sender: peer,
+                receiver: own,
+                msg_id: MessageID::from_bytes([n; 8]),
+                timestamp: 0,
+                flags: 0,
+                nickname: String::new(),
+                nonce: [n; 24],
+            };
+            server.send(&Packet::IncomingMessage(header, payload).serialize());
+        };
+        let seal =
+            |n: u8, data: &[u8]| crypto::seal(data, &crypto::Nonce([n; 24]), &own_pub, &peer_priv);
+
+        incoming(&mut server, 1, seal(1, &[]));
+        incoming(&mut server, 2, seal(2, &[1, 2, 5]));
+        incoming(&mut server, 3, seal(3, &[0x80, 1, 1]));
+        incoming(&mut server, 4, vec![1, 2, 3]);
+        // replay of the first message
+        incoming(&mut server, 1, seal(1, &[]));
+        server.send(&[0xe1, 0, 0, 0, b'h', b'i']);
+
+        let failed = |client: &mut Threema, n: u8| match client.next_event().unwrap() {
+            ClientEvent::MessageError {
+                sender,
+                msg_id,
+                cause,
+            } => {
+                assert_eq!((sender, msg_id), (peer, MessageID::from_bytes([n; 8])));
+                cause
+            }
+            ClientEvent::Message(msg) => panic!("unexpected message {:?}", msg),
+        };
+        assert!(matches!(failed(&mut client, 1), Error::InvalidPadding));
+        assert!(matches!(failed(&mut client, 2), Error::InvalidPadding));
+        assert!(matches!(failed(&mut client, 3), Error::InvalidData(_)));
+        assert!(matches!(
+            failed(&mut client, 4),
+            Error::MessageDecrypt { sender: Some(s) } if s == peer
+        ));
+        assert!(matches!(client.receive(), Err(Error::ServerAlert(_))));
+        assert!(client.nonces.contains(&[1; 24]));
+        assert!(!client.nonces.contains(&[4; 24]));
+
+        // bad messages are skipped when receiving
+        incoming(&mut server, 5, vec![1, 2, 3]);
+        incoming(&mut server, 6, seal(6, &[0x90, 1]));
+        let msg = client.receive().unwrap();
+        assert_eq!(msg.msg_id, MessageID::from_bytes([6; 8]));
+    }
+
+    #[test]
+    fn preloaded_keys() {
+        let peer = ThreemaID::from_string("ECHOECHO").unwrap();
+        let (public_key, _) = crypto::keypair_from_seed(&crypto::Seed([2; 32]));
+        let mut client = client(1);
+        client.set_directory(Box::new(MemoryDirectory::new()));
+        assert_eq!(client.peer_key(peer), None);
+
+        client.add_peer_key(peer, public_key);
+        assert_eq!(client.peer_key(peer), Some(public_key));
+        let mut server = connected(&mut client);
+        let msg_id = client.send_text_message(peer, "hi".to_owned()).unwrap();
+        assert!(matches!(
+            server.receive(),
+            Packet::OutgoingMessage(header, _) if header.msg_id == msg_id && header.receiver == peer
+        ));
+
+        client.invalidate_peer_key(peer);
+        assert_eq!(client.peer_key(peer), None);
+    }
+
+    #[test]
+    fn contacts() {
+        let peer = ThreemaID::from_string("ECHOECHO").unwrap();
+        let (peer_pub, peer_priv) = crypto::keypair_from_seed(&crypto::Seed([6; 32]));
+        let mut directory = MemoryDirectory::new();
+        directory.insert(peer, peer_pub, 0);
+        directory.link_email("echo@example.com", peer);
+        let mut client = client(1);
+        client.set_directory(Box::new(directory));
+        client
+            .contacts()
+            .put(contacts::Contact::new(peer, peer_pub));
+        assert_eq!(client.peer_key(peer), Some(peer_pub));
+        client.lookup_by_email("echo@example.com").unwrap();
+        assert_eq!(
+            client.contacts().get(peer).unwrap().verification,
+            contacts::VerificationLevel::ServerVerified
+        );
+
+        let own_pub = client.private_key.public_key();
+        let mut server = connected(&mut client);
+        let incoming = |server: &mut FakeServer, id: u8| {
+            let header = Header {
+                sender: peer,
+                receiver: peer,
+                msg_id: MessageID::from_bytes([id; 8]),
+                timestamp: 0,
+                flags: 0,
+                nickname: "Echo".to_owned(),
+                nonce: [id; 24],
+            };
+            let payload = crypto::seal(
+                &[1, b'a', 1],
+                &crypto::Nonce([id; 24]),
+                &own_pub,
+                &peer_priv,
+            );
+            server.send(&Packet::IncomingMessage(header, payload).serialize());
+        };
+        incoming(&mut server, 1);
+        assert_eq!(
+            client.receive().unwrap().msg_id,
+            MessageID::from_bytes([1; 8])
+        );
+        let mut contact = client.contacts().get(peer).unwrap();
+        assert_eq!(contact.nickname.as_deref(), Some("Echo"));
+
+        // messages of blocked contacts are skipped
+        contact.blocked = true;
+        client.contacts().put(contact);
+        incoming(&mut server, 2);
+        server.send(&[0xe1, 0, 0, 0, b'h', b'i']);
+        assert!(matches!(client.receive(), Err(Error::ServerAlert(_))));
+    }
+
+    #[test]
+    fn key_pinning() {
+        let peer = ThreemaID::from_string("ECHOECHO").unwrap();
+        let (old_key, _) = crypto::keypair_from_seed(&crypto::Seed([2; 32]));
+        let (new_key, _) = crypto::keypair_from_seed(&crypto::Seed([3; 32]));
+        let mut directory = MemoryDirectory::new();
+        directory.insert(peer, old_key, 0);
+        directory.link_phone("+41791234567", peer);
+        let mut client = client(1);
+        client.set_directory(Box::new(directory));
+        assert_eq!(client.get_peer_key(peer).unwrap(), old_key);
+        assert_eq!(client.contacts().get(peer).unwrap().public_key, old_key);
+        // invalidating forces a lookup even for pinned keys
+        client.invalidate_peer_key(peer);
+        assert!(!client.peer_status.contains_key(&peer));
+        assert_eq!(client.get_peer_key(peer).unwrap(), old_key);
+        assert!(client.peer_status.contains_key(&peer));
+
+        let mut directory = MemoryDirectory::new();
+        directory.insert(peer, new_key, 0);
+        directory.link_phone("+41791234567", peer);
+        client.set_directory(Box::new(directory));
+        client.invalidate_peer_key(peer);
+        assert_eq!(client.get_peer_key(peer).unwrap(), old_key);
+        // the changed key didn't replace the pinned one
+        assert_eq!(client.peer_key(peer), Some(old_key));
+        assert_eq!(client.contacts().get(peer).unwrap().public_key, old_key);
+        client.invalidate_peer_key(peer);
+        let changed = |res| matches!(res, Err(Error::KeyChanged { new, .. }) if new == new_key);
+        assert!(changed(client.peer_status(peer).map(|_| ())));
+        assert!(changed(client.lookup_by_phone("+41791234567").map(|_| ())));
+
+        client.accept_key_change(peer, new_key);
+        assert_eq!(client.get_peer_key(peer).unwrap(), new_key);
+        assert_eq!(client.lookup_by_phone("+41791234567").unwrap(), Some(peer));
+        assert_eq!(
+            client.contacts().get(peer).unwrap().verification,
+            contacts::VerificationLevel::ServerVerified
+        );
+    }
+
+    #[test]
+    fn key_refresh() {
+        let peer = ThreemaID::from_string("*TESTGW0").unwrap();
+        let (old_key, _) = crypto::keypair_from_seed(&crypto::Seed([2; 32]));
+        let (new_key, new_priv) = crypto::keypair_from_seed(&crypto::Seed([3; 32]));
+        let mut directory = MemoryDirectory::new();
+        directory.insert(peer, new_key, 0);
+        let mut client = client(1);
+        client.set_directory(Box::new(directory));
+        let mut contact = contacts::Contact::new(peer, old_key);
+        contact.verification = contacts::VerificationLevel::FullyVerified;
+        client.contacts().put(contact);
+        let own = client.id;
+        let own_pub = client.private_key.public_key();
+        let mut server = connected(&mut client);
+        let incoming = |server: &mut FakeServer, n: u8| {
+            let header = Header {
+                sender: peer,
+                receiver: own,
+                msg_id: MessageID::from_bytes([n; 8]),
+                timestamp: 0,
+                flags: 0,
+                nickname: String::new(),
+                nonce: [n; 24],
+            };
+            let data = [Message::TypingNotification.serialize(), vec![1]].concat();
+            let payload = crypto::seal(&data, &crypto::Nonce([n; 24]), &own_pub, &new_priv);
+            server.send(&Packet::IncomingMessage(header, payload).serialize());
+        };
+
+        incoming(&mut server, 1);
+        assert!(matches!(
+            client.next_event(),
+            Ok(ClientEvent::MessageError { cause: Error::MessageDecrypt { sender: Some(s) }, .. }) if s == peer
+        ));
+        assert_eq!(client.peer_key(peer), Some(old_key));
+
+        client.refresh_keys = true;
+        incoming(&mut server, 2);
+        let msg = client.receive().unwrap();
+        assert_eq!(msg.key_changed, Some(new_key));
+        // the directory alone doesn't replace a pinned key
+        assert_eq!(client.peer_key(peer), Some(old_key));
+        let contact = client.contacts().get(peer).unwrap();
+        assert_eq!(contact.public_key, old_key);
+        assert_eq!(
+            contact.verification,
+            contacts::VerificationLevel::FullyVerified
+        );
+        incoming(&mut server, 3);
+        assert_eq!(client.receive().unwrap().key_changed, Some(new_key));
+
+        client.accept_key_change(peer, new_key);
+        assert_eq!(client.peer_key(peer), Some(new_key));
+        assert_eq!(
+            client.contacts().get(peer).unwrap().verification,
+            contacts::VerificationLevel::Unverified
+        );
+        incoming(&mut server, 4);
+        assert_eq!(client.receive().unwrap().key_changed, None);
+    }
+
+    #[test]
+    fn key_refresh_limit() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        struct Counting(MemoryDirectory, Arc<AtomicUsize>);
+
+        impl DirectoryClient for Counting {
+            fn fetch_identity(&self, id: ThreemaID) -> Result<Option<directory::DirectoryEntry>> {
+                self.1.fetch_add(1, Ordering::SeqCst);
+                self.0.fetch_identity(id)
+            }
+
+            fn match_identities(
+                &self,
+                phones: &[&str],
+                emails: &[&str],
+            ) -> Result<Vec<identity::IdentityMatch>> {
+                self.0.match_identities(phones, emails)
+            }
+        }
+
+        let peer = ThreemaID::from_string("*TESTGW0").unwrap();
+        let (old_key, _) = crypto::keypair_from_seed(&crypto::Seed([2; 32]));
+        let (new_key, _) = crypto::keypair_from_seed(&crypto::Seed([3; 32]));
+        let (_, junk_priv) = crypto::keypair_from_seed(&crypto::Seed([4; 32]));
+        let mut directory = MemoryDirectory::new();
+        directory.insert(peer, new_key, 0);
+        let lookups = Arc::new(AtomicUsize::new(0));
+        let mut client = client(1);
+        client.set_directory(Box::new(Counting(directory, lookups.clone())));
+        client.contacts().put(contacts::Contact::new(peer, old_key));
+        client.refresh_keys = true;
+        // confirms the pinned key, only refreshes are counted below
+        client.get_peer_key(peer).unwrap();
+        lookups.store(0, Ordering::SeqCst);
+        let own = client.id;
+        let own_pub = client.private_key.public_key();
+        let mut server = connected(&mut client);
+        let junk = |server: &mut FakeServer, n: u8| {
+            let header = Header {
+                sender: peer,
+                receiver: own,
+                msg_id: MessageID::from_bytes([n; 8]),
+                timestamp: 0,
+                flags: 0,
+                nickname: String::new(),
+                nonce: [n; 24],
+            };
+            let data = [Message::TypingNotification.serialize(), vec![1]].concat();
+            let payload = crypto::seal(&data, &crypto::Nonce([n; 24]), &own_pub, &junk_priv);
+            server.send(&Packet::IncomingMessage(header, payload).serialize());
+        };
+        let failed = |client: &mut Threema| {
+            matches!(
+                client.next_event(),
+                Ok(ClientEvent::MessageError {
+                    cause: Error::MessageDecrypt { .. },
+                    ..
+                })
+            )
+        };
+
+        for n in 1..=3 {
+            junk(&mut server, n);
+            assert!(failed(&mut client));
+        }
+        assert_eq!(lookups.load(Ordering::SeqCst), 1);
+
+        client.set_clock(Box::new(FixedClock(
+            time::UNIX_EPOCH + time::Duration::from_secs(1_600_000_000) + KEY_REFRESH_INTERVAL,
+        )));
+        junk(&mut server, 4);
+        assert!(failed(&mut client));
+        assert_eq!(lookups.load(Ordering::SeqCst), 2);
+
+        // accepting a key forgets the refresh, the key is confirmed and refreshed again
+        client.accept_key_change(peer, old_key);
+        junk(&mut server, 5);
+        assert!(failed(&mut client));
+        assert_eq!(lookups.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn history() {
+        use store::{DeliveryState, MemoryMessageStore};
+
+        let peer = ThreemaID::from_string("*TESTGW0").unwrap();
+        let (peer_pub, peer_priv) = crypto::keypair_from_seed(&crypto::Seed([6; 32]));
+        let mut client = client(1);
+        client.add_peer_key(peer, peer_pub);
+        client.history = Some(Box::new(MemoryMessageStore::new()));
+        let own = client.id;
+        let own_pub = client.private_key.public_key();
+        let mut server = connected(&mut client);
+        let state = |client: &mut Threema, sender, msg_id| {
+            client
+                .message_store()
+                .unwrap()
+                .get(sender, msg_id)
+                .unwrap()
+                .map(|m| m.state)
+        };
+
+        let sent = client.send_text_message(peer, "ping".to_owned()).unwrap();
+        assert_eq!(state(&mut client, own, sent), Some(DeliveryState::Sent));
+        let incoming = |server: &mut FakeServer, id: u8, msg: Message| {
+            let header = Header {
+                sender: peer,
+                receiver: own,
+                msg_id: MessageID::from_bytes([id; 8]),
+                timestamp: 1_600_000_001,
+                flags: 0,
+                nickname: String::new(),
+                nonce: [id; 24],
+            };
+            let data = [msg.serialize(), vec![1]].concat();
+            let payload = crypto::seal(&data, &crypto::Nonce([id; 24]), &own_pub, &peer_priv);
+            server.send(&Packet::IncomingMessage(header, payload).serialize());
+        };
+        server.send(&Packet::OutgoingMessageAck(peer, sent).serialize());
+        incoming(
+            &mut server,
+            1,
+            Message::DeliveryReceipt(MessageStatus::Read, sent),
+        );
+        incoming(
+            &mut server,
+            2,
+            Message::Text(Text {
+                message: "pong".to_owned(),
+            }),
+        );
+        client.receive().unwrap();
+        assert_eq!(state(&mut client, own, sent), Some(DeliveryState::Read));
+        let msg = client.receive().unwrap();
+        let epoch = |secs| time::UNIX_EPOCH + time::Duration::from_secs(secs);
+        assert_eq!(msg.sent, epoch(1_600_000_001));
+        assert_eq!(msg.received, epoch(1_600_000_000));
+        let received = msg.msg_id;
+        assert_eq!(
+            state(&mut client, peer, received),
+            Some(DeliveryState::Delivered)
+        );
+        let history = client
+            .message_store()
+            .unwrap()
+            .conversation(peer, 10)
+            .unwrap();
+        assert_eq!(history.len(), 2);
+        assert!(matches!(history[1].message().unwrap(), Message::Text(t) if t.message == "pong"));
+    }
+
+    #[test]
+    fn conversation() {
+        let peer = ThreemaID::from_string("*TESTGW0").unwrap();
+        let (peer_pub, peer_priv) = crypto::keypair_from_seed(&crypto::Seed([6; 32]));
+        let mut client = client(2);
+        client.add_peer_key(peer, peer_pub);
+        let own_pub = client.private_key.public_key();
+        let mut server = connected(&mut client);
+        let mut received = || match server.receive() {
+            Packet::OutgoingMessage(header, payload) => {
+                assert_eq!(header.receiver, peer);
+                let nonce = crypto::Nonce(header.nonce);
+                let data = crypto::open(&payload, &nonce, &own_pub, &peer_priv).unwrap();
+                Message::deserialize(packets::unpad(&data).unwrap()).unwrap()
+            }
+            p => panic!("unexpected packet {:?}", p),
+        };
+
+        let mut chat = client.conversation(peer);
+        assert_eq!(chat.peer(), peer);
+        assert!(chat.history(10).unwrap().is_empty());
+        chat.send_text("hi").unwrap();
+        chat.send_typing().unwrap();
+        let read = MessageID::from_bytes([3; 8]);
+        chat.mark_read(read).unwrap();
+        chat.send_receipt(MessageStatus::Approved, read).unwrap();
+        assert!(matches!(received(), Message::Text(t) if t.message == "hi"));
+        assert_eq!(received(), Message::TypingNotification);
+        assert_eq!(
+            received(),
+            Message::DeliveryReceipt(MessageStatus::Read, read)
+        );
+        assert_eq!(
+            received(),
+            Message::DeliveryReceipt(MessageStatus::Approved, read)
+        );
+
+        client.history = Some(Box::new(store::MemoryMessageStore::new()));
+        let mut chat = client.conversation(peer);
+        let sent = chat.send_text("stored").unwrap();
+        chat.send_typing().unwrap();
+        let history = chat.history(10).unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].msg_id, sent);
+    }
+
+    #[test]
+    fn text_splitting() {
+        assert_eq!(split_text("short", 10), ["short"]);
+        assert_eq!(split_text("one two three", 8), ["one two ", "three"]);
+        assert_eq!(split_text("abcdefghij", 4), ["abcd", "efgh", "ij"]);
+        // never splits inside a character, nor leaves an empty part
+        assert_eq!(split_text("ääää", 5), ["ää", "ää"]);
+        assert_eq!(split_text(" abcdef", 4), [" abc", "def"]);
+    }
+
+    #[test]
+    fn long_texts() {
+        let peer = ThreemaID::from_string("*TESTGW0").unwrap();
+        let (peer_pub, peer_priv) = crypto::keypair_from_seed(&crypto::Seed([6; 32]));
+        let mut client = client(2);
+        client.add_peer_key(peer, peer_pub);
+        let own_pub = client.private_key.public_key();
+        let mut server = connected(&mut client);
+        let mut received = || match server.receive() {
+            Packet::OutgoingMessage(header, payload) => {
+                let nonce = crypto::Nonce(header.nonce);
+                let data = crypto::open(&payload, &nonce, &own_pub, &peer_priv).unwrap();
+                match Message::deserialize(packets::unpad(&data).unwrap()) {
+                    Some(Message::Text(text)) => text.message,
+                    msg => panic!("unexpected message {:?}", msg),
+                }
+            }
+            p => panic!("unexpected packet {:?}", p),
+        };
+
+        let text = format!("{} {}", "a".repeat(Text::MAX_LEN - 5), "b".repeat(10));
+        client.send_text_message(peer, text.clone()).unwrap();
+        assert_eq!(received().len(), Text::MAX_LEN - 4);
+        assert_eq!(received(), "b".repeat(10));
+
+        client.long_texts = LongTexts::Reject;
+        assert!(matches!(
+            client.send_text_message(peer, text.clone()),
+            Err(Error::TextTooLong { len, max: Text::MAX_LEN }) if len == text.len()
+        ));
+        client.long_texts = LongTexts::Truncate;
+        client.send_text_message(peer, text.clone()).unwrap();
+        assert_eq!(received(), text[..Text::MAX_LEN]);
+    }
+
+    #[test]
+    fn padding() {
+        let peer = ThreemaID::from_string("*TESTGW0").unwrap();
+        let (peer_pub, peer_priv) = crypto::keypair_from_seed(&crypto::Seed([6; 32]));
+        let mut client = client(1);
+        let own_pub = client.private_key.public_key();
+        let padded = |client: &mut Threema, len: usize| {
+            let msg_id = client.new_message_id();
+            let Packet::OutgoingMessage(header, payload) =
+                client.seal_message(peer, &peer_pub, msg_id, vec![1; len], None)
+            else {
+                panic!("expected an outgoing message");
+            };
+            let data =
+                crypto::open(&payload, &crypto::Nonce(header.nonce), &own_pub, &peer_priv).unwrap();
+            assert_eq!(packets::unpad(&data).unwrap(), vec![1; len]);
+            data.len()
+        };
+
+        for _ in 0..100 {
+            assert!((11..=42).contains(&padded(&mut client, 10)));
+        }
+        client.padding = Padding::Random;
+        for _ in 0..100 {
+            assert!((11..=265).contains(&padded(&mut client, 10)));
+        }
+        client.padding = Padding::Bucket(std::num::NonZeroU16::new(64).unwrap());
+        assert_eq!(padded(&mut client, 1), 64);
+        assert_eq!(padded(&mut client, 63), 64);
+        assert_eq!(padded(&mut client, 64), 128);
+        assert_eq!(padded(&mut client, 100), 128);
+    }
+
+    #[test]
+    fn offline_outbox() {
+        let peer = ThreemaID::from_string("*TESTGW0").unwrap();
+        let (peer_pub, _) = crypto::keypair_from_seed(&crypto::Seed([6; 32]));
+        let mut client = client(1);
+        client.add_peer_key(peer, peer_pub);
+        client.outbox = Some(Box::new(outbox::MemoryOutbox::new()));
+
+        let msg_id = client.send_text_message(peer, "later".to_owned()).unwrap();
+        assert_eq!(client.outbox().unwrap().pending().unwrap().len(), 1);
+
+        let mut server = connected(&mut client);
+        client.flush_outbox().unwrap();
+        assert!(matches!(
+            server.receive(),
+            Packet::OutgoingMessage(header, _) if header.msg_id == msg_id
+        ));
+        server.send(&Packet::OutgoingMessageAck(peer, msg_id).serialize());
+        let packet = client.receive_packet().unwrap();
+        assert!(client.handle_packet(packet).unwrap().is_none());
+        assert!(client.outbox().unwrap().pending().unwrap().is_empty());
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn metrics() {
+        use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        metrics::with_local_recorder(&recorder, || {
+            let peer = ThreemaID::from_string("*TESTGW0").unwrap();
+            let (peer_pub, _) = crypto::keypair_from_seed(&crypto::Seed([6; 32]));
+            let mut client = client(1);
+            client.add_peer_key(peer, peer_pub);
+            client.outbox = Some(Box::new(outbox::MemoryOutbox::new()));
+            let mut server = connected(&mut client);
+            let msg_id = client.send_text_message(peer, "hi".to_owned()).unwrap();
+            server.receive();
+            server.send(&Packet::OutgoingMessageAck(peer, msg_id).serialize());
+            let packet = client.receive_packet().unwrap();
+            client.handle_packet(packet).unwrap();
+        });
+
+        let metrics: HashMap<_, _> = snapshotter
+            .snapshot()
+            .into_vec()
+            .into_iter()
+            .map(|(key, _, _, value)| {
+                let key = key.key();
+                let labels: Vec<_> = key.labels().map(|l| l.value().to_owned()).collect();
+                ((key.name().to_owned(), labels), value)
+            })
+            .collect();
+        let get = |name: &str, labels: &[&str]| {
+            metrics.get(&(
+                name.to_owned(),
+                labels.iter().map(|&l| l.to_owned()).collect::<Vec<_>>(),
+            ))
+        };
+        assert_eq!(
+            get("threema_messages_sent_total", &["text"]),
+            Some(&DebugValue::Counter(1))
+        );
+        assert_eq!(
+            get("threema_acks_received_total", &[]),
+            Some(&DebugValue::Counter(1))
+        );
+        assert!(matches!(
+            get("threema_outbox_messages", &[]),
+            Some(DebugValue::Gauge(size)) if size.into_inner() == 0.0
+        ));
+    }
+
+    #[test]
+    fn blocking_and_filters() {
+        let peer = ThreemaID::from_string("ECHOECHO").unwrap();
+        let (peer_pub, peer_priv) = crypto::keypair_from_seed(&crypto::Seed([6; 32]));
+        let mut client = client(1);
+        client.add_peer_key(peer, peer_pub);
+        client.auto_replies.ack = false;
+        client.block_mode = filter::BlockMode::Hidden;
+        client.set_filter(Some(Box::new(|hdr: &Header| {
+            if hdr.nickname == "spam" {
+                filter::Verdict::Drop
+            } else {
+                filter::Verdict::Accept
+            }
+        })));
+        let own_pub = client.private_key.public_key();
+        let mut server = connected(&mut client);
+        let incoming = |server: &mut FakeServer, n: u8, nickname: &str| {
+            let header = Header {
+                sender: peer,
+                receiver: peer,
+                msg_id: MessageID::from_bytes([n; 8]),
+                timestamp: 0,
+                flags: 0,
+                nickname: nickname.to_owned(),
+                nonce: [n; 24],
+            };
+            let payload =
+                crypto::seal(&[1, b'a', 1], &crypto::Nonce([n; 24]), &own_pub, &peer_priv);
+            server.send(&Packet::IncomingMessage(header, payload).serialize());
+        };
+
+        client.block(peer);
+        assert!(client.is_blocked(peer));
+        incoming(&mut server, 1, "");
+        server.send(&[0xe1, 0, 0, 0, b'h', b'i']);
+        assert!(matches!(client.receive(), Err(Error::ServerAlert(_))));
+        client.unblock(peer);
+        incoming(&mut server, 2, "spam");
+        incoming(&mut server, 3, "");
+        assert_eq!(
+            client.receive().unwrap().msg_id,
+            MessageID::from_bytes([3; 8])
+        );
+
+        // the blocked message was acked and confirmed, the filtered one only acked
+        let ack = |n| Packet::IncomingMessageAck(peer, MessageID::from_bytes([n; 8]));
+        assert_eq!(server.receive(), ack(1));
+        assert!(matches!(server.receive(), Packet::OutgoingMessage(..)));
+        assert_eq!(server.receive(), ack(2));
+        assert!(matches!(server.receive(), Packet::OutgoingMessage(..)));
+    }
+
+    #[test]
+    fn duplicates() {
+        let peer = ThreemaID::from_string("ECHOECHO").unwrap();
+        let (peer_pub, peer_priv) = crypto::keypair_from_seed(&crypto::Seed([6; 32]));
+        let mut client = client(1);
+        client.add_peer_key(peer, peer_pub);
+        let own_pub = client.private_key.public_key();
+        let mut server = connected(&mut client);
+        // same message ID, but encrypted again
+        let incoming = |server: &mut FakeServer, id: u8, nonce: u8| {
+            let header = Header {
+                sender: peer,
+                receiver: peer,
+                msg_id: MessageID::from_bytes([id; 8]),
+                timestamp: 0,
+                flags: 0,
+                nickname: String::new(),
+                nonce: [nonce; 24],
+            };
+            let payload = crypto::seal(
+                &[1, b'a', 1],
+                &crypto::Nonce([nonce; 24]),
+                &own_pub,
+                &peer_priv,
+            );
+            server.send(&Packet::IncomingMessage(header, payload).serialize());
+        };
+
+        incoming(&mut server, 1, 1);
+        incoming(&mut server, 1, 2);
+        incoming(&mut server, 2, 3);
+        assert!(!client.receive().unwrap().duplicate);
+        assert_eq!(
+            client.receive().unwrap().msg_id,
+            MessageID::from_bytes([2; 8])
+        );
+
+        client.duplicates = dedup::DuplicatePolicy::Flag;
+        incoming(&mut server, 1, 4);
+        let msg = client.receive().unwrap();
+        assert!(msg.duplicate);
+        assert_eq!(msg.msg_id, MessageID::from_bytes([1; 8]));
+
+        // the identical frame delivered again is flagged as well
+        incoming(&mut server, 1, 4);
+        let msg = client.receive().unwrap();
+        assert!(msg.duplicate);
+        assert_eq!(msg.msg_id, MessageID::from_bytes([1; 8]));
+        // while a known nonce with an unknown ID is still a replay
+        incoming(&mut server, 3, 4);
+        server.send(&[0xe1, 0, 0, 0, b'h', b'i']);
+        assert!(matches!(client.receive(), Err(Error::ServerAlert(_))));
+    }
+
+    #[test]
+    fn manual_replies() {
+        let peer = ThreemaID::from_string("ECHOECHO").unwrap();
+        let (peer_pub, peer_priv) = crypto::keypair_from_seed(&crypto::Seed([6; 32]));
+        let mut directory = MemoryDirectory::new();
+        directory.insert(peer, peer_pub, 0);
+        let mut client = client(1);
+        client.set_directory(Box::new(directory));
+        client.auto_replies = AutoReplies {
+            ack: false,
+            delivery_receipt: false,
+            read_receipt: true,
+        };
+        let own_pub = client.private_key.public_key();
+        let mut server = connected(&mut client);
+
+        let header = Header {
+            sender: peer,
+            receiver: client.id,
+            msg_id: MessageID::from_bytes([1; 8]),
+            timestamp: 0,
+            flags: 0,
+            nickname: String::new(),
+            nonce: [9; 24],
+        };
+        let payload = crypto::seal(&[1, b'a', 1], &crypto::Nonce([9; 24]), &own_pub, &peer_priv);
+        server.send(&Packet::IncomingMessage(header, payload).serialize());
+        let msg = client.receive().unwrap();
+
+        // only the read receipt was sent
+        let Packet::OutgoingMessage(header, payload) = server.receive() else {
+            panic!("expected a receipt");
+        };
+        let data =
+            crypto::open(&payload, &crypto::Nonce(header.nonce), &own_pub, &peer_priv).unwrap();
+        assert_eq!(
+            Message::deserialize(packets::unpad(&data).unwrap()).unwrap(),
+            Message::DeliveryReceipt(MessageStatus::Read, msg.msg_id)
+        );
+
+        assert!(!client.nonces.contains(&[9; 24]));
+        client.ack(&msg).unwrap();
+        assert!(client.nonces.contains(&[9; 24]));
+        assert_eq!(
+            server.receive(),
+            Packet::IncomingMessageAck(peer, msg.msg_id)
+        );
+    }
+
+    #[test]
+    fn unacked_redelivery() {
+        let peer = ThreemaID::from_string("ECHOECHO").unwrap();
+        let (peer_pub, peer_priv) = crypto::keypair_from_seed(&crypto::Seed([6; 32]));
+        let mut client = client(1);
+        client.add_peer_key(peer, peer_pub);
+        client.auto_replies.ack = false;
+        client.auto_replies.delivery_receipt = false;
+        let own_pub = client.private_key.public_key();
+        let header = Header {
+            sender: peer,
+            receiver: client.id,
+            msg_id: MessageID::from_bytes([1; 8]),
+            timestamp: 0,
+            flags: 0,
+            nickname: String::new(),
+            nonce: [9; 24],
+        };
+        let payload = crypto::seal(&[1, b'a', 1], &crypto::Nonce([9; 24]), &own_pub, &peer_priv);
+        let frame = Packet::IncomingMessage(header, payload).serialize();
+
+        let mut server = connected(&mut client);
+        server.send(&frame);
+        client.receive().unwrap();
+        // the process dies before acking, only the nonces survive the restart
+        client.disconnect();
+        client.recent = dedup::RecentMessages::new(10);
+
+        let mut server = connected(&mut client);
+        server.send(&frame);
+        let msg = client.receive().unwrap();
+        assert_eq!(msg.msg_id, MessageID::from_bytes([1; 8]));
+        client.ack(&msg).unwrap();
+        assert_eq!(
+            server.receive(),
+            Packet::IncomingMessageAck(peer, msg.msg_id)
+        );
+
+        // acked messages delivered again are replays
+        server.send(&frame);
+        server.send(&[0xe1, 0, 0, 0, b'h', b'i']);
+        assert!(matches!(client.receive(), Err(Error::ServerAlert(_))));
+        assert_eq!(
+            server.receive(),
+            Packet::IncomingMessageAck(peer, msg.msg_id)
+        );
+    }
+
+    #[test]
+    fn message_iterator() {
+        let mut client = client(1);
+        assert!(client.messages().next().is_none());
+
+        let mut server = connected(&mut client);
+        server.send(&[0x99, 0, 0, 0]);
+        server.send(&[0xe1, 0, 0, 0, b'h', b'i']);
+        drop(server);
+        let results: Vec<_> = client.messages().collect();
+        assert!(matches!(results[0], Err(Error::InvalidData(_))));
+        assert!(matches!(&results[1], Err(Error::ServerAlert(alert)) if alert.message == "hi"));
+        assert!(matches!(&results[2], Err(Error::ConnectionClosed)));
+        assert_eq!(results.len(), 3);
+        assert!(client.conn.is_none());
+    }
+
+    #[test]
+    fn run_handler() {
+        #[derive(Default)]
+        struct Recorder {
+            texts: Vec<String>,
+            dropped: Vec<MessageID>,
+            disconnects: usize,
+        }
+
+        impl handler::Handler for Recorder {
+            fn on_text(&mut self, _: &mut Threema, _: ThreemaID, _: MessageID, text: &Text) {
+                self.texts.push(text.message.clone());
+            }
+
+            fn on_message_error(
+                &mut self,
+                _: &mut Threema,
+                _: ThreemaID,
+                id: MessageID,
+                _: &Error,
+            ) {
+                self.dropped.push(id);
+            }
+
+            fn on_disconnect(&mut self, _: &Error) -> bool {
+                self.disconnects += 1;
+                false
+            }
+        }
+
+        let peer = ThreemaID::from_string("ECHOECHO").unwrap();
+        let (peer_pub, peer_priv) = crypto::keypair_from_seed(&crypto::Seed([6; 32]));
+        let mut directory = MemoryDirectory::new();
+        directory.insert(peer, peer_pub, 0);
+        let mut client = client(1);
+        client.set_directory(Box::new(directory));
+        client.keepalive = time::Duration::from_millis(50);
+        let own_pub = client.private_key.public_key();
+        let mut server = connected(&mut client);
+
+        let server = std::thread::spawn(move || {
+            let bad = Header {
+                sender: peer,
+                receiver: ThreemaID::from_string("ECHOECHO").unwrap(),
+                msg_id: MessageID::from_bytes([2; 8]),
+                timestamp: 0,
+                flags: 0,
+                nickname: String::new(),
+                nonce: [8; 24],
+            };
+            server.send(&Packet::IncomingMessage(bad, vec![0; 32]).serialize());
+            assert!(matches!(server.receive(), Packet::IncomingMessageAck(..)));
+            let header = Header {
+                sender: peer,
+                receiver: ThreemaID::from_string("ECHOECHO").unwrap(),
+                msg_id: MessageID::from_bytes([1; 8]),
+                timestamp: 0,
+                flags: 0,
+                nickname: String::new(),
+                nonce: [9; 24],
+            };
+            let payload = crypto::seal(
+                &[&[1][..], b"hello", &[1]].concat(),
+                &crypto::Nonce([9; 24]),
+                &own_pub,
+                &peer_priv,
+            );
+            server.send(&Packet::IncomingMessage(header, payload).serialize());
+            assert!(matches!(server.receive(), Packet::IncomingMessageAck(..)));
+            assert!(matches!(server.receive(), Packet::OutgoingMessage(..)));
+            // idle client checks the connection
+            let Packet::EchoRequest(n) = server.receive() else {
+                panic!("expected an echo request");
+            };
+            server.send(&Packet::EchoReply(n).serialize());
+            assert!(matches!(server.receive(), Packet::EchoRequest(m) if m == n + 1));
+        });
+
+        let mut recorder = Recorder::default();
+        client.run(&mut recorder).unwrap();
+        server.join().unwrap();
+        assert_eq!(recorder.texts, ["hello"]);
+        assert_eq!(recorder.dropped, [MessageID::from_bytes([2; 8])]);
+        assert_eq!(recorder.disconnects, 1);
+        assert!(client.conn.is_none());
+    }
+
+    #[test]
+    fn empty_bodies() {
+        let peer = ThreemaID::from_string("*TESTGW0").unwrap();
+        let (peer_pub, peer_priv) = crypto::keypair_from_seed(&crypto::Seed([6; 32]));
+        let mut directory = MemoryDirectory::new();
+        directory.insert(peer, peer_pub, 0);
+        let mut client = client(1);
+        client.set_directory(Box::new(directory));
+        let own_pub = client.private_key.public_key();
+        let own = client.id;
+        let mut server = connected(&mut client);
+        let mut incoming = |n: u8, data: &[u8]| {
+            let header = Header {
+                sender: peer,
+                receiver: own,
+                msg_id: MessageID::from_bytes([n; 8]),
+                timestamp: 0,
+                flags: 0,
+                nickname: String::new(),
+                nonce: [n; 24],
+            };
+            let payload = crypto::seal(data, &crypto::Nonce([n; 24]), &own_pub, &peer_priv);
+            server.send(&Packet::IncomingMessage(header, payload).serialize());
+        };
+
+        incoming(1, &[0x1a, 1]);
+        assert_eq!(client.receive().unwrap().data, Message::ContactRequestPhoto);
+        incoming(2, &[0x33, 2, 2]);
+        assert_eq!(
+            client.receive().unwrap().data,
+            Message::Unknown(0x33, vec![])
+        );
+
+        // only padding is dropped, without affecting the connection
+        incoming(3, &[1]);
+        assert!(matches!(
+            client.next_event(),
+            Ok(ClientEvent::MessageError {
+                cause: Error::InvalidData(_),
+                ..
+            })
+        ));
+        incoming(4, &[1, 1]);
+        let msg = client.receive().unwrap();
+        assert_eq!(msg.msg_id, MessageID::from_bytes([4; 8]));
+        assert_eq!(
+            msg.data,
+            Message::Text(Text {
+                message: String::new()
+            })
+        );
+    }
+
+    #[test]
+    fn ping() {
+        let peer = ThreemaID::from_string("ECHOECHO").unwrap();
+        let mut directory = MemoryDirectory::new();
+        directory.insert(peer, PublicKey([6; 32]), 0);
+        let mut client = client(1);
+        client.set_directory(Box::new(directory));
+        client.keepalive = time::Duration::from_millis(200);
+        let mut server = connected(&mut client);
+
+        let server = std::thread::spawn(move || {
+            let Packet::EchoRequest(n) = server.receive() else {
+                panic!("expected an echo request");
+            };
+            // answers to other requests and messages don't end the wait
+            server.send(&Packet::EchoReply(n + 1).serialize());
+            let header = Header {
+                sender: peer,
+                receiver: ThreemaID::from_string("ECHOECHO").unwrap(),
+                msg_id: MessageID::from_bytes([2; 8]),
+                timestamp: 0,
+                flags: 0,
+                nickname: String::new(),
+                nonce: [8; 24],
+            };
+            server.send(&Packet::IncomingMessage(header, vec![0; 32]).serialize());
+            server.send(&Packet::EchoReply(n).serialize());
+            assert!(matches!(server.receive(), Packet::IncomingMessageAck(..)));
+            // the next one stays unanswered
+            assert!(matches!(server.receive(), Packet::EchoRequest(m) if m == n + 1));
+            // and the reply to the third one is cut off
+            let Packet::EchoRequest(m) = server.receive() else {
+                panic!("expected an echo request");
+            };
+            let frame = server.frame(&Packet::EchoReply(m).serialize());
+            server.raw(&frame[..frame.len() / 2]);
+            server
+        });
+
+        let rtt = client.ping().unwrap();
+        assert!(rtt < client.keepalive);
+        assert!(matches!(
+            client.next_event(),
+            Ok(ClientEvent::MessageError { msg_id, .. }) if msg_id == MessageID::from_bytes([2; 8])
+        ));
+        assert!(matches!(client.ping(), Err(Error::Timeout)));
+        assert!(client.conn.is_some());
+        assert!(matches!(client.ping(), Err(Error::Timeout)));
+        assert!(client.conn.is_none());
+        let _server = server.join().unwrap();
+    }
+
+    #[test]
+    fn status() {
+        let peer = ThreemaID::from_string("ECHOECHO").unwrap();
+        let mut directory = MemoryDirectory::new();
+        directory.insert(peer, PublicKey([6; 32]), 0);
+        let mut client = client(1);
+        client.set_directory(Box::new(directory));
+        assert_eq!(client.status(), status::Status::default());
+        let mut server = connected(&mut client);
+        let now = client.clock.now();
+
+        let status = client.status();
+        assert_eq!(status.connected_since, Some(now));
+        assert_eq!(status.last_frame, None);
+        assert_eq!(status.client_nonce, Some(1));
+        assert_eq!(status.server_nonce, Some(1));
+
+        let first = client.send_text_message(peer, "one".to_owned()).unwrap();
+        client.send_text_message(peer, "two".to_owned()).unwrap();
+        let status = client.status();
+        assert_eq!(status.messages_sent, 2);
+        assert_eq!(status.pending_acks, 2);
+        assert_eq!(status.client_nonce, Some(3));
+
+        server.send(&Packet::OutgoingMessageAck(peer, first).serialize());
+        let header = Header {
+            sender: peer,
+            receiver: ThreemaID::from_string("ECHOECHO").unwrap(),
+            msg_id: MessageID::from_bytes([2; 8]),
+            timestamp: 0,
+            flags: 0,
+            nickname: String::new(),
+            nonce: [8; 24],
+        };
+        server.send(&Packet::IncomingMessage(header, vec![0; 32]).serialize());
+        assert!(matches!(
+            client.next_event(),
+            Ok(ClientEvent::MessageError { .. })
+        ));
+        let status = client.status();
+        assert_eq!(status.pending_acks, 1);
+        assert_eq!(status.messages_received, 1);
+        assert_eq!(status.last_frame, Some(now));
+        assert_eq!(status.server_nonce, Some(3));
+        // and the ack of the message
+        assert_eq!(status.client_nonce, Some(4));
+
+        client.disconnect();
+        let status = client.status();
+        assert!(!status.is_connected());
+        assert_eq!(status.pending_acks, 0);
+        assert_eq!(status.client_nonce, None);
+        assert_eq!((status.messages_sent, status.messages_received), (2, 1));
+    }
+
+    #[test]
+    fn server_error_ends_run() {
+        struct Persistent(usize);
+
+        impl handler::Handler for Persistent {
+            fn on_disconnect(&mut self, _: &Error) -> bool {
+                self.0 += 1;
+                true
+            }
+        }
+
+        let mut client = client(1);
+        let mut server = connected(&mut client);
+        server.send(&[&[0xe0, 0, 0, 0, 0][..], b"Another connection"].concat());
+        let mut handler = Persistent(0);
+        let error = client.run(&mut handler).unwrap_err();
+        assert!(matches!(&error, Error::ServerError(e) if e.message == "Another connection"));
+        assert!(!error.can_reconnect());
+        assert_eq!(handler.0, 1);
+        assert!(client.conn.is_none());
+
+        assert!(Error::ServerError(ServerError {
+            can_reconnect: true,
+            message: String::new(),
+        })
+        .can_reconnect());
+        assert!(Error::ConnectionClosed.can_reconnect());
+    }
+
+    #[test]
+    fn server_error_disconnects() {
+        let mut client = client(1);
+        let mut server = connected(&mut client);
+        client.echo_pending = Some(1);
+        server.send(&[&[0xe0, 0, 0, 0, 0][..], b"Another connection"].concat());
+        assert!(matches!(client.receive(), Err(Error::ServerError(_))));
+
+        // torn down completely, not only by `run`
+        let status = client.status();
+        assert!(!status.is_connected());
+        assert_eq!(status.connected_since, None);
+        assert_eq!((status.client_nonce, status.server_nonce), (None, None));
+        assert!(client.client_nonce.is_none() && client.server_nonce.is_none());
+        assert!(client.ephemeral_private_key.is_none());
+        assert_eq!(client.echo_pending, None);
+        assert!(matches!(client.receive(), Err(Error::NotConnected)));
+    }
+
+    #[test]
+    fn client_pool() {
+        use std::sync::mpsc;
+
+        struct Alerts(mpsc::Sender<String>);
+
+        impl handler::Handler for Alerts {
+            fn on_alert(&mut self, _: &mut Threema, message: &str) {
+                self.0.send(message.to_owned()).unwrap();
+            }
+
+            fn on_disconnect(&mut self, _: &Error) -> bool {
+                false
+            }
+        }
+
+        let (mut first, mut second) = (client(1), client(2));
+        let (mut first_server, mut second_server) = (connected(&mut first), connected(&mut second));
+        let (first_tx, first_rx) = mpsc::channel();
+        let (second_tx, second_rx) = mpsc::channel();
+        let mut pool = pool::ClientPool::new(1);
+        pool.add(first, Alerts(first_tx));
+        pool.add(second, Alerts(second_tx));
+        assert_eq!(pool.len(), 2);
+
+        let servers = std::thread::spawn(move || {
+            first_server.send(&[0xe1, 0, 0, 0, b'1']);
+            assert_eq!(first_rx.recv().unwrap(), "1");
+            // the first client is still connected while the second one is served
+            second_server.send(&[0xe1, 0, 0, 0, b'2']);
+            assert_eq!(second_rx.recv().unwrap(), "2");
+        });
+        assert!(pool.run().is_empty());
+        servers.join().unwrap();
+    }
+
+    #[test]
+    fn client_pool_panic() {
+        struct Panicking;
+
+        impl handler::Handler for Panicking {
+            fn on_alert(&mut self, _: &mut Threema, message: &str) {
+                std::panic::panic_any(message.to_owned());
+            }
+        }
+
+        let (mut first, mut second) = (client(1), client(2));
+        let (mut first_server, _second_server) = (connected(&mut first), connected(&mut second));
+        let mut pool = pool::ClientPool::new(1);
+        pool.add(first, Panicking);
+        pool.add(second, Panicking);
+
+        first_server.send(&[0xe1, 0, 0, 0, b'b', b'o', b'o', b'm']);
+        // both clients shared the thread, neither vanishes silently
+        let failed = pool.run();
+        assert_eq!(failed.len(), 2);
+        assert!(failed
+            .iter()
+            .all(|(_, e)| matches!(e, Error::HandlerPanicked(m) if m == "boom")));
+    }
 }
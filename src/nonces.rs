@@ -0,0 +1,216 @@
+//! Nonces of processed incoming messages, used to reject replayed messages.
+
+use crate::Result;
+use std::collections::{HashSet, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+pub type MessageNonce = [u8; 24];
+
+/// Set of nonces of already processed messages.
+pub trait NonceStore: Send {
+    fn contains(&self, nonce: &MessageNonce) -> bool;
+    fn insert(&mut self, nonce: MessageNonce);
+}
+
+/// Number of nonces kept by default, far more than the server queues for a client.
+pub const DEFAULT_CAPACITY: usize = 100_000;
+
+/// The last `capacity` nonces, forgetting the oldest ones first.
+#[derive(Debug)]
+struct Window {
+    capacity: usize,
+    order: VecDeque<MessageNonce>,
+    nonces: HashSet<MessageNonce>,
+}
+
+impl Window {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            order: VecDeque::new(),
+            nonces: HashSet::new(),
+        }
+    }
+
+    /// Returns whether `nonce` is new.
+    fn insert(&mut self, nonce: MessageNonce) -> bool {
+        if self.capacity == 0 || !self.nonces.insert(nonce) {
+            return false;
+        }
+        self.order.push_back(nonce);
+        while self.order.len() > self.capacity {
+            if let Some(old) = self.order.pop_front() {
+                self.nonces.remove(&old);
+            }
+        }
+        true
+    }
+}
+
+/// Remembers the last [`DEFAULT_CAPACITY`] nonces for the lifetime of the process.
+#[derive(Debug)]
+pub struct MemoryNonceStore {
+    window: Window,
+}
+
+impl Default for MemoryNonceStore {
+    fn default() -> Self {
+        Self::with_capacity(DEFAULT_CAPACITY)
+    }
+}
+
+impl MemoryNonceStore {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Remembers the last `capacity` nonces.
+    #[must_use]
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            window: Window::new(capacity),
+        }
+    }
+}
+
+impl NonceStore for MemoryNonceStore {
+    fn contains(&self, nonce: &MessageNonce) -> bool {
+        self.window.nonces.contains(nonce)
+    }
+
+    fn insert(&mut self, nonce: MessageNonce) {
+        self.window.insert(nonce);
+    }
+}
+
+/// Persists nonces in a file with one hex encoded nonce per line, so replays are
+/// detected across restarts.
+///
+/// New nonces are appended; once the file holds twice as many lines as nonces are kept,
+/// it is rewritten with only the kept ones.
+#[derive(Debug)]
+pub struct FileNonceStore {
+    path: PathBuf,
+    file: File,
+    window: Window,
+    /// number of lines in the file
+    lines: usize,
+}
+
+impl FileNonceStore {
+    /// Opens the store at `path` keeping the last [`DEFAULT_CAPACITY`] nonces, creating
+    /// it if it doesn't exist.
+    pub fn open<P: Into<PathBuf>>(path: P) -> Result<Self> {
+        Self::open_with_capacity(path, DEFAULT_CAPACITY)
+    }
+
+    /// Like [`open`](Self::open), but keeps the last `capacity` nonces.
+    pub fn open_with_capacity<P: Into<PathBuf>>(path: P, capacity: usize) -> Result<Self> {
+        let path = path.into();
+        let mut window = Window::new(capacity);
+        let mut lines = 0;
+        match fs::read_to_string(&path) {
+            Ok(data) => {
+                for line in data.lines() {
+                    lines += 1;
+                    if let Some(nonce) = crate::decode_hex(line.trim()) {
+                        window.insert(nonce);
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let mut store = Self {
+            path,
+            file,
+            window,
+            lines,
+        };
+        store.compact_if_needed()?;
+        Ok(store)
+    }
+
+    fn compact_if_needed(&mut self) -> io::Result<()> {
+        if self.lines <= self.window.capacity.max(1) * 2 {
+            return Ok(());
+        }
+        let mut data = String::new();
+        for nonce in &self.window.order {
+            data.push_str(&crate::encode_hex(nonce));
+            data.push('\n');
+        }
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, data)?;
+        fs::rename(&tmp, &self.path)?;
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        self.lines = self.window.order.len();
+        Ok(())
+    }
+}
+
+impl NonceStore for FileNonceStore {
+    fn contains(&self, nonce: &MessageNonce) -> bool {
+        self.window.nonces.contains(nonce)
+    }
+
+    fn insert(&mut self, nonce: MessageNonce) {
+        if !self.window.insert(nonce) {
+            return;
+        }
+        let saved = writeln!(self.file, "{}", crate::encode_hex(&nonce)).and_then(|()| {
+            self.lines += 1;
+            self.compact_if_needed()
+        });
+        if let Err(e) = saved {
+            tracing::warn!("Couldn't save nonce to {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn persistence() {
+        let path = std::env::temp_dir().join(format!("threema-nonces-{}", std::process::id()));
+        let mut store = FileNonceStore::open(&path).unwrap();
+        store.insert([1; 24]);
+        store.insert([1; 24]);
+        store.insert([2; 24]);
+        let store = FileNonceStore::open(&path).unwrap();
+        assert!(store.contains(&[1; 24]));
+        assert!(store.contains(&[2; 24]));
+        assert!(!store.contains(&[3; 24]));
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 2);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn capacity() {
+        let mut store = MemoryNonceStore::with_capacity(2);
+        store.insert([1; 24]);
+        store.insert([2; 24]);
+        store.insert([3; 24]);
+        assert!(!store.contains(&[1; 24]));
+        assert!(store.contains(&[3; 24]));
+
+        let path = std::env::temp_dir().join(format!("threema-nonces-cap-{}", std::process::id()));
+        let mut store = FileNonceStore::open_with_capacity(&path, 2).unwrap();
+        for n in 1..=5 {
+            store.insert([n; 24]);
+        }
+        // compacted after the fifth line
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 2);
+        let store = FileNonceStore::open_with_capacity(&path, 2).unwrap();
+        assert!(!store.contains(&[3; 24]));
+        assert!(store.contains(&[4; 24]));
+        assert!(store.contains(&[5; 24]));
+        fs::remove_file(path).unwrap();
+    }
+}
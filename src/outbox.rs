@@ -0,0 +1,268 @@
+//! Messages which weren't acknowledged by the server yet, kept to be sent again on the
+//! next connect. Enabled with [`ThreemaBuilder::outbox`](crate::ThreemaBuilder::outbox).
+//!
+//! Messages are added when sent, or while disconnected instead of failing, and removed
+//! once the server acknowledged them. Since a message might have reached the server
+//! before the connection was lost, peers can receive it twice with the same ID.
+
+use crate::store::write_atomically;
+use crate::{MessageID, Result, ThreemaID};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OutboxEntry {
+    pub receiver: ThreemaID,
+    pub msg_id: MessageID,
+    /// The serialized [`Message`](crate::packets::Message)
+    pub body: Vec<u8>,
+    pub created: SystemTime,
+}
+
+/// Storage of unacknowledged messages, see [`MemoryOutbox`], [`FileOutbox`] and
+/// `SqliteOutbox` (requires the `sqlite` feature).
+pub trait Outbox: Send {
+    fn push(&mut self, entry: OutboxEntry) -> Result<()>;
+    /// Removes the message `msg_id` to `receiver`, if present.
+    fn remove(&mut self, receiver: ThreemaID, msg_id: MessageID) -> Result<()>;
+    /// All messages, oldest first.
+    fn pending(&self) -> Result<Vec<OutboxEntry>>;
+    /// Number of messages, without loading them if possible.
+    fn count(&self) -> Result<usize> {
+        Ok(self.pending()?.len())
+    }
+}
+
+/// Keeps messages for the lifetime of the process, e.g. to survive reconnects.
+#[derive(Debug, Default)]
+pub struct MemoryOutbox {
+    entries: Vec<OutboxEntry>,
+}
+
+impl MemoryOutbox {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Outbox for MemoryOutbox {
+    fn push(&mut self, entry: OutboxEntry) -> Result<()> {
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    fn remove(&mut self, receiver: ThreemaID, msg_id: MessageID) -> Result<()> {
+        self.entries
+            .retain(|e| (e.receiver, e.msg_id) != (receiver, msg_id));
+        Ok(())
+    }
+
+    fn pending(&self) -> Result<Vec<OutboxEntry>> {
+        Ok(self.entries.clone())
+    }
+
+    fn count(&self) -> Result<usize> {
+        Ok(self.entries.len())
+    }
+}
+
+/// Persists messages as JSON file, so they survive restarts.
+#[derive(Debug)]
+pub struct FileOutbox {
+    path: PathBuf,
+    entries: Vec<OutboxEntry>,
+}
+
+impl FileOutbox {
+    /// Opens the outbox at `path`, creating it on the first message if it doesn't exist.
+    pub fn open<P: Into<PathBuf>>(path: P) -> Result<Self> {
+        let path = path.into();
+        let entries = match fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self { path, entries })
+    }
+
+    fn save(&self) -> Result<()> {
+        let data = serde_json::to_vec_pretty(&self.entries)?;
+        write_atomically(&self.path, &data)?;
+        Ok(())
+    }
+}
+
+impl Outbox for FileOutbox {
+    fn push(&mut self, entry: OutboxEntry) -> Result<()> {
+        self.entries.push(entry);
+        self.save()
+    }
+
+    fn remove(&mut self, receiver: ThreemaID, msg_id: MessageID) -> Result<()> {
+        let len = self.entries.len();
+        self.entries
+            .retain(|e| (e.receiver, e.msg_id) != (receiver, msg_id));
+        if self.entries.len() == len {
+            return Ok(());
+        }
+        self.save()
+    }
+
+    fn pending(&self) -> Result<Vec<OutboxEntry>> {
+        Ok(self.entries.clone())
+    }
+
+    fn count(&self) -> Result<usize> {
+        Ok(self.entries.len())
+    }
+}
+
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteOutbox;
+
+#[cfg(feature = "sqlite")]
+mod sqlite {
+    use super::{Outbox, OutboxEntry};
+    use crate::store::sqlite::{invalid, secs, time};
+    use crate::{Error, MessageID, Result, ThreemaID};
+    use rusqlite::{params, Connection};
+    use std::path::Path;
+
+    const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS outbox (
+        receiver TEXT NOT NULL,
+        msg_id BLOB NOT NULL,
+        body BLOB NOT NULL,
+        created INTEGER NOT NULL,
+        PRIMARY KEY (receiver, msg_id)
+    )";
+
+    /// Keeps messages in a `SQLite` database, which may be shared with a
+    /// [`SqliteMessageStore`](crate::store::SqliteMessageStore).
+    #[derive(Debug)]
+    pub struct SqliteOutbox {
+        conn: Connection,
+    }
+
+    impl SqliteOutbox {
+        /// Opens the database at `path`, creating it if necessary.
+        pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+            let conn = Connection::open(path)?;
+            conn.execute(SCHEMA, [])?;
+            Ok(Self { conn })
+        }
+    }
+
+    impl Outbox for SqliteOutbox {
+        fn push(&mut self, entry: OutboxEntry) -> Result<()> {
+            self.conn.execute(
+                "INSERT OR REPLACE INTO outbox (receiver, msg_id, body, created)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![
+                    entry.receiver.to_string(),
+                    &entry.msg_id.0[..],
+                    entry.body,
+                    secs(entry.created),
+                ],
+            )?;
+            Ok(())
+        }
+
+        fn remove(&mut self, receiver: ThreemaID, msg_id: MessageID) -> Result<()> {
+            self.conn.execute(
+                "DELETE FROM outbox WHERE receiver = ?1 AND msg_id = ?2",
+                params![receiver.to_string(), &msg_id.0[..]],
+            )?;
+            Ok(())
+        }
+
+        fn pending(&self) -> Result<Vec<OutboxEntry>> {
+            let mut stmt = self.conn.prepare(
+                "SELECT receiver, msg_id, body, created FROM outbox ORDER BY created, rowid",
+            )?;
+            let rows = stmt.query_map([], |row| {
+                let receiver: String = row.get(0)?;
+                let msg_id: Vec<u8> = row.get(1)?;
+                Ok(OutboxEntry {
+                    receiver: ThreemaID::from_string(&receiver).map_err(|e| invalid(0, e))?,
+                    msg_id: MessageID::from_slice(&msg_id).ok_or_else(|| {
+                        invalid(1, Error::ParseError("invalid message ID".to_owned()))
+                    })?,
+                    body: row.get(2)?,
+                    created: time(row.get(3)?),
+                })
+            })?;
+            Ok(rows.collect::<rusqlite::Result<_>>()?)
+        }
+
+        fn count(&self) -> Result<usize> {
+            Ok(self
+                .conn
+                .query_row("SELECT COUNT(*) FROM outbox", [], |row| row.get(0))?)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    fn check(outbox: &mut dyn Outbox) {
+        let peer = ThreemaID::from_string("ECHOECHO").unwrap();
+        let entry = |id: u8| OutboxEntry {
+            receiver: peer,
+            msg_id: MessageID::from_bytes([id; 8]),
+            body: vec![1, id],
+            created: UNIX_EPOCH,
+        };
+        outbox.push(entry(1)).unwrap();
+        outbox.push(entry(2)).unwrap();
+        outbox.remove(peer, MessageID::from_bytes([1; 8])).unwrap();
+        outbox.remove(peer, MessageID::from_bytes([3; 8])).unwrap();
+        assert_eq!(outbox.pending().unwrap(), [entry(2)]);
+        assert_eq!(outbox.count().unwrap(), 1);
+    }
+
+    #[test]
+    fn file() {
+        let path = std::env::temp_dir().join(format!("threema-outbox-{}.json", std::process::id()));
+        check(&mut FileOutbox::open(&path).unwrap());
+        assert_eq!(FileOutbox::open(&path).unwrap().pending().unwrap().len(), 1);
+
+        // a write interrupted by a crash only leaves the temporary file behind
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, "[{\"receiver\":").unwrap();
+        let mut outbox = FileOutbox::open(&path).unwrap();
+        assert_eq!(outbox.count().unwrap(), 1);
+        outbox
+            .remove(
+                outbox.pending().unwrap()[0].receiver,
+                MessageID::from_bytes([2; 8]),
+            )
+            .unwrap();
+        assert!(!tmp.exists());
+        assert_eq!(FileOutbox::open(&path).unwrap().count().unwrap(), 0);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn memory() {
+        check(&mut MemoryOutbox::new());
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn sqlite() {
+        let path = std::env::temp_dir().join(format!("threema-outbox-{}.db", std::process::id()));
+        check(&mut SqliteOutbox::open(&path).unwrap());
+        assert_eq!(
+            SqliteOutbox::open(&path).unwrap().pending().unwrap().len(),
+            1
+        );
+        fs::remove_file(path).unwrap();
+    }
+}
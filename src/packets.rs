@@ -1,6 +1,6 @@
+use crate::GroupID;
 use crate::MessageID;
 use crate::ThreemaID;
-use flat_bytes::flat_enum;
 use flat_bytes::Flat;
 use serde::de::Error;
 use serde::de::Unexpected;
@@ -9,123 +9,266 @@ use serde::Deserializer;
 use serde::Serializer;
 use serde::{Deserialize, Serialize};
 use serde_json::{from_slice, to_vec};
+use serde_repr::{Deserialize_repr, Serialize_repr};
+use std::convert::TryFrom;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, PartialEq, Flat)]
+#[repr(u32)]
+pub enum Packet {
+    EchoRequest(u64) = 0,
+    EchoReply(u64) = 0x80,
+    /// Header followed by the encrypted message
+    OutgoingMessage(Header, #[flat(rest)] Vec<u8>) = 1,
+    OutgoingMessageAck(ThreemaID, MessageID) = 0x81,
+    IncomingMessage(Header, #[flat(rest)] Vec<u8>) = 2,
+    IncomingMessageAck(ThreemaID, MessageID) = 0x82,
+    PushNotificationToken = 0x20,
+    PushAllowedIdentities = 0x21,
+    VoipPushNotificationToken = 0x24,
+    QueueSendComplete = 0xd0,
+    LastEphemeralKeyHash = 0xd1,
+    Error(ServerError) = 0xe0,
+    Alert(ServerAlert) = 0xe1,
+}
+
+/// Alert sent by the server, meant to be shown to the user.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerAlert {
+    pub message: String,
+}
+
+/// Sent by the server right before it closes the connection, e.g. because the identity
+/// connected elsewhere.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerError {
+    /// Whether the client may connect again; if not, retrying would only take the
+    /// connection away from the other client again.
+    pub can_reconnect: bool,
+    pub message: String,
+}
+
+/// The text of an alert or error: UTF-8, though a stray invalid byte shouldn't hide it.
+fn server_text(data: &[u8]) -> String {
+    String::from_utf8_lossy(data).into_owned()
+}
+
+impl Flat for ServerAlert {
+    fn serialize(&self) -> Vec<u8> {
+        self.message.as_bytes().to_vec()
+    }
+
+    fn deserialize_with_size(data: &[u8]) -> Option<(Self, usize)> {
+        let message = server_text(data);
+        Some((ServerAlert { message }, data.len()))
+    }
+}
+
+impl Flat for ServerError {
+    fn serialize(&self) -> Vec<u8> {
+        [&[u8::from(self.can_reconnect)][..], self.message.as_bytes()].concat()
+    }
 
-flat_enum! {
-    #[derive(Debug)]
-    #[repr(u32)]
-    pub enum Packet {
-        EchoRequest(u64) = 0,
-        EchoReply(u64) = 0x80,
-        OutgoingMessage(Header) = 1,
-        OutgoingMessageAck(ThreemaID, MessageID) = 0x81,
-        IncomingMessage(Header) = 2,
-        IncomingMessageAck(ThreemaID, MessageID) = 0x82,
-        PushNotificationToken = 0x20,
-        PushAllowedIdentities = 0x21,
-        VoipPushNotificationToken = 0x24,
-        QueueSendComplete = 0xd0,
-        LastEphemeralKeyHash = 0xd1,
-        Error = 0xe0,
-        Alert = 0xe1,
+    fn deserialize_with_size(data: &[u8]) -> Option<(Self, usize)> {
+        let (flag, text) = data.split_first()?;
+        let error = ServerError {
+            can_reconnect: *flag != 0,
+            message: server_text(text),
+        };
+        Some((error, data.len()))
     }
 }
 
 pub type BallotID = [u8; 8];
 
-flat_enum! {
-    #[derive(Debug)]
-    #[repr(u8)]
-    pub enum Message {
-        Text(Text) = 1,
-        Image,
-        Location = 0x10,
-        Video = 0x13,
-        Audio = 0x14,
-        // Poll {
-        BallotCreate {
-            poll_id: BallotID,
-            details: Ballot,
-        } = 0x15,
-        BallotVote {
+/// Strips the PKCS#7 style padding (1 to 255 bytes) from a decrypted message.
+pub fn unpad(data: &[u8]) -> crate::Result<&[u8]> {
+    let pad = data.last().copied().unwrap_or_default() as usize;
+    if pad == 0 || pad > data.len() {
+        return Err(crate::Error::InvalidPadding);
+    }
+    Ok(&data[..data.len() - pad])
+}
+
+#[derive(Debug, PartialEq, Flat, Serialize, Deserialize)]
+#[repr(u8)]
+pub enum Message {
+    Text(Text) = 1,
+    Image,
+    Location = 0x10,
+    Video = 0x13,
+    Audio = 0x14,
+    // Poll {
+    BallotCreate {
+        poll_id: BallotID,
+        details: Ballot,
+    } = 0x15,
+    BallotVote {
         // PollUpdate {
-            sender: ThreemaID,
-            poll_id: BallotID,
-            updates: BallotUpdates,
-        } = 0x16,
-        File(File) = 0x17,
-        ContactSetPhoto = 0x18,
-        ContactDeletePhoto = 0x19,
-        ContactRequestPhoto = 0x1a,
-        GroupText = 0x41,
-        GroupLocation = 0x42,
-        GroupImage = 0x43,
-        GroupVideo = 0x44,
-        GroupAudio = 0x45,
-        GroupFile = 0x46,
-        GroupCreate = 0x4a,
-        GroupRename = 0x4b,
-        GroupLeave = 0x4c,
-        GroupAddMember = 0x4d,
-        GroupRemoveMember = 0x4e,
-        GroupDestroy = 0x4f,
-        GroupSetPhoto = 0x50,
-        GroupRequestSync = 0x51,
-        GroupBallotCreate = 0x52,
-        GroupBallotVote = 0x53,
-        GroupDeletePhoto = 0x54,
-        VoipCallOffer = 0x60,
-        VoipCallAnswer = 0x61,
-        VoipIceCandiates = 0x62,
-        VoipCallHangup = 0x63,
-        VoipCallRinging = 0x64,
-        DeliveryReceipt(MessageStatus, MessageID) = 0x80,
-        TypingNotification = 0x90,
-        FsEnvelope = 0xa0,
-        AuthToken = 0xff,
-    }
-}
-
-flat_enum! {
-    #[derive(Debug)]
-    #[repr(u8)]
-    pub enum MessageStatus {
-        Delivered = 1,
-        Read,
-        Approved,
-        Disapproved,
-    }
-}
-
-#[derive(Debug, Flat)]
+        sender: ThreemaID,
+        poll_id: BallotID,
+        updates: BallotUpdates,
+    } = 0x16,
+    File(File) = 0x17,
+    ContactSetPhoto = 0x18,
+    ContactDeletePhoto = 0x19,
+    ContactRequestPhoto = 0x1a,
+    GroupText(GroupText) = 0x41,
+    GroupLocation = 0x42,
+    GroupImage = 0x43,
+    GroupVideo = 0x44,
+    GroupAudio = 0x45,
+    GroupFile = 0x46,
+    GroupCreate = 0x4a,
+    GroupRename = 0x4b,
+    GroupLeave = 0x4c,
+    GroupAddMember = 0x4d,
+    GroupRemoveMember = 0x4e,
+    GroupDestroy = 0x4f,
+    GroupSetPhoto = 0x50,
+    GroupRequestSync = 0x51,
+    GroupBallotCreate = 0x52,
+    GroupBallotVote = 0x53,
+    GroupDeletePhoto = 0x54,
+    VoipCallOffer = 0x60,
+    VoipCallAnswer = 0x61,
+    VoipIceCandiates = 0x62,
+    VoipCallHangup = 0x63,
+    VoipCallRinging = 0x64,
+    DeliveryReceipt(MessageStatus, MessageID) = 0x80,
+    TypingNotification = 0x90,
+    FsEnvelope = 0xa0,
+    AuthToken = 0xff,
+    /// Message type not known to this crate, with its raw body
+    #[flat(other)]
+    Unknown(u8, Vec<u8>) = 0,
+}
+
+impl Message {
+    /// Short name of the message type, e.g. `group_text`, for logs and metrics.
+    #[must_use]
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Message::Text(_) => "text",
+            Message::Image => "image",
+            Message::Location => "location",
+            Message::Video => "video",
+            Message::Audio => "audio",
+            Message::BallotCreate { .. } => "ballot_create",
+            Message::BallotVote { .. } => "ballot_vote",
+            Message::File(_) => "file",
+            Message::ContactSetPhoto => "contact_set_photo",
+            Message::ContactDeletePhoto => "contact_delete_photo",
+            Message::ContactRequestPhoto => "contact_request_photo",
+            Message::GroupText(_) => "group_text",
+            Message::GroupLocation => "group_location",
+            Message::GroupImage => "group_image",
+            Message::GroupVideo => "group_video",
+            Message::GroupAudio => "group_audio",
+            Message::GroupFile => "group_file",
+            Message::GroupCreate => "group_create",
+            Message::GroupRename => "group_rename",
+            Message::GroupLeave => "group_leave",
+            Message::GroupAddMember => "group_add_member",
+            Message::GroupRemoveMember => "group_remove_member",
+            Message::GroupDestroy => "group_destroy",
+            Message::GroupSetPhoto => "group_set_photo",
+            Message::GroupRequestSync => "group_request_sync",
+            Message::GroupBallotCreate => "group_ballot_create",
+            Message::GroupBallotVote => "group_ballot_vote",
+            Message::GroupDeletePhoto => "group_delete_photo",
+            Message::VoipCallOffer => "voip_call_offer",
+            Message::VoipCallAnswer => "voip_call_answer",
+            Message::VoipIceCandiates => "voip_ice_candidates",
+            Message::VoipCallHangup => "voip_call_hangup",
+            Message::VoipCallRinging => "voip_call_ringing",
+            Message::DeliveryReceipt(..) => "delivery_receipt",
+            Message::TypingNotification => "typing_notification",
+            Message::FsEnvelope => "fs_envelope",
+            Message::AuthToken => "auth_token",
+            Message::Unknown(..) => "unknown",
+        }
+    }
+
+    /// Parses a decrypted message without its padding, returning it along with the number
+    /// of bytes it took. Many types have an empty body, but the type itself is required.
+    pub fn parse(data: &[u8]) -> crate::Result<(Self, usize)> {
+        if data.is_empty() {
+            let error = flat_bytes::DeserializeError::new::<Self>()
+                .with_detail("no message type, only padding".to_owned());
+            return Err(error.into());
+        }
+        Ok(Self::try_deserialize_with_size(data)?)
+    }
+}
+
+#[derive(Debug, PartialEq, Flat, Serialize, Deserialize)]
+#[repr(u8)]
+pub enum MessageStatus {
+    Delivered = 1,
+    Read,
+    Approved,
+    Disapproved,
+}
+
+#[derive(Debug, PartialEq, Flat, Serialize, Deserialize)]
 pub struct Header {
     pub sender: ThreemaID,
     pub receiver: ThreemaID,
     pub msg_id: MessageID,
+    /// Seconds since the Unix epoch, see [`Header::time`]
     pub timestamp: u32,
     pub flags: u32,
-    pub nickname: [u8; 32],
+    #[flat(size = 32)]
+    pub nickname: String,
     pub nonce: [u8; 24],
 }
 
-#[derive(Debug)]
+impl Header {
+    /// When the message was sent, according to the clock of the sender.
+    #[must_use]
+    pub fn time(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.timestamp.into())
+    }
+
+    /// The [`timestamp`](Header::timestamp) for `time`.
+    ///
+    /// The field only has 32 bits, so times before 1970 are sent as 0 and times after
+    /// 2106-02-07 06:28:15 UTC as that second, instead of wrapping around.
+    #[must_use]
+    pub fn timestamp_of(time: SystemTime) -> u32 {
+        let secs = time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        u32::try_from(secs).unwrap_or(u32::MAX)
+    }
+}
+
+#[derive(Debug, PartialEq, Flat, Serialize, Deserialize)]
 pub struct Text {
+    #[flat(rest)]
     pub message: String,
 }
 
-impl Flat for Text {
-    fn serialize(&self) -> Vec<u8> {
-        self.message.as_bytes().to_owned()
-    }
+impl Text {
+    /// Longest text the apps accept in a single message, in bytes.
+    pub const MAX_LEN: usize = 3500;
+}
 
-    fn deserialize_with_size(data: &[u8]) -> Option<(Self, usize)> {
-        let message = String::from_utf8(data.to_owned()).ok()?;
-        Some((Self { message }, data.len()))
-    }
+/// Text sent to a group, identified by its creator and ID.
+#[derive(Debug, PartialEq, Flat, Serialize, Deserialize)]
+pub struct GroupText {
+    pub creator: ThreemaID,
+    pub group_id: GroupID,
+    #[flat(rest)]
+    pub message: String,
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
 pub enum RenderingType {
     /// Display as default file message
+    #[default]
     File = 0,
     /// Display as media file message (e.g. image or audio message)
     Media = 1,
@@ -141,7 +284,7 @@ impl Serialize for RenderingType {
 
 struct EnumVisitor;
 
-impl<'de> Visitor<'de> for EnumVisitor {
+impl Visitor<'_> for EnumVisitor {
     type Value = RenderingType;
 
     fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
@@ -170,13 +313,7 @@ impl<'de> Deserialize<'de> for RenderingType {
     }
 }
 
-impl Default for RenderingType {
-    fn default() -> Self {
-        RenderingType::File
-    }
-}
-
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
 pub struct File {
     #[serde(rename = "b")]
     blob_id: String,
@@ -197,10 +334,129 @@ pub struct File {
     rendering_type: RenderingType,
     #[serde(rename = "k")]
     encryption_key: String,
+    #[serde(rename = "x")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<FileMetadata>,
     #[serde(flatten)]
     pub unknown: std::collections::HashMap<String, serde_json::Value>,
 }
 
+/// Details on the content of a [`File`], depending on its type.
+#[derive(Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct FileMetadata {
+    #[serde(rename = "w")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>,
+    #[serde(rename = "h")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+    #[serde(flatten)]
+    pub unknown: std::collections::HashMap<String, serde_json::Value>,
+}
+
+impl File {
+    /// Nonce the file data is encrypted with, using the key of the message.
+    pub const DATA_NONCE: crate::crypto::Nonce = crate::crypto::Nonce(nonce_suffix(1));
+    /// Nonce the thumbnail is encrypted with, using the key of the message.
+    pub const THUMBNAIL_NONCE: crate::crypto::Nonce = crate::crypto::Nonce(nonce_suffix(2));
+
+    /// Describes the file `name` uploaded as blob `blob_id` (hex) after encrypting it
+    /// with `encryption_key` and [`File::DATA_NONCE`].
+    #[must_use]
+    pub fn new(
+        blob_id: &str,
+        encryption_key: &[u8; crate::crypto::KEYBYTES],
+        name: &str,
+        mime: &str,
+        size: u64,
+    ) -> Self {
+        Self {
+            blob_id: blob_id.to_owned(),
+            name: name.to_owned(),
+            mime: mime.to_owned(),
+            thumbnail_blob_id: None,
+            thumbnail_mime: String::new(),
+            size,
+            description: String::new(),
+            rendering_type: RenderingType::File,
+            encryption_key: crate::encode_hex(encryption_key),
+            metadata: None,
+            unknown: std::collections::HashMap::new(),
+        }
+    }
+
+    /// Adds the preview uploaded as blob `blob_id` (hex), encrypted with the key of the
+    /// file and [`File::THUMBNAIL_NONCE`].
+    #[must_use]
+    pub fn with_thumbnail(mut self, blob_id: &str, mime: &str) -> Self {
+        self.thumbnail_blob_id = Some(blob_id.to_owned());
+        mime.clone_into(&mut self.thumbnail_mime);
+        self
+    }
+
+    /// Marks the file as image or video of the given size, shown inline by the apps.
+    #[must_use]
+    pub fn with_dimensions(mut self, width: u32, height: u32) -> Self {
+        let metadata = self.metadata.get_or_insert_with(FileMetadata::default);
+        metadata.width = Some(width);
+        metadata.height = Some(height);
+        self.rendering_type = RenderingType::Media;
+        self
+    }
+
+    /// Adds a caption, shown below the file.
+    #[must_use]
+    pub fn with_caption(mut self, caption: &str) -> Self {
+        caption.clone_into(&mut self.description);
+        self
+    }
+
+    /// How the apps show the file, e.g. [`RenderingType::Media`] to show an image inline
+    /// or play audio like a voice message.
+    #[must_use]
+    pub fn with_rendering(mut self, rendering: RenderingType) -> Self {
+        self.rendering_type = rendering;
+        self
+    }
+
+    #[must_use]
+    pub fn rendering(&self) -> RenderingType {
+        self.rendering_type
+    }
+
+    /// The blob ID (hex) of the data.
+    #[must_use]
+    pub fn blob_id(&self) -> &str {
+        &self.blob_id
+    }
+
+    /// The key the data and preview are encrypted with, `None` if it isn't valid hex.
+    #[must_use]
+    pub fn encryption_key(&self) -> Option<[u8; crate::crypto::KEYBYTES]> {
+        crate::decode_hex(&self.encryption_key)
+    }
+
+    /// The blob ID (hex) of the preview, if any.
+    #[must_use]
+    pub fn thumbnail_blob_id(&self) -> Option<&str> {
+        self.thumbnail_blob_id.as_deref()
+    }
+
+    /// Width and height of an image or video.
+    #[must_use]
+    pub fn dimensions(&self) -> Option<(u32, u32)> {
+        let metadata = self.metadata.as_ref()?;
+        Some((metadata.width?, metadata.height?))
+    }
+}
+
+/// Nonce of zeros ending with `n`.
+const fn nonce_suffix(n: u8) -> [u8; 24] {
+    let mut nonce = [0; 24];
+    nonce[23] = n;
+    nonce
+}
+
 impl Flat for File {
     fn serialize(&self) -> Vec<u8> {
         to_vec(self).unwrap()
@@ -212,7 +468,7 @@ impl Flat for File {
     }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
 pub struct PollChoice {
     #[serde(rename = "i")]
     pub id: u32,
@@ -226,41 +482,38 @@ pub struct PollChoice {
     pub unknown: std::collections::HashMap<String, serde_json::Value>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, PartialEq, Deserialize_repr, Serialize_repr)]
 #[repr(u8)]
 pub enum BallotState {
     Open = 0,
     Closed = 1,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, PartialEq, Deserialize_repr, Serialize_repr)]
 #[repr(u8)]
 pub enum BallotType {
     ResultOnClose = 0,
     Intermediate = 1,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, PartialEq, Deserialize_repr, Serialize_repr)]
 #[repr(u8)]
 pub enum AssessmentType {
     Single = 0,
     Multiple = 1,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, PartialEq, Deserialize_repr, Serialize_repr)]
 #[repr(u8)]
 pub enum ChoiceType {
     Text = 0,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+/// Fields are in the order the apps write them.
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
 pub struct Ballot {
     #[serde(rename = "d")]
     pub description: String,
-    #[serde(rename = "c")]
-    pub choices: Vec<PollChoice>,
-    #[serde(rename = "p")]
-    pub participants: Vec<String>,
     #[serde(rename = "s")]
     pub state: BallotState,
     #[serde(rename = "a")]
@@ -269,6 +522,10 @@ pub struct Ballot {
     pub ballot_type: BallotType,
     #[serde(rename = "o")]
     pub choice_type: ChoiceType,
+    #[serde(rename = "c")]
+    pub choices: Vec<PollChoice>,
+    #[serde(rename = "p")]
+    pub participants: Vec<String>,
     #[serde(flatten)]
     pub unknown: std::collections::HashMap<String, serde_json::Value>,
 }
@@ -287,7 +544,7 @@ impl Flat for Ballot {
 #[deprecated = "please use Ballot instead"]
 pub type PollDetails = Ballot;
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
 #[serde(transparent)]
 pub struct BallotUpdates {
     updates: Vec<(u32, u32)>,
@@ -306,3 +563,355 @@ impl Flat for BallotUpdates {
 
 #[deprecated = "please use BallotUpdates instead"]
 pub type PollUpdate = BallotUpdates;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::fmt::Debug;
+
+    /// Asserts that `value` is serialized to exactly `bytes` and parsed back from them.
+    fn golden<T: Flat + PartialEq + Debug>(value: &T, bytes: &[u8]) {
+        assert_eq!(value.serialize(), bytes, "serializing {value:?}");
+        assert_eq!(value.serialized_size(), bytes.len());
+        let (parsed, size) = T::try_deserialize_with_size(bytes).unwrap();
+        assert_eq!(&parsed, value);
+        assert_eq!(size, bytes.len());
+    }
+
+    fn sender() -> ThreemaID {
+        ThreemaID::from_string("ECHOECHO").unwrap()
+    }
+
+    fn receiver() -> ThreemaID {
+        ThreemaID::from_string("*TESTGW0").unwrap()
+    }
+
+    fn msg_id() -> MessageID {
+        MessageID::from_bytes([1, 2, 3, 4, 5, 6, 7, 8])
+    }
+
+    fn header() -> (Header, Vec<u8>) {
+        let header = Header {
+            sender: sender(),
+            receiver: receiver(),
+            msg_id: msg_id(),
+            timestamp: 0x6000_0001,
+            flags: 1,
+            nickname: "Echo".to_owned(),
+            nonce: [0xaa; 24],
+        };
+        let mut bytes = [&b"ECHOECHO"[..], b"*TESTGW0", &[1, 2, 3, 4, 5, 6, 7, 8]].concat();
+        bytes.extend_from_slice(&[1, 0, 0, 0x60, 1, 0, 0, 0]);
+        bytes.extend_from_slice(b"Echo");
+        bytes.extend_from_slice(&[0; 28]);
+        bytes.extend_from_slice(&[0xaa; 24]);
+        (header, bytes)
+    }
+
+    #[test]
+    fn timestamps() {
+        let time = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let header = Header {
+            sender: sender(),
+            receiver: sender(),
+            msg_id: MessageID::from_bytes([1; 8]),
+            timestamp: Header::timestamp_of(time + Duration::from_millis(999)),
+            flags: 0,
+            nickname: String::new(),
+            nonce: [0; 24],
+        };
+        assert_eq!(header.timestamp, 1_600_000_000);
+        assert_eq!(header.time(), time);
+        assert_eq!(Header::timestamp_of(UNIX_EPOCH - Duration::from_secs(1)), 0);
+        let last = UNIX_EPOCH + Duration::from_secs(u32::MAX.into());
+        assert_eq!(Header::timestamp_of(last), u32::MAX);
+        assert_eq!(
+            Header::timestamp_of(last + Duration::from_secs(1)),
+            u32::MAX
+        );
+    }
+
+    #[test]
+    fn packets() {
+        golden(
+            &Packet::EchoRequest(0x0102_0304),
+            &[0, 0, 0, 0, 4, 3, 2, 1, 0, 0, 0, 0],
+        );
+        golden(
+            &Packet::EchoReply(7),
+            &[0x80, 0, 0, 0, 7, 0, 0, 0, 0, 0, 0, 0],
+        );
+
+        let (hdr, hdr_bytes) = header();
+        assert_eq!(hdr_bytes.len(), 88);
+        golden(&hdr, &hdr_bytes);
+        golden(
+            &Packet::OutgoingMessage(hdr, vec![0xde, 0xad]),
+            &[&[1, 0, 0, 0][..], &hdr_bytes, &[0xde, 0xad]].concat(),
+        );
+        let (hdr, _) = header();
+        golden(
+            &Packet::IncomingMessage(hdr, vec![]),
+            &[&[2, 0, 0, 0][..], &hdr_bytes].concat(),
+        );
+        let ack = [&b"ECHOECHO"[..], &[1, 2, 3, 4, 5, 6, 7, 8]].concat();
+        golden(
+            &Packet::OutgoingMessageAck(sender(), msg_id()),
+            &[&[0x81, 0, 0, 0][..], &ack].concat(),
+        );
+        golden(
+            &Packet::IncomingMessageAck(sender(), msg_id()),
+            &[&[0x82, 0, 0, 0][..], &ack].concat(),
+        );
+
+        golden(&Packet::PushNotificationToken, &[0x20, 0, 0, 0]);
+        golden(&Packet::PushAllowedIdentities, &[0x21, 0, 0, 0]);
+        golden(&Packet::VoipPushNotificationToken, &[0x24, 0, 0, 0]);
+        golden(&Packet::QueueSendComplete, &[0xd0, 0, 0, 0]);
+        golden(&Packet::LastEphemeralKeyHash, &[0xd1, 0, 0, 0]);
+
+        golden(
+            &Packet::Error(ServerError {
+                can_reconnect: true,
+                message: "Another connection".to_owned(),
+            }),
+            &[&[0xe0, 0, 0, 0, 1][..], b"Another connection"].concat(),
+        );
+        golden(
+            &Packet::Alert(ServerAlert {
+                message: "Hi".to_owned(),
+            }),
+            &[0xe1, 0, 0, 0, b'H', b'i'],
+        );
+        assert_eq!(
+            Packet::deserialize(&[0xe1, 0, 0, 0, b'H', 0xff]),
+            Some(Packet::Alert(ServerAlert {
+                message: "H\u{fffd}".to_owned()
+            }))
+        );
+        assert!(Packet::deserialize(&[0xe0, 0, 0, 0]).is_none());
+        assert!(Packet::deserialize(&[0x99, 0, 0, 0]).is_none());
+    }
+
+    #[test]
+    fn messages() {
+        golden(
+            &Message::Text(Text {
+                message: "Grüezi".to_owned(),
+            }),
+            &[&[1][..], "Grüezi".as_bytes()].concat(),
+        );
+        golden(
+            &Message::GroupText(GroupText {
+                creator: sender(),
+                group_id: GroupID([0xa1; 8]),
+                message: "hi all".to_owned(),
+            }),
+            &[&[0x41][..], b"ECHOECHO", &[0xa1; 8], b"hi all"].concat(),
+        );
+        golden(
+            &Message::DeliveryReceipt(MessageStatus::Read, msg_id()),
+            &[0x80, 2, 1, 2, 3, 4, 5, 6, 7, 8],
+        );
+        for (status, byte) in [
+            (MessageStatus::Delivered, 1),
+            (MessageStatus::Read, 2),
+            (MessageStatus::Approved, 3),
+            (MessageStatus::Disapproved, 4),
+        ] {
+            golden(&status, &[byte]);
+        }
+        golden(&Message::Unknown(0x7f, vec![1, 2, 3]), &[0x7f, 1, 2, 3]);
+
+        let units = [
+            (Message::Image, 0x02),
+            (Message::Location, 0x10),
+            (Message::Video, 0x13),
+            (Message::Audio, 0x14),
+            (Message::ContactSetPhoto, 0x18),
+            (Message::ContactDeletePhoto, 0x19),
+            (Message::ContactRequestPhoto, 0x1a),
+            (Message::GroupLocation, 0x42),
+            (Message::GroupImage, 0x43),
+            (Message::GroupVideo, 0x44),
+            (Message::GroupAudio, 0x45),
+            (Message::GroupFile, 0x46),
+            (Message::GroupCreate, 0x4a),
+            (Message::GroupRename, 0x4b),
+            (Message::GroupLeave, 0x4c),
+            (Message::GroupAddMember, 0x4d),
+            (Message::GroupRemoveMember, 0x4e),
+            (Message::GroupDestroy, 0x4f),
+            (Message::GroupSetPhoto, 0x50),
+            (Message::GroupRequestSync, 0x51),
+            (Message::GroupBallotCreate, 0x52),
+            (Message::GroupBallotVote, 0x53),
+            (Message::GroupDeletePhoto, 0x54),
+            (Message::VoipCallOffer, 0x60),
+            (Message::VoipCallAnswer, 0x61),
+            (Message::VoipIceCandiates, 0x62),
+            (Message::VoipCallHangup, 0x63),
+            (Message::VoipCallRinging, 0x64),
+            (Message::TypingNotification, 0x90),
+            (Message::FsEnvelope, 0xa0),
+            (Message::AuthToken, 0xff),
+        ];
+        for (msg, byte) in &units {
+            golden(msg, &[*byte]);
+        }
+    }
+
+    #[test]
+    fn file_message() {
+        let file = File {
+            blob_id: "00112233445566778899aabbccddeeff".to_owned(),
+            name: "a.txt".to_owned(),
+            mime: "text/plain".to_owned(),
+            thumbnail_blob_id: None,
+            thumbnail_mime: "image/jpeg".to_owned(),
+            size: 3,
+            description: String::new(),
+            rendering_type: RenderingType::Media,
+            encryption_key: "ff".repeat(32),
+            metadata: None,
+            unknown: HashMap::new(),
+        };
+        let json = format!(
+            r#"{{"b":"00112233445566778899aabbccddeeff","n":"a.txt","m":"text/plain","p":"image/jpeg","s":3,"d":"","j":1,"k":"{}"}}"#,
+            "ff".repeat(32)
+        );
+        golden(
+            &Message::File(file),
+            &[&[0x17][..], json.as_bytes()].concat(),
+        );
+
+        let file = File::new("00112233445566778899aabbccddeeff", &[0xff; 32], "a", "b", 1);
+        assert_eq!(file.rendering_type, RenderingType::File);
+        assert_eq!(file.encryption_key, "ff".repeat(32));
+        assert_eq!(file.encryption_key(), Some([0xff; 32]));
+        assert_eq!(file.blob_id(), "00112233445566778899aabbccddeeff");
+        assert_eq!(File::DATA_NONCE.0[22..], [0, 1]);
+        assert_eq!(file.dimensions(), None);
+
+        let file = file
+            .with_caption("caption")
+            .with_rendering(RenderingType::Sticker);
+        assert_eq!(file.description, "caption");
+        assert_eq!(file.rendering(), RenderingType::Sticker);
+
+        let file = file
+            .with_caption("")
+            .with_thumbnail("ffeeddccbbaa99887766554433221100", "image/jpeg")
+            .with_dimensions(640, 480);
+        assert_eq!(file.dimensions(), Some((640, 480)));
+        let json = format!(
+            r#"{{"b":"00112233445566778899aabbccddeeff","n":"a","m":"b","t":"ffeeddccbbaa99887766554433221100","p":"image/jpeg","s":1,"d":"","j":1,"k":"{}","x":{{"w":640,"h":480}}}}"#,
+            "ff".repeat(32)
+        );
+        golden(
+            &Message::File(file),
+            &[&[0x17][..], json.as_bytes()].concat(),
+        );
+    }
+
+    #[test]
+    fn ballot_messages() {
+        let details = Ballot {
+            description: "Lunch?".to_owned(),
+            choices: vec![PollChoice {
+                id: 1,
+                text: "Pizza".to_owned(),
+                order: 0,
+                results: vec![1, 0],
+                unknown: HashMap::new(),
+            }],
+            participants: vec!["ECHOECHO".to_owned()],
+            state: BallotState::Open,
+            assessment_type: AssessmentType::Single,
+            ballot_type: BallotType::Intermediate,
+            choice_type: ChoiceType::Text,
+            unknown: HashMap::new(),
+        };
+        // layout of the apps' BallotData, which encodes the enums as integers
+        let json = r#"{"d":"Lunch?","s":0,"a":0,"t":1,"o":0,"c":[{"i":1,"n":"Pizza","o":0,"r":[1,0]}],"p":["ECHOECHO"]}"#;
+        golden(
+            &Message::BallotCreate {
+                poll_id: *b"poll0001",
+                details,
+            },
+            &[&[0x15][..], b"poll0001", json.as_bytes()].concat(),
+        );
+
+        golden(
+            &Message::BallotVote {
+                sender: sender(),
+                poll_id: *b"poll0001",
+                updates: BallotUpdates {
+                    updates: vec![(1, 1), (2, 0)],
+                },
+            },
+            &[&[0x16][..], b"ECHOECHO", b"poll0001", b"[[1,1],[2,0]]"].concat(),
+        );
+    }
+
+    #[test]
+    fn json() {
+        let (hdr, _) = header();
+        let json = serde_json::to_string(&hdr).unwrap();
+        assert!(json.contains(r#""sender":"ECHOECHO""#), "{}", json);
+        assert!(json.contains(r#""msg_id":"0102030405060708""#), "{}", json);
+        assert_eq!(serde_json::from_str::<Header>(&json).unwrap(), hdr);
+
+        let msg = Message::GroupText(GroupText {
+            creator: sender(),
+            group_id: GroupID([0xa1; 8]),
+            message: "Hi".to_owned(),
+        });
+        let json = serde_json::to_string(&msg).unwrap();
+        assert_eq!(
+            json,
+            r#"{"GroupText":{"creator":"ECHOECHO","group_id":"a1a1a1a1a1a1a1a1","message":"Hi"}}"#
+        );
+        assert_eq!(serde_json::from_str::<Message>(&json).unwrap(), msg);
+
+        let receipt = Message::DeliveryReceipt(MessageStatus::Read, msg_id());
+        let json = serde_json::to_string(&receipt).unwrap();
+        assert_eq!(serde_json::from_str::<Message>(&json).unwrap(), receipt);
+        assert!(serde_json::from_str::<GroupID>(r#""a1""#).is_err());
+    }
+
+    #[test]
+    fn padding() {
+        assert_eq!(unpad(&[1, 2, 3, 2, 2]).unwrap(), &[1, 2, 3]);
+        assert_eq!(unpad(&[3, 3, 3]).unwrap(), &[] as &[u8]);
+        assert!(unpad(&[]).is_err());
+        assert!(unpad(&[1, 0]).is_err());
+        assert!(unpad(&[4, 4, 4]).is_err());
+    }
+
+    #[test]
+    fn empty_bodies() {
+        let messages = [
+            Message::Text(Text {
+                message: String::new(),
+            }),
+            Message::ContactRequestPhoto,
+            Message::GroupLeave,
+            Message::TypingNotification,
+            Message::Unknown(0x33, vec![]),
+        ];
+        for msg in messages {
+            let padded = [Flat::serialize(&msg), vec![3; 3]].concat();
+            let data = unpad(&padded).unwrap();
+            assert_eq!(data.len(), 1, "{msg:?}");
+            assert_eq!(Message::parse(data).unwrap(), (msg, 1));
+        }
+
+        // nothing but padding
+        let Err(crate::Error::InvalidData(e)) = Message::parse(unpad(&[2, 2]).unwrap()) else {
+            panic!("expected invalid data");
+        };
+        assert_eq!(e.detail.as_deref(), Some("no message type, only padding"));
+    }
+}
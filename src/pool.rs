@@ -0,0 +1,161 @@
+//! Running several identities in one process.
+//!
+//! [`ClientPool`] multiplexes the receive loops of several clients onto a few threads.
+//! The HTTP agent of the [REST API](crate::rest) is shared by all clients anyway; key
+//! caches and stores can be shared by wrapping them in an `Arc<Mutex<_>>`, and a
+//! directory client in an `Arc`:
+//!
+//! ```no_run
+//! # use threema::{handler::Handler, keystore::MemoryKeyStore, pool::ClientPool};
+//! # use threema::{Threema, ThreemaID};
+//! # use std::sync::{Arc, Mutex};
+//! struct Bot;
+//! impl Handler for Bot {}
+//!
+//! let keys = Arc::new(Mutex::new(MemoryKeyStore::default()));
+//! let mut pool = ClientPool::new(2);
+//! for (id, secret) in [("ECHOECHO", [1; 32]), ("*BOTBOT1", [2; 32])] {
+//!     let client = Threema::builder()
+//!         .identity(ThreemaID::from_string(id)?, &secret)
+//!         .key_store(Box::new(Arc::clone(&keys)))
+//!         .build()?;
+//!     pool.add(client, Bot);
+//! }
+//! for (id, error) in pool.run() {
+//!     eprintln!("{}: {}", id, error);
+//! }
+//! # Ok::<(), threema::Error>(())
+//! ```
+
+use crate::handler::{Handler, RunState};
+use crate::{Error, Threema, ThreemaID};
+use std::any::Any;
+use std::thread;
+use std::time::Duration;
+use tracing::warn;
+
+/// How long one client may block its thread before the next one is served.
+const SLICE: Duration = Duration::from_millis(50);
+
+/// Runs the event loops of several clients like [`Threema::run`], on a fixed number of
+/// threads.
+pub struct ClientPool {
+    threads: usize,
+    members: Vec<Member>,
+}
+
+struct Member {
+    client: Threema,
+    handler: Box<dyn Handler + Send>,
+    state: RunState,
+}
+
+impl Member {
+    fn connect(&mut self) -> crate::Result<()> {
+        if self.client.conn.is_none() {
+            self.client.connect()?;
+        }
+        Ok(())
+    }
+}
+
+impl ClientPool {
+    /// A pool using up to `threads` threads, at least one.
+    #[must_use]
+    pub fn new(threads: usize) -> Self {
+        Self {
+            threads: threads.max(1),
+            members: vec![],
+        }
+    }
+
+    /// Adds a client whose events are passed to `handler`.
+    pub fn add(&mut self, client: Threema, handler: impl Handler + Send + 'static) {
+        self.members.push(Member {
+            client,
+            handler: Box::new(handler),
+            state: RunState::default(),
+        });
+    }
+
+    /// Number of clients in the pool.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    /// Runs all clients until each one stopped, see [`Threema::run`].
+    ///
+    /// Returns the clients that stopped with an error: they either couldn't connect
+    /// initially or the server didn't allow reconnecting. If a handler panics, all
+    /// clients on its thread stop with [`Error::HandlerPanicked`].
+    #[must_use]
+    pub fn run(self) -> Vec<(ThreemaID, Error)> {
+        let threads = self.threads.min(self.members.len());
+        let mut groups: Vec<Vec<Member>> = (0..threads).map(|_| vec![]).collect();
+        for (i, member) in self.members.into_iter().enumerate() {
+            groups[i % threads].push(member);
+        }
+        let handles: Vec<_> = groups
+            .into_iter()
+            .map(|members| {
+                let ids: Vec<_> = members.iter().map(|m| m.client.id()).collect();
+                (ids, thread::spawn(move || run_group(members)))
+            })
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|(ids, handle)| {
+                handle.join().unwrap_or_else(|panic| {
+                    let message = panic_message(&*panic);
+                    warn!(%message, "Handler panicked");
+                    ids.into_iter()
+                        .map(|id| (id, Error::HandlerPanicked(message.clone())))
+                        .collect()
+                })
+            })
+            .collect()
+    }
+}
+
+/// The message `panic!` was called with, if any.
+fn panic_message(panic: &(dyn Any + Send)) -> String {
+    panic
+        .downcast_ref::<&str>()
+        .map(|s| (*s).to_owned())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_default()
+}
+
+/// Steps through `members` in turn until all are done.
+fn run_group(mut members: Vec<Member>) -> Vec<(ThreemaID, Error)> {
+    let mut failed = vec![];
+    members.retain_mut(|member| match member.connect() {
+        Ok(()) => true,
+        Err(e) => {
+            warn!(id = %member.client.id(), error = %e, "Couldn't connect");
+            failed.push((member.client.id(), e));
+            false
+        }
+    });
+    while !members.is_empty() {
+        members.retain_mut(|member| {
+            match member
+                .client
+                .step(&mut *member.handler, &mut member.state, Some(SLICE))
+            {
+                Ok(running) => running,
+                Err(e) => {
+                    failed.push((member.client.id(), e));
+                    false
+                }
+            }
+        });
+    }
+    failed
+}
@@ -1,69 +1,73 @@
-pub mod messages;
+// `ureq::Error` is large, but never stored or passed far
+#![allow(clippy::result_large_err)]
 
-use crate::Error;
-use crate::Result;
-use std::sync::Arc;
-use webpki::TrustAnchor;
+#[cfg(feature = "rest")]
+pub mod blob;
+#[cfg(feature = "rest")]
+mod client;
+pub mod messages;
 
-// from https://github.com/threema-ch/threema-android/blob/997fd7baacf314bb0238cca4912bd4d3d28b6886/app/src/main/java/ch/threema/client/ProtocolStrings.java
-const API: &str = "https://apip.threema.ch";
-const USER_AGENT: &str = "Threema";
+#[cfg(feature = "rest")]
+pub use client::{
+    add_trust_anchor, reset_trust_anchors, set_proxy, set_retry_policy, set_trust_anchors,
+    RetryPolicy,
+};
+#[cfg(feature = "rest")]
+pub(crate) use client::{
+    agent, get_text, post, post_form, post_json, proxy_agent, request_optional, Retry,
+};
+use std::fmt;
 
-include!(concat!(env!("OUT_DIR"), "/src/ca.rs"));
+/// Serializes the tests changing or depending on the process-wide settings.
+#[cfg(all(test, feature = "rest"))]
+pub(crate) static SETTINGS: std::sync::Mutex<()> = std::sync::Mutex::new(());
 
-impl From<serde_json::error::Error> for Error {
-    fn from(e: serde_json::error::Error) -> Self {
-        Self::ParseError(e.to_string())
-    }
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RestErrorKind {
+    /// The directory doesn't know the requested identity
+    UnknownIdentity,
+    NotFound,
+    BadRequest,
+    Unauthorized,
+    /// The gateway account is out of credits
+    PaymentRequired,
+    PayloadTooLarge,
+    RateLimited,
+    ServerError,
+    /// DNS, connection or TLS failure, no response was received
+    Transport,
+    Other,
 }
 
-impl From<ureq::Error> for Error {
-    fn from(_e: ureq::Error) -> Self {
-        Self::RequestError
-    }
+#[derive(Debug)]
+pub struct RestError {
+    pub status: Option<u16>,
+    /// Response body, or the transport error description if no response was received
+    pub body: Option<String>,
+    pub kind: RestErrorKind,
 }
 
-fn tls_config() -> Arc<rustls::ClientConfig> {
-    let mut root_store = rustls::RootCertStore::empty();
-    root_store.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
-        rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
-            ta.subject,
-            ta.spki,
-            ta.name_constraints,
-        )
-    }));
-    root_store.add_server_trust_anchors(webpki::TlsServerTrustAnchors(&THREEMA_CA).0.iter().map(
-        |ta| {
-            rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
-                ta.subject,
-                ta.spki,
-                ta.name_constraints,
-            )
-        },
-    ));
-    Arc::new(
-        rustls::ClientConfig::builder()
-            .with_safe_defaults()
-            .with_root_certificates(root_store)
-            .with_no_client_auth(),
-    )
+impl RestError {
+    #[cfg(feature = "rest")]
+    fn for_path(mut self, path: &str) -> Self {
+        if self.kind == RestErrorKind::NotFound && path.starts_with("/identity/") {
+            self.kind = RestErrorKind::UnknownIdentity;
+        }
+        self
+    }
 }
 
-fn agent() -> ureq::Agent {
-    ureq::AgentBuilder::new().tls_config(tls_config()).build()
+impl fmt::Display for RestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.kind)?;
+        if let Some(status) = self.status {
+            write!(f, " (HTTP {status})")?;
+        }
+        if let Some(body) = &self.body {
+            write!(f, ": {body}")?;
+        }
+        Ok(())
+    }
 }
 
-pub(crate) fn request<R>(path: &str) -> Result<R>
-where
-    R: serde::de::DeserializeOwned,
-{
-    let agent = agent();
-
-    let path = API.to_owned() + path;
-    let resp = agent
-        .get(&path)
-        .set("user-agent", USER_AGENT)
-        .set("accept", "application/json")
-        .call()?;
-    Ok(resp.into_json()?)
-}
+impl std::error::Error for RestError {}
@@ -0,0 +1,411 @@
+use super::client::{agent, with_retry, Retry, USER_AGENT};
+use crate::crypto::{self, Nonce, SecretboxStream};
+use crate::packets::File;
+use crate::servers::{self, ServerInfo};
+use crate::sources::{OsRng, RngSource};
+use crate::Error;
+use crate::Result;
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// Size of the chunks blobs are encrypted and decrypted in when streaming.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Largest blob [`download`] keeps in memory: the 100 MiB file limit of the apps, plus
+/// the MAC. Use [`download_to`] for anything else.
+pub const MAX_BLOB_SIZE: u64 = 100 * 1024 * 1024 + crypto::MACBYTES as u64;
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+pub struct BlobId([u8; 16]);
+
+impl BlobId {
+    #[must_use]
+    pub fn from_bytes(data: [u8; 16]) -> Self {
+        Self(data)
+    }
+
+    pub fn from_hex(s: &str) -> Result<Self> {
+        crate::decode_hex(s)
+            .map(Self)
+            .ok_or_else(|| Error::ParseError(format!("blob id: {s:?}")))
+    }
+
+    #[must_use]
+    pub fn as_bytes(&self) -> &[u8; 16] {
+        &self.0
+    }
+
+    fn url(self, template: &str) -> String {
+        ServerInfo::blob_url(template, &self.to_string())
+    }
+}
+
+impl fmt::Display for BlobId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for b in &self.0 {
+            write!(f, "{b:02x}")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for BlobId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("BlobId").field(&self.to_string()).finish()
+    }
+}
+
+/// Multipart envelope around an uploaded blob.
+struct Multipart {
+    boundary: String,
+}
+
+impl Multipart {
+    fn new() -> Self {
+        let mut boundary = [0u8; 16];
+        OsRng.fill(&mut boundary);
+        Self {
+            boundary: format!("{:032x}", u128::from_le_bytes(boundary)),
+        }
+    }
+
+    fn content_type(&self) -> String {
+        format!("multipart/form-data; boundary={}", self.boundary)
+    }
+
+    fn head(&self) -> Vec<u8> {
+        format!(
+            "--{}\r\n\
+             Content-Disposition: form-data; name=\"blob\"; filename=\"blob.bin\"\r\n\
+             Content-Type: application/octet-stream\r\n\r\n",
+            self.boundary
+        )
+        .into_bytes()
+    }
+
+    fn tail(&self) -> Vec<u8> {
+        format!("\r\n--{}--\r\n", self.boundary).into_bytes()
+    }
+}
+
+/// Uploads already encrypted `data` to the blob server.
+pub fn upload(data: &[u8]) -> Result<BlobId> {
+    upload_with(&agent(), data)
+}
+
+/// Like [`upload`], sending the request with `agent`.
+pub(crate) fn upload_with(agent: &ureq::Agent, data: &[u8]) -> Result<BlobId> {
+    let multipart = Multipart::new();
+    let body = [&multipart.head()[..], data, &multipart.tail()].concat();
+
+    let content_type = multipart.content_type();
+    let url = servers::current().blob_upload_url;
+    let resp = with_retry(&url, Retry::Never, || {
+        agent
+            .post(&url)
+            .set("user-agent", USER_AGENT)
+            .set("content-type", &content_type)
+            .send_bytes(&body)
+    })?;
+    BlobId::from_hex(resp.into_string()?.trim())
+}
+
+/// Encrypts the rest of `data` with `key` while uploading it, instead of keeping all of
+/// it in memory like [`upload`].
+///
+/// `data` is read twice, first to compute the MAC which is sent in front, and must not
+/// change in between. `progress` is called with the number of bytes sent so far and the
+/// total.
+pub fn upload_from<R: Read + Seek>(
+    data: &mut R,
+    nonce: &Nonce,
+    key: &[u8; crypto::KEYBYTES],
+    progress: impl FnMut(u64, u64),
+) -> Result<BlobId> {
+    upload_from_with(&agent(), data, nonce, key, progress)
+}
+
+/// Like [`upload_from`], sending the request with `agent`.
+pub(crate) fn upload_from_with<R: Read + Seek>(
+    agent: &ureq::Agent,
+    data: &mut R,
+    nonce: &Nonce,
+    key: &[u8; crypto::KEYBYTES],
+    mut progress: impl FnMut(u64, u64),
+) -> Result<BlobId> {
+    let start = data.stream_position()?;
+    let len = data.seek(SeekFrom::End(0))? - start;
+    data.seek(SeekFrom::Start(start))?;
+    let mut stream = SecretboxStream::new(nonce, key);
+    let mut buf = vec![0; CHUNK_SIZE];
+    loop {
+        let n = match data.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        stream.encrypt(&mut buf[..n]);
+    }
+    let multipart = Multipart::new();
+    let head = [&multipart.head()[..], &stream.mac()].concat();
+    let tail = multipart.tail();
+    let size = head.len() as u64 + len + tail.len() as u64;
+
+    let content_type = multipart.content_type();
+    let url = servers::current().blob_upload_url;
+    let resp = with_retry(&url, Retry::Never, || {
+        data.seek(SeekFrom::Start(start))?;
+        let body = Encrypting {
+            inner: (&mut *data).take(len),
+            stream: SecretboxStream::new(nonce, key),
+            done: 0,
+            total: len,
+            progress: &mut progress,
+        };
+        agent
+            .post(&url)
+            .set("user-agent", USER_AGENT)
+            .set("content-type", &content_type)
+            .set("content-length", &size.to_string())
+            .send(
+                io::Cursor::new(&head)
+                    .chain(body)
+                    .chain(io::Cursor::new(&tail)),
+            )
+    })?;
+    BlobId::from_hex(resp.into_string()?.trim())
+}
+
+/// Encrypts what's read from `inner`, reporting the progress.
+struct Encrypting<'a, R, P> {
+    inner: R,
+    stream: SecretboxStream,
+    done: u64,
+    total: u64,
+    progress: &'a mut P,
+}
+
+impl<R: Read, P: FnMut(u64, u64)> Read for Encrypting<'_, R, P> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.stream.encrypt(&mut buf[..n]);
+        self.done += n as u64;
+        (self.progress)(self.done, self.total);
+        Ok(n)
+    }
+}
+
+/// Downloads the (still encrypted) blob `id`, failing with [`Error::BlobTooLarge`] for
+/// more than [`MAX_BLOB_SIZE`] bytes.
+pub fn download(id: BlobId) -> Result<Vec<u8>> {
+    let agent = agent();
+    let url = id.url(&servers::current().blob_download_url);
+    let resp = with_retry(&url, Retry::Idempotent, || {
+        agent.get(&url).set("user-agent", USER_AGENT).call()
+    })?;
+    read_limited(resp.into_reader(), MAX_BLOB_SIZE)
+}
+
+/// Reads all of `reader`, unless it has more than `max` bytes.
+fn read_limited(reader: impl Read, max: u64) -> Result<Vec<u8>> {
+    let mut data = vec![];
+    reader.take(max + 1).read_to_end(&mut data)?;
+    if data.len() as u64 > max {
+        return Err(Error::BlobTooLarge { max });
+    }
+    Ok(data)
+}
+
+/// Downloads the blob `id` and decrypts it with `key` to `out` while receiving it,
+/// instead of keeping all of it in memory like [`download`]. Returns the size of the data.
+///
+/// The data can only be authenticated at the end, so everything written to `out` must be
+/// discarded on [`Error::BlobDecrypt`]. `progress` is called with the number of bytes
+/// received so far and the total, if the server announced it.
+pub fn download_to(
+    id: BlobId,
+    nonce: &Nonce,
+    key: &[u8; crypto::KEYBYTES],
+    out: &mut impl Write,
+    mut progress: impl FnMut(u64, Option<u64>),
+) -> Result<u64> {
+    let agent = agent();
+    let url = id.url(&servers::current().blob_download_url);
+    let resp = with_retry(&url, Retry::Idempotent, || {
+        agent.get(&url).set("user-agent", USER_AGENT).call()
+    })?;
+    let total = resp
+        .header("content-length")
+        .and_then(|len| len.parse::<u64>().ok())
+        .map(|len| len.saturating_sub(crypto::MACBYTES as u64));
+    decrypt_to(&mut resp.into_reader(), nonce, key, out, |done| {
+        progress(done, total);
+    })
+}
+
+/// Downloads the data of a received `file` to `out`, see [`download_to`].
+pub fn download_file(
+    file: &File,
+    out: &mut impl Write,
+    progress: impl FnMut(u64, Option<u64>),
+) -> Result<u64> {
+    let key = file
+        .encryption_key()
+        .ok_or_else(|| Error::ParseError("file encryption key".to_owned()))?;
+    let id = BlobId::from_hex(file.blob_id())?;
+    download_to(id, &File::DATA_NONCE, &key, out, progress)
+}
+
+/// Decrypts the secret box read from `data` to `out` chunk by chunk.
+fn decrypt_to(
+    data: &mut impl Read,
+    nonce: &Nonce,
+    key: &[u8; crypto::KEYBYTES],
+    out: &mut impl Write,
+    mut progress: impl FnMut(u64),
+) -> Result<u64> {
+    let mut mac = [0; crypto::MACBYTES];
+    data.read_exact(&mut mac).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => Error::BlobDecrypt,
+        _ => e.into(),
+    })?;
+    let mut stream = SecretboxStream::new(nonce, key);
+    let mut buf = vec![0; CHUNK_SIZE];
+    let mut done = 0;
+    loop {
+        let n = match data.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        stream.decrypt(&mut buf[..n]);
+        out.write_all(&buf[..n])?;
+        done += n as u64;
+        progress(done);
+    }
+    if stream.verify(&mac) {
+        Ok(done)
+    } else {
+        Err(Error::BlobDecrypt)
+    }
+}
+
+/// Tells the blob server that `id` was downloaded and can be deleted.
+pub fn mark_done(id: BlobId) -> Result<()> {
+    let agent = agent();
+    let url = id.url(&servers::current().blob_done_url);
+    with_retry(&url, Retry::Idempotent, || {
+        agent.post(&url).set("user-agent", USER_AGENT).call()
+    })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blob_ids() {
+        let hex = "0123456789abcdef0123456789abcdef";
+        let id = BlobId::from_hex(hex).unwrap();
+        assert_eq!(id.to_string(), hex);
+        assert_eq!(BlobId::from_hex(&id.to_string()).unwrap(), id);
+        assert_eq!(
+            BlobId::from_hex("0123456789ABCDEF0123456789ABCDEF").unwrap(),
+            id
+        );
+        assert_eq!(
+            BlobId::from_bytes(*id.as_bytes()).as_bytes()[..2],
+            [0x01, 0x23]
+        );
+        assert_eq!(format!("{id:?}"), format!("BlobId(\"{hex}\")"));
+        for invalid in [
+            "",
+            "0123",
+            &hex[1..],
+            &format!("{hex}00"),
+            "xx23456789abcdef0123456789abcdef",
+        ] {
+            assert!(matches!(
+                BlobId::from_hex(invalid),
+                Err(Error::ParseError(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn blob_urls() {
+        let id = BlobId::from_bytes([0xab; 16]);
+        let info = ServerInfo::default();
+        assert_eq!(
+            id.url(&info.blob_download_url),
+            format!("https://blobp-ab.threema.ch/{id}")
+        );
+        assert_eq!(
+            id.url(&info.blob_done_url),
+            format!("https://blobp-ab.threema.ch/{id}/done")
+        );
+        assert_eq!(
+            id.url("https://blob.example.com/{blobIdPrefix}/{blobId}"),
+            format!("https://blob.example.com/ab/{id}")
+        );
+    }
+
+    #[test]
+    fn size_limit() {
+        let data = [7; 10];
+        assert_eq!(read_limited(&data[..], 10).unwrap(), data);
+        assert!(matches!(
+            read_limited(&data[..], 9),
+            Err(Error::BlobTooLarge { max: 9 })
+        ));
+    }
+
+    #[test]
+    fn streaming() {
+        let data: Vec<u8> = (0..=255).cycle().take(CHUNK_SIZE * 2 + 7).collect();
+        let nonce = Nonce([1; 24]);
+        let key = [2; crypto::KEYBYTES];
+        let sealed = crypto::secretbox_seal(&data, &nonce, &key);
+
+        let mut progress = vec![];
+        let mut encrypted = vec![];
+        Encrypting {
+            inner: &data[..],
+            stream: SecretboxStream::new(&nonce, &key),
+            done: 0,
+            total: data.len() as u64,
+            progress: &mut |done, total| progress.push((done, total)),
+        }
+        .read_to_end(&mut encrypted)
+        .unwrap();
+        assert_eq!(encrypted, sealed[crypto::MACBYTES..]);
+        assert_eq!(
+            progress.last(),
+            Some(&(data.len() as u64, data.len() as u64))
+        );
+
+        let mut out = vec![];
+        let mut received = 0;
+        let size = decrypt_to(&mut &sealed[..], &nonce, &key, &mut out, |done| {
+            received = done;
+        })
+        .unwrap();
+        assert_eq!(size, data.len() as u64);
+        assert_eq!(received, size);
+        assert_eq!(out, data);
+
+        let mut tampered = sealed.clone();
+        tampered[CHUNK_SIZE] ^= 1;
+        assert!(matches!(
+            decrypt_to(&mut &tampered[..], &nonce, &key, &mut vec![], |_| {}),
+            Err(Error::BlobDecrypt)
+        ));
+        assert!(matches!(
+            decrypt_to(&mut &sealed[..10], &nonce, &key, &mut vec![], |_| {}),
+            Err(Error::BlobDecrypt)
+        ));
+    }
+}
@@ -49,4 +49,53 @@ impl From<Vec<u8>> for Bytes {
 pub struct GetPubKeyResponse {
     pub identity: String,
     pub public_key: Bytes,
+    #[serde(default)]
+    pub state: u8,
+    #[serde(default)]
+    pub feature_mask: u64,
+}
+
+#[derive(Default, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MatchRequest {
+    pub email_hashes: Vec<Bytes>,
+    pub mobile_no_hashes: Vec<Bytes>,
+}
+
+#[derive(Default, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MatchResponseEntry {
+    pub identity: String,
+    pub public_key: Bytes,
+}
+
+#[derive(Default, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreateIdentityRequest {
+    pub public_key: Bytes,
+    /// The remaining fields are only sent in the second step, answering the challenge
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<Bytes>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response: Option<Bytes>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<Bytes>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub license_key: Option<String>,
+}
+
+#[derive(Default, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreateIdentityChallenge {
+    pub token: Bytes,
+    pub token_resp_key_pub: Bytes,
+}
+
+#[derive(Default, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreateIdentityResponse {
+    #[serde(default)]
+    pub success: bool,
+    pub identity: Option<String>,
+    pub error: Option<String>,
 }
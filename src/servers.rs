@@ -0,0 +1,292 @@
+//! Addresses of the chat, directory and blob servers.
+//!
+//! Defaults to the public Threema servers, but can be replaced by the server info
+//! published by a provisioning endpoint (e.g. the OPPF file of an on-premises deployment).
+
+use crate::crypto::PublicKey;
+#[cfg(feature = "rest")]
+use crate::{rest, Error, Result};
+#[cfg(feature = "rest")]
+use serde::Deserialize;
+use std::sync::{PoisonError, RwLock};
+use std::time::{Duration, Instant};
+
+// https://github.com/threema-ch/threema-android/blob/329b33d7bace99f5078ff08ef996a27c628be6e5/app/build.gradle#L91-L93
+const CHAT_SERVER: &str = "g-33.0.threema.ch";
+const CHAT_PORT: u16 = 5222;
+// https://github.com/threema-ch/threema-android/blob/329b33d7bace99f5078ff08ef996a27c628be6e5/app/build.gradle#L98
+const CHAT_SERVER_PUBKEY: [u8; 32] = [
+    69, 11, 151, 87, 53, 39, 159, 222, 203, 51, 19, 100, 143, 95, 198, 238, 159, 244, 54, 14, 169,
+    42, 140, 23, 81, 198, 97, 228, 192, 216, 201, 9,
+];
+// from https://github.com/threema-ch/threema-android/blob/997fd7baacf314bb0238cca4912bd4d3d28b6886/app/src/main/java/ch/threema/client/ProtocolStrings.java
+const DIRECTORY_URL: &str = "https://apip.threema.ch";
+const BLOB_UPLOAD_URL: &str = "https://blobp-upload.threema.ch/upload";
+const BLOB_DOWNLOAD_URL: &str = "https://blobp-{blobIdPrefix}.threema.ch/{blobId}";
+const BLOB_DONE_URL: &str = "https://blobp-{blobIdPrefix}.threema.ch/{blobId}/done";
+
+// https://github.com/threema-ch/threema-android/blob/329b33d7bace99f5078ff08ef996a27c628be6e5/app/build.gradle
+// (ONPREM_CONFIG_TRUSTED_PUBLIC_KEYS)
+#[cfg(feature = "rest")]
+const OPPF_PUBLIC_KEYS: [&str; 2] = [
+    "ek1qBp4DyRmLL9J5sCmsKSfwbsiGNB4veDAODjkwe/k=",
+    "Hrk8aCjwKkXySubI7CZ3y9Sx+oToEHjNkGw98WSRneU=",
+];
+
+/// How long fetched server info is used before it is fetched again.
+#[cfg(feature = "rest")]
+const DEFAULT_REFRESH: Duration = Duration::from_hours(24);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerInfo {
+    pub chat_host: String,
+    pub chat_ports: Vec<u16>,
+    /// Long term public key of the chat server
+    pub chat_public_key: PublicKey,
+    /// Base URL of the directory API, without trailing slash
+    pub directory_url: String,
+    pub blob_upload_url: String,
+    /// URL template containing `{blobId}` and optionally `{blobIdPrefix}`
+    pub blob_download_url: String,
+    /// URL template containing `{blobId}` and optionally `{blobIdPrefix}`
+    pub blob_done_url: String,
+}
+
+impl Default for ServerInfo {
+    fn default() -> Self {
+        Self {
+            chat_host: CHAT_SERVER.to_owned(),
+            chat_ports: vec![CHAT_PORT],
+            chat_public_key: PublicKey(CHAT_SERVER_PUBKEY),
+            directory_url: DIRECTORY_URL.to_owned(),
+            blob_upload_url: BLOB_UPLOAD_URL.to_owned(),
+            blob_download_url: BLOB_DOWNLOAD_URL.to_owned(),
+            blob_done_url: BLOB_DONE_URL.to_owned(),
+        }
+    }
+}
+
+impl ServerInfo {
+    /// Addresses to try when connecting to the chat server, in order.
+    #[must_use]
+    pub fn chat_addresses(&self) -> Vec<(String, u16)> {
+        self.chat_ports
+            .iter()
+            .map(|port| (self.chat_host.clone(), *port))
+            .collect()
+    }
+
+    #[cfg(feature = "rest")]
+    pub(crate) fn blob_url(template: &str, blob_id: &str) -> String {
+        template
+            .replace("{blobIdPrefix}", &blob_id[..2.min(blob_id.len())])
+            .replace("{blobId}", blob_id)
+    }
+}
+
+#[cfg(feature = "rest")]
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ProvisionedChat {
+    hostname: String,
+    ports: Vec<u16>,
+    public_key: rest::messages::Bytes,
+}
+
+#[cfg(feature = "rest")]
+#[derive(Deserialize)]
+struct ProvisionedUrl {
+    url: String,
+}
+
+#[cfg(feature = "rest")]
+#[derive(Deserialize)]
+struct ProvisionedBlob {
+    #[serde(rename = "uploadUrl")]
+    upload: String,
+    #[serde(rename = "downloadUrl")]
+    download: String,
+    #[serde(rename = "doneUrl")]
+    done: String,
+}
+
+#[cfg(feature = "rest")]
+#[derive(Deserialize)]
+struct Provisioning {
+    /// seconds
+    refresh: Option<u64>,
+    chat: ProvisionedChat,
+    directory: ProvisionedUrl,
+    blob: ProvisionedBlob,
+}
+
+#[cfg_attr(not(feature = "rest"), allow(dead_code))]
+struct Cached {
+    info: ServerInfo,
+    source: Option<String>,
+    /// `None` if set explicitly, which avoids reading the clock where there's none
+    fetched: Option<Instant>,
+    refresh: Duration,
+}
+
+static SERVER_INFO: RwLock<Option<Cached>> = RwLock::new(None);
+
+/// Fetches the server info from the provisioning endpoint `url`.
+///
+/// The response is an OPPF file: the JSON, followed by a line with its base64 encoded
+/// Ed25519 signature. Files not signed by one of the keys pinned by the Threema apps are
+/// rejected with [`Error::InvalidServerInfoSignature`].
+#[cfg(feature = "rest")]
+pub fn fetch(url: &str) -> Result<(ServerInfo, Duration)> {
+    let resp = rest::get_text(url, &[])?;
+    let keys = OPPF_PUBLIC_KEYS
+        .iter()
+        .filter_map(|key| base64::decode(key).ok())
+        .collect::<Vec<_>>();
+    parse(&resp, &keys)
+}
+
+/// Returns the JSON of the OPPF file `oppf` if it is signed by one of `keys`.
+#[cfg(feature = "rest")]
+fn verify<'a>(oppf: &'a str, keys: &[Vec<u8>]) -> Result<&'a str> {
+    use ring::signature::{UnparsedPublicKey, ED25519};
+
+    let (json, signature) = oppf
+        .trim_end()
+        .rsplit_once('\n')
+        .ok_or(Error::InvalidServerInfoSignature)?;
+    let signature =
+        base64::decode(signature.trim()).map_err(|_| Error::InvalidServerInfoSignature)?;
+    if keys.iter().any(|key| {
+        UnparsedPublicKey::new(&ED25519, key)
+            .verify(json.as_bytes(), &signature)
+            .is_ok()
+    }) {
+        Ok(json)
+    } else {
+        Err(Error::InvalidServerInfoSignature)
+    }
+}
+
+#[cfg(feature = "rest")]
+fn parse(oppf: &str, keys: &[Vec<u8>]) -> Result<(ServerInfo, Duration)> {
+    let prov: Provisioning = serde_json::from_str(verify(oppf, keys)?)?;
+    let info = ServerInfo {
+        chat_host: prov.chat.hostname,
+        chat_ports: prov.chat.ports,
+        chat_public_key: PublicKey::from_slice(prov.chat.public_key.as_ref())
+            .ok_or(Error::InvalidPublicKey)?,
+        directory_url: prov.directory.url.trim_end_matches('/').to_owned(),
+        blob_upload_url: prov.blob.upload,
+        blob_download_url: prov.blob.download,
+        blob_done_url: prov.blob.done,
+    };
+    let refresh = prov.refresh.map_or(DEFAULT_REFRESH, Duration::from_secs);
+    Ok((info, refresh))
+}
+
+/// Uses the server info from the provisioning endpoint `url`, unless it was already
+/// fetched from there and is still fresh.
+#[cfg(feature = "rest")]
+pub fn refresh(url: &str) -> Result<()> {
+    {
+        let cached = SERVER_INFO.read().unwrap_or_else(PoisonError::into_inner);
+        if let Some(cached) = cached.as_ref() {
+            if cached.source.as_deref() == Some(url)
+                && cached.fetched.is_some_and(|t| t.elapsed() < cached.refresh)
+            {
+                return Ok(());
+            }
+        }
+    }
+    let (info, refresh) = fetch(url)?;
+    *SERVER_INFO.write().unwrap_or_else(PoisonError::into_inner) = Some(Cached {
+        info,
+        source: Some(url.to_owned()),
+        fetched: Some(Instant::now()),
+        refresh,
+    });
+    Ok(())
+}
+
+/// Uses `info` instead of the default servers.
+pub fn set(info: ServerInfo) {
+    *SERVER_INFO.write().unwrap_or_else(PoisonError::into_inner) = Some(Cached {
+        info,
+        source: None,
+        fetched: None,
+        refresh: Duration::MAX,
+    });
+}
+
+/// Returns the current server info, falling back to the public Threema servers.
+#[must_use]
+pub fn current() -> ServerInfo {
+    SERVER_INFO
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .as_ref()
+        .map(|cached| cached.info.clone())
+        .unwrap_or_default()
+}
+
+#[cfg(all(test, feature = "rest"))]
+mod tests {
+    use super::*;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    const OPPF: &str = r#"{
+    "refresh": 3600,
+    "chat": {"hostname": "chat.example.com", "ports": [5222, 443], "publicKey": "RQuXVzUnn97LMxNkj1/G7p/0Ng6pKowXUcZh5MDYyQk="},
+    "directory": {"url": "https://directory.example.com/"},
+    "blob": {
+        "uploadUrl": "https://blob.example.com/upload",
+        "downloadUrl": "https://blob.example.com/{blobId}",
+        "doneUrl": "https://blob.example.com/{blobId}/done"
+    }
+}"#;
+
+    fn signed(key: &Ed25519KeyPair, json: &str) -> String {
+        format!("{json}\n{}\n", base64::encode(key.sign(json.as_bytes())))
+    }
+
+    #[test]
+    fn signature() {
+        let key = Ed25519KeyPair::from_seed_unchecked(&[7; 32]).unwrap();
+        let keys = [key.public_key().as_ref().to_vec()];
+
+        let (info, refresh) = parse(&signed(&key, OPPF), &keys).unwrap();
+        assert_eq!(info.chat_public_key, PublicKey(CHAT_SERVER_PUBKEY));
+        assert_eq!(info.directory_url, "https://directory.example.com");
+        assert_eq!(refresh, Duration::from_hours(1));
+
+        let tampered = signed(&key, OPPF).replace("chat.example.com", "evil.example.com");
+        assert!(matches!(
+            parse(&tampered, &keys),
+            Err(Error::InvalidServerInfoSignature)
+        ));
+        assert!(matches!(
+            parse(OPPF, &keys),
+            Err(Error::InvalidServerInfoSignature)
+        ));
+        // only the pinned keys are trusted
+        let pinned = OPPF_PUBLIC_KEYS
+            .iter()
+            .map(|key| base64::decode(key).unwrap())
+            .collect::<Vec<_>>();
+        assert!(pinned.iter().all(|key| key.len() == 32));
+        assert!(matches!(
+            parse(&signed(&key, OPPF), &pinned),
+            Err(Error::InvalidServerInfoSignature)
+        ));
+    }
+
+    #[test]
+    fn blob_url() {
+        let info = ServerInfo::default();
+        assert_eq!(
+            ServerInfo::blob_url(&info.blob_done_url, "0123456789abcdef0123456789abcdef"),
+            "https://blobp-01.threema.ch/0123456789abcdef0123456789abcdef/done"
+        );
+    }
+}
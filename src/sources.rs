@@ -0,0 +1,112 @@
+//! Sources of randomness and time used by [`Threema`](crate::Threema).
+//!
+//! Default to the OS random number generator and the system clock; tests can replace them
+//! with [`SeededRng`] and [`FixedClock`] to get reproducible nonces, message IDs, padding
+//! and timestamps.
+
+use crate::crypto;
+use std::time::SystemTime;
+
+/// Random bytes for nonces, keys, message IDs and padding.
+pub trait RngSource: Send {
+    fn fill(&mut self, buf: &mut [u8]);
+
+    /// Uniformly distributed number in `0..upper_bound`, or 0 if `upper_bound` is below 2.
+    fn uniform(&mut self, upper_bound: u32) -> u32 {
+        if upper_bound < 2 {
+            return 0;
+        }
+        // reject the values which would make the modulo biased, like libsodium does
+        let min = upper_bound.wrapping_neg() % upper_bound;
+        loop {
+            let mut buf = [0u8; 4];
+            self.fill(&mut buf);
+            let r = u32::from_le_bytes(buf);
+            if r >= min {
+                return r % upper_bound;
+            }
+        }
+    }
+}
+
+/// Current time, used for message timestamps.
+pub trait Clock: Send {
+    fn now(&self) -> SystemTime;
+}
+
+/// Cryptographically secure randomness from the operating system, via the
+/// [crypto backend](crate::crypto).
+#[derive(Debug, Default, Clone, Copy)]
+pub struct OsRng;
+
+impl RngSource for OsRng {
+    fn fill(&mut self, buf: &mut [u8]) {
+        crypto::random_bytes(buf);
+    }
+}
+
+/// Reproducible stream of bytes derived from a seed. Only meant for tests.
+#[derive(Debug, Clone)]
+pub struct SeededRng {
+    seed: [u8; crypto::SEEDBYTES],
+    counter: u64,
+}
+
+impl SeededRng {
+    #[must_use]
+    pub fn new(seed: [u8; crypto::SEEDBYTES]) -> Self {
+        Self { seed, counter: 0 }
+    }
+}
+
+impl RngSource for SeededRng {
+    fn fill(&mut self, buf: &mut [u8]) {
+        let mut seed = self.seed;
+        for (s, c) in seed.iter_mut().zip(self.counter.to_le_bytes()) {
+            *s ^= c;
+        }
+        self.counter += 1;
+        crypto::deterministic_bytes(buf, &seed);
+    }
+}
+
+/// The system clock.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// Clock which always returns the same time.
+#[derive(Debug, Clone, Copy)]
+pub struct FixedClock(pub SystemTime);
+
+impl Clock for FixedClock {
+    fn now(&self) -> SystemTime {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seeded() {
+        let mut a = SeededRng::new([7; 32]);
+        let mut b = SeededRng::new([7; 32]);
+        let (mut x, mut y) = ([0u8; 16], [0u8; 16]);
+        a.fill(&mut x);
+        b.fill(&mut y);
+        assert_eq!(x, y);
+        a.fill(&mut x);
+        assert_ne!(x, y);
+        for _ in 0..100 {
+            assert!(a.uniform(32) < 32);
+        }
+        assert_eq!(a.uniform(1), 0);
+    }
+}
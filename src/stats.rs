@@ -0,0 +1,72 @@
+//! Reports to the `metrics` facade, no-ops without the `metrics` feature.
+//!
+//! See [`Threema`](crate::Threema) for the metrics and their meaning.
+
+#[cfg(feature = "metrics")]
+use ::metrics::{counter, gauge, histogram};
+use std::time::Duration;
+
+pub(crate) fn message_sent(kind: &'static str) {
+    #[cfg(feature = "metrics")]
+    counter!("threema_messages_sent_total", "type" => kind).increment(1);
+    #[cfg(not(feature = "metrics"))]
+    let _ = kind;
+}
+
+pub(crate) fn message_received(kind: &'static str) {
+    #[cfg(feature = "metrics")]
+    counter!("threema_messages_received_total", "type" => kind).increment(1);
+    #[cfg(not(feature = "metrics"))]
+    let _ = kind;
+}
+
+pub(crate) fn ack_sent() {
+    #[cfg(feature = "metrics")]
+    counter!("threema_acks_sent_total").increment(1);
+}
+
+pub(crate) fn ack_received() {
+    #[cfg(feature = "metrics")]
+    counter!("threema_acks_received_total").increment(1);
+}
+
+pub(crate) fn reconnect(ok: bool) {
+    #[cfg(feature = "metrics")]
+    counter!("threema_reconnects_total", "result" => if ok { "ok" } else { "error" }).increment(1);
+    #[cfg(not(feature = "metrics"))]
+    let _ = ok;
+}
+
+pub(crate) fn handshake(duration: Duration) {
+    #[cfg(feature = "metrics")]
+    histogram!("threema_handshake_duration_seconds").record(duration);
+    #[cfg(not(feature = "metrics"))]
+    let _ = duration;
+}
+
+pub(crate) fn ping(rtt: Duration) {
+    #[cfg(feature = "metrics")]
+    histogram!("threema_ping_duration_seconds").record(rtt);
+    #[cfg(not(feature = "metrics"))]
+    let _ = rtt;
+}
+
+#[cfg(feature = "rest")]
+pub(crate) fn rest_request(duration: Duration, ok: bool) {
+    #[cfg(feature = "metrics")]
+    histogram!(
+        "threema_rest_request_duration_seconds",
+        "result" => if ok { "ok" } else { "error" }
+    )
+    .record(duration);
+    #[cfg(not(feature = "metrics"))]
+    let _ = (duration, ok);
+}
+
+pub(crate) fn outbox_size(size: usize) {
+    #[cfg(feature = "metrics")]
+    #[allow(clippy::cast_precision_loss)]
+    gauge!("threema_outbox_messages").set(size as f64);
+    #[cfg(not(feature = "metrics"))]
+    let _ = size;
+}
@@ -0,0 +1,83 @@
+//! Introspection of the connection to the chat server, see
+//! [`Threema::status`](crate::Threema::status).
+//!
+//! Meant for supervisors deciding whether a connection is still worth keeping, e.g. one
+//! which hasn't received a frame for a while or leaves many messages unacknowledged.
+
+use crate::{MessageID, ThreemaID};
+use std::collections::HashSet;
+use std::time::{Duration, SystemTime};
+
+/// Snapshot of the client's connection.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Status {
+    /// `host:port` of the chat server, unknown for transports passed to
+    /// [`Threema::connect_with`](crate::Threema::connect_with)
+    pub server: Option<String>,
+    /// When the handshake of the current connection completed
+    pub connected_since: Option<SystemTime>,
+    /// When the last frame of the current connection arrived
+    pub last_frame: Option<SystemTime>,
+    /// Messages put on the wire, over all connections and including resent ones
+    pub messages_sent: u64,
+    /// Messages the server delivered, over all connections
+    pub messages_received: u64,
+    /// Messages sent on the current connection which the server hasn't acknowledged yet
+    pub pending_acks: usize,
+    /// Counter of the nonce of the next frame the client sends
+    pub client_nonce: Option<u64>,
+    /// Counter of the nonce of the next frame the server sends
+    pub server_nonce: Option<u64>,
+}
+
+impl Status {
+    #[must_use]
+    pub fn is_connected(&self) -> bool {
+        self.connected_since.is_some()
+    }
+
+    /// Time since the last frame, or since connecting if none arrived yet. `None` while
+    /// disconnected.
+    #[must_use]
+    pub fn idle(&self, now: SystemTime) -> Option<Duration> {
+        let since = self.last_frame.or(self.connected_since)?;
+        Some(now.duration_since(since).unwrap_or_default())
+    }
+}
+
+/// What [`Status`] reports about the current connection, reset when it's closed.
+#[derive(Debug, Default)]
+pub(crate) struct Session {
+    pub(crate) server: Option<String>,
+    pub(crate) connected_since: Option<SystemTime>,
+    pub(crate) last_frame: Option<SystemTime>,
+    /// messages sent without an ack from the server so far
+    pub(crate) unacked: HashSet<(ThreemaID, MessageID)>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn idle() {
+        let start = UNIX_EPOCH + Duration::from_secs(100);
+        let mut status = Status::default();
+        assert!(!status.is_connected());
+        assert_eq!(status.idle(start), None);
+        status.connected_since = Some(start);
+        assert!(status.is_connected());
+        assert_eq!(
+            status.idle(start + Duration::from_secs(5)),
+            Some(Duration::from_secs(5))
+        );
+        status.last_frame = Some(start + Duration::from_secs(3));
+        assert_eq!(
+            status.idle(start + Duration::from_secs(5)),
+            Some(Duration::from_secs(2))
+        );
+        // clocks may go backwards
+        assert_eq!(status.idle(start), Some(Duration::ZERO));
+    }
+}
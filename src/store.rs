@@ -0,0 +1,648 @@
+//! History of sent and received messages, updated by [`Threema`](crate::Threema) if
+//! configured with [`ThreemaBuilder::message_store`](crate::ThreemaBuilder::message_store).
+//!
+//! Typing notifications and receipts aren't stored as messages, receipts update the
+//! [state](DeliveryState) of the message they refer to instead.
+
+use crate::packets::{Message, MessageStatus};
+use crate::{MessageID, Result, ThreemaID};
+use flat_bytes::Flat;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, SystemTime};
+
+/// Replaces `path` by `data`, so readers never see a partially written file, not even
+/// after a crash: the data is synced to `<path>.tmp` first, which is then renamed.
+pub fn write_atomically(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    let mut file = fs::File::create(&tmp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)
+}
+
+/// How far a message got.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliveryState {
+    /// Outgoing message waiting in the [outbox](crate::outbox) for a connection
+    Queued,
+    /// Outgoing message written to the connection
+    Sent,
+    /// Outgoing message accepted by the server
+    Acked,
+    /// Incoming message which wasn't confirmed yet
+    Received,
+    Delivered,
+    Read,
+    Approved,
+    Disapproved,
+}
+
+impl DeliveryState {
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Queued => "queued",
+            Self::Sent => "sent",
+            Self::Acked => "acked",
+            Self::Received => "received",
+            Self::Delivered => "delivered",
+            Self::Read => "read",
+            Self::Approved => "approved",
+            Self::Disapproved => "disapproved",
+        }
+    }
+
+    #[must_use]
+    pub fn parse(s: &str) -> Option<Self> {
+        Some(match s {
+            "queued" => Self::Queued,
+            "sent" => Self::Sent,
+            "acked" => Self::Acked,
+            "received" => Self::Received,
+            "delivered" => Self::Delivered,
+            "read" => Self::Read,
+            "approved" => Self::Approved,
+            "disapproved" => Self::Disapproved,
+            _ => return None,
+        })
+    }
+}
+
+impl From<&MessageStatus> for DeliveryState {
+    fn from(status: &MessageStatus) -> Self {
+        match status {
+            MessageStatus::Delivered => Self::Delivered,
+            MessageStatus::Read => Self::Read,
+            MessageStatus::Approved => Self::Approved,
+            MessageStatus::Disapproved => Self::Disapproved,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredMessage {
+    pub sender: ThreemaID,
+    pub receiver: ThreemaID,
+    pub msg_id: MessageID,
+    /// The serialized [`Message`], starting with its type
+    pub body: Vec<u8>,
+    pub state: DeliveryState,
+    /// When the message was sent, according to its sender
+    pub timestamp: SystemTime,
+    /// Last change of `state`
+    pub updated: SystemTime,
+}
+
+impl StoredMessage {
+    /// Type of the message, e.g. 1 for text.
+    #[must_use]
+    pub fn kind(&self) -> u8 {
+        self.body.first().copied().unwrap_or_default()
+    }
+
+    pub fn message(&self) -> Result<Message> {
+        Ok(Message::try_deserialize(&self.body)?)
+    }
+}
+
+/// Storage of the message history, see [`MemoryMessageStore`] and `SqliteMessageStore`
+/// (requires the `sqlite` feature).
+///
+/// Messages are identified by their sender and ID.
+pub trait MessageStore: Send {
+    /// Adds `msg`, replacing a message with the same sender and ID.
+    fn insert(&mut self, msg: StoredMessage) -> Result<()>;
+    /// Updates the state of a message, ignoring unknown messages.
+    fn set_state(
+        &mut self,
+        sender: ThreemaID,
+        msg_id: MessageID,
+        state: DeliveryState,
+        at: SystemTime,
+    ) -> Result<()>;
+    fn get(&self, sender: ThreemaID, msg_id: MessageID) -> Result<Option<StoredMessage>>;
+    /// The last `limit` messages sent to or received from `peer`, oldest first.
+    fn conversation(&self, peer: ThreemaID, limit: usize) -> Result<Vec<StoredMessage>>;
+    /// Notes an attempt to reconnect after the connection was lost, if the store keeps
+    /// them for [statistics](HistoryStats).
+    fn record_reconnect(&mut self, at: SystemTime, ok: bool) -> Result<()> {
+        let _ = (at, ok);
+        Ok(())
+    }
+}
+
+/// Messages exchanged with one peer, see [`HistoryStats`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerStats {
+    pub peer: ThreemaID,
+    pub sent: u64,
+    pub received: u64,
+}
+
+/// Summary of a history, see `SqliteMessageStore::stats` (requires the `sqlite`
+/// feature).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HistoryStats {
+    /// The peers, those with the most messages first
+    pub peers: Vec<PeerStats>,
+    /// Number of messages of each [kind](Message::kind)
+    pub kinds: BTreeMap<&'static str, u64>,
+    /// Number of messages sent on each day, counted in days since the Unix epoch (UTC)
+    pub days: BTreeMap<u64, u64>,
+    /// Number of sent messages acknowledged by the server
+    pub acked: u64,
+    /// Sum of the times from creating these messages until their acknowledgement,
+    /// including any time spent in the outbox
+    pub ack_time: Duration,
+    pub reconnects: u64,
+    pub failed_reconnects: u64,
+}
+
+impl HistoryStats {
+    /// Average time until the server acknowledged a sent message.
+    #[must_use]
+    pub fn ack_latency(&self) -> Option<Duration> {
+        let acked = u32::try_from(self.acked).ok().filter(|acked| *acked > 0)?;
+        Some(self.ack_time / acked)
+    }
+
+    /// Counts `msg`, sent by `own` if it's the sender, and acknowledged at `acked`.
+    #[cfg(feature = "sqlite")]
+    fn add(&mut self, own: ThreemaID, msg: &StoredMessage, acked: Option<SystemTime>) {
+        let outgoing = msg.sender == own;
+        let peer = if outgoing { msg.receiver } else { msg.sender };
+        let index = self
+            .peers
+            .iter()
+            .position(|stats| stats.peer == peer)
+            .unwrap_or_else(|| {
+                self.peers.push(PeerStats {
+                    peer,
+                    sent: 0,
+                    received: 0,
+                });
+                self.peers.len() - 1
+            });
+        if outgoing {
+            self.peers[index].sent += 1;
+        } else {
+            self.peers[index].received += 1;
+        }
+        let kind = msg.message().map_or("unknown", |msg| msg.kind());
+        *self.kinds.entry(kind).or_default() += 1;
+        let secs = msg
+            .timestamp
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        *self.days.entry(secs / 86_400).or_default() += 1;
+        if let (true, Some(acked)) = (outgoing, acked) {
+            self.acked += 1;
+            self.ack_time += acked.duration_since(msg.timestamp).unwrap_or_default();
+        }
+    }
+}
+
+/// Keeps the history of several clients in one store.
+impl<S: MessageStore + ?Sized> MessageStore for Arc<Mutex<S>> {
+    fn insert(&mut self, msg: StoredMessage) -> Result<()> {
+        self.lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(msg)
+    }
+
+    fn set_state(
+        &mut self,
+        sender: ThreemaID,
+        msg_id: MessageID,
+        state: DeliveryState,
+        at: SystemTime,
+    ) -> Result<()> {
+        self.lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .set_state(sender, msg_id, state, at)
+    }
+
+    fn get(&self, sender: ThreemaID, msg_id: MessageID) -> Result<Option<StoredMessage>> {
+        self.lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(sender, msg_id)
+    }
+
+    fn conversation(&self, peer: ThreemaID, limit: usize) -> Result<Vec<StoredMessage>> {
+        self.lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .conversation(peer, limit)
+    }
+
+    fn record_reconnect(&mut self, at: SystemTime, ok: bool) -> Result<()> {
+        self.lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .record_reconnect(at, ok)
+    }
+}
+
+/// Keeps the history for the lifetime of the process.
+#[derive(Debug, Default)]
+pub struct MemoryMessageStore {
+    messages: Vec<StoredMessage>,
+}
+
+impl MemoryMessageStore {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl MessageStore for MemoryMessageStore {
+    fn insert(&mut self, msg: StoredMessage) -> Result<()> {
+        self.messages
+            .retain(|m| (m.sender, m.msg_id) != (msg.sender, msg.msg_id));
+        self.messages.push(msg);
+        Ok(())
+    }
+
+    fn set_state(
+        &mut self,
+        sender: ThreemaID,
+        msg_id: MessageID,
+        state: DeliveryState,
+        at: SystemTime,
+    ) -> Result<()> {
+        if let Some(msg) = self
+            .messages
+            .iter_mut()
+            .find(|m| (m.sender, m.msg_id) == (sender, msg_id))
+        {
+            msg.state = state;
+            msg.updated = at;
+        }
+        Ok(())
+    }
+
+    fn get(&self, sender: ThreemaID, msg_id: MessageID) -> Result<Option<StoredMessage>> {
+        Ok(self
+            .messages
+            .iter()
+            .find(|m| (m.sender, m.msg_id) == (sender, msg_id))
+            .cloned())
+    }
+
+    fn conversation(&self, peer: ThreemaID, limit: usize) -> Result<Vec<StoredMessage>> {
+        let mut msgs: Vec<_> = self
+            .messages
+            .iter()
+            .filter(|m| m.sender == peer || m.receiver == peer)
+            .cloned()
+            .collect();
+        msgs.sort_by_key(|m| m.timestamp);
+        Ok(msgs.split_off(msgs.len().saturating_sub(limit)))
+    }
+}
+
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteMessageStore;
+
+#[cfg(feature = "sqlite")]
+pub(crate) mod sqlite {
+    use super::{DeliveryState, HistoryStats, MessageStore, StoredMessage};
+    use crate::{Error, MessageID, Result, ThreemaID};
+    use rusqlite::{params, Connection, OptionalExtension, Row};
+    use std::convert::TryFrom;
+    use std::path::Path;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS messages (
+        sender TEXT NOT NULL,
+        msg_id BLOB NOT NULL,
+        receiver TEXT NOT NULL,
+        kind INTEGER NOT NULL,
+        body BLOB NOT NULL,
+        state TEXT NOT NULL,
+        timestamp INTEGER NOT NULL,
+        updated INTEGER NOT NULL,
+        acked INTEGER,
+        PRIMARY KEY (sender, msg_id)
+    )";
+
+    const RECONNECTS_SCHEMA: &str = "CREATE TABLE IF NOT EXISTS reconnects (
+        at INTEGER NOT NULL,
+        ok INTEGER NOT NULL
+    )";
+
+    /// Keeps the history in a `SQLite` database.
+    #[derive(Debug)]
+    pub struct SqliteMessageStore {
+        conn: Connection,
+    }
+
+    impl SqliteMessageStore {
+        /// Opens the database at `path`, creating it if necessary.
+        pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+            Self::with_connection(Connection::open(path)?)
+        }
+
+        pub fn in_memory() -> Result<Self> {
+            Self::with_connection(Connection::open_in_memory()?)
+        }
+
+        fn with_connection(conn: Connection) -> Result<Self> {
+            conn.execute(SCHEMA, [])?;
+            // databases created before the acknowledgements were kept lack the column
+            if conn.prepare("SELECT acked FROM messages LIMIT 0").is_err() {
+                conn.execute("ALTER TABLE messages ADD COLUMN acked INTEGER", [])?;
+            }
+            conn.execute(RECONNECTS_SCHEMA, [])?;
+            Ok(Self { conn })
+        }
+
+        /// Summarizes the messages sent or received by `own` since `since`, or all of
+        /// them, and the reconnects in that time.
+        pub fn stats(&self, own: ThreemaID, since: Option<SystemTime>) -> Result<HistoryStats> {
+            let since = since.map_or(0, secs);
+            let mut stats = HistoryStats::default();
+            let mut stmt = self.conn.prepare(&format!(
+                "SELECT {COLUMNS}, acked FROM messages
+                 WHERE timestamp >= ?1 AND (sender = ?2 OR receiver = ?2)"
+            ))?;
+            let mut rows = stmt.query(params![since, own.to_string()])?;
+            while let Some(row) = rows.next()? {
+                let acked: Option<i64> = row.get(7)?;
+                stats.add(own, &from_row(row)?, acked.map(time));
+            }
+            stats
+                .peers
+                .sort_by_key(|peer| std::cmp::Reverse(peer.sent + peer.received));
+            let mut stmt = self
+                .conn
+                .prepare("SELECT ok, COUNT(*) FROM reconnects WHERE at >= ?1 GROUP BY ok")?;
+            let mut rows = stmt.query(params![since])?;
+            while let Some(row) = rows.next()? {
+                let count = u64::try_from(row.get::<_, i64>(1)?).unwrap_or_default();
+                if row.get(0)? {
+                    stats.reconnects += count;
+                } else {
+                    stats.failed_reconnects += count;
+                }
+            }
+            Ok(stats)
+        }
+    }
+
+    pub(crate) fn secs(t: SystemTime) -> i64 {
+        let secs = t.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        i64::try_from(secs).unwrap_or(i64::MAX)
+    }
+
+    pub(crate) fn time(secs: i64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(u64::try_from(secs).unwrap_or_default())
+    }
+
+    pub(crate) fn invalid(column: usize, e: Error) -> rusqlite::Error {
+        rusqlite::Error::FromSqlConversionFailure(column, rusqlite::types::Type::Text, e.into())
+    }
+
+    fn from_row(row: &Row<'_>) -> rusqlite::Result<StoredMessage> {
+        let sender: String = row.get(0)?;
+        let msg_id: Vec<u8> = row.get(1)?;
+        let receiver: String = row.get(2)?;
+        let state: String = row.get(4)?;
+        Ok(StoredMessage {
+            sender: ThreemaID::from_string(&sender).map_err(|e| invalid(0, e))?,
+            msg_id: MessageID::from_slice(&msg_id)
+                .ok_or_else(|| invalid(1, Error::ParseError("invalid message ID".to_owned())))?,
+            receiver: ThreemaID::from_string(&receiver).map_err(|e| invalid(2, e))?,
+            body: row.get(3)?,
+            state: DeliveryState::parse(&state)
+                .ok_or_else(|| invalid(4, Error::ParseError(format!("invalid state {state}"))))?,
+            timestamp: time(row.get(5)?),
+            updated: time(row.get(6)?),
+        })
+    }
+
+    const COLUMNS: &str = "sender, msg_id, receiver, body, state, timestamp, updated";
+
+    impl MessageStore for SqliteMessageStore {
+        fn insert(&mut self, msg: StoredMessage) -> Result<()> {
+            self.conn.execute(
+                "INSERT OR REPLACE INTO messages
+                 (sender, msg_id, receiver, kind, body, state, timestamp, updated, acked)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                params![
+                    msg.sender.to_string(),
+                    &msg.msg_id.0[..],
+                    msg.receiver.to_string(),
+                    msg.kind(),
+                    msg.body,
+                    msg.state.as_str(),
+                    secs(msg.timestamp),
+                    secs(msg.updated),
+                    (msg.state == DeliveryState::Acked).then(|| secs(msg.updated)),
+                ],
+            )?;
+            Ok(())
+        }
+
+        fn set_state(
+            &mut self,
+            sender: ThreemaID,
+            msg_id: MessageID,
+            state: DeliveryState,
+            at: SystemTime,
+        ) -> Result<()> {
+            self.conn.execute(
+                "UPDATE messages SET state = ?3, updated = ?4,
+                 acked = CASE WHEN ?3 = 'acked' THEN ?4 ELSE acked END
+                 WHERE sender = ?1 AND msg_id = ?2",
+                params![sender.to_string(), &msg_id.0[..], state.as_str(), secs(at)],
+            )?;
+            Ok(())
+        }
+
+        fn get(&self, sender: ThreemaID, msg_id: MessageID) -> Result<Option<StoredMessage>> {
+            Ok(self
+                .conn
+                .query_row(
+                    &format!("SELECT {COLUMNS} FROM messages WHERE sender = ?1 AND msg_id = ?2"),
+                    params![sender.to_string(), &msg_id.0[..]],
+                    from_row,
+                )
+                .optional()?)
+        }
+
+        fn conversation(&self, peer: ThreemaID, limit: usize) -> Result<Vec<StoredMessage>> {
+            let mut stmt = self.conn.prepare(&format!(
+                "SELECT * FROM (SELECT {COLUMNS}, rowid FROM messages
+                 WHERE sender = ?1 OR receiver = ?1 ORDER BY timestamp DESC, rowid DESC LIMIT ?2)
+                 ORDER BY timestamp, rowid"
+            ))?;
+            let limit = i64::try_from(limit).unwrap_or(i64::MAX);
+            let rows = stmt.query_map(params![peer.to_string(), limit], from_row)?;
+            Ok(rows.collect::<rusqlite::Result<_>>()?)
+        }
+
+        fn record_reconnect(&mut self, at: SystemTime, ok: bool) -> Result<()> {
+            self.conn.execute(
+                "INSERT INTO reconnects (at, ok) VALUES (?1, ?2)",
+                params![secs(at), ok],
+            )?;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packets::Text;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn check(store: &mut dyn MessageStore) {
+        let own = ThreemaID::from_string("ECHOECHO").unwrap();
+        let peer = ThreemaID::from_string("*TESTGW0").unwrap();
+        let msg = |sender, receiver, id: u8| StoredMessage {
+            sender,
+            receiver,
+            msg_id: MessageID::from_bytes([id; 8]),
+            body: Message::Text(Text {
+                message: format!("msg {id}"),
+            })
+            .serialize(),
+            state: DeliveryState::Sent,
+            timestamp: UNIX_EPOCH + Duration::from_secs(id.into()),
+            updated: UNIX_EPOCH,
+        };
+        store.insert(msg(own, peer, 1)).unwrap();
+        store.insert(msg(peer, own, 2)).unwrap();
+        store.insert(msg(own, peer, 3)).unwrap();
+        let id = MessageID::from_bytes([1; 8]);
+        store
+            .set_state(
+                own,
+                id,
+                DeliveryState::Read,
+                UNIX_EPOCH + Duration::from_secs(9),
+            )
+            .unwrap();
+
+        let stored = store.get(own, id).unwrap().unwrap();
+        assert_eq!(stored.state, DeliveryState::Read);
+        assert_eq!(stored.updated, UNIX_EPOCH + Duration::from_secs(9));
+        assert_eq!(stored.kind(), 1);
+        assert!(matches!(stored.message().unwrap(), Message::Text(t) if t.message == "msg 1"));
+        assert_eq!(store.get(peer, id).unwrap(), None);
+
+        let ids: Vec<_> = store
+            .conversation(peer, 2)
+            .unwrap()
+            .iter()
+            .map(|m| m.msg_id)
+            .collect();
+        assert_eq!(
+            ids,
+            [MessageID::from_bytes([2; 8]), MessageID::from_bytes([3; 8])]
+        );
+    }
+
+    #[test]
+    fn memory() {
+        check(&mut MemoryMessageStore::new());
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn sqlite() {
+        check(&mut SqliteMessageStore::in_memory().unwrap());
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn stats() {
+        let own = ThreemaID::from_string("ECHOECHO").unwrap();
+        let (peer, other) = (
+            ThreemaID::from_string("*TESTGW0").unwrap(),
+            ThreemaID::from_string("*TESTGW1").unwrap(),
+        );
+        let day = 86_400;
+        let msg = |sender, receiver, id: u8, secs: u64| StoredMessage {
+            sender,
+            receiver,
+            msg_id: MessageID::from_bytes([id; 8]),
+            body: if id == 4 {
+                Message::TypingNotification.serialize()
+            } else {
+                Message::Text(Text {
+                    message: format!("msg {id}"),
+                })
+                .serialize()
+            },
+            state: DeliveryState::Sent,
+            timestamp: UNIX_EPOCH + Duration::from_secs(secs),
+            updated: UNIX_EPOCH + Duration::from_secs(secs),
+        };
+        let mut store = SqliteMessageStore::in_memory().unwrap();
+        store.insert(msg(own, peer, 1, day)).unwrap();
+        store.insert(msg(peer, own, 2, day + 10)).unwrap();
+        store.insert(msg(own, peer, 3, 2 * day)).unwrap();
+        store.insert(msg(own, other, 4, 2 * day)).unwrap();
+        for (id, secs, ok) in [(1, day + 2, true), (3, 2 * day + 4, false)] {
+            let at = UNIX_EPOCH + Duration::from_secs(secs);
+            store
+                .set_state(
+                    own,
+                    MessageID::from_bytes([id; 8]),
+                    DeliveryState::Acked,
+                    at,
+                )
+                .unwrap();
+            store.record_reconnect(at, ok).unwrap();
+        }
+        // a later receipt keeps the time of the acknowledgement
+        store
+            .set_state(
+                own,
+                MessageID::from_bytes([1; 8]),
+                DeliveryState::Read,
+                UNIX_EPOCH + Duration::from_secs(3 * day),
+            )
+            .unwrap();
+
+        let stats = store.stats(own, None).unwrap();
+        assert_eq!(
+            stats.peers,
+            [
+                PeerStats {
+                    peer,
+                    sent: 2,
+                    received: 1
+                },
+                PeerStats {
+                    peer: other,
+                    sent: 1,
+                    received: 0
+                },
+            ]
+        );
+        assert_eq!(stats.kinds["text"], 3);
+        assert_eq!(stats.kinds["typing_notification"], 1);
+        assert_eq!(stats.days.iter().collect::<Vec<_>>(), [(&1, &2), (&2, &2)]);
+        assert_eq!(stats.acked, 2);
+        assert_eq!(stats.ack_latency(), Some(Duration::from_secs(3)));
+        assert_eq!((stats.reconnects, stats.failed_reconnects), (1, 1));
+
+        let since = UNIX_EPOCH + Duration::from_secs(2 * day);
+        let stats = store.stats(own, Some(since)).unwrap();
+        assert_eq!(stats.peers.len(), 2);
+        assert_eq!(stats.days.len(), 1);
+        assert_eq!((stats.reconnects, stats.failed_reconnects), (0, 1));
+        assert_eq!(HistoryStats::default().ack_latency(), None);
+    }
+}
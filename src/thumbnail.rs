@@ -0,0 +1,73 @@
+//! Previews for images and videos sent as file messages, like the official apps create
+//! them. Requires the `thumbnails` feature.
+
+use crate::Result;
+use image::codecs::jpeg::JpegEncoder;
+use image::GenericImageView;
+
+/// Longest edge of a thumbnail in pixels.
+pub const MAX_SIZE: u32 = 512;
+/// Type of the generated thumbnails.
+pub const MIME: &str = "image/jpeg";
+const QUALITY: u8 = 80;
+
+/// A JPEG preview of an image.
+pub struct Thumbnail {
+    pub jpeg: Vec<u8>,
+    /// Width of the original image
+    pub width: u32,
+    /// Height of the original image
+    pub height: u32,
+}
+
+/// Scales the encoded `image` down to at most [`MAX_SIZE`], keeping its aspect ratio.
+///
+/// Smaller images aren't scaled up. Any transparency is dropped.
+pub fn generate(image: &[u8]) -> Result<Thumbnail> {
+    let image = image::load_from_memory(image)?;
+    let (width, height) = image.dimensions();
+    let preview = if width > MAX_SIZE || height > MAX_SIZE {
+        image.thumbnail(MAX_SIZE, MAX_SIZE)
+    } else {
+        image
+    };
+    let mut jpeg = vec![];
+    JpegEncoder::new_with_quality(&mut jpeg, QUALITY).encode_image(&preview.to_rgb8())?;
+    Ok(Thumbnail {
+        jpeg,
+        width,
+        height,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageOutputFormat, RgbaImage};
+    use std::io::Cursor;
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut data = vec![];
+        image::DynamicImage::ImageRgba8(RgbaImage::new(width, height))
+            .write_to(&mut Cursor::new(&mut data), ImageOutputFormat::Png)
+            .unwrap();
+        data
+    }
+
+    #[test]
+    fn thumbnails() {
+        let thumbnail = generate(&png(1024, 256)).unwrap();
+        assert_eq!((thumbnail.width, thumbnail.height), (1024, 256));
+        let preview = image::load_from_memory(&thumbnail.jpeg).unwrap();
+        assert_eq!(preview.dimensions(), (512, 128));
+
+        let thumbnail = generate(&png(20, 30)).unwrap();
+        let preview = image::load_from_memory(&thumbnail.jpeg).unwrap();
+        assert_eq!(preview.dimensions(), (20, 30));
+
+        assert!(matches!(
+            generate(b"not an image"),
+            Err(crate::Error::Image(_))
+        ));
+    }
+}
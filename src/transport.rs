@@ -0,0 +1,89 @@
+//! The byte stream to the chat server, see
+//! [`Threema::connect_with`](crate::Threema::connect_with).
+//!
+//! [`Threema::connect`](crate::Threema::connect) uses a [`TcpStream`](std::net::TcpStream),
+//! which isn't available on `wasm32`. There, a transport bridging to the chat server, e.g.
+//! over a WebSocket proxy, has to be provided instead.
+
+use std::io::{self, Read, Write};
+use std::time::Duration;
+
+/// Bidirectional stream the encrypted frames are exchanged over.
+pub trait Transport: Read + Write + Send {
+    /// Limits how long reads block, `None` waits forever. Reads running into the limit
+    /// fail with [`io::ErrorKind::WouldBlock`] or [`io::ErrorKind::TimedOut`].
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()>;
+
+    /// Waits until data is available and copies it to `buf` without consuming it.
+    /// Returns 0 once the stream is closed.
+    fn peek(&mut self, buf: &mut [u8]) -> io::Result<usize>;
+}
+
+/// Options for the TCP connection opened by [`Threema::connect`](crate::Threema::connect),
+/// unset ones keep the OS defaults.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SocketOptions {
+    /// Sends frames right away instead of coalescing small writes (`TCP_NODELAY`)
+    pub nodelay: bool,
+    /// Idle time before the OS starts probing the connection
+    pub keepalive: Option<Duration>,
+    pub recv_buffer_size: Option<usize>,
+    pub send_buffer_size: Option<usize>,
+}
+
+impl SocketOptions {
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) fn apply(&self, conn: &std::net::TcpStream) -> io::Result<()> {
+        let socket = socket2::SockRef::from(conn);
+        if self.nodelay {
+            socket.set_nodelay(true)?;
+        }
+        if let Some(time) = self.keepalive {
+            socket.set_tcp_keepalive(&socket2::TcpKeepalive::new().with_time(time))?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Transport for std::net::TcpStream {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        std::net::TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn peek(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        std::net::TcpStream::peek(self, buf)
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+    use std::net::{TcpListener, TcpStream};
+
+    #[test]
+    fn socket_options() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let conn = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let options = SocketOptions {
+            nodelay: true,
+            keepalive: Some(Duration::from_secs(90)),
+            recv_buffer_size: Some(32 * 1024),
+            send_buffer_size: Some(32 * 1024),
+        };
+        options.apply(&conn).unwrap();
+
+        let socket = socket2::SockRef::from(&conn);
+        assert!(socket.nodelay().unwrap());
+        assert!(socket.keepalive().unwrap());
+        // the OS may round the sizes up
+        assert!(socket.recv_buffer_size().unwrap() >= 32 * 1024);
+        assert!(socket.send_buffer_size().unwrap() >= 32 * 1024);
+    }
+}
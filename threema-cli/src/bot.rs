@@ -0,0 +1,160 @@
+//! `bot`: ready-made bots answering incoming messages.
+
+use crate::output::Format;
+use log::{debug, error, info};
+use serde_json::json;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+use threema::handler::Handler;
+use threema::packets::Message;
+use threema::{MessageID, ServerMessage, Threema, ThreemaID};
+
+/// What a bot answers.
+pub enum Reply {
+    /// The received text
+    Echo,
+    /// The template, with `{sender}` replaced by the sender's ID and `{text}` by the
+    /// received text or file name
+    Template(String),
+}
+
+impl Reply {
+    /// The answer to `msg` from `sender`, `None` for messages which aren't answered.
+    fn to(&self, sender: ThreemaID, msg: &Message) -> Option<String> {
+        let text = match (self, msg) {
+            (_, Message::Text(text)) => &text.message,
+            (Reply::Template(_), Message::File(file)) => &file.name,
+            _ => return None,
+        };
+        Some(match self {
+            Reply::Echo => text.clone(),
+            Reply::Template(template) => template
+                .replace("{sender}", sender.as_str())
+                .replace("{text}", text),
+        })
+    }
+}
+
+/// Allows at most `max` replies to each sender within `window`.
+pub struct RateLimit {
+    max: usize,
+    window: Duration,
+    /// times of the replies in the current window, oldest first
+    replies: HashMap<ThreemaID, VecDeque<Instant>>,
+}
+
+impl RateLimit {
+    pub fn new(max: usize, window: Duration) -> Self {
+        RateLimit {
+            max,
+            window,
+            replies: HashMap::new(),
+        }
+    }
+
+    /// Whether `sender` may get another reply at `now`, which counts as given if so.
+    fn allow(&mut self, sender: ThreemaID, now: Instant) -> bool {
+        // forget senders who are quiet for a window, so the map doesn't grow forever
+        let window = self.window;
+        self.replies.retain(|_, times| {
+            while times
+                .front()
+                .is_some_and(|time| now.duration_since(*time) >= window)
+            {
+                times.pop_front();
+            }
+            !times.is_empty()
+        });
+        let times = self.replies.entry(sender).or_default();
+        if times.len() >= self.max {
+            return false;
+        }
+        times.push_back(now);
+        true
+    }
+}
+
+/// Answers incoming messages with a [`Reply`], within a [`RateLimit`].
+pub struct Bot {
+    pub format: Format,
+    pub reply: Reply,
+    pub limit: RateLimit,
+}
+
+impl Bot {
+    fn report(&self, kind: &str, sender: ThreemaID, mid: MessageID) {
+        match self.format {
+            Format::Text if kind == "replied" => info!("Replied to {sender}"),
+            Format::Text => info!("Not replying to {sender}, rate limit reached"),
+            Format::Json => println!(
+                "{}",
+                json!({
+                    "type": kind,
+                    "recipient": sender.to_string(),
+                    "msg_id": mid.to_string(),
+                })
+            ),
+        }
+    }
+}
+
+impl Handler for Bot {
+    fn on_message(&mut self, client: &mut Threema, msg: &ServerMessage) {
+        if msg.duplicate {
+            return;
+        }
+        let Some(reply) = self.reply.to(msg.sender, &msg.data) else {
+            debug!("Not replying to {} message", msg.data.kind());
+            return;
+        };
+        if !self.limit.allow(msg.sender, Instant::now()) {
+            self.report("rate_limited", msg.sender, msg.msg_id);
+            return;
+        }
+        match client.conversation(msg.sender).send_text(reply) {
+            Ok(mid) => self.report("replied", msg.sender, mid),
+            Err(e) => error!("Couldn't reply to {}: {e:?}", msg.sender),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use threema::packets::Text;
+
+    #[test]
+    fn replies() {
+        let sender = ThreemaID::from_string("ECHOECHO").unwrap();
+        let text = Message::Text(Text {
+            message: "hi".to_owned(),
+        });
+        assert_eq!(Reply::Echo.to(sender, &text), Some("hi".to_owned()));
+        assert_eq!(Reply::Echo.to(sender, &Message::TypingNotification), None);
+        let template = Reply::Template("Away, {sender}. You said: {text}".to_owned());
+        assert_eq!(
+            template.to(sender, &text),
+            Some("Away, ECHOECHO. You said: hi".to_owned())
+        );
+        assert_eq!(template.to(sender, &Message::TypingNotification), None);
+    }
+
+    #[test]
+    fn rate_limit() {
+        let (a, b) = (
+            ThreemaID::from_string("ECHOECHO").unwrap(),
+            ThreemaID::from_string("*TESTGW0").unwrap(),
+        );
+        let mut limit = RateLimit::new(2, Duration::from_mins(1));
+        let start = Instant::now();
+        assert!(limit.allow(a, start));
+        assert!(limit.allow(a, start + Duration::from_secs(10)));
+        assert!(!limit.allow(a, start + Duration::from_secs(20)));
+        assert!(limit.allow(b, start + Duration::from_secs(20)));
+        // the first reply left the window
+        assert!(limit.allow(a, start + Duration::from_mins(1)));
+        assert!(!limit.allow(a, start + Duration::from_secs(61)));
+        assert!(limit.allow(a, start + Duration::from_mins(2)));
+        assert!(!limit.replies.contains_key(&b));
+    }
+}
@@ -0,0 +1,124 @@
+//! `send` to several recipients.
+
+use crate::output::{self, Format};
+use log::debug;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::thread;
+use threema::directory::DirectoryClient;
+use threema::packets::Packet;
+use threema::{Error, MessageID, Threema, ThreemaID};
+
+/// Looks up the keys of the `recipients` the client doesn't know yet, with up to `parallel`
+/// requests at a time. Returns the recipients whose key couldn't be found.
+pub fn fetch_keys(
+    threema: &mut Threema,
+    directory: &(dyn DirectoryClient + Sync),
+    recipients: &[ThreemaID],
+    parallel: usize,
+) -> Vec<(ThreemaID, String)> {
+    let missing: Vec<_> = recipients
+        .iter()
+        .copied()
+        .filter(|id| threema.peer_key(*id).is_none())
+        .collect();
+    let queue = Mutex::new(missing.into_iter());
+    let results = Mutex::new(vec![]);
+    thread::scope(|scope| {
+        for _ in 0..parallel.max(1) {
+            scope.spawn(|| loop {
+                let Some(id) = queue.lock().unwrap().next() else {
+                    break;
+                };
+                debug!("Looking up {id}");
+                let result = directory.fetch_identity(id);
+                results.lock().unwrap().push((id, result));
+            });
+        }
+    });
+
+    let mut failed = vec![];
+    for (id, result) in results.into_inner().unwrap() {
+        match result {
+            Ok(Some(entry)) => threema.add_peer_key(id, entry.public_key),
+            Ok(None) => failed.push((id, "unknown identity".to_owned())),
+            Err(e) => failed.push((id, e.to_string())),
+        }
+    }
+    failed
+}
+
+/// Sends `messages` to each of the `recipients`, with at most `parallel` messages waiting
+/// for the server's ack, and reports each ack or failure. Returns the number of
+/// recipients that didn't get all messages, and the messages acked.
+pub fn send(
+    threema: &mut Threema,
+    format: Format,
+    recipients: &[ThreemaID],
+    messages: &[String],
+    parallel: usize,
+) -> Result<(usize, Vec<(MessageID, ThreemaID)>), Error> {
+    let mut failed = 0;
+    let mut sent = vec![];
+    let mut pending: HashMap<MessageID, ThreemaID> = HashMap::new();
+    for &recipient in recipients {
+        for message in messages {
+            while pending.len() >= parallel.max(1) {
+                sent.push(acked(threema, format, &mut pending)?);
+            }
+            match threema.send_text_message(recipient, message.clone()) {
+                Ok(mid) => {
+                    pending.insert(mid, recipient);
+                }
+                Err(e) => {
+                    output::failed(format, recipient.as_str(), &e);
+                    failed += 1;
+                    break;
+                }
+            }
+        }
+    }
+    while !pending.is_empty() {
+        sent.push(acked(threema, format, &mut pending)?);
+    }
+    Ok((failed, sent))
+}
+
+/// Waits for the next ack of one of the `pending` messages, and reports and returns it.
+fn acked(
+    threema: &mut Threema,
+    format: Format,
+    pending: &mut HashMap<MessageID, ThreemaID>,
+) -> Result<(MessageID, ThreemaID), Error> {
+    loop {
+        if let Packet::OutgoingMessageAck(_, mid) = threema.receive_packet()? {
+            if let Some(recipient) = pending.remove(&mid) {
+                output::sent(format, recipient, mid);
+                return Ok((mid, recipient));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use threema::crypto::PublicKey;
+    use threema::directory::MemoryDirectory;
+
+    #[test]
+    fn key_lookups() {
+        let mut threema = Threema::builder()
+            .identity(ThreemaID::from_string("*TESTGW0").unwrap(), &[1; 32])
+            .build()
+            .unwrap();
+        let known = ThreemaID::from_string("ECHOECHO").unwrap();
+        let unknown = ThreemaID::from_string("UNKNOWN1").unwrap();
+        let mut directory = MemoryDirectory::new();
+        directory.insert(known, PublicKey([2; 32]), 0);
+
+        let failed = fetch_keys(&mut threema, &directory, &[known, unknown], 2);
+        assert_eq!(failed, [(unknown, "unknown identity".to_owned())]);
+        assert_eq!(threema.peer_key(known), Some(PublicKey([2; 32])));
+    }
+}
@@ -0,0 +1,389 @@
+//! `chat`: an interactive session, with completion of contacts, commands and file paths
+//! and a persistent input history.
+//!
+//! Lines are sent as text to the current peer, lines starting with `/` are commands. The
+//! terminal is read in a thread of its own, the lines are handled between the packets
+//! of the connection.
+
+use crate::input;
+use log::{debug, warn};
+use rustyline::completion::{Completer, FilenameCompleter, Pair};
+use rustyline::error::ReadlineError;
+use rustyline::history::FileHistory;
+use rustyline::{Context, Editor, ExternalPrinter, Helper, Highlighter, Hinter, Validator};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::Duration;
+use threema::contacts::Contact;
+use threema::handler::Handler;
+use threema::packets::Message;
+use threema::{Error, ServerMessage, Threema, ThreemaID};
+
+/// The commands, with what they take and do.
+const COMMANDS: [(&str, &str); 5] = [
+    ("/to", "ID|NAME  chat with a contact"),
+    ("/file", "PATH  send a file"),
+    ("/contacts", "  list the contacts"),
+    ("/help", "  show this help"),
+    ("/quit", "  end the chat"),
+];
+
+/// How often lines typed in the meantime are handled.
+const TICK: Duration = Duration::from_millis(100);
+
+/// Completes the first word of a line to a command, the argument of `/to` to a contact
+/// and the one of `/file` to a path.
+#[derive(Helper, Hinter, Highlighter, Validator)]
+struct ChatHelper {
+    /// Contact IDs and names
+    names: Vec<String>,
+    files: FilenameCompleter,
+}
+
+impl Completer for ChatHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        if line[..pos].starts_with("/file ") {
+            return self.files.complete(line, pos, ctx);
+        }
+        let (start, candidates) = complete(&self.names, &line[..pos]);
+        let pairs = candidates
+            .into_iter()
+            .map(|candidate| Pair {
+                display: candidate.clone(),
+                replacement: candidate,
+            })
+            .collect();
+        Ok((start, pairs))
+    }
+}
+
+/// Where the replacement for the end of `line` starts, and the candidates for it.
+fn complete(names: &[String], line: &str) -> (usize, Vec<String>) {
+    if let Some(name) = line.strip_prefix("/to ") {
+        let lower = name.to_lowercase();
+        let names = names
+            .iter()
+            .filter(|candidate| candidate.to_lowercase().starts_with(&lower))
+            .cloned()
+            .collect();
+        return (line.len() - name.len(), names);
+    }
+    if line.starts_with('/') && !line.contains(' ') {
+        let commands = COMMANDS
+            .iter()
+            .filter(|(command, _)| command.starts_with(line))
+            .map(|(command, _)| format!("{command} "))
+            .collect();
+        return (0, commands);
+    }
+    (line.len(), vec![])
+}
+
+/// The contact `query` refers to: its name ignoring case, or its ID.
+fn resolve(contacts: &[Contact], query: &str) -> Option<ThreemaID> {
+    let lower = query.to_lowercase();
+    contacts
+        .iter()
+        .find(|contact| {
+            contact.display_name().to_lowercase() == lower
+                || contact
+                    .nickname
+                    .as_ref()
+                    .is_some_and(|nick| nick.to_lowercase() == lower)
+        })
+        .map(|contact| contact.id)
+        .or_else(|| ThreemaID::from_string(&query.to_uppercase()).ok())
+}
+
+/// Sends the typed lines and shows the received messages.
+struct Chat {
+    contacts: Vec<Contact>,
+    peer: Option<ThreemaID>,
+    lines: Receiver<String>,
+    /// Prints above the line being edited
+    out: Box<dyn ExternalPrinter>,
+    prompt: Arc<Mutex<String>>,
+    quit: bool,
+}
+
+impl Chat {
+    fn print(&mut self, text: String) {
+        if let Err(e) = self.out.print(text) {
+            debug!("Couldn't print: {e}");
+        }
+    }
+
+    fn name(&self, id: ThreemaID) -> String {
+        self.contacts
+            .iter()
+            .find(|contact| contact.id == id)
+            .map_or_else(|| id.to_string(), Contact::display_name)
+    }
+
+    fn set_peer(&mut self, peer: ThreemaID) {
+        self.peer = Some(peer);
+        *self.prompt.lock().unwrap_or_else(PoisonError::into_inner) = prompt(Some(peer));
+        let name = self.name(peer);
+        self.print(format!("Chatting with {name}"));
+    }
+
+    fn handle(&mut self, client: &mut Threema, line: &str) {
+        let (command, arg) = line.split_once(' ').unwrap_or((line, ""));
+        let arg = arg.trim();
+        match command {
+            "/to" => match resolve(&self.contacts, arg) {
+                Some(peer) => self.set_peer(peer),
+                None => self.print(format!("Unknown contact {arg}")),
+            },
+            "/file" => {
+                // as escaped by the completion
+                let path = PathBuf::from(arg.replace("\\ ", " "));
+                self.send(client, |client, peer| {
+                    let file = input::upload(&path)?;
+                    client.conversation(peer).send_file_message(file)
+                });
+            }
+            "/contacts" => {
+                let list: Vec<_> = self
+                    .contacts
+                    .iter()
+                    .map(|contact| format!("{} {}", contact.id, contact.display_name()))
+                    .collect();
+                self.print(list.join("\n"));
+            }
+            "/help" => {
+                let help: Vec<_> = COMMANDS
+                    .iter()
+                    .map(|(command, usage)| format!("{command} {usage}"))
+                    .collect();
+                self.print(help.join("\n"));
+            }
+            "/quit" => self.quit = true,
+            _ if command.starts_with('/') => {
+                self.print(format!("Unknown command {command}, see /help"));
+            }
+            _ if line.trim().is_empty() => {}
+            _ => {
+                let text = line.to_owned();
+                self.send(client, |client, peer| {
+                    client.conversation(peer).send_text(text)
+                });
+            }
+        }
+    }
+
+    /// Sends a message to the current peer with `send`.
+    fn send(
+        &mut self,
+        client: &mut Threema,
+        send: impl FnOnce(&mut Threema, ThreemaID) -> Result<threema::MessageID, Error>,
+    ) {
+        let Some(peer) = self.peer else {
+            self.print("Choose a contact with /to first".to_owned());
+            return;
+        };
+        if let Err(e) = send(client, peer) {
+            self.print(format!("Couldn't send: {e}"));
+        }
+    }
+}
+
+impl Handler for Chat {
+    fn on_message(&mut self, _: &mut Threema, msg: &ServerMessage) {
+        let text = match &msg.data {
+            Message::Text(text) => text.message.clone(),
+            Message::GroupText(text) => format!("[group {}] {}", text.group_id, text.message),
+            Message::File(file) if file.description.is_empty() => {
+                format!("<{} {}, {} bytes>", file.name, file.mime, file.size)
+            }
+            Message::File(file) => format!(
+                "<{} {}, {} bytes> {}",
+                file.name, file.mime, file.size, file.description
+            ),
+            Message::DeliveryReceipt(..) | Message::TypingNotification => return,
+            other => format!("<{}>", other.kind()),
+        };
+        let name = self.name(msg.sender);
+        self.print(format!("{name}: {text}"));
+    }
+
+    fn on_alert(&mut self, _: &mut Threema, message: &str) {
+        self.print(format!("Alert from the server: {message}"));
+    }
+
+    fn tick_interval(&self) -> Option<Duration> {
+        Some(TICK)
+    }
+
+    fn on_tick(&mut self, client: &mut Threema) {
+        loop {
+            match self.lines.try_recv() {
+                Ok(line) => self.handle(client, &line),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    self.quit = true;
+                    break;
+                }
+            }
+            if self.quit {
+                break;
+            }
+        }
+        if self.quit {
+            client.disconnect();
+        }
+    }
+
+    fn on_disconnect(&mut self, error: &Error) -> bool {
+        if self.quit {
+            return false;
+        }
+        if error.can_reconnect() {
+            warn!("Disconnected: {error}, reconnecting");
+        }
+        true
+    }
+}
+
+fn prompt(peer: Option<ThreemaID>) -> String {
+    peer.map_or_else(|| "> ".to_owned(), |peer| format!("{peer}> "))
+}
+
+fn readline_error(e: ReadlineError) -> Error {
+    match e {
+        ReadlineError::Io(e) => Error::Io(e),
+        e => Error::Io(std::io::Error::other(e)),
+    }
+}
+
+/// Reads lines from the terminal until the end of input or `/quit`, keeping them in
+/// `history`, and sends them to `lines`.
+fn read_lines(
+    mut editor: Editor<ChatHelper, FileHistory>,
+    prompt: &Mutex<String>,
+    history: Option<&Path>,
+    lines: &mpsc::Sender<String>,
+) {
+    loop {
+        let current = prompt
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+        let line = match editor.readline(&current) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted | ReadlineError::Eof) => break,
+            Err(e) => {
+                warn!("Couldn't read input: {e}");
+                break;
+            }
+        };
+        if !line.trim().is_empty() {
+            let _ = editor.add_history_entry(line.as_str());
+            if let Some(path) = history {
+                if let Err(e) = editor.save_history(path) {
+                    debug!("Couldn't save input history {}: {e}", path.display());
+                }
+            }
+        }
+        let quit = line.trim() == "/quit";
+        if lines.send(line).is_err() || quit {
+            break;
+        }
+    }
+}
+
+/// Chats with the contact `peer` names, or whoever is chosen with `/to`, until the end
+/// of input. The typed lines are kept in `history`.
+pub fn run(
+    threema: &mut Threema,
+    peer: Option<&str>,
+    history: Option<PathBuf>,
+) -> Result<(), Error> {
+    let contacts = threema.contacts().list();
+    let mut names: Vec<_> = contacts
+        .iter()
+        .map(|contact| contact.id.to_string())
+        .collect();
+    names.extend(contacts.iter().map(Contact::display_name));
+    names.sort();
+    names.dedup();
+
+    let mut editor = Editor::new().map_err(readline_error)?;
+    editor.set_helper(Some(ChatHelper {
+        names,
+        files: FilenameCompleter::new(),
+    }));
+    if let Some(path) = &history {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        if let Err(e) = editor.load_history(path) {
+            debug!("No input history {}: {e}", path.display());
+        }
+    }
+    let out = editor.create_external_printer().map_err(readline_error)?;
+    let prompt = Arc::new(Mutex::new(prompt(None)));
+    let (sender, lines) = mpsc::channel();
+    let mut chat = Chat {
+        contacts,
+        peer: None,
+        lines,
+        out: Box::new(out),
+        prompt: Arc::clone(&prompt),
+        quit: false,
+    };
+    if let Some(peer) = peer {
+        chat.handle(threema, &format!("/to {peer}"));
+    }
+    // not joined: it may still wait for input when the connection fails for good
+    thread::spawn(move || read_lines(editor, &prompt, history.as_deref(), &sender));
+    threema.run(&mut chat)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use threema::crypto::PublicKey;
+
+    #[test]
+    fn completion() {
+        let names = [
+            "ECHOECHO".to_owned(),
+            "Eve".to_owned(),
+            "*TESTGW0".to_owned(),
+        ];
+        assert_eq!(complete(&names, "/t"), (0, vec!["/to ".to_owned()]));
+        assert_eq!(complete(&names, "/").1.len(), COMMANDS.len());
+        assert_eq!(
+            complete(&names, "/to e"),
+            (4, vec!["ECHOECHO".to_owned(), "Eve".to_owned()])
+        );
+        assert_eq!(complete(&names, "/to *"), (4, vec!["*TESTGW0".to_owned()]));
+        assert_eq!(complete(&names, "hello /to"), (9, vec![]));
+    }
+
+    #[test]
+    fn resolving() {
+        let id = ThreemaID::from_string("ECHOECHO").unwrap();
+        let mut contact = Contact::new(id, PublicKey([1; 32]));
+        contact.first_name = Some("Eve".to_owned());
+        contact.nickname = Some("echo".to_owned());
+        let contacts = [contact];
+        assert_eq!(resolve(&contacts, "echoecho"), Some(id));
+        assert_eq!(resolve(&contacts, "eve"), Some(id));
+        assert_eq!(resolve(&contacts, "Echo"), Some(id));
+        assert_eq!(resolve(&contacts, "Bob"), None);
+        let other = ThreemaID::from_string("*TESTGW0").unwrap();
+        assert_eq!(resolve(&contacts, "*testgw0"), Some(other));
+    }
+}
@@ -0,0 +1,383 @@
+//! Settings from `~/.config/threema-cli/config.toml`, overridden by command line flags.
+//!
+//! ```toml
+//! identity = "~/threema/identity"
+//! # the first one set is used: a file, a command printing it, or (if built with the
+//! # `keyring` feature) the keyring entry of service `threema-cli` for this account
+//! password_file = "~/.threema-password"
+//! password_command = "pass show threema"
+//! keyring = "bot"
+//! nick = "Bot"
+//! # provisioning endpoint of an on-premises deployment
+//! servers = "https://threema.example.com/prov/config.oppf"
+//! proxy = "socks5://localhost:9050"
+//! download_dir = "~/Downloads/threema"
+//! # message history, if built with the `sqlite` feature
+//! history = "~/.local/share/threema-cli/history.sqlite"
+//! # pinned keys and verification levels of contacts, see `verify`
+//! contacts = "~/.local/share/threema-cli/contacts.json"
+//! # state of a running `receive`, for `receive --healthcheck`
+//! health_file = "/run/threema-cli/health.json"
+//!
+//! # further identities, selected with `--account work`
+//! [accounts.work]
+//! identity = "~/threema/work"
+//! password_command = "pass show threema-work"
+//! nick = "Alice (work)"
+//! ```
+//!
+//! Relative paths are relative to the directory of the configuration file.
+//!
+//! An account has its own identity, password, history, contacts and health file, which default to
+//! files in an `accounts/<name>` directory instead of the global settings. Its other
+//! settings default to the global ones.
+
+use crate::hook;
+use crate::password;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// ID export to load the identity from
+    pub identity: Option<PathBuf>,
+    /// File containing the password of the ID export
+    pub password_file: Option<PathBuf>,
+    /// Shell command printing the password of the ID export
+    pub password_command: Option<String>,
+    /// Keyring account with the password of the ID export
+    pub keyring: Option<String>,
+    pub nick: Option<String>,
+    /// URL of the provisioning endpoint to fetch the server addresses from
+    pub servers: Option<String>,
+    pub proxy: Option<String>,
+    /// Where `receive` saves files
+    pub download_dir: Option<PathBuf>,
+    /// `SQLite` database keeping all sent and received messages
+    pub history: Option<PathBuf>,
+    /// JSON file with the contacts, their pinned keys and verification levels
+    pub contacts: Option<PathBuf>,
+    /// Where `receive` keeps its connection state
+    pub health_file: Option<PathBuf>,
+    /// Further identities with their settings, see [`select`](Self::select)
+    pub accounts: BTreeMap<String, Config>,
+    /// Name of the [selected](Self::select) account
+    #[serde(skip)]
+    pub account: Option<String>,
+}
+
+impl Config {
+    /// `$XDG_CONFIG_HOME/threema-cli/config.toml`, with `~/.config` as default base.
+    pub fn default_path() -> Option<PathBuf> {
+        let base = env::var_os("XDG_CONFIG_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| home().map(|home| home.join(".config")))?;
+        Some(base.join("threema-cli").join("config.toml"))
+    }
+
+    /// The [history](Self::history) database, by default
+    /// `$XDG_DATA_HOME/threema-cli/history.sqlite` with `~/.local/share` as default base.
+    #[cfg(feature = "sqlite")]
+    pub fn history_path(&self) -> Option<PathBuf> {
+        if let Some(path) = &self.history {
+            return Some(path.clone());
+        }
+        Some(self.data_dir()?.join("history.sqlite"))
+    }
+
+    /// The [contacts](Self::contacts) file, by default
+    /// `$XDG_DATA_HOME/threema-cli/contacts.json` with `~/.local/share` as default base.
+    pub fn contacts_path(&self) -> Option<PathBuf> {
+        if let Some(path) = &self.contacts {
+            return Some(path.clone());
+        }
+        Some(self.data_dir()?.join("contacts.json"))
+    }
+
+    /// Where `chat` keeps the typed lines, `$XDG_DATA_HOME/threema-cli/chat_history`
+    /// with `~/.local/share` as default base.
+    pub fn chat_history_path(&self) -> Option<PathBuf> {
+        Some(self.data_dir()?.join("chat_history"))
+    }
+
+    /// Own directory in `$XDG_DATA_HOME`, with `~/.local/share` as default base.
+    fn data_dir(&self) -> Option<PathBuf> {
+        let base = env::var_os("XDG_DATA_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| home().map(|home| home.join(".local").join("share")))?;
+        Some(self.own_dir(&base))
+    }
+
+    /// The [health file](Self::health_file), by default
+    /// `$XDG_RUNTIME_DIR/threema-cli/health.json` or in the temp directory without it.
+    pub fn health_path(&self) -> PathBuf {
+        if let Some(path) = &self.health_file {
+            return path.clone();
+        }
+        let base = env::var_os("XDG_RUNTIME_DIR")
+            .filter(|dir| !dir.is_empty())
+            .map_or_else(env::temp_dir, PathBuf::from);
+        self.own_dir(&base).join("health.json")
+    }
+
+    /// `threema-cli` in `base`, or the directory of the selected account in it.
+    fn own_dir(&self, base: &Path) -> PathBuf {
+        let dir = base.join("threema-cli");
+        match &self.account {
+            Some(name) => dir.join("accounts").join(name),
+            None => dir,
+        }
+    }
+
+    /// The settings of the account `name`: its own identity, password, history, contacts
+    /// and health file, and the global settings for everything it doesn't set.
+    pub fn select(mut self, name: &str) -> Result<Self, String> {
+        let Some(mut account) = self.accounts.remove(name) else {
+            let known: Vec<_> = self.accounts.keys().map(String::as_str).collect();
+            return Err(format!(
+                "Unknown account {name}, the configuration has: {}",
+                if known.is_empty() {
+                    "none".to_owned()
+                } else {
+                    known.join(", ")
+                }
+            ));
+        };
+        if account.identity.is_none() {
+            return Err(format!("accounts.{name} needs an identity"));
+        }
+        if !account.accounts.is_empty() {
+            return Err(format!("accounts.{name} can't have accounts itself"));
+        }
+        account.nick = account.nick.or(self.nick);
+        account.servers = account.servers.or(self.servers);
+        account.proxy = account.proxy.or(self.proxy);
+        account.download_dir = account.download_dir.or(self.download_dir);
+        account.account = Some(name.to_owned());
+        Ok(account)
+    }
+
+    /// Reads the configuration from `path`, or from the [default path](Self::default_path)
+    /// if it exists.
+    pub fn load(path: Option<&Path>) -> Result<Self, String> {
+        let path = match path {
+            Some(path) => path.to_owned(),
+            None => match Self::default_path() {
+                Some(path) if path.exists() => path,
+                _ => return Ok(Self::default()),
+            },
+        };
+        let text = fs::read_to_string(&path)
+            .map_err(|e| format!("Couldn't read {}: {}", path.display(), e))?;
+        let mut config = Self::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))?;
+        config.resolve_paths(path.parent().unwrap_or_else(|| Path::new(".")));
+        Ok(config)
+    }
+
+    /// Makes the paths of this configuration and its accounts relative to `dir`.
+    fn resolve_paths(&mut self, dir: &Path) {
+        self.identity = self.identity.take().map(|path| resolve(dir, &path));
+        self.password_file = self.password_file.take().map(|path| resolve(dir, &path));
+        self.download_dir = self.download_dir.take().map(|path| resolve(dir, &path));
+        self.history = self.history.take().map(|path| resolve(dir, &path));
+        self.contacts = self.contacts.take().map(|path| resolve(dir, &path));
+        self.health_file = self.health_file.take().map(|path| resolve(dir, &path));
+        for account in self.accounts.values_mut() {
+            account.resolve_paths(dir);
+        }
+    }
+
+    fn parse(text: &str) -> Result<Self, toml::de::Error> {
+        toml::from_str(text)
+    }
+
+    /// Reads the [password file](Self::password_file), runs the
+    /// [password command](Self::password_command) or asks the [keyring](Self::keyring),
+    /// whichever is set first.
+    pub fn password(&self) -> io::Result<Option<String>> {
+        if let Some(path) = &self.password_file {
+            return password::from_file(path).map(Some);
+        }
+        if let Some(command) = &self.password_command {
+            return run_password_command(command).map(Some);
+        }
+        #[cfg(feature = "keyring")]
+        if let Some(account) = &self.keyring {
+            return password::from_keyring(account);
+        }
+        #[cfg(not(feature = "keyring"))]
+        if self.keyring.is_some() {
+            log::warn!("Ignoring the keyring setting, it requires the keyring feature");
+        }
+        Ok(None)
+    }
+}
+
+/// The first line printed by `command`.
+fn run_password_command(command: &str) -> io::Result<String> {
+    let output = hook::shell(command).stderr(Stdio::inherit()).output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "password command failed with {}",
+            output.status
+        )));
+    }
+    let output = String::from_utf8(output.stdout)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(password::first_line(&output).to_owned())
+}
+
+fn home() -> Option<PathBuf> {
+    env::var_os("HOME")
+        .or_else(|| env::var_os("USERPROFILE"))
+        .filter(|home| !home.is_empty())
+        .map(PathBuf::from)
+}
+
+/// Expands a leading `~` and makes `path` relative to `dir`.
+fn resolve(dir: &Path, path: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), home()) {
+        (Ok(rest), Some(home)) => home.join(rest),
+        _ => dir.join(path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parsing() {
+        let config = Config::parse(
+            r#"
+            identity = "id"
+            password_command = "echo secret"
+            nick = "Bot"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config,
+            Config {
+                identity: Some("id".into()),
+                password_command: Some("echo secret".to_owned()),
+                nick: Some("Bot".to_owned()),
+                ..Config::default()
+            }
+        );
+        assert_eq!(Config::parse("").unwrap(), Config::default());
+        assert!(Config::parse("idenity = \"typo\"").is_err());
+    }
+
+    #[test]
+    fn paths() {
+        let dir = Path::new("/etc/threema-cli");
+        assert_eq!(resolve(dir, Path::new("id")), dir.join("id"));
+        assert_eq!(resolve(dir, Path::new("/id")), Path::new("/id"));
+        if let Some(home) = home() {
+            assert_eq!(resolve(dir, Path::new("~/id")), home.join("id"));
+        }
+    }
+
+    #[test]
+    fn accounts() {
+        let config = Config::parse(
+            r#"
+            identity = "home"
+            nick = "Alice"
+            proxy = "socks5://localhost:9050"
+            password_command = "echo home"
+
+            [accounts.work]
+            identity = "work"
+            nick = "Alice (work)"
+
+            [accounts.broken]
+            nick = "No identity"
+            "#,
+        )
+        .unwrap();
+        let work = config.select("work");
+        assert_eq!(
+            work,
+            Ok(Config {
+                identity: Some("work".into()),
+                nick: Some("Alice (work)".to_owned()),
+                proxy: Some("socks5://localhost:9050".to_owned()),
+                account: Some("work".to_owned()),
+                ..Config::default()
+            })
+        );
+        let work = work.unwrap();
+        assert!(work
+            .health_path()
+            .ends_with("threema-cli/accounts/work/health.json"));
+        if home().is_some() {
+            assert!(work
+                .contacts_path()
+                .unwrap()
+                .ends_with("threema-cli/accounts/work/contacts.json"));
+        }
+
+        let config = Config::parse("[accounts.broken]\nnick = \"No identity\"").unwrap();
+        assert!(config.clone().select("broken").is_err());
+        assert_eq!(
+            config.select("other"),
+            Err("Unknown account other, the configuration has: broken".to_owned())
+        );
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn history_path() {
+        let config = Config {
+            history: Some("/var/lib/history.sqlite".into()),
+            ..Config::default()
+        };
+        assert_eq!(
+            config.history_path(),
+            Some(PathBuf::from("/var/lib/history.sqlite"))
+        );
+        if home().is_some() {
+            assert!(Config::default()
+                .history_path()
+                .unwrap()
+                .ends_with("threema-cli/history.sqlite"));
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn password_command() {
+        let config = Config {
+            password_command: Some("printf 'secret\\nrest'".to_owned()),
+            ..Config::default()
+        };
+        assert_eq!(config.password().unwrap().as_deref(), Some("secret"));
+        let config = Config {
+            password_command: Some("exit 1".to_owned()),
+            ..Config::default()
+        };
+        assert!(config.password().is_err());
+        assert_eq!(Config::default().password().unwrap(), None);
+
+        let path = env::temp_dir().join(format!("threema-cli-pw-{}", std::process::id()));
+        fs::write(&path, "from file\n").unwrap();
+        let config = Config {
+            password_file: Some(path.clone()),
+            password_command: Some("exit 1".to_owned()),
+            ..Config::default()
+        };
+        let password = config.password();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(password.unwrap().as_deref(), Some("from file"));
+    }
+}
@@ -0,0 +1,269 @@
+//! Running `receive` as a service: systemd notifications, clean shutdown on SIGTERM and
+//! a health file for `receive --healthcheck`.
+
+use crate::output::Format;
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use signal_hook::consts::TERM_SIGNALS;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use threema::crypto::PublicKey;
+use threema::handler::Handler;
+use threema::store::write_atomically;
+use threema::{Error, MessageID, ServerMessage, Threema, ThreemaID};
+
+/// Longest time between checks for a shutdown request.
+const TICK: Duration = Duration::from_secs(1);
+/// How often the health file is rewritten while connected.
+const HEALTH_INTERVAL: Duration = Duration::from_secs(5);
+/// Age after which `--healthcheck` considers a health file stale.
+const MAX_HEALTH_AGE: Duration = Duration::from_secs(30);
+
+/// State of a running `receive`, saved in its health file.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Health {
+    pub pid: u32,
+    pub id: String,
+    pub connected: bool,
+    /// Seconds since the Unix epoch
+    pub updated: u64,
+}
+
+/// Wraps the handler of `receive`, telling systemd how it's doing and stopping on
+/// SIGTERM or SIGINT.
+pub struct Daemon<H> {
+    inner: H,
+    /// ID of the client, set by [`run`](Self::run)
+    id: String,
+    health_file: PathBuf,
+    stop: Arc<AtomicBool>,
+    stopping: bool,
+    last_health: Option<Instant>,
+    /// Interval of the systemd watchdog pings, if enabled
+    watchdog: Option<Duration>,
+}
+
+impl<H: Handler> Daemon<H> {
+    pub fn new(inner: H, health_file: PathBuf) -> io::Result<Self> {
+        let stop = Arc::new(AtomicBool::new(false));
+        for signal in TERM_SIGNALS {
+            signal_hook::flag::register(*signal, Arc::clone(&stop))?;
+        }
+        Ok(Daemon {
+            inner,
+            id: String::new(),
+            health_file,
+            stop,
+            stopping: false,
+            last_health: None,
+            watchdog: watchdog_interval(),
+        })
+    }
+
+    /// Receives messages until stopped by a signal.
+    pub fn run(mut self, threema: &mut Threema) -> Result<(), Error> {
+        if let Some(dir) = self.health_file.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        self.id = threema.id().to_string();
+        self.write_health(true);
+        #[cfg(unix)]
+        notify(&[sd_notify::NotifyState::Ready]);
+        let result = threema.run(&mut self);
+        if let Err(e) = fs::remove_file(&self.health_file) {
+            debug!("Couldn't remove {}: {e}", self.health_file.display());
+        }
+        result
+    }
+
+    fn write_health(&mut self, connected: bool) {
+        let health = Health {
+            pid: std::process::id(),
+            id: self.id.clone(),
+            connected,
+            updated: unix_time(SystemTime::now()),
+        };
+        let json = serde_json::to_vec(&health).unwrap_or_default();
+        if let Err(e) = write_atomically(&self.health_file, &json) {
+            warn!("Couldn't write {}: {e}", self.health_file.display());
+        }
+        self.last_health = Some(Instant::now());
+    }
+
+    /// Sends what's left in the outbox and closes the connection, which ends
+    /// [`Threema::run`].
+    fn shut_down(&mut self, client: &mut Threema) {
+        info!("Shutting down");
+        #[cfg(unix)]
+        notify(&[sd_notify::NotifyState::Stopping]);
+        self.stopping = true;
+        if let Err(e) = client.flush_outbox() {
+            error!("Couldn't send the outbox: {e}");
+        }
+        client.disconnect();
+    }
+}
+
+impl<H: Handler> Handler for Daemon<H> {
+    fn on_message(&mut self, client: &mut Threema, msg: &ServerMessage) {
+        self.inner.on_message(client, msg);
+    }
+
+    fn on_key_changed(&mut self, client: &mut Threema, peer: ThreemaID, public_key: &PublicKey) {
+        self.inner.on_key_changed(client, peer, public_key);
+    }
+
+    fn on_alert(&mut self, client: &mut Threema, message: &str) {
+        self.inner.on_alert(client, message);
+    }
+
+    fn on_message_error(
+        &mut self,
+        client: &mut Threema,
+        sender: ThreemaID,
+        msg_id: MessageID,
+        cause: &Error,
+    ) {
+        self.inner.on_message_error(client, sender, msg_id, cause);
+    }
+
+    fn on_error(&mut self, client: &mut Threema, error: &Error) {
+        self.inner.on_error(client, error);
+    }
+
+    fn tick_interval(&self) -> Option<Duration> {
+        let tick = self.watchdog.map_or(TICK, |watchdog| watchdog.min(TICK));
+        Some(
+            self.inner
+                .tick_interval()
+                .map_or(tick, |inner| inner.min(tick)),
+        )
+    }
+
+    fn on_tick(&mut self, client: &mut Threema) {
+        self.inner.on_tick(client);
+        if self.stopping {
+            return;
+        }
+        if self.stop.load(Ordering::Relaxed) {
+            self.shut_down(client);
+            return;
+        }
+        #[cfg(unix)]
+        if self.watchdog.is_some() {
+            notify(&[sd_notify::NotifyState::Watchdog]);
+        }
+        if self
+            .last_health
+            .is_none_or(|last| last.elapsed() >= HEALTH_INTERVAL)
+        {
+            self.write_health(true);
+        }
+    }
+
+    fn on_disconnect(&mut self, error: &Error) -> bool {
+        if self.stopping || self.stop.load(Ordering::Relaxed) {
+            return false;
+        }
+        #[cfg(unix)]
+        notify(&[sd_notify::NotifyState::Status(&format!(
+            "Disconnected: {error}"
+        ))]);
+        self.write_health(false);
+        self.inner.on_disconnect(error)
+    }
+}
+
+/// Reads the health file at `path`, and whether the `receive` writing it is healthy:
+/// connected, and the file updated recently.
+pub fn check(path: &Path, now: SystemTime) -> Result<Health, String> {
+    let json = fs::read(path).map_err(|e| format!("Couldn't read {}: {e}", path.display()))?;
+    let health: Health = serde_json::from_slice(&json)
+        .map_err(|e| format!("Invalid health file {}: {e}", path.display()))?;
+    let age = unix_time(now).saturating_sub(health.updated);
+    if age > MAX_HEALTH_AGE.as_secs() {
+        return Err(format!("No update for {age}s"));
+    }
+    if !health.connected {
+        return Err(format!("{} is not connected", health.id));
+    }
+    Ok(health)
+}
+
+/// Prints the result of [`check`], returns whether it was healthy.
+pub fn healthcheck(format: Format, path: &Path) -> bool {
+    let result = check(path, SystemTime::now());
+    match (format, &result) {
+        (Format::Text, Ok(health)) => println!("healthy: {} connected", health.id),
+        (Format::Text, Err(e)) => println!("unhealthy: {e}"),
+        (Format::Json, Ok(health)) => println!(
+            "{}",
+            json!({ "healthy": true, "id": health.id, "pid": health.pid })
+        ),
+        (Format::Json, Err(e)) => println!("{}", json!({ "healthy": false, "error": e })),
+    }
+    result.is_ok()
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+/// Half the interval of the systemd watchdog, if enabled for this service.
+fn watchdog_interval() -> Option<Duration> {
+    #[cfg(unix)]
+    {
+        let mut usec = 0;
+        if sd_notify::watchdog_enabled(false, &mut usec) {
+            return Some(Duration::from_micros(usec / 2));
+        }
+    }
+    None
+}
+
+/// Tells systemd about `state`, if it started the service with `Type=notify`.
+#[cfg(unix)]
+fn notify(state: &[sd_notify::NotifyState<'_>]) {
+    if let Err(e) = sd_notify::notify(false, state) {
+        debug!("Couldn't notify systemd: {e}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn health_check() {
+        let path = env::temp_dir().join(format!("threema-cli-health-{}", std::process::id()));
+        let now = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let mut health = Health {
+            pid: 1,
+            id: "*TESTGW0".to_owned(),
+            connected: true,
+            updated: 1_000_000 - 10,
+        };
+        let check_with = |health: &Health| {
+            write_atomically(&path, &serde_json::to_vec(health).unwrap()).unwrap();
+            check(&path, now)
+        };
+        assert_eq!(check_with(&health).as_ref(), Ok(&health));
+        health.updated -= 60;
+        assert_eq!(check_with(&health), Err("No update for 70s".to_owned()));
+        health.updated = 1_000_000;
+        health.connected = false;
+        assert_eq!(
+            check_with(&health),
+            Err("*TESTGW0 is not connected".to_owned())
+        );
+        fs::remove_file(&path).unwrap();
+        assert!(check(&path, now).unwrap_err().starts_with("Couldn't read"));
+    }
+}
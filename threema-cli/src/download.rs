@@ -0,0 +1,99 @@
+//! Saving received files to disk.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use threema::packets;
+use threema::rest::blob;
+
+/// Downloads and decrypts `file` into `dir`, returning the path it was saved to.
+///
+/// Nothing is kept if the download fails.
+pub fn save(dir: &Path, file: &packets::File) -> Result<PathBuf, threema::Error> {
+    let (path, out) = create_unique(dir, &file_name(&file.name))?;
+    let mut out = BufWriter::new(out);
+    let saved = blob::download_file(file, &mut out, |_, _| {}).and_then(|_| Ok(out.flush()?));
+    if let Err(e) = saved {
+        drop(out);
+        let _ = fs::remove_file(&path);
+        return Err(e);
+    }
+    Ok(path)
+}
+
+/// `name` in `dir`, or `stem (n).ext` for the `n`th file of that name.
+pub fn numbered(dir: &Path, name: &str, n: usize) -> PathBuf {
+    if n == 0 {
+        return dir.join(name);
+    }
+    let (stem, ext) = match name.rfind('.') {
+        Some(pos) if pos > 0 => name.split_at(pos),
+        _ => (name, ""),
+    };
+    dir.join(format!("{stem} ({n}){ext}"))
+}
+
+/// The last component of the name chosen by the sender, without anything that could
+/// escape the download directory or confuse a terminal.
+pub fn file_name(name: &str) -> String {
+    let name = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let name: String = name
+        .chars()
+        .map(|c| if c.is_control() { '_' } else { c })
+        .collect();
+    let name = name.trim_start_matches('.').trim();
+    if name.is_empty() {
+        "file".to_owned()
+    } else {
+        name.to_owned()
+    }
+}
+
+/// Creates `name` in `dir`, or the first free [numbered](numbered) name if it exists.
+fn create_unique(dir: &Path, name: &str) -> io::Result<(PathBuf, File)> {
+    for n in 0.. {
+        let path = numbered(dir, name, n);
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => return Ok((path, file)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+            Err(e) => return Err(e),
+        }
+    }
+    unreachable!()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_names() {
+        assert_eq!(file_name("photo.jpg"), "photo.jpg");
+        assert_eq!(file_name("../../etc/passwd"), "passwd");
+        assert_eq!(file_name("C:\\temp\\a.txt"), "a.txt");
+        assert_eq!(file_name("..."), "file");
+        assert_eq!(file_name(".bashrc"), "bashrc");
+        assert_eq!(file_name("a\x1b[31m.txt"), "a_[31m.txt");
+        assert_eq!(file_name(""), "file");
+    }
+
+    #[test]
+    fn unique_paths() {
+        let dir = std::env::temp_dir().join(format!("threema-cli-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let names: Vec<_> = ["a.txt", "a.txt", "a.txt", "b"]
+            .iter()
+            .map(|name| create_unique(&dir, name).unwrap().0)
+            .collect();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            names,
+            [
+                dir.join("a.txt"),
+                dir.join("a (1).txt"),
+                dir.join("a (2).txt"),
+                dir.join("b")
+            ]
+        );
+    }
+}
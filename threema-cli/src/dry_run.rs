@@ -0,0 +1,140 @@
+//! `send --dry-run`: showing the bytes of messages instead of sending them.
+//!
+//! Everything the client doesn't know without a connection is replaced by fixed test
+//! keys: the recipients' public keys and the session keys encrypting the frames. As their
+//! secret keys derive from public seeds, the output can be decrypted and inspected.
+
+use crate::lookup::hex;
+use crate::output::{self, Format};
+use flat_bytes::Flat;
+use serde_json::json;
+use std::convert::TryFrom;
+use threema::crypto::{self, Nonce, PublicKey, SecretKey, Seed};
+use threema::packets::{Message, Packet, Text};
+use threema::{Threema, ThreemaID};
+
+/// Seed of the key pair standing in for all recipients.
+const RECIPIENT_SEED: Seed = Seed([1; 32]);
+/// Seed of the key pair standing in for the chat server.
+const SERVER_SEED: Seed = Seed([2; 32]);
+/// Seed of the client's ephemeral key pair of the session.
+const SESSION_SEED: Seed = Seed([3; 32]);
+/// Prefix of the nonces of the frames the client sends.
+const NONCE_PREFIX: [u8; 16] = [4; 16];
+
+/// What sending a message puts on the wire.
+struct Wire {
+    /// The packet, with the end-to-end encrypted message
+    packet: Packet,
+    /// The packet encrypted for the server and prefixed with its length
+    frame: Vec<u8>,
+}
+
+/// Seals `text` for `recipient` as the `counter`th frame of the session.
+fn seal(threema: &mut Threema, recipient: ThreemaID, text: &str, counter: u64) -> Wire {
+    let (recipient_key, _) = crypto::keypair_from_seed(&RECIPIENT_SEED);
+    let msg = Message::Text(Text {
+        message: text.to_owned(),
+    });
+    let packet = threema.seal_raw_message(recipient, &recipient_key, &msg);
+    let (server_key, _) = crypto::keypair_from_seed(&SERVER_SEED);
+    let (_, session_key) = crypto::keypair_from_seed(&SESSION_SEED);
+    let frame = frame(&packet, counter, &server_key, &session_key);
+    Wire { packet, frame }
+}
+
+/// `packet` as the client sends it in the `counter`th frame of a session.
+fn frame(
+    packet: &Packet,
+    counter: u64,
+    server_key: &PublicKey,
+    session_key: &SecretKey,
+) -> Vec<u8> {
+    let mut nonce = [0; 24];
+    nonce[..16].copy_from_slice(&NONCE_PREFIX);
+    nonce[16..].copy_from_slice(&counter.to_le_bytes());
+    let sealed = crypto::seal(&packet.serialize(), &Nonce(nonce), server_key, session_key);
+    let len = u16::try_from(sealed.len()).expect("texts are limited to fit into a frame");
+    [&len.to_le_bytes()[..], &sealed].concat()
+}
+
+/// Prints the payload and frame of each of the `messages` to each of the `recipients`.
+pub fn print(threema: &mut Threema, format: Format, recipients: &[ThreemaID], messages: &[String]) {
+    // the first frame of a session is the login of the handshake
+    let mut counter = 2;
+    for &recipient in recipients {
+        for message in messages {
+            if message.len() > Text::MAX_LEN {
+                // sending would split it, see `LongTexts`
+                output::failed(
+                    format,
+                    recipient.as_str(),
+                    &format!("text longer than {} bytes", Text::MAX_LEN),
+                );
+                continue;
+            }
+            let wire = seal(threema, recipient, message, counter);
+            counter += 1;
+            let Packet::OutgoingMessage(header, payload) = &wire.packet else {
+                continue;
+            };
+            let (payload, frame) = (hex(payload), hex(&wire.frame));
+            match format {
+                Format::Text => {
+                    println!("{} [{recipient}]", header.msg_id);
+                    println!("  nonce:   {}", hex(&header.nonce));
+                    println!("  payload: {payload}");
+                    println!("  frame:   {frame}");
+                }
+                Format::Json => println!(
+                    "{}",
+                    json!({
+                        "type": "dry_run",
+                        "recipient": recipient.to_string(),
+                        "msg_id": header.msg_id.to_string(),
+                        "nonce": hex(&header.nonce),
+                        "payload": payload,
+                        "frame": frame,
+                    })
+                ),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decryptable() {
+        let mut threema = Threema::builder()
+            .identity(ThreemaID::from_string("*TESTGW0").unwrap(), &[1; 32])
+            .build()
+            .unwrap();
+        let recipient = ThreemaID::from_string("ECHOECHO").unwrap();
+        let wire = seal(&mut threema, recipient, "hi", 2);
+
+        let (len, sealed) = wire.frame.split_at(2);
+        assert_eq!(
+            usize::from(u16::from_le_bytes([len[0], len[1]])),
+            sealed.len()
+        );
+        let (_, server_key) = crypto::keypair_from_seed(&SERVER_SEED);
+        let (session_key, _) = crypto::keypair_from_seed(&SESSION_SEED);
+        let mut nonce = [4; 24];
+        nonce[16..].copy_from_slice(&2u64.to_le_bytes());
+        let opened = crypto::open(sealed, &Nonce(nonce), &session_key, &server_key).unwrap();
+        assert_eq!(opened, wire.packet.serialize());
+
+        let Packet::OutgoingMessage(header, payload) = wire.packet else {
+            panic!("expected an outgoing message");
+        };
+        assert_eq!(header.receiver, recipient);
+        let (_, recipient_key) = crypto::keypair_from_seed(&RECIPIENT_SEED);
+        let own_key = SecretKey([1; 32]).public_key();
+        let plaintext =
+            crypto::open(&payload, &Nonce(header.nonce), &own_key, &recipient_key).unwrap();
+        assert_eq!(plaintext[..3], [1, b'h', b'i']);
+    }
+}
@@ -0,0 +1,284 @@
+//! `export`: transcripts of the conversations kept in the history, for archiving.
+
+use crate::download;
+use crate::output::preview;
+use serde_json::{json, Value};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use threema::packets::{File, Message};
+use threema::store::StoredMessage;
+use threema::ThreemaID;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Txt,
+    Json,
+    /// A standalone page
+    Html,
+}
+
+impl Format {
+    /// Values accepted by `export --format`.
+    pub const NAMES: [&'static str; 3] = ["txt", "json", "html"];
+
+    pub fn from_name(name: &str) -> Self {
+        match name {
+            "json" => Format::Json,
+            "html" => Format::Html,
+            _ => Format::Txt,
+        }
+    }
+}
+
+/// One message of a transcript.
+struct Entry<'a> {
+    stored: &'a StoredMessage,
+    msg: Option<Message>,
+    /// Where a received file was saved, if it's still there
+    saved: Option<PathBuf>,
+}
+
+impl<'a> Entry<'a> {
+    fn new(stored: &'a StoredMessage, download_dir: Option<&Path>) -> Self {
+        let msg = stored.message().ok();
+        let saved = match (&msg, download_dir) {
+            (Some(Message::File(file)), Some(dir)) => saved_file(dir, file),
+            _ => None,
+        };
+        Entry { stored, msg, saved }
+    }
+
+    fn time(&self) -> String {
+        humantime::format_rfc3339_seconds(self.stored.timestamp).to_string()
+    }
+
+    fn kind(&self) -> &'static str {
+        self.msg.as_ref().map_or("unknown", Message::kind)
+    }
+
+    fn file(&self) -> Option<&File> {
+        match &self.msg {
+            Some(Message::File(file)) => Some(file),
+            _ => None,
+        }
+    }
+}
+
+/// Where `receive --download-dir` saved `file`, if it's still there.
+///
+/// Files with the same name are saved as `name (1).ext` and so on, which can't be told
+/// apart later, so this is the first of them with the file's size.
+fn saved_file(dir: &Path, file: &File) -> Option<PathBuf> {
+    let name = download::file_name(&file.name);
+    for n in 0.. {
+        let path = download::numbered(dir, &name, n);
+        let meta = path.metadata().ok()?;
+        if meta.len() == file.size {
+            return Some(path);
+        }
+    }
+    None
+}
+
+/// Writes the conversation with `peer` in `messages` to `out`, referring to received
+/// files saved in `download_dir`.
+pub fn write(
+    out: &mut dyn Write,
+    format: Format,
+    peer: ThreemaID,
+    messages: &[StoredMessage],
+    download_dir: Option<&Path>,
+) -> io::Result<()> {
+    let entries: Vec<_> = messages
+        .iter()
+        .map(|stored| Entry::new(stored, download_dir))
+        .collect();
+    match format {
+        Format::Txt => txt(out, peer, &entries),
+        Format::Json => {
+            let messages: Vec<_> = entries.iter().map(json).collect();
+            let transcript = json!({ "peer": peer.to_string(), "messages": messages });
+            serde_json::to_writer_pretty(&mut *out, &transcript)?;
+            writeln!(out)
+        }
+        Format::Html => html(out, peer, &entries),
+    }
+}
+
+fn txt(out: &mut dyn Write, peer: ThreemaID, entries: &[Entry<'_>]) -> io::Result<()> {
+    writeln!(out, "Conversation with {peer}")?;
+    for entry in entries {
+        write!(out, "{} {}: ", entry.time(), entry.stored.sender)?;
+        match (entry.file(), &entry.msg) {
+            (Some(file), _) => {
+                write!(out, "[{} {}, {} bytes]", file.name, file.mime, file.size)?;
+                if !file.description.is_empty() {
+                    write!(out, " {}", file.description)?;
+                }
+                if let Some(path) = &entry.saved {
+                    write!(out, " => {}", path.display())?;
+                }
+                writeln!(out)?;
+            }
+            (None, Some(msg)) => match preview(msg) {
+                Some(text) => writeln!(out, "{text}")?,
+                None => writeln!(out, "<{}>", msg.kind())?,
+            },
+            (None, None) => writeln!(out, "<unknown>")?,
+        }
+    }
+    Ok(())
+}
+
+fn json(entry: &Entry<'_>) -> Value {
+    let file = entry.file().map(|file| {
+        json!({
+            "name": file.name,
+            "mime": file.mime,
+            "size": file.size,
+            "caption": file.description,
+            "saved": entry.saved,
+        })
+    });
+    json!({
+        "type": entry.kind(),
+        "sender": entry.stored.sender.to_string(),
+        "receiver": entry.stored.receiver.to_string(),
+        "msg_id": entry.stored.msg_id.to_string(),
+        "timestamp": entry
+            .stored
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs()),
+        "state": entry.stored.state.as_str(),
+        "text": if file.is_some() { None } else { entry.msg.as_ref().and_then(preview) },
+        "file": file,
+    })
+}
+
+fn html(out: &mut dyn Write, peer: ThreemaID, entries: &[Entry<'_>]) -> io::Result<()> {
+    writeln!(
+        out,
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+         <title>Conversation with {peer}</title>\n<style>\n\
+         body {{ font-family: sans-serif; max-width: 50em; margin: auto; }}\n\
+         .msg {{ margin: 0.5em 0; padding: 0.5em; border-radius: 0.5em; background: #eee; }}\n\
+         .own {{ background: #dfd; margin-left: 5em; }}\n\
+         .meta {{ color: #666; font-size: small; }}\n\
+         img {{ max-width: 100%; }}\n\
+         </style>\n</head>\n<body>\n<h1>Conversation with {peer}</h1>"
+    )?;
+    for entry in entries {
+        let class = if entry.stored.sender == peer {
+            "msg"
+        } else {
+            "msg own"
+        };
+        writeln!(
+            out,
+            "<div class=\"{class}\">\n<div class=\"meta\">{} {} {}</div>",
+            entry.stored.sender,
+            entry.time(),
+            entry.stored.state.as_str()
+        )?;
+        match (entry.file(), &entry.msg) {
+            (Some(file), _) => {
+                let name = escape(&file.name);
+                match &entry.saved {
+                    Some(path) if file.mime.starts_with("image/") => {
+                        let href = escape(&path.to_string_lossy());
+                        writeln!(
+                            out,
+                            "<a href=\"{href}\"><img src=\"{href}\" alt=\"{name}\"></a>"
+                        )?;
+                    }
+                    Some(path) => writeln!(
+                        out,
+                        "<a href=\"{}\">{name}</a> ({} bytes)",
+                        escape(&path.to_string_lossy()),
+                        file.size
+                    )?,
+                    None => writeln!(out, "{name} ({} bytes, not downloaded)", file.size)?,
+                }
+                if !file.description.is_empty() {
+                    writeln!(out, "<p>{}</p>", escape(&file.description))?;
+                }
+            }
+            (None, Some(msg)) => match preview(msg) {
+                Some(text) => writeln!(out, "<p>{}</p>", escape(&text).replace('\n', "<br>"))?,
+                None => writeln!(out, "<p><i>{}</i></p>", msg.kind())?,
+            },
+            (None, None) => writeln!(out, "<p><i>unknown</i></p>")?,
+        }
+        writeln!(out, "</div>")?;
+    }
+    writeln!(out, "</body>\n</html>")
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flat_bytes::Flat;
+    use std::time::Duration;
+    use threema::packets::Text;
+    use threema::store::DeliveryState;
+    use threema::MessageID;
+
+    #[test]
+    fn transcripts() {
+        let peer = ThreemaID::from_string("ECHOECHO").unwrap();
+        let own = ThreemaID::from_string("*TESTGW0").unwrap();
+        let time = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let message = |sender, receiver, text: &str| StoredMessage {
+            sender,
+            receiver,
+            msg_id: MessageID::from_bytes([1; 8]),
+            body: Message::Text(Text {
+                message: text.to_owned(),
+            })
+            .serialize(),
+            state: DeliveryState::Read,
+            timestamp: time,
+            updated: time,
+        };
+        let messages = [
+            message(own, peer, "Hi <b>"),
+            message(peer, own, "Hello\nthere"),
+        ];
+        let export = |format| {
+            let mut out = vec![];
+            write(&mut out, format, peer, &messages, None).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert_eq!(
+            export(Format::Txt),
+            "Conversation with ECHOECHO\n\
+             2020-09-13T12:26:40Z *TESTGW0: Hi <b>\n\
+             2020-09-13T12:26:40Z ECHOECHO: Hello\nthere\n"
+        );
+        let json: Value = serde_json::from_str(&export(Format::Json)).unwrap();
+        assert_eq!(json["messages"][0]["text"], "Hi <b>");
+        assert_eq!(json["messages"][1]["sender"], "ECHOECHO");
+        assert_eq!(json["messages"][1]["state"], "read");
+        let html = export(Format::Html);
+        assert!(html.contains("<p>Hi &lt;b&gt;</p>"));
+        assert!(html.contains("<p>Hello<br>there</p>"));
+    }
+}
@@ -0,0 +1,214 @@
+//! `history`: searching the messages kept in the `SQLite` store.
+
+use crate::output::{preview, Format};
+use crate::verify;
+use serde_json::json;
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use threema::contacts::VerificationLevel;
+use threema::packets::Message;
+use threema::store::{MessageStore, SqliteMessageStore, StoredMessage};
+use threema::{Error, ThreemaID};
+
+/// Opens the history at `path`, creating the database and its directory if necessary.
+pub fn open(path: &Path) -> Result<SqliteMessageStore, Error> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    SqliteMessageStore::open(path)
+}
+
+/// Which messages of a conversation to show.
+#[derive(Clone, Copy)]
+pub struct Filter<'a> {
+    /// Only messages sent at or after this time
+    pub since: Option<SystemTime>,
+    /// Only messages containing this text, ignoring case
+    pub grep: Option<&'a str>,
+    /// At most this many of the last matching messages
+    pub limit: usize,
+}
+
+impl Filter<'_> {
+    fn matches(&self, msg: &StoredMessage) -> bool {
+        if self.since.is_some_and(|since| msg.timestamp < since) {
+            return false;
+        }
+        match self.grep {
+            Some(pattern) => msg
+                .message()
+                .ok()
+                .and_then(|msg| preview(&msg))
+                .is_some_and(|text| text.to_lowercase().contains(&pattern.to_lowercase())),
+            None => true,
+        }
+    }
+}
+
+/// The messages exchanged with `peer` which pass `filter`, oldest first.
+pub fn query(
+    store: &dyn MessageStore,
+    peer: ThreemaID,
+    filter: &Filter<'_>,
+) -> Result<Vec<StoredMessage>, Error> {
+    let filtered = filter.since.is_some() || filter.grep.is_some();
+    let messages = store.conversation(peer, if filtered { usize::MAX } else { filter.limit })?;
+    let mut messages: Vec<_> = messages
+        .into_iter()
+        .filter(|msg| filter.matches(msg))
+        .collect();
+    let skip = messages.len().saturating_sub(filter.limit);
+    messages.drain(..skip);
+    Ok(messages)
+}
+
+/// Prints `messages` of the conversation with `peer`, whose key is verified as far as
+/// `verification`.
+pub fn print(
+    format: Format,
+    peer: ThreemaID,
+    messages: &[StoredMessage],
+    verification: VerificationLevel,
+) {
+    if format == Format::Text && !messages.is_empty() {
+        println!("Key of {peer}: {}", verify::describe(verification));
+    }
+    for stored in messages {
+        let msg = stored.message().ok();
+        let text = msg.as_ref().and_then(preview);
+        match format {
+            Format::Text => println!(
+                "{} {} [{} -> {}] {}",
+                humantime::format_rfc3339_seconds(stored.timestamp),
+                stored.msg_id,
+                stored.sender,
+                stored.receiver,
+                text.map_or_else(|| format!("<{}>", kind(msg.as_ref())), |t| format!("`{t}`")),
+            ),
+            Format::Json => println!(
+                "{}",
+                json!({
+                    "type": kind(msg.as_ref()),
+                    "sender": stored.sender.to_string(),
+                    "receiver": stored.receiver.to_string(),
+                    "msg_id": stored.msg_id.to_string(),
+                    "timestamp": stored
+                        .timestamp
+                        .duration_since(UNIX_EPOCH)
+                        .map_or(0, |since| since.as_secs()),
+                    "state": stored.state.as_str(),
+                    "text": text,
+                    "verification": verification.as_str(),
+                })
+            ),
+        }
+    }
+}
+
+fn kind(msg: Option<&Message>) -> &'static str {
+    msg.map_or("unknown", Message::kind)
+}
+
+/// Parses `--since`: a date like `2024-05-01`, a time like `2024-05-01 12:00:00` (both
+/// UTC), or a duration before `now` like `2h` or `7days`.
+pub fn parse_since(since: &str, now: SystemTime) -> Option<SystemTime> {
+    if let Ok(duration) = humantime::parse_duration(since) {
+        return now.checked_sub(duration);
+    }
+    let time = if since.len() == "2024-05-01".len() {
+        format!("{since} 00:00:00")
+    } else {
+        since.to_owned()
+    };
+    humantime::parse_rfc3339_weak(&time).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flat_bytes::Flat;
+    use std::time::Duration;
+    use threema::packets::Text;
+    use threema::store::DeliveryState;
+    use threema::MessageID;
+
+    fn stored(n: u8, text: &str) -> StoredMessage {
+        let peer = ThreemaID::from_string("ECHOECHO").unwrap();
+        let time = UNIX_EPOCH + Duration::from_secs(u64::from(n) * 1000);
+        StoredMessage {
+            sender: peer,
+            receiver: ThreemaID::from_string("*TESTGW0").unwrap(),
+            msg_id: MessageID::from_bytes([n; 8]),
+            body: Message::Text(Text {
+                message: text.to_owned(),
+            })
+            .serialize(),
+            state: DeliveryState::Received,
+            timestamp: time,
+            updated: time,
+        }
+    }
+
+    #[test]
+    fn queries() {
+        let peer = ThreemaID::from_string("ECHOECHO").unwrap();
+        let mut store = SqliteMessageStore::in_memory().unwrap();
+        for (n, text) in [(1, "Hello"), (2, "weather?"), (3, "hello again")] {
+            store.insert(stored(n, text)).unwrap();
+        }
+        // messages are numbered by the second they were sent in, divided by 1000
+        let ids = |filter: &Filter<'_>| -> Vec<u64> {
+            query(&store, peer, filter)
+                .unwrap()
+                .iter()
+                .map(|msg| msg.timestamp.duration_since(UNIX_EPOCH).unwrap().as_secs() / 1000)
+                .collect()
+        };
+        let all = Filter {
+            since: None,
+            grep: None,
+            limit: 10,
+        };
+        assert_eq!(ids(&all), [1, 2, 3]);
+        assert_eq!(ids(&Filter { limit: 2, ..all }), [2, 3]);
+        assert_eq!(
+            ids(&Filter {
+                grep: Some("HELLO"),
+                ..all
+            }),
+            [1, 3]
+        );
+        assert_eq!(
+            ids(&Filter {
+                grep: Some("hello"),
+                limit: 1,
+                ..all
+            }),
+            [3]
+        );
+        let since = UNIX_EPOCH + Duration::from_secs(2000);
+        assert_eq!(
+            ids(&Filter {
+                since: Some(since),
+                ..all
+            }),
+            [2, 3]
+        );
+    }
+
+    #[test]
+    fn since() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        assert_eq!(parse_since("2h", now), Some(now - Duration::from_hours(2)));
+        assert_eq!(
+            parse_since("1970-01-02", now),
+            Some(UNIX_EPOCH + Duration::from_hours(24))
+        );
+        assert_eq!(
+            parse_since("1970-01-01 00:01:00", now),
+            Some(UNIX_EPOCH + Duration::from_mins(1))
+        );
+        assert_eq!(parse_since("yesterday", now), None);
+    }
+}
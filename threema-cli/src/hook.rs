@@ -0,0 +1,79 @@
+//! `receive --exec`: running a command for each incoming message.
+
+use serde_json::Value;
+use std::io::{self, Write};
+use std::process::{Command, ExitStatus, Stdio};
+
+/// `command` run by the platform's shell.
+pub fn shell(command: &str) -> Command {
+    let mut shell = Command::new(if cfg!(windows) { "cmd" } else { "sh" });
+    shell
+        .arg(if cfg!(windows) { "/C" } else { "-c" })
+        .arg(command);
+    shell
+}
+
+/// A shell command getting the [JSON line](crate::output::Format::Json) of a message on
+/// stdin, and its most used fields in `THREEMA_SENDER`, `THREEMA_MSG_ID`, `THREEMA_TYPE`
+/// and `THREEMA_TEXT`.
+pub struct Hook {
+    pub command: String,
+}
+
+impl Hook {
+    /// Runs the command for `event` and waits for it to finish.
+    pub fn run(&self, event: &Value) -> io::Result<ExitStatus> {
+        let field = |name: &str| event[name].as_str().unwrap_or_default();
+        let mut child = shell(&self.command)
+            .env("THREEMA_SENDER", field("sender"))
+            .env("THREEMA_MSG_ID", field("msg_id"))
+            .env("THREEMA_TYPE", field("type"))
+            .env(
+                "THREEMA_TEXT",
+                event["payload"]["text"].as_str().unwrap_or_default(),
+            )
+            .stdin(Stdio::piped())
+            .spawn()?;
+        if let Some(mut stdin) = child.stdin.take() {
+            // commands only looking at the environment may exit without reading
+            match writeln!(stdin, "{event}") {
+                Err(e) if e.kind() != io::ErrorKind::BrokenPipe => return Err(e),
+                _ => {}
+            }
+        }
+        child.wait()
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::fs;
+
+    #[test]
+    fn environment_and_stdin() {
+        let out = std::env::temp_dir().join(format!("threema-cli-hook-{}", std::process::id()));
+        let hook = Hook {
+            command: format!(
+                "echo \"$THREEMA_SENDER $THREEMA_TYPE $THREEMA_TEXT\" > {0}; cat >> {0}",
+                out.display()
+            ),
+        };
+        let event = json!({
+            "type": "text",
+            "sender": "ECHOECHO",
+            "msg_id": "0101010101010101",
+            "payload": {"text": "hi there"},
+        });
+        assert!(hook.run(&event).unwrap().success());
+        let written = fs::read_to_string(&out).unwrap();
+        fs::remove_file(&out).unwrap();
+        assert_eq!(written, format!("ECHOECHO text hi there\n{event}\n"));
+
+        let hook = Hook {
+            command: "exit 3".to_owned(),
+        };
+        assert_eq!(hook.run(&event).unwrap().code(), Some(3));
+    }
+}
@@ -0,0 +1,138 @@
+//! `identity`: creating and exporting identities.
+
+use crate::lookup::hex;
+use crate::output::Format;
+use crate::qr;
+use serde_json::json;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::Path;
+use threema::crypto::SecretKey;
+use threema::{identity, Error, ThreemaID};
+
+/// Registers a new identity and saves its ID export, protected by `password`, to `path`.
+///
+/// An existing file is never overwritten, it might hold the only copy of another identity.
+pub fn create(
+    format: Format,
+    path: &Path,
+    password: &str,
+    license_key: Option<&str>,
+) -> Result<(), threema::Error> {
+    // fail before registering an identity which would be lost
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::AlreadyExists => io::Error::new(
+                e.kind(),
+                format!("{} already exists, not overwriting it", path.display()),
+            ),
+            _ => e,
+        })?;
+    let created = identity::create(license_key).and_then(|(id, secret_key)| {
+        let backup = identity::encrypt(id, &secret_key, password);
+        writeln!(file, "{backup}")?;
+        file.sync_all()?;
+        Ok((id, secret_key))
+    });
+    let (id, secret_key) = match created {
+        Ok(created) => created,
+        Err(e) => {
+            drop(file);
+            let _ = std::fs::remove_file(path);
+            return Err(e);
+        }
+    };
+    let fingerprint = hex(&secret_key.public_key().fingerprint());
+    match format {
+        Format::Text => {
+            println!("ID:          {id}");
+            println!("Fingerprint: {fingerprint}");
+            println!("Saved to:    {}", path.display());
+        }
+        Format::Json => println!(
+            "{}",
+            json!({
+                "id": id.to_string(),
+                "fingerprint": fingerprint,
+                "path": path,
+            })
+        ),
+    }
+    Ok(())
+}
+
+/// Prints the identity of the ID export `backup` as new export protected by
+/// `new_password`, and as QR code which the apps can scan to restore it if `qr` is set.
+///
+/// The QR code goes to stderr, so the export can be piped somewhere else.
+pub fn export(
+    format: Format,
+    backup: &str,
+    password: &str,
+    new_password: &str,
+    qr: bool,
+) -> Result<(), Error> {
+    let (id, backup) = reencrypt(backup, password, new_password)?;
+    match format {
+        Format::Text => println!("{backup}"),
+        Format::Json => println!("{}", json!({ "id": id.to_string(), "backup": backup })),
+    }
+    if qr {
+        eprintln!("{}", qr::terminal(&backup));
+    }
+    Ok(())
+}
+
+/// The ID and a new export of the identity in `backup`, protected by `new_password`.
+fn reencrypt(
+    backup: &str,
+    password: &str,
+    new_password: &str,
+) -> Result<(ThreemaID, String), Error> {
+    let (id, private_key) = load(backup, password)?;
+    Ok((id, identity::encrypt(id, &private_key, new_password)))
+}
+
+/// The ID and private key in the ID export `backup`.
+pub fn load(backup: &str, password: &str) -> Result<(ThreemaID, SecretKey), Error> {
+    let (id, private_key) =
+        identity::decrypt(backup, password).ok_or(Error::InvalidBackupOrPassword)?;
+    let id = ThreemaID::from_string(&id)?;
+    let private_key = SecretKey::from_slice(&private_key).ok_or(Error::InvalidPrivateKey)?;
+    Ok((id, private_key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_existing_file() {
+        let path = std::env::temp_dir().join(format!("threema-cli-id-{}", std::process::id()));
+        std::fs::write(&path, "old").unwrap();
+        let result = create(Format::Text, &path, "password", None);
+        let kept = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(result.unwrap_err().to_string().contains("already exists"));
+        assert_eq!(kept, "old");
+    }
+
+    #[test]
+    fn reencryption() {
+        let id = ThreemaID::from_string("ECHOECHO").unwrap();
+        let old = identity::encrypt(id, &SecretKey([3; 32]), "old");
+        let (exported, new) = reencrypt(&old, "old", "new").unwrap();
+        assert_eq!(exported, id);
+        assert_eq!(
+            identity::decrypt(&new, "new"),
+            Some(("ECHOECHO".to_owned(), vec![3; 32]))
+        );
+        assert!(matches!(
+            reencrypt(&old, "wrong", "new"),
+            Err(Error::InvalidBackupOrPassword)
+        ));
+    }
+}
@@ -0,0 +1,90 @@
+//! Reading the messages to send from stdin, for `send RECIPIENT -`, recipients from a
+//! file, and files to send.
+
+use log::{debug, info};
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+use threema::conversation::upload_file_from;
+use threema::packets::File;
+use threema::Error;
+
+/// Reads all of stdin, see [`messages`].
+pub fn read_stdin(each_line: bool) -> io::Result<Vec<String>> {
+    let mut input = String::new();
+    io::stdin().read_to_string(&mut input)?;
+    Ok(messages(&input, each_line))
+}
+
+/// `input` as one message without its final line break, or one message per non-empty
+/// line if `each_line` is set.
+fn messages(input: &str, each_line: bool) -> Vec<String> {
+    if each_line {
+        return input
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(str::to_owned)
+            .collect();
+    }
+    let message = input
+        .strip_suffix('\n')
+        .map_or(input, |rest| rest.strip_suffix('\r').unwrap_or(rest));
+    if message.trim().is_empty() {
+        vec![]
+    } else {
+        vec![message.to_owned()]
+    }
+}
+
+/// The IDs listed in the file at `path`, one per line. Empty lines and lines starting
+/// with `#` are skipped.
+pub fn read_recipients(path: &Path) -> io::Result<Vec<String>> {
+    Ok(recipients(&fs::read_to_string(path)?))
+}
+
+/// Uploads the file at `path`, with its type guessed from the extension.
+pub fn upload(path: &Path) -> Result<File, Error> {
+    let mut data = fs::File::open(path)?;
+    let name = path
+        .file_name()
+        .map_or_else(|| "file".into(), |n| n.to_string_lossy());
+    let mime = mime_guess::from_path(path).first_or_octet_stream();
+    info!("Uploading {name} as {mime}");
+    upload_file_from(&name, mime.essence_str(), &mut data, |sent, total| {
+        debug!("Uploaded {sent}/{total} bytes");
+    })
+}
+
+fn recipients(list: &str) -> Vec<String> {
+    list.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_owned)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splitting() {
+        assert_eq!(messages("deploy finished\n", false), ["deploy finished"]);
+        assert_eq!(
+            messages("line 1\r\nline 2\r\n", false),
+            ["line 1\r\nline 2"]
+        );
+        assert_eq!(messages("a\n\nb", false), ["a\n\nb"]);
+        assert_eq!(messages("a\n \nb\n", true), ["a", "b"]);
+        assert!(messages("\n", false).is_empty());
+        assert!(messages("", true).is_empty());
+    }
+
+    #[test]
+    fn recipient_lists() {
+        assert_eq!(
+            recipients("# ops team\nECHOECHO\n\n  *TESTGW0  \r\n"),
+            ["ECHOECHO", "*TESTGW0"]
+        );
+    }
+}
@@ -0,0 +1,127 @@
+//! `lookup`: what the directory knows about an identity.
+
+use crate::output::Format;
+use crate::verify;
+use serde_json::json;
+use std::fmt::Write;
+use threema::contacts::{ContactStore, VerificationLevel};
+use threema::directory::DirectoryClient;
+use threema::identity::IdentityState;
+use threema::ThreemaID;
+
+/// Who to look up.
+pub enum Query<'a> {
+    Id(&'a str),
+    Phone(&'a str),
+    Email(&'a str),
+}
+
+/// Prints the ID, public key, features and verification level in `contacts` of the
+/// identities matching `query`. Returns whether there were any.
+pub fn lookup(
+    format: Format,
+    directory: &dyn DirectoryClient,
+    contacts: &dyn ContactStore,
+    query: &Query<'_>,
+) -> Result<bool, threema::Error> {
+    let matches = match query {
+        Query::Id(id) => {
+            let id = ThreemaID::from_string(id)?;
+            return print(
+                format,
+                directory,
+                contacts,
+                id,
+                VerificationLevel::Unverified,
+            );
+        }
+        Query::Phone(phone) => directory.match_identities(&[phone], &[])?,
+        Query::Email(email) => directory.match_identities(&[], &[email])?,
+    };
+    let mut found = false;
+    for m in matches {
+        // found by one of its links
+        found |= print(
+            format,
+            directory,
+            contacts,
+            m.id,
+            VerificationLevel::ServerVerified,
+        )?;
+    }
+    Ok(found)
+}
+
+/// Prints what the directory knows about `id`, returns `false` if it doesn't know it.
+/// The verification level is at least `found_as`.
+fn print(
+    format: Format,
+    directory: &dyn DirectoryClient,
+    contacts: &dyn ContactStore,
+    id: ThreemaID,
+    found_as: VerificationLevel,
+) -> Result<bool, threema::Error> {
+    let Some(entry) = directory.fetch_identity(id)? else {
+        return Ok(false);
+    };
+    let public_key = entry.public_key.to_hex();
+    let fingerprint = hex(&entry.public_key.fingerprint());
+    let state = match entry.status.state {
+        IdentityState::Active => "active",
+        IdentityState::Inactive => "inactive",
+        IdentityState::Revoked => "revoked",
+        IdentityState::Invalid => "invalid",
+    };
+    let features = entry.status.feature_mask.names();
+    let verification = verify::level(contacts, id, &entry.public_key).max(found_as);
+    match format {
+        Format::Text => {
+            println!("ID:          {id}");
+            println!("State:       {state}");
+            println!("Public key:  {public_key}");
+            println!("Fingerprint: {fingerprint}");
+            println!("Features:    {}", features.join(", "));
+            println!("Verified:    {}", verify::describe(verification));
+        }
+        Format::Json => println!(
+            "{}",
+            json!({
+                "id": id.to_string(),
+                "state": state,
+                "public_key": public_key,
+                "fingerprint": fingerprint,
+                "features": features,
+                "verification": verification.as_str(),
+            })
+        ),
+    }
+    Ok(true)
+}
+
+pub fn hex(data: &[u8]) -> String {
+    data.iter().fold(String::new(), |mut s, b| {
+        let _ = write!(s, "{b:02x}");
+        s
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use threema::contacts::MemoryContactStore;
+    use threema::crypto::PublicKey;
+    use threema::directory::MemoryDirectory;
+
+    #[test]
+    fn lookups() {
+        let id = ThreemaID::from_string("ECHOECHO").unwrap();
+        let mut directory = MemoryDirectory::new();
+        directory.insert(id, PublicKey([1; 32]), 0);
+        let contacts = MemoryContactStore::new();
+        let lookup = |format, query| lookup(format, &directory, &contacts, &query);
+        assert!(lookup(Format::Json, Query::Id("ECHOECHO")).unwrap());
+        assert!(!lookup(Format::Json, Query::Id("*TESTGW0")).unwrap());
+        assert!(lookup(Format::Text, Query::Id("invalid")).is_err());
+        assert_eq!(hex(&[0, 0xab]), "00ab");
+    }
+}
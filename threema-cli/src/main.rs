@@ -1,78 +1,378 @@
 #![deny(clippy::pedantic)]
 
+mod bot;
+mod broadcast;
+mod chat;
+mod config;
+mod daemon;
+mod download;
+mod dry_run;
+#[cfg(feature = "sqlite")]
+mod export;
+#[cfg(feature = "sqlite")]
+mod history;
+mod hook;
+mod identity;
+mod input;
+mod lookup;
+#[cfg(feature = "notify")]
+mod notify;
+mod output;
+mod password;
+mod qr;
+mod receipts;
+#[cfg(feature = "sqlite")]
+mod stats;
+mod verify;
+
+use bot::{Bot, RateLimit, Reply};
 use clap::Arg;
 use clap::ArgAction;
+use clap::ArgMatches;
 use clap::Command;
+use config::Config;
+use daemon::Daemon;
+use hook::Hook;
 use log::error;
 use log::info;
+use lookup::Query;
+use output::{Format, Printer};
+use receipts::{Progress, Tracker};
+use std::collections::HashSet;
 use std::env;
 use std::fs;
+use std::io::{self, IsTerminal};
+use std::path::{Path, PathBuf};
 use std::process::exit;
-use threema::packets::Message;
-use threema::packets::Packet;
-use threema::Threema;
-use threema::ThreemaID;
+use std::time::Duration;
+use threema::bridge::Webhook;
+use threema::contacts::{ContactStore, FileContactStore, MemoryContactStore};
+use threema::directory::HttpDirectory;
+use threema::handler::Handler;
+use threema::packets::{MessageStatus, Packet, RenderingType};
+use threema::{rest, servers};
+use threema::{MessageID, Threema, ThreemaID};
+
+/// Sends `messages` to all `recipients` and reports which got them, waiting for their
+/// receipts if `wait_for` is set.
+fn send(
+    mut threema: Threema,
+    format: Format,
+    recipients: &[ThreemaID],
+    messages: &[String],
+    parallel: usize,
+    wait_for: Option<(Progress, Duration)>,
+) {
+    let sent = match broadcast::send(&mut threema, format, recipients, messages, parallel) {
+        Ok((0, sent)) => sent,
+        Ok(_) => exit(1),
+        Err(e) => {
+            error!("Error during receiving packets: {e:?}");
+            exit(1);
+        }
+    };
+    let Some((progress, timeout)) = wait_for else {
+        return;
+    };
+    match Tracker::new(format, progress, timeout, sent).run(&mut threema) {
+        Ok(code) => exit(code),
+        Err(e) => {
+            error!("Error while waiting for receipts: {e:?}");
+            exit(1);
+        }
+    }
+}
 
-fn send(mut threema: Threema, recipient: &str, message: String) {
+/// The valid recipients given to `send`, with their keys looked up unless it's a dry run.
+/// Exits after reporting the others unless `--skip-invalid` is set.
+fn recipients(threema: &mut Threema, format: Format, matches: &ArgMatches) -> Vec<ThreemaID> {
+    let mut names: Vec<String> = vec![];
+    // otherwise the only positional argument is the message, see `messages`
+    if matches.contains_id("message") || !matches.contains_id("to_file") {
+        names.extend(
+            matches
+                .get_many::<String>("recipient")
+                .into_iter()
+                .flatten()
+                .cloned(),
+        );
+    }
+    if let Some(path) = matches.get_one::<PathBuf>("to_file") {
+        match input::read_recipients(path) {
+            Ok(list) => names.extend(list),
+            Err(e) => {
+                error!("Couldn't read {}: {:?}", path.display(), e);
+                exit(1);
+            }
+        }
+    }
+    let mut failed = false;
+    let mut recipients = vec![];
+    for name in names {
+        match ThreemaID::from_string(&name) {
+            Ok(id) if !recipients.contains(&id) => recipients.push(id),
+            Ok(_) => {}
+            Err(e) => {
+                output::failed(format, &name, &e);
+                failed = true;
+            }
+        }
+    }
+    let parallel = *matches.get_one::<usize>("parallel").unwrap();
+    let lookups = if matches.get_flag("dry_run") {
+        &[][..]
+    } else {
+        &recipients[..]
+    };
+    for (id, cause) in broadcast::fetch_keys(threema, &HttpDirectory::new(), lookups, parallel) {
+        output::failed(format, id.as_str(), &cause);
+        recipients.retain(|r| *r != id);
+        failed = true;
+    }
+    if (failed && !matches.get_flag("skip_invalid")) || recipients.is_empty() {
+        exit(1);
+    }
+    recipients
+}
+
+/// The messages given to `send`, read from stdin for `-`.
+fn messages(matches: &ArgMatches) -> Vec<String> {
+    let each_line = matches.get_flag("stdin_each_line");
+    let message = match (
+        matches.get_one::<String>("message"),
+        matches.contains_id("to_file"),
+    ) {
+        // with --to-file, the only positional argument is the message
+        (None, true) => matches.get_one::<String>("recipient"),
+        (message, _) => message,
+    };
+    let messages = match message {
+        Some(message) if message != "-" && each_line => {
+            error!("--stdin-each-line reads the messages from stdin, use - as MESSAGE");
+            exit(1);
+        }
+        Some(message) if message != "-" => return vec![message.clone()],
+        _ => input::read_stdin(each_line),
+    };
+    match messages {
+        Ok(messages) if messages.is_empty() => {
+            error!("No message on stdin");
+            exit(1);
+        }
+        Ok(messages) => messages,
+        Err(e) => {
+            error!("Couldn't read stdin: {e:?}");
+            exit(1);
+        }
+    }
+}
+
+fn send_file(
+    mut threema: Threema,
+    format: Format,
+    recipient: &str,
+    path: &Path,
+    caption: Option<&str>,
+    as_media: bool,
+) {
     let recipient = match ThreemaID::from_string(recipient) {
         Ok(id) => id,
         Err(e) => {
-            error!("Invalid threema id: {:?}", e);
+            error!("Invalid threema id: {e:?}");
+            exit(1);
+        }
+    };
+    let mut file = match input::upload(path) {
+        Ok(f) => f,
+        Err(e) => {
+            error!("Couldn't upload {}: {:?}", path.display(), e);
+            exit(1);
+        }
+    };
+    if let Some(caption) = caption {
+        file = file.with_caption(caption);
+    }
+    if as_media {
+        file = file.with_rendering(RenderingType::Media);
+    }
+    let mid = match threema.conversation(recipient).send_file_message(file) {
+        Ok(mid) => mid,
+        Err(e) => {
+            error!("Couldn't send message: {e:?}");
+            exit(1);
+        }
+    };
+    wait_for_ack(&mut threema, mid);
+    output::sent(format, recipient, mid);
+}
+
+fn ack(mut threema: Threema, format: Format, sender: &str, msg_id: &str, status: &str) {
+    let sender = match ThreemaID::from_string(sender) {
+        Ok(id) => id,
+        Err(e) => {
+            error!("Invalid threema id: {e:?}");
             exit(1);
         }
     };
-    let mid = match threema.send_text_message(recipient, message) {
+    let Some(msg_id) = MessageID::from_hex(msg_id) else {
+        error!("Invalid message id {msg_id}");
+        exit(1);
+    };
+    let status = match status {
+        "agree" => MessageStatus::Approved,
+        "disagree" => MessageStatus::Disapproved,
+        _ => MessageStatus::Read,
+    };
+    let mid = match threema.conversation(sender).send_receipt(status, msg_id) {
         Ok(mid) => mid,
         Err(e) => {
-            error!("Couldn't send message: {:?}", e);
+            error!("Couldn't send receipt: {e:?}");
             exit(1);
         }
     };
+    wait_for_ack(&mut threema, mid);
+    output::sent(format, sender, mid);
+}
 
-    loop {
+/// Waits until the server acknowledged the message `mid`.
+fn wait_for_ack(threema: &mut Threema, mid: MessageID) {
+    wait_for_acks(threema, &[mid]);
+}
+
+/// Waits until the server acknowledged all messages in `mids`.
+fn wait_for_acks(threema: &mut Threema, mids: &[MessageID]) {
+    let mut pending: HashSet<_> = mids.iter().copied().collect();
+    while !pending.is_empty() {
         let packet = match threema.receive_packet() {
-            Ok((p, _)) => p,
+            Ok(p) => p,
             Err(e) => {
-                error!("Error during receiving packets: {:?}", e);
+                error!("Error during receiving packets: {e:?}");
                 exit(1);
             }
         };
         if let Packet::OutgoingMessageAck(_, ack_mid) = packet {
-            if ack_mid == mid {
-                info!("Message processed by server");
-                return;
-            }
+            pending.remove(&ack_mid);
         }
     }
 }
 
-fn receive(mut threema: Threema) {
+fn receive(mut threema: Threema, printer: Printer, health_file: PathBuf) {
+    if let Some(dir) = &printer.download_dir {
+        if let Err(e) = fs::create_dir_all(dir) {
+            error!("Couldn't create {}: {:?}", dir.display(), e);
+            exit(1);
+        }
+    }
     info!("Entering receive loop");
-    loop {
-        let msg = match threema.receive() {
-            Ok(m) => m,
+    if let Err(e) = daemon(printer, health_file).run(&mut threema) {
+        error!("Error during receiving packets: {e:?}");
+        exit(1);
+    }
+}
+
+fn bridge(
+    mut threema: Threema,
+    url: Option<String>,
+    listen: Option<&str>,
+    token: Option<String>,
+    health_file: PathBuf,
+) {
+    let mut webhook = Webhook::new(url);
+    if let Some(addr) = listen {
+        webhook = match webhook.listen(addr, token) {
+            Ok(w) => w,
             Err(e) => {
-                error!("Error during receiving packets: {:?}", e);
+                error!("Couldn't listen on {addr}: {e:?}");
                 exit(1);
             }
         };
+    }
+    info!("Entering bridge loop");
+    if let Err(e) = daemon(webhook, health_file).run(&mut threema) {
+        error!("Error during receiving packets: {e:?}");
+        exit(1);
+    }
+}
 
-        let sender = msg.sender;
-        let mid = msg.msg_id;
-        match msg.data {
-            Message::Text(t) => {
-                println!("{} [{}] `{}`", mid, sender, t.message);
-            }
-            Message::DeliveryReceipt(status, mid) => {
-                println!("{} [{}] => {:?}", mid, sender, status);
-            }
-            other => {
-                println!("{} [{}] :: {:?}", mid, sender, other);
+/// The printer for the options of `receive`.
+fn printer(format: Format, matches: &ArgMatches, config: &Config) -> Printer {
+    Printer {
+        format,
+        download_dir: matches
+            .get_one::<PathBuf>("download_dir")
+            .or(config.download_dir.as_ref())
+            .cloned(),
+        exec: matches.get_one::<String>("exec").map(|command| Hook {
+            command: command.clone(),
+        }),
+        #[cfg(feature = "notify")]
+        notify: matches.get_flag("notify"),
+    }
+}
+
+fn chat(mut threema: Threema, matches: &ArgMatches, config: &Config) {
+    let peer = matches.get_one::<String>("peer").map(String::as_str);
+    if let Err(e) = chat::run(&mut threema, peer, config.chat_history_path()) {
+        error!("Chat failed: {e:?}");
+        exit(1);
+    }
+}
+
+fn run_bot(mut threema: Threema, bot: Bot, health_file: PathBuf) {
+    info!("Entering bot loop");
+    if let Err(e) = daemon(bot, health_file).run(&mut threema) {
+        error!("Error during receiving packets: {e:?}");
+        exit(1);
+    }
+}
+
+/// The bot of the `bot` subcommand in `matches`, and its health file.
+fn bot(format: Format, matches: &ArgMatches, config: &Config) -> (Bot, PathBuf) {
+    let (kind, matches) = matches.subcommand().unwrap();
+    let reply = match kind {
+        "autoreply" => {
+            let path = matches.get_one::<PathBuf>("template").unwrap();
+            match fs::read_to_string(path) {
+                Ok(template) => Reply::Template(template.trim_end().to_owned()),
+                Err(e) => {
+                    error!("Couldn't read {}: {:?}", path.display(), e);
+                    exit(1);
+                }
             }
         }
+        _ => Reply::Echo,
+    };
+    let limit = RateLimit::new(
+        *matches.get_one::<usize>("max_replies").unwrap(),
+        Duration::from_secs(*matches.get_one::<u64>("window").unwrap()),
+    );
+    let bot = Bot {
+        format,
+        reply,
+        limit,
+    };
+    (bot, health_file(matches, config))
+}
+
+fn daemon<H: Handler>(handler: H, health_file: PathBuf) -> Daemon<H> {
+    match Daemon::new(handler, health_file) {
+        Ok(daemon) => daemon,
+        Err(e) => {
+            error!("Couldn't handle signals: {e:?}");
+            exit(1);
+        }
     }
 }
 
+/// The health file written by `receive` and read by `receive --healthcheck`.
+fn health_file(matches: &ArgMatches, config: &Config) -> PathBuf {
+    matches
+        .get_one::<PathBuf>("health_file")
+        .or(config.health_file.as_ref())
+        .cloned()
+        .unwrap_or_else(|| config.health_path())
+}
+
 fn setup_logging() {
     if env::var("RUST_LOG").is_err() {
         env::set_var("RUST_LOG", "info");
@@ -80,16 +380,49 @@ fn setup_logging() {
     pretty_env_logger::init();
 }
 
-fn main() {
-    setup_logging();
-    let matches = Command::new("threema-cli")
+/// Options to override the nickname of sent messages.
+fn nick_args() -> [Arg; 2] {
+    [
+        Arg::new("nick")
+            .short('n')
+            .long("nick")
+            .value_name("NICK")
+            .action(ArgAction::Set)
+            .conflicts_with("hide_nick"),
+        Arg::new("hide_nick")
+            .long("hide-nick")
+            .help("Send without a nickname")
+            .action(ArgAction::SetTrue),
+    ]
+}
+
+fn cli() -> Command {
+    let cli = Command::new("threema-cli")
         .subcommand_required(true)
+        .arg(
+            Arg::new("config")
+                .short('c')
+                .long("config")
+                .value_name("FILE")
+                .help("Configuration file [default: ~/.config/threema-cli/config.toml]")
+                .value_parser(clap::value_parser!(PathBuf))
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("account")
+                .short('a')
+                .long("account")
+                .value_name("NAME")
+                .help("Use the identity and settings of [accounts.NAME] in the configuration")
+                .action(ArgAction::Set),
+        )
         .arg(
             Arg::new("identity")
                 .short('i')
                 .long("identity")
                 .value_name("FILE")
-                .default_value("identity")
+                .help("ID export to use [default: identity]")
+                .value_parser(clap::value_parser!(PathBuf))
                 .action(ArgAction::Set),
         )
         .arg(
@@ -97,64 +430,956 @@ fn main() {
                 .short('p')
                 .long("password")
                 .value_name("PWD")
-                .default_value("testtest")
+                .help("Password of the ID export, visible to other users; prefer --password-file")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("password_file")
+                .long("password-file")
+                .value_name("FILE")
+                .help("Read the password of the ID export from FILE")
+                .value_parser(clap::value_parser!(PathBuf))
+                .conflicts_with("identity_password")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("output")
+                .short('o')
+                .long("output")
+                .value_name("FORMAT")
+                .help("Print messages and results as text or one JSON object per line")
+                .value_parser(Format::NAMES)
+                .default_value("text")
+                .global(true)
+                .action(ArgAction::Set),
+        )
+        .subcommand(send_command())
+        .subcommand(send_file_command())
+        .subcommand(ack_command())
+        .subcommand(receive_command())
+        .subcommand(bot_command())
+        .subcommand(
+            Command::new("chat")
+                .about("Chat interactively, with completion of contacts, commands and paths")
+                .arg(
+                    Arg::new("peer")
+                        .value_name("PEER")
+                        .help("Contact ID or name to chat with"),
+                ),
+        )
+        .subcommand(identity_command())
+        .subcommand(
+            Command::new("qr")
+                .about("Show a QR code which contacts scan to add and verify this identity")
+                .arg(
+                    Arg::new("out")
+                        .long("out")
+                        .value_name("FILE")
+                        .help("Also save the QR code as PNG")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .action(ArgAction::Set),
+                ),
+        )
+        .subcommand(lookup_command())
+        .subcommand(verify_command());
+    #[cfg(feature = "sqlite")]
+    let cli = cli
+        .subcommand(history_command())
+        .subcommand(export_command())
+        .subcommand(stats_command());
+    cli
+}
+
+#[cfg(feature = "sqlite")]
+fn history_command() -> Command {
+    Command::new("history")
+        .about("Show the messages exchanged with a peer")
+        .arg(Arg::new("peer").value_name("PEER").required(true))
+        .arg(
+            Arg::new("since")
+                .long("since")
+                .value_name("TIME")
+                .help("Only messages since a date (2024-05-01), UTC time or duration (2h)")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("grep")
+                .long("grep")
+                .value_name("TEXT")
+                .help("Only messages containing TEXT, ignoring case")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("limit")
+                .long("limit")
+                .value_name("N")
+                .help("Show at most the last N messages")
+                .value_parser(clap::value_parser!(usize))
+                .default_value("50")
+                .action(ArgAction::Set),
+        )
+}
+
+#[cfg(feature = "sqlite")]
+fn export_command() -> Command {
+    Command::new("export")
+        .about("Write a transcript of the conversation with a peer from the history")
+        .arg(Arg::new("peer").value_name("PEER").required(true))
+        .arg(
+            Arg::new("format")
+                .long("format")
+                .value_name("FORMAT")
+                .value_parser(export::Format::NAMES)
+                .default_value("txt")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("out")
+                .long("out")
+                .value_name("FILE")
+                .help("Write the transcript to FILE instead of stdout")
+                .value_parser(clap::value_parser!(PathBuf))
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("since")
+                .long("since")
+                .value_name("TIME")
+                .help("Only messages since a date (2024-05-01), UTC time or duration (2h)")
+                .action(ArgAction::Set),
+        )
+}
+
+fn send_command() -> Command {
+    Command::new("send")
+        .args(nick_args())
+        .arg(
+            Arg::new("recipient")
+                .value_name("RECIPIENT")
+                .help("ID to send to, or several separated by commas")
+                .value_delimiter(',')
+                .required_unless_present("to_file"),
+        )
+        .arg(
+            Arg::new("message")
+                .value_name("MESSAGE")
+                .help("Text to send, - to read it from stdin")
+                .required_unless_present_any(["stdin_each_line", "to_file"]),
+        )
+        .arg(
+            Arg::new("to_file")
+                .long("to-file")
+                .value_name("FILE")
+                .help("Also send to the IDs in FILE, one per line")
+                .value_parser(clap::value_parser!(PathBuf))
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("parallel")
+                .long("parallel")
+                .value_name("N")
+                .help("Look up N recipients and have N messages unacknowledged at a time")
+                .value_parser(clap::value_parser!(usize))
+                .default_value("4")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("skip_invalid")
+                .long("skip-invalid")
+                .help("Send to the valid recipients even if others are invalid or unknown")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("stdin_each_line")
+                .long("stdin-each-line")
+                .help("Send each line read from stdin as a message")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("dry_run")
+                .long("dry-run")
+                .help("Print the encrypted payload and frame of each message as hex instead of sending it, using test keys for the recipients and the server")
+                .conflicts_with("wait_for")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("wait_for")
+                .long("wait-for")
+                .value_name("STATUS")
+                .help(
+                    "Wait until all messages were delivered or read; exits with 2 if some \
+                     weren't delivered in time, 3 if all were delivered but some not read",
+                )
+                .value_parser(Progress::NAMES)
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("timeout")
+                .long("timeout")
+                .value_name("SECONDS")
+                .help("How long to wait for receipts")
+                .value_parser(clap::value_parser!(u64))
+                .default_value("60")
+                .requires("wait_for")
+                .action(ArgAction::Set),
+        )
+}
+
+fn send_file_command() -> Command {
+    Command::new("send-file")
+        .args(nick_args())
+        .arg(
+            Arg::new("caption")
+                .long("caption")
+                .value_name("TEXT")
+                .help("Text shown below the file")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("as_media")
+                .long("as-media")
+                .help("Show images and videos inline, play audio as voice message")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(Arg::new("recipient").value_name("RECIPIENT").required(true))
+        .arg(
+            Arg::new("path")
+                .value_name("PATH")
+                .value_parser(clap::value_parser!(PathBuf))
+                .required(true),
+        )
+}
+
+fn verify_command() -> Command {
+    Command::new("verify")
+        .about("Compare the key of a contact with its app and mark it as fully verified")
+        .arg(Arg::new("id").value_name("ID").required(true))
+        .arg(
+            Arg::new("yes")
+                .long("yes")
+                .short('y')
+                .help("Mark the contact as fully verified without asking")
+                .action(ArgAction::SetTrue),
+        )
+}
+
+#[cfg(feature = "sqlite")]
+fn stats_command() -> Command {
+    Command::new("stats")
+        .about("Summarize the history: messages per peer, type and day, acks and reconnects")
+        .arg(
+            Arg::new("since")
+                .long("since")
+                .value_name("TIME")
+                .help("Only since a date (2024-05-01), UTC time or duration (2h)")
+                .action(ArgAction::Set),
+        )
+}
+
+fn lookup_command() -> Command {
+    Command::new("lookup")
+        .about("Show the public key and features of an identity")
+        .arg(Arg::new("id").value_name("ID"))
+        .arg(
+            Arg::new("phone")
+                .long("phone")
+                .value_name("NUMBER")
+                .help("Find the identity linked to a phone number, e.g. +41791234567"),
+        )
+        .arg(
+            Arg::new("email")
+                .long("email")
+                .value_name("ADDRESS")
+                .help("Find the identity linked to an email address"),
+        )
+        .group(
+            clap::ArgGroup::new("query")
+                .args(["id", "phone", "email"])
+                .required(true),
+        )
+}
+
+fn ack_command() -> Command {
+    Command::new("ack")
+        .about("Mark a received message as read, or react to it")
+        .arg(Arg::new("sender").value_name("SENDER").required(true))
+        .arg(Arg::new("msg_id").value_name("MSG_ID").required(true))
+        .arg(
+            Arg::new("status")
+                .long("status")
+                .value_name("STATUS")
+                .value_parser(["read", "agree", "disagree"])
+                .default_value("read")
+                .action(ArgAction::Set),
+        )
+}
+
+fn receive_command() -> Command {
+    let receive = Command::new("receive")
+        .arg(
+            Arg::new("mark_read")
+                .long("mark-read")
+                .help("Send read receipts for received messages")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("webhook")
+                .long("webhook")
+                .value_name("URL")
+                .help("POST incoming messages as JSON to URL instead of printing them")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("listen")
+                .long("listen")
+                .value_name("ADDR")
+                .help("Send messages POSTed to http://ADDR/send")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("token")
+                .long("token")
+                .value_name("TOKEN")
+                .help("Bearer token required by --listen, mandatory unless listening on loopback")
+                .requires("listen")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("download_dir")
+                .long("download-dir")
+                .value_name("DIR")
+                .help("Save received files to DIR")
+                .value_parser(clap::value_parser!(PathBuf))
+                .conflicts_with_all(["webhook", "listen"])
                 .action(ArgAction::Set),
         )
+        .arg(
+            Arg::new("exec")
+                .long("exec")
+                .value_name("COMMAND")
+                .help(
+                    "Run COMMAND for each message, with the JSON line on stdin and \
+                     THREEMA_SENDER, THREEMA_MSG_ID, THREEMA_TYPE and THREEMA_TEXT set",
+                )
+                .conflicts_with_all(["webhook", "listen"])
+                .action(ArgAction::Set),
+        )
+        .arg(health_file_arg())
+        .arg(
+            Arg::new("healthcheck")
+                .long("healthcheck")
+                .help("Check whether the running receive is connected, and exit with 1 if not")
+                .action(ArgAction::SetTrue),
+        );
+    #[cfg(feature = "notify")]
+    let receive = receive.arg(
+        Arg::new("notify")
+            .long("notify")
+            .help("Show a desktop notification for each message")
+            .conflicts_with_all(["webhook", "listen"])
+            .action(ArgAction::SetTrue),
+    );
+    receive
+}
+
+fn health_file_arg() -> Arg {
+    Arg::new("health_file")
+        .long("health-file")
+        .value_name("FILE")
+        .help("Where to keep the connection state for --healthcheck [default: $XDG_RUNTIME_DIR/threema-cli/health.json]")
+        .value_parser(clap::value_parser!(PathBuf))
+        .action(ArgAction::Set)
+}
+
+fn bot_command() -> Command {
+    /// Options of all bots, with the rate limit defaults of each.
+    fn bot_args(max_replies: &'static str, window: &'static str) -> [Arg; 3] {
+        [
+            Arg::new("max_replies")
+                .long("max-replies")
+                .value_name("N")
+                .help("Reply at most N times to each sender within the window")
+                .value_parser(clap::value_parser!(usize))
+                .default_value(max_replies)
+                .action(ArgAction::Set),
+            Arg::new("window")
+                .long("window")
+                .value_name("SECONDS")
+                .help("Length of the rate limit window")
+                .value_parser(clap::value_parser!(u64))
+                .default_value(window)
+                .action(ArgAction::Set),
+            health_file_arg(),
+        ]
+    }
+
+    Command::new("bot")
+        .about("Run a bot answering incoming messages")
+        .subcommand_required(true)
+        .subcommand(
+            Command::new("echo")
+                .about("Reply with the received text")
+                .args(bot_args("10", "60")),
+        )
         .subcommand(
-            Command::new("send")
+            Command::new("autoreply")
+                .about("Reply with a fixed text, e.g. as out-of-office note")
                 .arg(
-                    Arg::new("nick")
-                        .short('n')
-                        .long("nick")
-                        .value_name("NICK")
+                    Arg::new("template")
+                        .long("template")
+                        .value_name("FILE")
+                        .help("Text of the reply; {sender} and {text} are replaced by the sender's ID and message")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .required(true)
                         .action(ArgAction::Set),
                 )
-                .arg(Arg::new("recipient").value_name("RECIPIENT").required(true))
-                .arg(Arg::new("message").value_name("MESSAGE").required(true)),
+                .args(bot_args("1", "86400")),
         )
-        .subcommand(Command::new("receive"))
-        .get_matches();
+}
+
+fn identity_command() -> Command {
+    Command::new("identity")
+        .about("Manage the identity file")
+        .subcommand_required(true)
+        .subcommand(
+            Command::new("new")
+                .about("Create a new identity and save its ID export to the identity file")
+                .arg(
+                    Arg::new("password")
+                        .long("password")
+                        .value_name("PWD")
+                        .help("Password protecting the ID export [default: the configured one, or ask]")
+                        .action(ArgAction::Set),
+                )
+                .arg(
+                    Arg::new("license")
+                        .long("license")
+                        .value_name("KEY")
+                        .help("License key, required by Threema Work and on-premises servers")
+                        .action(ArgAction::Set),
+                ),
+        )
+        .subcommand(
+            Command::new("export")
+                .about("Print the ID export under a new password, e.g. to restore it on a phone")
+                .arg(
+                    Arg::new("new_password")
+                        .long("new-password")
+                        .value_name("PWD")
+                        .help("Password protecting the printed ID export [default: ask]")
+                        .action(ArgAction::Set),
+                )
+                .arg(
+                    Arg::new("qr")
+                        .long("qr")
+                        .help("Also show a QR code to scan with the app, on stderr")
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+}
+
+/// Applies the server and proxy settings of `config` to all clients and requests.
+fn setup_network(config: &Config) {
+    if let Err(e) = rest::set_proxy(config.proxy.as_deref()) {
+        error!("Invalid proxy: {e:?}");
+        exit(1);
+    }
+    if let Some(url) = &config.servers {
+        info!("Fetching server info from {url}");
+        if let Err(e) = servers::refresh(url) {
+            error!("Couldn't fetch server info: {e:?}");
+            exit(1);
+        }
+    }
+}
+
+/// The ID export given on the command line or in `config`.
+fn identity_path(matches: &ArgMatches, config: &Config) -> PathBuf {
+    matches
+        .get_one::<PathBuf>("identity")
+        .or(config.identity.as_ref())
+        .map_or_else(|| PathBuf::from("identity"), Clone::clone)
+}
+
+/// The password of the ID export, from `--password` or the configured command.
+fn identity_password(matches: &ArgMatches, config: &Config) -> String {
+    match configured_password(matches, config).and_then(|password| match password {
+        Some(password) => Ok(Some(password)),
+        None => password::prompt("Password: "),
+    }) {
+        Ok(Some(password)) => password,
+        Ok(None) => {
+            error!("No password given, use --password-file or password_command in the config");
+            exit(1);
+        }
+        Err(e) => {
+            error!("Couldn't get the password: {e:?}");
+            exit(1);
+        }
+    }
+}
+
+/// The password given with `--password`, `--password-file` or in the config.
+fn configured_password(matches: &ArgMatches, config: &Config) -> io::Result<Option<String>> {
+    if let Some(password) = matches.get_one::<String>("identity_password") {
+        return Ok(Some(password.clone()));
+    }
+    match matches.get_one::<PathBuf>("password_file") {
+        Some(path) => password::from_file(path).map(Some),
+        None => config.password(),
+    }
+}
+
+/// A password for a new ID export, given with `arg`, or typed in if `fallback` has none.
+fn new_password(
+    matches: &ArgMatches,
+    arg: &str,
+    fallback: impl FnOnce() -> io::Result<Option<String>>,
+) -> String {
+    if let Some(password) = matches.get_one::<String>(arg) {
+        return password.clone();
+    }
+    match fallback().and_then(|password| match password {
+        Some(password) => Ok(Some(password)),
+        None => password::prompt_new(),
+    }) {
+        Ok(Some(password)) => password,
+        Ok(None) => {
+            error!("No new password given and no terminal to ask for it");
+            exit(1);
+        }
+        Err(e) => {
+            error!("Couldn't get the password: {e:?}");
+            exit(1);
+        }
+    }
+}
+
+/// Runs `identity` subcommands, which don't need a connection.
+fn identity(matches: &ArgMatches, config: &Config, format: Format) {
+    let path = identity_path(matches, config);
+    match matches
+        .subcommand()
+        .and_then(|(_, matches)| matches.subcommand())
+    {
+        Some(("new", sub)) => {
+            let password = new_password(sub, "password", || configured_password(matches, config));
+            info!("Creating identity");
+            let license = sub.get_one::<String>("license").map(String::as_str);
+            if let Err(e) = identity::create(format, &path, &password, license) {
+                error!("Couldn't create identity: {e}");
+                exit(1);
+            }
+        }
+        Some(("export", sub)) => {
+            let data = read_identity(&path);
+            let password = identity_password(matches, config);
+            let new_password = new_password(sub, "new_password", || Ok(None));
+            if let Err(e) =
+                identity::export(format, &data, &password, &new_password, sub.get_flag("qr"))
+            {
+                error!("Couldn't export identity: {e}");
+                exit(1);
+            }
+        }
+        _ => {}
+    }
+}
 
-    let ifile = matches.get_one::<String>("identity").unwrap();
-    info!("Loading identity from {}", ifile);
-    let data = match fs::read_to_string(ifile) {
+fn read_identity(path: &Path) -> String {
+    info!("Loading identity from {}", path.display());
+    match fs::read_to_string(path) {
         Ok(d) => d,
         Err(e) => {
-            error!("Could't read identity file: {:?}", e);
+            error!("Could't read identity file: {e:?}");
             exit(1);
         }
-    };
+    }
+}
 
-    let mut threema = match Threema::from_backup(
-        &data,
-        matches.get_one::<String>("identity_password").unwrap(),
-    ) {
+/// Creates the client configured by the command line and `config`.
+fn client(matches: &ArgMatches, config: &Config) -> Threema {
+    let data = read_identity(&identity_path(matches, config));
+    let password = identity_password(matches, config);
+
+    let mut builder = Threema::builder().backup(&data, &password);
+    let (nick, hide_nick) = match matches.subcommand() {
+        Some(("send" | "send-file", matches)) => (
+            matches.get_one::<String>("nick"),
+            matches.get_flag("hide_nick"),
+        ),
+        _ => (None, false),
+    };
+    if hide_nick {
+        builder = builder.hide_nick();
+    } else if let Some(nick) = nick.or(config.nick.as_ref()) {
+        builder = builder.nick(nick.clone());
+    }
+    if let Some(("receive", matches)) = matches.subcommand() {
+        builder = builder.auto_read_receipt(matches.get_flag("mark_read"));
+    }
+    if let Some(contacts) = contacts(config) {
+        builder = builder.contacts(Box::new(contacts));
+    }
+    #[cfg(not(feature = "sqlite"))]
+    if config.history.is_some() {
+        log::warn!("Ignoring the history setting, it requires the sqlite feature");
+    }
+    #[cfg(feature = "sqlite")]
+    if let Some(path) = config.history_path() {
+        match history::open(&path) {
+            Ok(store) => builder = builder.message_store(Box::new(store)),
+            Err(e) => {
+                error!("Couldn't open history {}: {:?}", path.display(), e);
+                exit(1);
+            }
+        }
+    }
+    match builder.build() {
         Ok(t) => t,
         Err(e) => {
-            error!("Couldn't initialize client: {:?}", e);
+            error!("Couldn't initialize client: {e:?}");
             exit(1);
         }
+    }
+}
+
+/// The contacts from the [file](Config::contacts_path), if there is one.
+fn contacts(config: &Config) -> Option<FileContactStore> {
+    let path = config.contacts_path()?;
+    match verify::open(&path) {
+        Ok(contacts) => Some(contacts),
+        Err(e) => {
+            error!("Couldn't open contacts {}: {:?}", path.display(), e);
+            exit(1);
+        }
+    }
+}
+
+fn lookup(matches: &ArgMatches, config: &Config, format: Format) {
+    let query = if let Some(phone) = matches.get_one::<String>("phone") {
+        Query::Phone(phone)
+    } else if let Some(email) = matches.get_one::<String>("email") {
+        Query::Email(email)
+    } else {
+        Query::Id(matches.get_one::<String>("id").unwrap())
+    };
+    let contacts: Box<dyn ContactStore> = match contacts(config) {
+        Some(contacts) => Box::new(contacts),
+        None => Box::new(MemoryContactStore::new()),
+    };
+    match lookup::lookup(format, &HttpDirectory::new(), contacts.as_ref(), &query) {
+        Ok(true) => return,
+        Ok(false) => error!("No identity found"),
+        Err(e) => error!("Lookup failed: {e:?}"),
+    }
+    exit(1);
+}
+
+fn verify(matches: &ArgMatches, config: &Config, format: Format) {
+    let id = match ThreemaID::from_string(matches.get_one::<String>("id").unwrap()) {
+        Ok(id) => id,
+        Err(e) => {
+            error!("Invalid threema id: {e:?}");
+            exit(1);
+        }
+    };
+    let Some(mut contacts) = contacts(config) else {
+        error!("No contacts file configured");
+        exit(1);
+    };
+    let confirm = || {
+        if matches.get_flag("yes") {
+            Ok(true)
+        } else if io::stdin().is_terminal() {
+            verify::ask()
+        } else {
+            info!("Not marking {id} as verified without a terminal to ask, see --yes");
+            Ok(false)
+        }
+    };
+    match verify::verify(format, &HttpDirectory::new(), &mut contacts, id, confirm) {
+        Ok(Some(_)) => return,
+        Ok(None) => error!("No identity found"),
+        Err(e) => error!("Verification failed: {e:?}"),
+    }
+    exit(1);
+}
+
+fn show_qr(matches: &ArgMatches, config: &Config, format: Format, out: Option<&Path>) {
+    let backup = read_identity(&identity_path(matches, config));
+    let shown = identity::load(&backup, &identity_password(matches, config))
+        .and_then(|(id, secret_key)| qr::show(format, id, &secret_key.public_key(), out));
+    if let Err(e) = shown {
+        error!("Couldn't create QR code: {e}");
+        exit(1);
+    }
+}
+
+#[cfg(feature = "sqlite")]
+fn since(matches: &ArgMatches) -> Option<std::time::SystemTime> {
+    matches.get_one::<String>("since").map(|since| {
+        history::parse_since(since, std::time::SystemTime::now()).unwrap_or_else(|| {
+            error!("Invalid time {since}");
+            exit(1);
+        })
+    })
+}
+
+#[cfg(feature = "sqlite")]
+fn show_history(matches: &ArgMatches, config: &Config, format: Format) {
+    let peer = match ThreemaID::from_string(matches.get_one::<String>("peer").unwrap()) {
+        Ok(id) => id,
+        Err(e) => {
+            error!("Invalid threema id: {e:?}");
+            exit(1);
+        }
+    };
+    let filter = history::Filter {
+        since: since(matches),
+        grep: matches.get_one::<String>("grep").map(String::as_str),
+        limit: *matches.get_one::<usize>("limit").unwrap(),
+    };
+    let Some(path) = config.history_path() else {
+        error!("No history configured");
+        exit(1);
+    };
+    match history::open(&path).and_then(|store| history::query(&store, peer, &filter)) {
+        Ok(messages) => history::print(format, peer, &messages, verification(config, peer)),
+        Err(e) => {
+            error!("Couldn't read history {}: {:?}", path.display(), e);
+            exit(1);
+        }
+    }
+}
+
+#[cfg(feature = "sqlite")]
+fn show_stats(matches: &ArgMatches, sub: &ArgMatches, config: &Config, format: Format) {
+    let backup = read_identity(&identity_path(matches, config));
+    let own = match identity::load(&backup, &identity_password(matches, config)) {
+        Ok((id, _)) => id,
+        Err(e) => {
+            error!("Couldn't load identity: {e}");
+            exit(1);
+        }
+    };
+    let Some(path) = config.history_path() else {
+        error!("No history configured");
+        exit(1);
+    };
+    match history::open(&path).and_then(|store| store.stats(own, since(sub))) {
+        Ok(summary) => stats::print(format, &summary),
+        Err(e) => {
+            error!("Couldn't read history {}: {:?}", path.display(), e);
+            exit(1);
+        }
+    }
+}
+
+/// The verification level of the contact `peer`.
+#[cfg(feature = "sqlite")]
+fn verification(config: &Config, peer: ThreemaID) -> threema::contacts::VerificationLevel {
+    contacts(config)
+        .and_then(|contacts| contacts.get(peer))
+        .map(|contact| contact.verification)
+        .unwrap_or_default()
+}
+
+#[cfg(feature = "sqlite")]
+fn export(matches: &ArgMatches, config: &Config) {
+    use std::io::Write;
+
+    let peer = match ThreemaID::from_string(matches.get_one::<String>("peer").unwrap()) {
+        Ok(id) => id,
+        Err(e) => {
+            error!("Invalid threema id: {e:?}");
+            exit(1);
+        }
+    };
+    let filter = history::Filter {
+        since: since(matches),
+        grep: None,
+        limit: usize::MAX,
+    };
+    let Some(path) = config.history_path() else {
+        error!("No history configured");
+        exit(1);
+    };
+    let messages =
+        match history::open(&path).and_then(|store| history::query(&store, peer, &filter)) {
+            Ok(messages) => messages,
+            Err(e) => {
+                error!("Couldn't read history {}: {:?}", path.display(), e);
+                exit(1);
+            }
+        };
+    let format = export::Format::from_name(matches.get_one::<String>("format").unwrap());
+    let download_dir = config.download_dir.as_deref();
+    let result = match matches.get_one::<PathBuf>("out") {
+        Some(out) => fs::File::create(out).and_then(|file| {
+            let mut out = io::BufWriter::new(file);
+            export::write(&mut out, format, peer, &messages, download_dir)?;
+            out.flush()
+        }),
+        None => export::write(
+            &mut io::stdout().lock(),
+            format,
+            peer,
+            &messages,
+            download_dir,
+        ),
+    };
+    if let Err(e) = result {
+        error!("Couldn't write the transcript: {e}");
+        exit(1);
+    }
+}
+
+/// Runs the subcommands which don't need a connection, returns `false` for the others.
+fn run_offline(matches: &ArgMatches, config: &Config, format: Format) -> bool {
+    match matches.subcommand() {
+        Some(("lookup", sub)) => lookup(sub, config, format),
+        Some(("verify", sub)) => verify(sub, config, format),
+        #[cfg(feature = "sqlite")]
+        Some(("history", sub)) => show_history(sub, config, format),
+        #[cfg(feature = "sqlite")]
+        Some(("export", sub)) => export(sub, config),
+        #[cfg(feature = "sqlite")]
+        Some(("stats", sub)) => show_stats(matches, sub, config, format),
+        Some(("identity", _)) => identity(matches, config, format),
+        Some(("receive", sub)) if sub.get_flag("healthcheck") => {
+            if !daemon::healthcheck(format, &health_file(sub, config)) {
+                exit(1);
+            }
+        }
+        Some(("qr", sub)) => show_qr(
+            matches,
+            config,
+            format,
+            sub.get_one::<PathBuf>("out").map(PathBuf::as_path),
+        ),
+        _ => return false,
+    }
+    true
+}
+
+/// The configuration, of the account given with `--account` if any.
+fn config(matches: &ArgMatches) -> Config {
+    let config = Config::load(matches.get_one::<PathBuf>("config").map(PathBuf::as_path)).and_then(
+        |config| match matches.get_one::<String>("account") {
+            Some(name) => config.select(name),
+            None => Ok(config),
+        },
+    );
+    match config {
+        Ok(config) => config,
+        Err(e) => {
+            error!("Invalid configuration: {e}");
+            exit(1);
+        }
+    }
+}
+
+fn main() {
+    setup_logging();
+    let matches = cli().get_matches();
+    let config = config(&matches);
+
+    setup_network(&config);
+    let format = Format::from_name(matches.get_one::<String>("output").unwrap());
+    if run_offline(&matches, &config, format) {
+        return;
+    }
+
+    // before connecting, stdin might take a while
+    let messages = match matches.subcommand() {
+        Some(("send", matches)) => messages(matches),
+        _ => vec![],
+    };
+    let mut threema = client(&matches, &config);
+    // also before connecting, there might be many
+    let recipients = match matches.subcommand() {
+        Some(("send", matches)) => recipients(&mut threema, format, matches),
+        _ => vec![],
+    };
+    if let Some(("send", sub)) = matches.subcommand() {
+        if sub.get_flag("dry_run") {
+            dry_run::print(&mut threema, format, &recipients, &messages);
+            return;
+        }
+    }
+    // the same for a bot's template
+    let bot = match matches.subcommand() {
+        Some(("bot", matches)) => Some(bot(format, matches, &config)),
+        _ => None,
     };
     info!("Connecting to backend");
     if let Err(e) = threema.connect() {
-        error!("Couldn't connect: {:?}", e);
+        error!("Couldn't connect: {e:?}");
         exit(1);
     }
 
     match matches.subcommand() {
         Some(("send", matches)) => {
-            if let Some(n) = matches.get_one::<String>("nick") {
-                threema.nick = Some(n.to_string());
-            }
             send(
                 threema,
+                format,
+                &recipients,
+                &messages,
+                *matches.get_one::<usize>("parallel").unwrap(),
+                matches.get_one::<String>("wait_for").map(|progress| {
+                    (
+                        Progress::from_name(progress),
+                        Duration::from_secs(*matches.get_one::<u64>("timeout").unwrap()),
+                    )
+                }),
+            );
+        }
+        Some(("send-file", matches)) => {
+            send_file(
+                threema,
+                format,
                 matches.get_one::<String>("recipient").unwrap(),
-                matches.get_one::<String>("message").unwrap().clone(),
+                matches.get_one::<PathBuf>("path").unwrap(),
+                matches.get_one::<String>("caption").map(String::as_str),
+                matches.get_flag("as_media"),
+            );
+        }
+        Some(("ack", matches)) => {
+            ack(
+                threema,
+                format,
+                matches.get_one::<String>("sender").unwrap(),
+                matches.get_one::<String>("msg_id").unwrap(),
+                matches.get_one::<String>("status").unwrap(),
+            );
+        }
+        Some(("bot", _)) => {
+            if let Some((bot, health_file)) = bot {
+                run_bot(threema, bot, health_file);
+            }
+        }
+        Some(("receive", matches))
+            if matches.contains_id("webhook") || matches.contains_id("listen") =>
+        {
+            bridge(
+                threema,
+                matches.get_one::<String>("webhook").cloned(),
+                matches.get_one::<String>("listen").map(String::as_str),
+                matches.get_one::<String>("token").cloned(),
+                health_file(matches, &config),
+            );
+        }
+        Some(("chat", matches)) => chat(threema, matches, &config),
+        Some(("receive", matches)) => {
+            receive(
+                threema,
+                printer(format, matches, &config),
+                health_file(matches, &config),
             );
         }
-        Some(("receive", _)) => receive(threema),
         Some((other, _)) => {
-            error!("Unexpected command {}", other);
+            error!("Unexpected command {other}");
             exit(1)
         }
         None => {
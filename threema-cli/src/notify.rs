@@ -0,0 +1,80 @@
+//! `receive --notify`: desktop notifications for incoming messages.
+
+use crate::output::preview;
+use log::warn;
+use notify_rust::Notification;
+use threema::packets::Message;
+use threema::ThreemaID;
+
+/// Longest preview shown, in characters.
+const MAX_PREVIEW: usize = 100;
+
+/// Raises a notification for `msg` from `sender`, unless it's a receipt, typing
+/// notification or another message nobody needs to look at.
+pub fn show(sender: ThreemaID, msg: &Message) {
+    let Some(body) = body(msg) else {
+        return;
+    };
+    if let Err(e) = Notification::new()
+        .appname("threema-cli")
+        .summary(&sender.to_string())
+        .body(&body)
+        .show()
+    {
+        warn!("Couldn't show notification: {e}");
+    }
+}
+
+/// The notification text for `msg`, if it deserves one.
+fn body(msg: &Message) -> Option<String> {
+    if let Some(text) = preview(msg) {
+        return Some(truncate(&text));
+    }
+    match msg {
+        Message::Image
+        | Message::Location
+        | Message::Video
+        | Message::Audio
+        | Message::GroupLocation
+        | Message::GroupImage
+        | Message::GroupVideo
+        | Message::GroupAudio
+        | Message::GroupFile => Some(format!("<{}>", msg.kind())),
+        _ => None,
+    }
+}
+
+fn truncate(text: &str) -> String {
+    match text.char_indices().nth(MAX_PREVIEW) {
+        Some((end, _)) => format!("{}…", &text[..end]),
+        None => text.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use threema::packets::{MessageStatus, Text};
+    use threema::MessageID;
+
+    #[test]
+    fn bodies() {
+        let text = |message: &str| {
+            Message::Text(Text {
+                message: message.to_owned(),
+            })
+        };
+        assert_eq!(body(&text("hi")), Some("hi".to_owned()));
+        let long = body(&text(&"ä".repeat(150))).unwrap();
+        assert_eq!(long, format!("{}…", "ä".repeat(MAX_PREVIEW)));
+        assert_eq!(body(&Message::Image), Some("<image>".to_owned()));
+        assert_eq!(body(&Message::TypingNotification), None);
+        assert_eq!(
+            body(&Message::DeliveryReceipt(
+                MessageStatus::Read,
+                MessageID::from_bytes([1; 8])
+            )),
+            None
+        );
+    }
+}
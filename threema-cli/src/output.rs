@@ -0,0 +1,278 @@
+//! Printing received messages and send results, for humans or as JSON lines.
+
+use crate::download;
+use crate::hook::Hook;
+use log::{error, info, warn};
+use serde_json::{json, Value};
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use threema::handler::{self, Handler};
+use threema::packets::{File, GroupText, Message, MessageStatus, Text};
+use threema::{Error, MessageID, ServerMessage, Threema, ThreemaID};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+impl Format {
+    /// Values accepted by `--output`.
+    pub const NAMES: [&'static str; 2] = ["text", "json"];
+
+    pub fn from_name(name: &str) -> Self {
+        if name == "json" {
+            Format::Json
+        } else {
+            Format::Text
+        }
+    }
+}
+
+/// Reports that the server accepted the message `mid` to `recipient`.
+pub fn sent(format: Format, recipient: ThreemaID, mid: MessageID) {
+    match format {
+        Format::Text => info!("Message processed by server"),
+        Format::Json => println!(
+            "{}",
+            json!({
+                "type": "sent",
+                "recipient": recipient.to_string(),
+                "msg_id": mid.to_string(),
+            })
+        ),
+    }
+}
+
+/// Reports that sending to `recipient` failed.
+pub fn failed(format: Format, recipient: &str, cause: &dyn Display) {
+    match format {
+        Format::Text => error!("Couldn't send to {recipient}: {cause}"),
+        Format::Json => println!(
+            "{}",
+            json!({
+                "type": "failed",
+                "recipient": recipient,
+                "error": cause.to_string(),
+            })
+        ),
+    }
+}
+
+/// The text of `msg` shown in short listings like the history, if it has any.
+#[cfg(any(feature = "sqlite", feature = "notify"))]
+pub fn preview(msg: &Message) -> Option<String> {
+    match msg {
+        Message::Text(text) => Some(text.message.clone()),
+        Message::GroupText(text) => Some(text.message.clone()),
+        Message::File(file) if file.description.is_empty() => Some(file.name.clone()),
+        Message::File(file) => Some(format!("{}: {}", file.name, file.description)),
+        _ => None,
+    }
+}
+
+/// Prints received messages.
+pub struct Printer {
+    pub format: Format,
+    /// where received files are saved, if at all
+    pub download_dir: Option<PathBuf>,
+    /// run for each message
+    pub exec: Option<Hook>,
+    /// raise a desktop notification for each message
+    #[cfg(feature = "notify")]
+    pub notify: bool,
+}
+
+impl Printer {
+    /// Saves `file` if a download directory is set.
+    fn save(&self, file: &File) -> Option<PathBuf> {
+        let dir = self.download_dir.as_ref()?;
+        download::save(dir, file)
+            .map_err(|e| error!("Couldn't download {}: {:?}", file.name, e))
+            .ok()
+    }
+
+    /// The line printed for `msg` in [`Format::Json`], `saved` is where an attached file
+    /// was saved.
+    fn json(msg: &ServerMessage, saved: Option<&Path>) -> Value {
+        let payload = match &msg.data {
+            Message::Text(text) => json!({ "text": text.message }),
+            Message::GroupText(text) => json!({
+                "creator": text.creator.to_string(),
+                "group_id": text.group_id.to_string(),
+                "text": text.message,
+            }),
+            Message::File(file) => json!({
+                "name": file.name,
+                "mime": file.mime,
+                "size": file.size,
+                "caption": file.description,
+                "saved": saved,
+            }),
+            Message::DeliveryReceipt(status, receipt_for) => json!({
+                "status": status,
+                "for": receipt_for.to_string(),
+            }),
+            other => match serde_json::to_value(other) {
+                // externally tagged, drop the name which is in `type` already
+                Ok(Value::Object(map)) if map.len() == 1 => {
+                    map.into_iter().next().map(|(_, v)| v).unwrap_or_default()
+                }
+                _ => Value::Null,
+            },
+        };
+        json!({
+            "type": msg.data.kind(),
+            "sender": msg.sender.to_string(),
+            "msg_id": msg.msg_id.to_string(),
+            "timestamp": unix_time(msg.sent),
+            "payload": payload,
+        })
+    }
+}
+
+fn print_file(sender: ThreemaID, mid: MessageID, file: &File, saved: Option<&Path>) {
+    let caption = if file.description.is_empty() {
+        String::new()
+    } else {
+        format!(" `{}`", file.description)
+    };
+    let saved = saved
+        .map(|path| format!(" => {}", path.display()))
+        .unwrap_or_default();
+    println!(
+        "{mid} [{sender}] <{} {}, {} bytes>{caption}{saved}",
+        file.name, file.mime, file.size
+    );
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+impl Handler for Printer {
+    fn on_message(&mut self, client: &mut Threema, msg: &ServerMessage) {
+        // saved once here, for the output and the hook
+        let saved = match &msg.data {
+            Message::File(file) => self.save(file),
+            _ => None,
+        };
+        match (self.format, &msg.data) {
+            (Format::Text, Message::File(file)) => {
+                print_file(msg.sender, msg.msg_id, file, saved.as_deref());
+            }
+            (Format::Text, _) => handler::dispatch(self, client, msg),
+            (Format::Json, _) => println!("{}", Self::json(msg, saved.as_deref())),
+        }
+        #[cfg(feature = "notify")]
+        if self.notify {
+            crate::notify::show(msg.sender, &msg.data);
+        }
+        if let Some(hook) = &self.exec {
+            match hook.run(&Self::json(msg, saved.as_deref())) {
+                Ok(status) if !status.success() => warn!("--exec command failed with {status}"),
+                Ok(_) => {}
+                Err(e) => error!("Couldn't run --exec command: {e:?}"),
+            }
+        }
+    }
+
+    fn on_text(&mut self, _: &mut Threema, sender: ThreemaID, mid: MessageID, text: &Text) {
+        println!("{mid} [{sender}] `{}`", text.message);
+    }
+
+    fn on_file(&mut self, _: &mut Threema, sender: ThreemaID, mid: MessageID, file: &File) {
+        print_file(sender, mid, file, self.save(file).as_deref());
+    }
+
+    fn on_group_text(
+        &mut self,
+        _: &mut Threema,
+        sender: ThreemaID,
+        mid: MessageID,
+        text: &GroupText,
+    ) {
+        println!(
+            "{mid} [{sender}@{}/{}] `{}`",
+            text.creator, text.group_id, text.message
+        );
+    }
+
+    fn on_receipt(
+        &mut self,
+        _: &mut Threema,
+        sender: ThreemaID,
+        status: &MessageStatus,
+        mid: MessageID,
+    ) {
+        println!("{mid} [{sender}] => {status:?}");
+    }
+
+    fn on_other(&mut self, _: &mut Threema, msg: &ServerMessage) {
+        println!("{} [{}] :: {:?}", msg.msg_id, msg.sender, msg.data);
+    }
+
+    fn on_message_error(
+        &mut self,
+        _: &mut Threema,
+        sender: ThreemaID,
+        mid: MessageID,
+        cause: &Error,
+    ) {
+        match self.format {
+            Format::Text => warn!("Dropped message {mid} from {sender}: {cause}"),
+            Format::Json => println!(
+                "{}",
+                json!({
+                    "type": "error",
+                    "sender": sender.to_string(),
+                    "msg_id": mid.to_string(),
+                    "error": cause.to_string(),
+                })
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn json_events() {
+        let mut msg = ServerMessage {
+            msg_id: MessageID::from_bytes([1; 8]),
+            sender: ThreemaID::from_string("ECHOECHO").unwrap(),
+            data: Message::Text(Text {
+                message: "hi".to_owned(),
+            }),
+            duplicate: false,
+            key_changed: None,
+            sent: UNIX_EPOCH + Duration::from_secs(1_600_000_000),
+            received: UNIX_EPOCH,
+        };
+        assert_eq!(
+            Printer::json(&msg, None),
+            json!({
+                "type": "text",
+                "sender": "ECHOECHO",
+                "msg_id": "0101010101010101",
+                "timestamp": 1_600_000_000,
+                "payload": {"text": "hi"},
+            })
+        );
+
+        msg.data = Message::DeliveryReceipt(MessageStatus::Read, MessageID::from_bytes([2; 8]));
+        assert_eq!(
+            Printer::json(&msg, None)["payload"],
+            json!({"status": "Read", "for": "0202020202020202"})
+        );
+        msg.data = Message::TypingNotification;
+        assert_eq!(Printer::json(&msg, None)["type"], "typing_notification");
+        assert_eq!(Printer::json(&msg, None)["payload"], Value::Null);
+    }
+}
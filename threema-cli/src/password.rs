@@ -0,0 +1,49 @@
+//! Getting the password of the ID export without putting it on the command line, where
+//! it would show up in `ps` and the shell history.
+
+use std::fs;
+use std::io::{self, IsTerminal};
+use std::path::Path;
+
+/// The first line of the file at `path`.
+pub fn from_file(path: &Path) -> io::Result<String> {
+    Ok(first_line(&fs::read_to_string(path)?).to_owned())
+}
+
+pub fn first_line(text: &str) -> &str {
+    text.lines().next().unwrap_or_default()
+}
+
+/// The password stored for `account` of the `threema-cli` service in the OS keyring, e.g.
+/// with `secret-tool store --label threema-cli service threema-cli username ACCOUNT`.
+#[cfg(feature = "keyring")]
+pub fn from_keyring(account: &str) -> io::Result<Option<String>> {
+    let entry = keyring::Entry::new("threema-cli", account).map_err(io::Error::other)?;
+    match entry.get_password() {
+        Ok(password) => Ok(Some(password)),
+        Err(keyring::Error::NoEntry) => Ok(None),
+        Err(e) => Err(io::Error::other(e)),
+    }
+}
+
+/// Asks for the password on the terminal, if there is one.
+pub fn prompt(prompt: &str) -> io::Result<Option<String>> {
+    if !io::stderr().is_terminal() {
+        return Ok(None);
+    }
+    rpassword::prompt_password(prompt).map(Some)
+}
+
+/// Asks for a new password twice, to catch typos.
+pub fn prompt_new() -> io::Result<Option<String>> {
+    let Some(password) = prompt("New password: ")? else {
+        return Ok(None);
+    };
+    if prompt("Repeat password: ")?.as_deref() != Some(&password) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "passwords don't match",
+        ));
+    }
+    Ok(Some(password))
+}
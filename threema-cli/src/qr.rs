@@ -0,0 +1,80 @@
+//! `qr`: QR codes to scan with the official apps.
+
+use crate::lookup::hex;
+use crate::output::Format;
+use image::Luma;
+use qrcode::render::unicode::Dense1x2;
+use qrcode::QrCode;
+use serde_json::json;
+use std::io;
+use std::path::Path;
+use threema::crypto::PublicKey;
+use threema::{Error, ThreemaID};
+
+/// What the apps show in "My ID": scanning it adds the identity as verified contact.
+pub fn contact_payload(id: ThreemaID, public_key: &PublicKey) -> String {
+    format!("3mid:{},{}", id, hex(&public_key.0))
+}
+
+fn code(data: &str) -> QrCode {
+    // anything up to ~2 KiB fits, far more than IDs and exports need
+    QrCode::new(data).expect("data fits into a QR code")
+}
+
+/// `data` as QR code drawn with block characters.
+pub fn terminal(data: &str) -> String {
+    // inverted, for the usual light text on dark background
+    code(data)
+        .render::<Dense1x2>()
+        .dark_color(Dense1x2::Light)
+        .light_color(Dense1x2::Dark)
+        .build()
+}
+
+/// Saves `data` as QR code to the PNG file `path`.
+pub fn save_png(data: &str, path: &Path) -> Result<(), Error> {
+    code(data)
+        .render::<Luma<u8>>()
+        .min_dimensions(256, 256)
+        .build()
+        .save(path)
+        .map_err(|e| Error::Io(io::Error::other(e)))
+}
+
+/// Shows the QR code for verifying `id`, and saves it to `out` if given.
+pub fn show(
+    format: Format,
+    id: ThreemaID,
+    public_key: &PublicKey,
+    out: Option<&Path>,
+) -> Result<(), Error> {
+    let payload = contact_payload(id, public_key);
+    if let Some(out) = out {
+        save_png(&payload, out)?;
+    }
+    match format {
+        Format::Text => println!("{}\n{payload}", terminal(&payload)),
+        Format::Json => println!(
+            "{}",
+            json!({ "id": id.to_string(), "payload": payload, "saved": out })
+        ),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn payload() {
+        let id = ThreemaID::from_string("ECHOECHO").unwrap();
+        assert_eq!(
+            contact_payload(id, &PublicKey([0xab; 32])),
+            format!("3mid:ECHOECHO,{}", "ab".repeat(32))
+        );
+        let image = terminal("3mid:ECHOECHO");
+        assert!(image.lines().count() > 10);
+        assert!(image.contains('\u{2588}'));
+    }
+}
@@ -0,0 +1,225 @@
+//! `send --wait-for`: waiting for the recipients' delivery receipts.
+
+use crate::output::Format;
+use log::warn;
+use serde_json::json;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use threema::handler::Handler;
+use threema::packets::MessageStatus;
+use threema::{Error, MessageID, Threema, ThreemaID};
+
+/// Exit code if some messages weren't even delivered before the timeout.
+pub const NOT_DELIVERED: i32 = 2;
+/// Exit code if all messages were delivered, but some not read before the timeout.
+pub const NOT_READ: i32 = 3;
+
+/// How far a message got, in the order the receipts are sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Progress {
+    Sent,
+    Delivered,
+    /// Read, or agreed or disagreed to, which means it was read as well
+    Read,
+}
+
+impl Progress {
+    /// Values accepted by `--wait-for`.
+    pub const NAMES: [&'static str; 2] = ["delivered", "read"];
+
+    pub fn from_name(name: &str) -> Self {
+        if name == "read" {
+            Progress::Read
+        } else {
+            Progress::Delivered
+        }
+    }
+
+    fn of(status: &MessageStatus) -> Self {
+        match status {
+            MessageStatus::Delivered => Progress::Delivered,
+            _ => Progress::Read,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Progress::Sent => "sent",
+            Progress::Delivered => "delivered",
+            Progress::Read => "read",
+        }
+    }
+}
+
+/// Reports the receipts of the messages sent, until all got to the wanted [`Progress`]
+/// or the time is up.
+pub struct Tracker {
+    format: Format,
+    wanted: Progress,
+    deadline: Instant,
+    /// recipient and progress of the messages still waited for
+    pending: HashMap<MessageID, (ThreemaID, Progress)>,
+}
+
+impl Tracker {
+    pub fn new(
+        format: Format,
+        wanted: Progress,
+        timeout: Duration,
+        sent: impl IntoIterator<Item = (MessageID, ThreemaID)>,
+    ) -> Self {
+        Tracker {
+            format,
+            wanted,
+            deadline: Instant::now() + timeout,
+            pending: sent
+                .into_iter()
+                .map(|(mid, recipient)| (mid, (recipient, Progress::Sent)))
+                .collect(),
+        }
+    }
+
+    /// Waits for the receipts and returns the exit code: 0 if all messages got to the
+    /// wanted progress, [`NOT_DELIVERED`] or [`NOT_READ`] otherwise.
+    pub fn run(mut self, threema: &mut Threema) -> Result<i32, Error> {
+        if !self.pending.is_empty() {
+            threema.run(&mut self)?;
+        }
+        Ok(self.report_missing())
+    }
+
+    /// Reports the messages still waited for, returns the exit code for them.
+    fn report_missing(&self) -> i32 {
+        let mut code = 0;
+        for (mid, (recipient, progress)) in &self.pending {
+            match self.format {
+                Format::Text => warn!(
+                    "Message {mid} to {recipient} not {} in time, only {}",
+                    self.wanted.name(),
+                    progress.name()
+                ),
+                Format::Json => println!(
+                    "{}",
+                    json!({
+                        "type": "timeout",
+                        "recipient": recipient.to_string(),
+                        "msg_id": mid.to_string(),
+                        "status": progress.name(),
+                    })
+                ),
+            }
+            code = code.max(if *progress < Progress::Delivered {
+                NOT_DELIVERED
+            } else {
+                NOT_READ
+            });
+        }
+        code
+    }
+
+    fn done(&self) -> bool {
+        self.pending.is_empty() || Instant::now() >= self.deadline
+    }
+
+    /// Notes that `sender` reported `status` for `mid`, returns whether it was news.
+    fn update(&mut self, sender: ThreemaID, status: &MessageStatus, mid: MessageID) -> bool {
+        let Some((recipient, progress)) = self.pending.get_mut(&mid) else {
+            return false;
+        };
+        let reported = Progress::of(status);
+        if *recipient != sender || reported <= *progress {
+            return false;
+        }
+        *progress = reported;
+        if reported >= self.wanted {
+            self.pending.remove(&mid);
+        }
+        true
+    }
+}
+
+impl Handler for Tracker {
+    fn on_receipt(
+        &mut self,
+        client: &mut Threema,
+        sender: ThreemaID,
+        status: &MessageStatus,
+        receipt_for: MessageID,
+    ) {
+        if !self.update(sender, status, receipt_for) {
+            return;
+        }
+        match self.format {
+            Format::Text => println!("{receipt_for} [{sender}] => {status:?}"),
+            Format::Json => println!(
+                "{}",
+                json!({
+                    "type": "receipt",
+                    "recipient": sender.to_string(),
+                    "msg_id": receipt_for.to_string(),
+                    "status": status,
+                })
+            ),
+        }
+        if self.done() {
+            client.disconnect();
+        }
+    }
+
+    fn tick_interval(&self) -> Option<Duration> {
+        Some(Duration::from_secs(1))
+    }
+
+    fn on_tick(&mut self, client: &mut Threema) {
+        if self.done() {
+            client.disconnect();
+        }
+    }
+
+    fn on_disconnect(&mut self, error: &Error) -> bool {
+        if self.done() {
+            return false;
+        }
+        warn!("Disconnected while waiting for receipts: {error}");
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn progress() {
+        let peer = ThreemaID::from_string("ECHOECHO").unwrap();
+        let other = ThreemaID::from_string("*TESTGW0").unwrap();
+        let (first, second) = (MessageID::from_bytes([1; 8]), MessageID::from_bytes([2; 8]));
+        let mut tracker = Tracker::new(
+            Format::Json,
+            Progress::Read,
+            Duration::from_mins(1),
+            [(first, peer), (second, peer)],
+        );
+        assert!(!tracker.update(other, &MessageStatus::Read, first));
+        assert!(tracker.update(peer, &MessageStatus::Delivered, first));
+        assert!(!tracker.update(peer, &MessageStatus::Delivered, first));
+        assert!(tracker.update(peer, &MessageStatus::Approved, first));
+        assert!(!tracker.update(peer, &MessageStatus::Read, first));
+        assert!(tracker.update(peer, &MessageStatus::Delivered, second));
+        assert!(!tracker.done());
+        assert_eq!(tracker.pending[&second], (peer, Progress::Delivered));
+        assert_eq!(tracker.report_missing(), NOT_READ);
+        assert!(tracker.update(peer, &MessageStatus::Read, second));
+        assert!(tracker.done());
+        assert_eq!(tracker.report_missing(), 0);
+
+        let tracker = Tracker::new(
+            Format::Json,
+            Progress::Delivered,
+            Duration::ZERO,
+            [(first, peer)],
+        );
+        assert!(tracker.done());
+        assert_eq!(tracker.report_missing(), NOT_DELIVERED);
+    }
+}
@@ -0,0 +1,114 @@
+//! `stats`: a summary of the history, for keeping an eye on gateways.
+
+use crate::output::Format;
+use serde_json::{json, Map, Value};
+use std::convert::TryFrom;
+use std::time::{Duration, UNIX_EPOCH};
+use threema::store::HistoryStats;
+
+/// `day` counted since the Unix epoch as date, e.g. `2024-05-01`.
+fn date(day: u64) -> String {
+    let time = UNIX_EPOCH + Duration::from_secs(day * 86_400);
+    humantime::format_rfc3339_seconds(time).to_string()[..10].to_owned()
+}
+
+fn json(stats: &HistoryStats) -> Value {
+    let peers: Vec<_> = stats
+        .peers
+        .iter()
+        .map(|peer| {
+            json!({
+                "peer": peer.peer.to_string(),
+                "sent": peer.sent,
+                "received": peer.received,
+            })
+        })
+        .collect();
+    let days: Map<_, _> = stats
+        .days
+        .iter()
+        .map(|(day, count)| (date(*day), json!(count)))
+        .collect();
+    json!({
+        "peers": peers,
+        "types": stats.kinds,
+        "days": days,
+        "acked": stats.acked,
+        "ack_latency_ms": stats
+            .ack_latency()
+            .map(|latency| u64::try_from(latency.as_millis()).unwrap_or(u64::MAX)),
+        "reconnects": stats.reconnects,
+        "failed_reconnects": stats.failed_reconnects,
+    })
+}
+
+pub fn print(format: Format, stats: &HistoryStats) {
+    if format == Format::Json {
+        println!("{}", json(stats));
+        return;
+    }
+    println!("Messages per peer:");
+    for peer in &stats.peers {
+        println!(
+            "  {}  {:>6} sent  {:>6} received",
+            peer.peer, peer.sent, peer.received
+        );
+    }
+    println!("Messages per type:");
+    for (kind, count) in &stats.kinds {
+        println!("  {kind:<20} {count:>6}");
+    }
+    println!("Messages per day:");
+    for (day, count) in &stats.days {
+        println!("  {}  {count:>6}", date(*day));
+    }
+    match stats.ack_latency() {
+        Some(latency) => println!(
+            "Acknowledged: {}, after {} on average",
+            stats.acked,
+            humantime::format_duration(latency)
+        ),
+        None => println!("Acknowledged: 0"),
+    }
+    println!(
+        "Reconnects: {} successful, {} failed",
+        stats.reconnects, stats.failed_reconnects
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use threema::store::PeerStats;
+    use threema::ThreemaID;
+
+    #[test]
+    fn summary() {
+        let mut stats = HistoryStats {
+            peers: vec![PeerStats {
+                peer: ThreemaID::from_string("ECHOECHO").unwrap(),
+                sent: 2,
+                received: 1,
+            }],
+            acked: 2,
+            ack_time: Duration::from_millis(500),
+            reconnects: 3,
+            ..HistoryStats::default()
+        };
+        stats.kinds.insert("text", 3);
+        stats.days.insert(19_844, 3);
+        assert_eq!(date(0), "1970-01-01");
+        assert_eq!(
+            json(&stats),
+            json!({
+                "peers": [{ "peer": "ECHOECHO", "sent": 2, "received": 1 }],
+                "types": { "text": 3 },
+                "days": { "2024-05-01": 3 },
+                "acked": 2,
+                "ack_latency_ms": 250,
+                "reconnects": 3,
+                "failed_reconnects": 0,
+            })
+        );
+    }
+}
@@ -0,0 +1,263 @@
+//! `verify`: comparing the key of a contact with the one shown in its app, and marking it
+//! as fully verified in the contact store.
+
+use crate::lookup::hex;
+use crate::output::Format;
+use log::warn;
+use serde_json::json;
+use std::convert::TryFrom;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use threema::contacts::{Contact, ContactStore, FileContactStore, VerificationLevel};
+use threema::crypto::PublicKey;
+use threema::directory::DirectoryClient;
+use threema::{Error, ThreemaID};
+
+/// Width and height of the [randomart] field, as in OpenSSH.
+const WIDTH: usize = 17;
+const HEIGHT: usize = 9;
+/// Symbols for the number of visits of a randomart field, the last two mark the start
+/// and end of the walk.
+const SYMBOLS: &[u8] = b" .o+=*BOX@%&#/^SE";
+
+/// 64 distinct emoji, one for every 6 bits of a fingerprint.
+const EMOJI: [char; 64] = [
+    '🐶', '🐱', '🐭', '🐹', '🐰', '🦊', '🐻', '🐼', '🐨', '🐯', '🦁', '🐮', '🐷', '🐸', '🐵', '🐔',
+    '🐧', '🐦', '🐤', '🦆', '🦅', '🦉', '🦇', '🐺', '🐗', '🐴', '🦄', '🐝', '🐛', '🦋', '🐌', '🐞',
+    '🐢', '🐍', '🦎', '🐙', '🦑', '🦀', '🐡', '🐠', '🐬', '🐳', '🦈', '🐊', '🐅', '🐆', '🦓', '🐘',
+    '🦏', '🐪', '🦒', '🦘', '🐃', '🐎', '🐑', '🐐', '🦌', '🐕', '🐈', '🐓', '🦃', '🦜', '🦢', '🐇',
+];
+/// Number of emoji shown, covering the first 48 bits of the fingerprint.
+const EMOJI_COUNT: usize = 8;
+
+/// Opens the contacts at `path`, creating their directory if necessary.
+pub fn open(path: &Path) -> Result<FileContactStore, Error> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    FileContactStore::open(path)
+}
+
+/// How far the key of `id` in `contacts` is verified, if it is `public_key`.
+pub fn level(
+    contacts: &dyn ContactStore,
+    id: ThreemaID,
+    public_key: &PublicKey,
+) -> VerificationLevel {
+    match contacts.get(id) {
+        Some(contact) if contact.public_key == *public_key => contact.verification,
+        _ => VerificationLevel::Unverified,
+    }
+}
+
+/// `level` for people, `fully verified` instead of `fully_verified`.
+pub fn describe(level: VerificationLevel) -> String {
+    level.as_str().replace('_', " ")
+}
+
+/// The "drunken bishop" drawing of `fingerprint` known from `ssh-keygen`, with `title`
+/// in the top border.
+pub fn randomart(fingerprint: &[u8], title: &str) -> String {
+    let mut field = [[0_usize; WIDTH]; HEIGHT];
+    let (mut x, mut y) = (WIDTH / 2, HEIGHT / 2);
+    let start = (x, y);
+    for byte in fingerprint {
+        let mut bits = *byte;
+        for _ in 0..4 {
+            x = if bits & 1 == 0 {
+                x.saturating_sub(1)
+            } else {
+                (x + 1).min(WIDTH - 1)
+            };
+            y = if bits & 2 == 0 {
+                y.saturating_sub(1)
+            } else {
+                (y + 1).min(HEIGHT - 1)
+            };
+            field[y][x] += 1;
+            bits >>= 2;
+        }
+    }
+    let mut art = format!("+{:-^WIDTH$}+\n", format!("[{title}]"));
+    for (row, cells) in field.iter().enumerate() {
+        art.push('|');
+        for (column, visits) in cells.iter().enumerate() {
+            let symbol = if (column, row) == (x, y) {
+                SYMBOLS.len() - 1
+            } else if (column, row) == start {
+                SYMBOLS.len() - 2
+            } else {
+                (*visits).min(SYMBOLS.len() - 3)
+            };
+            art.push(char::from(SYMBOLS[symbol]));
+        }
+        art.push_str("|\n");
+    }
+    art.push('+');
+    art.push_str(&"-".repeat(WIDTH));
+    art.push('+');
+    art
+}
+
+/// The start of `fingerprint` as emoji, easier to compare aloud than hex digits.
+pub fn emoji(fingerprint: &[u8]) -> String {
+    let mut bits = fingerprint
+        .iter()
+        .take(EMOJI_COUNT * 6 / 8)
+        .fold(0_u64, |bits, byte| bits << 8 | u64::from(*byte));
+    let mut emoji: Vec<_> = (0..EMOJI_COUNT)
+        .map(|_| {
+            let index = usize::try_from(bits & 63).expect("6 bits fit into usize");
+            bits >>= 6;
+            EMOJI[index]
+        })
+        .collect();
+    emoji.reverse();
+    emoji.into_iter().collect()
+}
+
+/// Shows the key of `id` in the directory along with its fingerprint, and marks it as
+/// fully verified in `contacts` if `confirm` agrees. Returns the verification level
+/// afterwards, `None` if the directory doesn't know `id`.
+pub fn verify(
+    format: Format,
+    directory: &dyn DirectoryClient,
+    contacts: &mut dyn ContactStore,
+    id: ThreemaID,
+    confirm: impl FnOnce() -> io::Result<bool>,
+) -> Result<Option<VerificationLevel>, Error> {
+    let Some(entry) = directory.fetch_identity(id)? else {
+        return Ok(None);
+    };
+    let public_key = entry.public_key;
+    if contacts
+        .get(id)
+        .is_some_and(|contact| contact.public_key != public_key)
+    {
+        warn!("The key of {id} in the directory differs from the one pinned for the contact");
+    }
+    let level = level(contacts, id, &public_key);
+    let fingerprint = public_key.fingerprint();
+    match format {
+        Format::Text => {
+            println!("ID:           {id}");
+            println!("Public key:   {}", public_key.to_hex());
+            println!("Fingerprint:  {}", hex(&fingerprint));
+            println!("Emoji:        {}", emoji(&fingerprint));
+            println!("Verification: {}", describe(level));
+            println!("{}", randomart(&fingerprint, id.as_str()));
+        }
+        Format::Json => println!(
+            "{}",
+            json!({
+                "id": id.to_string(),
+                "public_key": public_key.to_hex(),
+                "fingerprint": hex(&fingerprint),
+                "emoji": emoji(&fingerprint),
+                "verification": level.as_str(),
+            })
+        ),
+    }
+    if level == VerificationLevel::FullyVerified || !confirm()? {
+        return Ok(Some(level));
+    }
+    let mut contact = contacts
+        .get(id)
+        .unwrap_or_else(|| Contact::new(id, public_key));
+    contact.public_key = public_key;
+    contact.verification = VerificationLevel::FullyVerified;
+    contacts.put(contact);
+    match format {
+        Format::Text => println!("Marked {id} as fully verified"),
+        Format::Json => println!(
+            "{}",
+            json!({
+                "id": id.to_string(),
+                "verification": VerificationLevel::FullyVerified.as_str(),
+            })
+        ),
+    }
+    Ok(Some(VerificationLevel::FullyVerified))
+}
+
+/// Asks on the terminal whether the fingerprint matches the one in the contact's app.
+pub fn ask() -> io::Result<bool> {
+    eprint!("Does this match what the contact's app shows? Mark as fully verified [y/N] ");
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use threema::contacts::MemoryContactStore;
+    use threema::directory::MemoryDirectory;
+
+    #[test]
+    fn representations() {
+        let fingerprint = PublicKey([1; 32]).fingerprint();
+        let art = randomart(&fingerprint, "ECHOECHO");
+        let lines: Vec<_> = art.lines().collect();
+        assert_eq!(lines.len(), HEIGHT + 2);
+        assert_eq!(lines[0], "+---[ECHOECHO]----+");
+        assert!(lines.iter().all(|line| line.len() == WIDTH + 2));
+        assert_eq!(art.matches('S').count(), 1);
+        assert_eq!(randomart(&fingerprint, "ECHOECHO"), art);
+        assert_ne!(
+            randomart(&PublicKey([2; 32]).fingerprint(), "ECHOECHO"),
+            art
+        );
+
+        assert_eq!(emoji(&[0; 16]), "🐶".repeat(EMOJI_COUNT));
+        assert_eq!(emoji(&[0, 0, 0, 0, 0, 0x41]), "🐶🐶🐶🐶🐶🐶🐱🐱");
+        assert_eq!(emoji(&fingerprint).chars().count(), EMOJI_COUNT);
+    }
+
+    #[test]
+    fn marking() {
+        let id = ThreemaID::from_string("ECHOECHO").unwrap();
+        let mut directory = MemoryDirectory::new();
+        directory.insert(id, PublicKey([1; 32]), 0);
+        let mut contacts = MemoryContactStore::new();
+
+        let unknown = ThreemaID::from_string("*TESTGW0").unwrap();
+        let verified = |contacts: &mut MemoryContactStore, id, answer| {
+            verify(Format::Json, &directory, contacts, id, || Ok(answer)).unwrap()
+        };
+        assert_eq!(verified(&mut contacts, unknown, true), None);
+        assert_eq!(
+            verified(&mut contacts, id, false),
+            Some(VerificationLevel::Unverified)
+        );
+        assert_eq!(contacts.get(id), None);
+        assert_eq!(
+            verified(&mut contacts, id, true),
+            Some(VerificationLevel::FullyVerified)
+        );
+        assert_eq!(
+            level(&contacts, id, &PublicKey([1; 32])),
+            VerificationLevel::FullyVerified
+        );
+        assert_eq!(
+            level(&contacts, id, &PublicKey([2; 32])),
+            VerificationLevel::Unverified
+        );
+
+        // a new key replaces the pinned one once verified
+        let mut contact = contacts.get(id).unwrap();
+        contact.public_key = PublicKey([2; 32]);
+        contact.nickname = Some("Echo".to_owned());
+        contacts.put(contact);
+        assert_eq!(
+            verified(&mut contacts, id, true),
+            Some(VerificationLevel::FullyVerified)
+        );
+        let contact = contacts.get(id).unwrap();
+        assert_eq!(contact.public_key, PublicKey([1; 32]));
+        assert_eq!(contact.nickname.as_deref(), Some("Echo"));
+        assert_eq!(describe(contact.verification), "fully verified");
+    }
+}